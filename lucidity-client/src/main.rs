@@ -1,15 +1,25 @@
 use anyhow::{anyhow, Context};
 use clap::{Parser, Subcommand};
-use lucidity_host::{TYPE_JSON, TYPE_PANE_INPUT, TYPE_PANE_OUTPUT};
-use lucidity_pairing::{Keypair, PairingPayload, PairingRequest, PairingResponse};
+use lucidity_host::{
+    TYPE_JSON, TYPE_PANE_INPUT, TYPE_PANE_OUTPUT, TYPE_PANE_SNAPSHOT, TYPE_SEALED,
+};
+use lucidity_pairing::{
+    Keypair, PairingPayload, PairingRequest, PairingResponse, PublicKey, Signature,
+};
 use lucidity_proto::frame::{encode_frame, Frame, FrameDecoder};
+use futures_util::{SinkExt, StreamExt};
+use lucidity_proto::secure::{
+    decode_ephemeral_key, device_auth_message, encode_ephemeral_key, FrameOpener, FrameSealer,
+    KeyExchange, Role, Transcript,
+};
 use serde::{Deserialize, Serialize};
 use std::fs;
 use std::io::{Read, Write};
 use std::net::{SocketAddr, TcpStream};
-use std::path::PathBuf;
-use std::sync::{Arc, Mutex};
+use std::path::{Path, PathBuf};
+use std::sync::{mpsc, Arc, Mutex};
 use std::thread;
+use tokio_tungstenite::tungstenite::Message;
 
 #[derive(Debug, Parser)]
 #[command(about = "Lucidity test client")]
@@ -43,15 +53,36 @@ enum Command {
         #[arg(long)]
         addr: Option<String>,
     },
+    /// List hosts advertising themselves on the LAN
+    Discover {
+        /// Only show the host this identity is paired with
+        #[arg(long)]
+        identity: Option<PathBuf>,
+
+        /// How long to listen for, in seconds
+        #[arg(long, default_value_t = 3)]
+        timeout: u64,
+    },
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 struct ClientIdentity {
     mobile_keypair: String, // Base64 encoded keypair
     desktop_public_key: String, // Base64 encoded public key
     relay_id: String,
     lan_addr: Option<String>,
     external_addr: Option<String>,
+    /// Every address from the pairing payload, best first
+    #[serde(default)]
+    candidates: Vec<String>,
+    /// Relay to fall back on when no address answers
+    #[serde(default)]
+    relay_url: Option<String>,
+    #[serde(default)]
+    relay_secret: Option<String>,
+    /// SHA-256 fingerprint the relay's certificate must have
+    #[serde(default)]
+    relay_cert_sha256: Option<String>,
     paired_at: i64,
 }
 
@@ -68,6 +99,7 @@ enum JsonRequest {
     AuthResponse {
         public_key: String,
         signature: String,
+        ephemeral_key: String,
     },
 }
 
@@ -86,17 +118,31 @@ enum JsonResponse {
     AuthChallenge {
         nonce: String,
     },
-    AuthSuccess,
+    AuthSuccess {
+        ephemeral_key: Option<String>,
+        key_signature: Option<String>,
+    },
+    SessionToken {
+        token: String,
+    },
     Error {
         message: String,
     },
 }
 
-fn read_one_frame(stream: &mut TcpStream, dec: &mut FrameDecoder) -> anyhow::Result<Frame> {
+fn read_one_frame(
+    stream: &mut dyn Read,
+    dec: &mut FrameDecoder,
+    opener: &mut Option<FrameOpener>,
+) -> anyhow::Result<Frame> {
     let mut buf = [0u8; 64 * 1024];
     loop {
         if let Some(frame) = dec.next_frame()? {
-            return Ok(frame);
+            return match (opener.as_mut(), frame.typ) {
+                (Some(opener), TYPE_SEALED) => Ok(opener.open(&frame.payload)?),
+                (Some(_), typ) => Err(anyhow!("host sent plaintext frame type {typ}")),
+                (None, _) => Ok(frame),
+            };
         }
         let n = stream.read(&mut buf)?;
         if n == 0 {
@@ -106,19 +152,37 @@ fn read_one_frame(stream: &mut TcpStream, dec: &mut FrameDecoder) -> anyhow::Res
     }
 }
 
-fn send_json(stream: &mut dyn Write, req: &JsonRequest) -> anyhow::Result<()> {
-    let payload = serde_json::to_vec(req)?;
-    stream.write_all(&encode_frame(TYPE_JSON, &payload))?;
+fn send_frame(
+    stream: &mut dyn Write,
+    sealer: &mut Option<FrameSealer>,
+    typ: u8,
+    payload: &[u8],
+) -> anyhow::Result<()> {
+    let bytes = match sealer.as_mut() {
+        Some(sealer) => encode_frame(TYPE_SEALED, &sealer.seal(typ, payload)?),
+        None => encode_frame(typ, payload),
+    };
+    stream.write_all(&bytes)?;
     stream.flush().ok();
     Ok(())
 }
 
+fn send_json(
+    stream: &mut dyn Write,
+    sealer: &mut Option<FrameSealer>,
+    req: &JsonRequest,
+) -> anyhow::Result<()> {
+    let payload = serde_json::to_vec(req)?;
+    send_frame(stream, sealer, TYPE_JSON, &payload)
+}
+
 fn expect_json_response(
-    stream: &mut TcpStream,
+    stream: &mut dyn Read,
     dec: &mut FrameDecoder,
+    opener: &mut Option<FrameOpener>,
 ) -> anyhow::Result<JsonResponse> {
     loop {
-        let frame = read_one_frame(stream, dec)?;
+        let frame = read_one_frame(stream, dec, opener)?;
         if frame.typ == TYPE_JSON {
             return Ok(serde_json::from_slice(&frame.payload)?);
         }
@@ -162,9 +226,9 @@ fn perform_pair(uri: String, identity_path: PathBuf) -> anyhow::Result<()> {
     let mut dec = FrameDecoder::new();
 
     let mobile_keypair = Keypair::generate();
-    let request = PairingRequest::new(
+    let request = PairingRequest::for_payload(
         &mobile_keypair,
-        &payload.desktop_public_key,
+        &payload,
         "mock-client@localhost".to_string(),
         "Mock Client (Rust)".to_string(),
     );
@@ -172,21 +236,26 @@ fn perform_pair(uri: String, identity_path: PathBuf) -> anyhow::Result<()> {
     println!("Submitting pairing request...");
     send_json(
         &mut stream,
+        &mut None,
         &JsonRequest::PairingSubmit {
             request: request.clone(),
         },
     )?;
 
-    match expect_json_response(&mut stream, &mut dec)? {
+    match expect_json_response(&mut stream, &mut dec, &mut None)? {
         JsonResponse::PairingResponse { response } => {
             if response.approved {
                 println!("✅ Pairing APPROVED!");
                 let identity = ClientIdentity {
                     mobile_keypair: to_base64(&mobile_keypair.to_bytes()),
                     desktop_public_key: payload.desktop_public_key.to_base64(),
+                    candidates: payload.candidate_addrs(),
                     relay_id: payload.relay_id,
                     lan_addr: payload.lan_addr,
                     external_addr: payload.external_addr,
+                    relay_url: payload.relay_url,
+                    relay_secret: payload.relay_secret,
+                    relay_cert_sha256: payload.relay_cert_sha256,
                     paired_at: chrono::Utc::now().timestamp(),
                 };
                 let json = serde_json::to_string_pretty(&identity)?;
@@ -206,6 +275,191 @@ fn perform_pair(uri: String, identity_path: PathBuf) -> anyhow::Result<()> {
     Ok(())
 }
 
+/// Connect to the first of `addrs` that answers
+fn connect_first(addrs: &[String]) -> anyhow::Result<TcpStream> {
+    let mut last_err = anyhow!("No address known");
+    for addr in addrs {
+        println!("Connecting to {}...", addr);
+        let resolved = match addr.parse::<SocketAddr>() {
+            Ok(resolved) => resolved,
+            Err(e) => {
+                last_err = anyhow!("bad address {}: {}", addr, e);
+                continue;
+            }
+        };
+        match TcpStream::connect_timeout(&resolved, std::time::Duration::from_secs(5)) {
+            Ok(stream) => return Ok(stream),
+            Err(e) => {
+                println!("  {}", e);
+                last_err = anyhow!(e).context(format!("connect {}", addr));
+            }
+        }
+    }
+    Err(last_err)
+}
+
+/// Reads what the relay delivers, as from a TCP stream
+struct RelayReader {
+    rx: mpsc::Receiver<Vec<u8>>,
+    buf: Vec<u8>,
+    pos: usize,
+}
+
+impl Read for RelayReader {
+    fn read(&mut self, out: &mut [u8]) -> std::io::Result<usize> {
+        while self.pos == self.buf.len() {
+            match self.rx.recv() {
+                Ok(bytes) => {
+                    self.buf = bytes;
+                    self.pos = 0;
+                }
+                Err(_) => return Ok(0),
+            }
+        }
+        let n = out.len().min(self.buf.len() - self.pos);
+        out[..n].copy_from_slice(&self.buf[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+/// Sends each write to the relay as one message
+struct RelayWriter(tokio::sync::mpsc::UnboundedSender<Vec<u8>>);
+
+impl Write for RelayWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0
+            .send(buf.to_vec())
+            .map_err(|_| std::io::Error::from(std::io::ErrorKind::BrokenPipe))?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Reach the host through its relay. The relay's certificate is checked
+/// against `relay_cert_sha256`, if the pairing gave one, before anything
+/// is sent.
+fn connect_relay(id: &ClientIdentity, relay_url: &str) -> anyhow::Result<(RelayReader, RelayWriter)> {
+    println!("Connecting through the relay at {}...", relay_url);
+    let relay_url = relay_url.to_string();
+    let relay_id = id.relay_id.clone();
+    let secret = id.relay_secret.clone();
+    let cert_sha256 = id.relay_cert_sha256.clone();
+    let (connected_tx, connected_rx) = mpsc::channel::<anyhow::Result<()>>();
+    let (in_tx, in_rx) = mpsc::channel();
+    let (out_tx, mut out_rx) = tokio::sync::mpsc::unbounded_channel::<Vec<u8>>();
+
+    thread::spawn(move || {
+        let rt = match tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+        {
+            Ok(rt) => rt,
+            Err(e) => {
+                connected_tx.send(Err(e.into())).ok();
+                return;
+            }
+        };
+        rt.block_on(async move {
+            let connected = lucidity_host::connect_mobile(
+                &relay_url,
+                &relay_id,
+                secret.as_deref(),
+                cert_sha256.as_deref(),
+            )
+            .await;
+            let ws = match connected {
+                Ok(ws) => {
+                    connected_tx.send(Ok(())).ok();
+                    ws
+                }
+                Err(e) => {
+                    connected_tx.send(Err(e)).ok();
+                    return;
+                }
+            };
+            let (mut ws_tx, mut ws_rx) = ws.split();
+            loop {
+                tokio::select! {
+                    msg = ws_rx.next() => match msg {
+                        Some(Ok(Message::Binary(data))) => {
+                            if in_tx.send(data).is_err() {
+                                break;
+                            }
+                        }
+                        Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                        Some(Ok(_)) => {}
+                    },
+                    bytes = out_rx.recv() => match bytes {
+                        Some(bytes) => {
+                            if ws_tx.send(Message::Binary(bytes)).await.is_err() {
+                                break;
+                            }
+                        }
+                        None => break,
+                    },
+                }
+            }
+        });
+    });
+
+    connected_rx
+        .recv()
+        .map_err(|_| anyhow!("relay connection thread ended"))??;
+    let reader = RelayReader {
+        rx: in_rx,
+        buf: vec![],
+        pos: 0,
+    };
+    Ok((reader, RelayWriter(out_tx)))
+}
+
+const LAN_DISCOVERY_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(3);
+
+/// Save addresses the host was found at, to try first next time
+fn remember_lan_addrs(
+    identity_path: &Path,
+    mut id: ClientIdentity,
+    found: Vec<String>,
+) -> anyhow::Result<()> {
+    id.lan_addr = found.first().cloned();
+    id.candidates.retain(|addr| !found.contains(addr));
+    id.candidates.splice(0..0, found);
+    fs::write(identity_path, serde_json::to_string_pretty(&id)?)
+        .with_context(|| format!("writing {:?}", identity_path))
+}
+
+fn perform_discover(identity_path: Option<PathBuf>, timeout: u64) -> anyhow::Result<()> {
+    let timeout = std::time::Duration::from_secs(timeout);
+    let hosts = match identity_path {
+        Some(path) => {
+            let json = fs::read_to_string(&path).with_context(|| format!("reading {:?}", path))?;
+            let id: ClientIdentity = serde_json::from_str(&json)?;
+            let key = PublicKey::from_base64(&id.desktop_public_key)?;
+            lucidity_host::find_host(&key, timeout)?
+                .into_iter()
+                .collect()
+        }
+        None => lucidity_host::browse_hosts(timeout)?,
+    };
+    if hosts.is_empty() {
+        println!("No hosts found");
+    }
+    for host in hosts {
+        let addrs: Vec<String> = host.addrs.iter().map(|a| a.to_string()).collect();
+        println!(
+            "{} (key {}) at {}",
+            host.relay_id,
+            host.fingerprint,
+            addrs.join(", ")
+        );
+    }
+    Ok(())
+}
+
 fn perform_connect(
     identity_path: PathBuf,
     pane_id: Option<usize>,
@@ -217,45 +471,111 @@ fn perform_connect(
     
     let key_bytes = from_base64_32(&id.mobile_keypair)?;
     let keypair = Keypair::from_bytes(&key_bytes);
+    let desktop_public_key = PublicKey::from_base64(&id.desktop_public_key)?;
 
-    let addr = addr_override
-        .or(id.lan_addr)
-        .ok_or_else(|| anyhow!("No LAN address known"))?;
-
-    println!("Connecting to {}...", addr);
-    let mut stream = TcpStream::connect(addr)?;
+    let (addrs, may_discover) = match addr_override {
+        Some(addr) => (vec![addr], false),
+        None if !id.candidates.is_empty() => (id.candidates.clone(), true),
+        None => (id.lan_addr.iter().cloned().collect(), true),
+    };
+    let relay_url = id.relay_url.clone().filter(|_| may_discover);
+    let direct = match connect_first(&addrs) {
+        Ok(stream) => Ok(stream),
+        Err(e) if may_discover => {
+            // Our addresses may be stale; look for the host by its key
+            println!("Looking for the host on the LAN...");
+            match lucidity_host::find_host(&desktop_public_key, LAN_DISCOVERY_TIMEOUT) {
+                Ok(Some(host)) => {
+                    let found: Vec<String> = host.addrs.iter().map(|a| a.to_string()).collect();
+                    connect_first(&found).and_then(|stream| {
+                        remember_lan_addrs(&identity_path, id.clone(), found)?;
+                        Ok(stream)
+                    })
+                }
+                Ok(None) => Err(e),
+                Err(e) => Err(e),
+            }
+        }
+        Err(e) => Err(e),
+    };
+    let (mut reader, mut writer): (Box<dyn Read + Send>, Box<dyn Write + Send>) = match direct {
+        Ok(stream) => (Box::new(stream.try_clone()?), Box::new(stream)),
+        Err(e) => match &relay_url {
+            Some(relay_url) => {
+                println!("  {:#}", e);
+                let (reader, writer) = connect_relay(&id, relay_url)?;
+                (Box::new(reader), Box::new(writer))
+            }
+            None => return Err(e),
+        },
+    };
     let mut dec = FrameDecoder::new();
+    let mut sealer = None;
+    let mut opener = None;
 
     // 1. Wait for Auth Challenge (or success if localhost shortcut is active, but we shouldn't rely on it)
-    let challenge = match expect_json_response(&mut stream, &mut dec)? {
+    let challenge = match expect_json_response(&mut reader, &mut dec, &mut opener)? {
         JsonResponse::AuthChallenge { nonce } => nonce,
         JsonResponse::Error { message } => return Err(anyhow!("Connect error: {}", message)),
         other => return Err(anyhow!("Expected AuthChallenge, got {:?}", other)),
     };
 
-    // 2. Respond
-    let signature = keypair.sign(challenge.as_bytes());
+    // 2. Respond, offering an ephemeral key so the session is end-to-end encrypted
+    let kx = KeyExchange::new();
+    let device_ephemeral = kx.public_key();
+    let signature = keypair.sign(&device_auth_message(&challenge, &device_ephemeral));
     send_json(
-        &mut stream,
+        &mut writer,
+        &mut sealer,
         &JsonRequest::AuthResponse {
             public_key: keypair.public_key().to_base64(),
             signature: signature.to_base64(),
+            ephemeral_key: encode_ephemeral_key(&device_ephemeral),
         },
     )?;
 
-    // 3. Wait for success
-    match expect_json_response(&mut stream, &mut dec)? {
-        JsonResponse::AuthSuccess => println!("✅ Authenticated"),
+    // 3. Wait for success and check the host signed our key exchange
+    match expect_json_response(&mut reader, &mut dec, &mut opener)? {
+        JsonResponse::AuthSuccess {
+            ephemeral_key: Some(host_ephemeral),
+            key_signature: Some(key_signature),
+        } => {
+            let host_ephemeral = decode_ephemeral_key(&host_ephemeral)?;
+            let transcript = Transcript::new(
+                &challenge,
+                &keypair.public_key(),
+                &desktop_public_key,
+                &device_ephemeral,
+                &host_ephemeral,
+            );
+            desktop_public_key
+                .verify(transcript.as_bytes(), &Signature::from_base64(&key_signature)?)
+                .context("host key exchange signature")?;
+            let (s, o) = kx.finish(Role::Device, &host_ephemeral, &transcript)?;
+            sealer = Some(s);
+            opener = Some(o);
+            println!("✅ Authenticated (end-to-end encrypted)");
+        }
+        JsonResponse::AuthSuccess { .. } => {
+            return Err(anyhow!("Host did not complete the key exchange"))
+        }
         JsonResponse::Error { message } => return Err(anyhow!("Auth failed: {}", message)),
         other => return Err(anyhow!("Expected AuthSuccess, got {:?}", other)),
     }
 
+    // The resume token is the first sealed frame. This client doesn't
+    // resume, so it only checks the token arrived.
+    match expect_json_response(&mut reader, &mut dec, &mut opener)? {
+        JsonResponse::SessionToken { .. } => {}
+        other => return Err(anyhow!("Expected SessionToken, got {:?}", other)),
+    }
+
     // 4. List/Attach
     let pane_id = if let Some(p) = pane_id {
         p
     } else {
-        send_json(&mut stream, &JsonRequest::ListPanes)?;
-        let resp = expect_json_response(&mut stream, &mut dec)?;
+        send_json(&mut writer, &mut sealer, &JsonRequest::ListPanes)?;
+        let resp = expect_json_response(&mut reader, &mut dec, &mut opener)?;
         if let JsonResponse::ListPanes { panes } = resp {
             eprintln!("Panes:");
             for p in &panes {
@@ -270,16 +590,15 @@ fn perform_connect(
         }
     };
 
-    send_json(&mut stream, &JsonRequest::Attach { pane_id })?;
-    match expect_json_response(&mut stream, &mut dec)? {
+    send_json(&mut writer, &mut sealer, &JsonRequest::Attach { pane_id })?;
+    match expect_json_response(&mut reader, &mut dec, &mut opener)? {
         JsonResponse::AttachOk { pane_id: p } => eprintln!("Attached to pane {p}"),
         JsonResponse::Error { message } => return Err(anyhow!("Attach error: {message}")),
         other => return Err(anyhow!("Unexpected response: {other:?}")),
     }
 
     // 5. Pipe I/O
-    let read_stream = stream.try_clone()?;
-    let write_stream = Arc::new(Mutex::new(stream));
+    let write_stream = Arc::new(Mutex::new((writer, sealer)));
 
     thread::spawn(move || {
         let mut stdin = std::io::stdin();
@@ -290,20 +609,24 @@ fn perform_connect(
                 Ok(n) => n,
                 Err(_) => break,
             };
-            let frame = encode_frame(TYPE_PANE_INPUT, &buf[..n]);
             let mut w = write_stream.lock().unwrap();
-            if w.write_all(&frame).is_err() {
+            let (writer, sealer) = &mut *w;
+            if send_frame(writer, sealer, TYPE_PANE_INPUT, &buf[..n]).is_err() {
                 break;
             }
-            w.flush().ok();
         }
     });
 
     let mut out = std::io::stdout();
-    let mut reader = read_stream;
     loop {
-        let frame = read_one_frame(&mut reader, &mut dec)?;
+        let frame = read_one_frame(&mut reader, &mut dec, &mut opener)?;
         match frame.typ {
+            TYPE_PANE_SNAPSHOT => {
+                let snapshot: lucidity_proto::protocol::PaneSnapshot =
+                    serde_json::from_slice(&frame.payload)?;
+                out.write_all(snapshot.to_vt().as_bytes())?;
+                out.flush().ok();
+            }
             TYPE_PANE_OUTPUT => {
                 out.write_all(&frame.payload)?;
                 out.flush().ok();
@@ -331,5 +654,6 @@ fn main() -> anyhow::Result<()> {
             pane_id,
             addr,
         } => perform_connect(identity, pane_id, addr),
+        Command::Discover { identity, timeout } => perform_discover(identity, timeout),
     }
 }
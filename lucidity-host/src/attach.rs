@@ -0,0 +1,223 @@
+//! The panes one connection is attached to.
+//!
+//! A connection may hold one unaddressed attachment, which receives
+//! `TYPE_PANE_INPUT` and (in raw mode) sends `TYPE_PANE_OUTPUT`, plus any
+//! number of addressed ones whose frames carry the pane id. Each attachment
+//! has its own output pump: raw output is forwarded by the resumable session,
+//! cell-mode updates by a screen pump that stops on `Detach` or when the
+//! connection ends.
+
+use crate::bridge::PaneBridge;
+use crate::cells::{spawn_screen_pump, ScreenStream};
+use crate::protocol::TYPE_PANE_SNAPSHOT;
+use crate::resume::{ResumedPanes, SessionHandle};
+use anyhow::anyhow;
+use lucidity_proto::protocol::{JsonResponse, StreamMode};
+use mux::pane::PaneId;
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+struct ScreenAttachment {
+    stream: Arc<ScreenStream>,
+    stop: Arc<AtomicBool>,
+}
+
+#[derive(Default)]
+pub(crate) struct Attachments {
+    /// Target of unaddressed input
+    unaddressed: Option<PaneId>,
+    addressed: HashSet<PaneId>,
+    screens: HashMap<PaneId, ScreenAttachment>,
+}
+
+impl Attachments {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn is_attached(&self, pane_id: PaneId) -> bool {
+        self.unaddressed == Some(pane_id) || self.addressed.contains(&pane_id)
+    }
+
+    /// Attach to `pane_id` and write `AttachOk`, followed by the snapshot in
+    /// raw mode.
+    pub fn attach(
+        &mut self,
+        bridge: &Arc<dyn PaneBridge>,
+        session: &SessionHandle,
+        pane_id: PaneId,
+        scrollback: Option<usize>,
+        mode: StreamMode,
+        addressed: bool,
+    ) -> anyhow::Result<()> {
+        if self.is_attached(pane_id) {
+            return Err(anyhow!("already attached to pane {pane_id}"));
+        }
+        if !addressed && self.unaddressed.is_some() {
+            return Err(anyhow!("already attached"));
+        }
+
+        let writer = session.writer();
+        match mode {
+            StreamMode::Cells => {
+                let sub = bridge.subscribe_output(pane_id)?;
+                let stream = Arc::new(ScreenStream::new(pane_id));
+                let stop = Arc::new(AtomicBool::new(false));
+                writer
+                    .lock()
+                    .unwrap()
+                    .write_json(&JsonResponse::AttachOk { pane_id })?;
+                spawn_screen_pump(
+                    Arc::clone(bridge),
+                    Arc::clone(&stream),
+                    sub,
+                    Arc::clone(writer),
+                    Arc::clone(&stop),
+                );
+                self.screens
+                    .insert(pane_id, ScreenAttachment { stream, stop });
+            }
+            StreamMode::Raw => {
+                // Subscribe before capturing so that nothing written after
+                // the snapshot is lost; it queues until the snapshot has
+                // been sent.
+                let sub = bridge.subscribe_output(pane_id)?;
+                let snapshot = bridge.snapshot(pane_id, crate::snapshot::scrollback_rows(scrollback))?;
+                let snapshot = serde_json::to_vec(&snapshot)?;
+                {
+                    let mut w = writer.lock().unwrap();
+                    w.write_json(&JsonResponse::AttachOk { pane_id })?;
+                    w.write_frame(TYPE_PANE_SNAPSHOT, &snapshot)?;
+                }
+                session.session().start_output(bridge, pane_id, addressed, sub);
+            }
+        }
+
+        if addressed {
+            self.addressed.insert(pane_id);
+        } else {
+            self.unaddressed = Some(pane_id);
+        }
+        Ok(())
+    }
+
+    /// Stop the output pump for `pane_id`. Returns false if it was not
+    /// attached.
+    pub fn detach(&mut self, session: &SessionHandle, pane_id: PaneId) -> bool {
+        if !self.is_attached(pane_id) {
+            return false;
+        }
+        if let Some(screen) = self.screens.remove(&pane_id) {
+            screen.stop.store(true, Ordering::Relaxed);
+        }
+        session.session().stop_output(pane_id);
+        self.addressed.remove(&pane_id);
+        if self.unaddressed == Some(pane_id) {
+            self.unaddressed = None;
+        }
+        true
+    }
+
+    /// Take over the raw attachments of a resumed session. Those of the
+    /// session this connection had before now belong to it.
+    pub fn resumed(&mut self, panes: ResumedPanes) {
+        let screens = &self.screens;
+        self.addressed.retain(|pane_id| screens.contains_key(pane_id));
+        if self.unaddressed.is_some_and(|pane_id| !screens.contains_key(&pane_id)) {
+            self.unaddressed = None;
+        }
+        if panes.pane_id.is_some() {
+            if let Some(old) = self.unaddressed.take() {
+                self.detach_screen(old);
+            }
+            self.unaddressed = panes.pane_id;
+        }
+        for pane_id in panes.addressed {
+            self.detach_screen(pane_id);
+            self.addressed.insert(pane_id);
+        }
+    }
+
+    fn detach_screen(&mut self, pane_id: PaneId) {
+        if let Some(screen) = self.screens.remove(&pane_id) {
+            screen.stop.store(true, Ordering::Relaxed);
+        }
+        self.addressed.remove(&pane_id);
+    }
+
+    pub fn ack(&self, pane_id: PaneId, seqno: u64) {
+        if let Some(screen) = self.screens.get(&pane_id) {
+            screen.stream.ack(seqno);
+        }
+    }
+
+    /// The pane that `TYPE_PANE_INPUT` goes to
+    pub fn input_target(&self) -> Option<PaneId> {
+        self.unaddressed
+    }
+
+    /// Whether `TYPE_PANE_INPUT_ADDRESSED` may target `pane_id`
+    pub fn accepts_addressed_input(&self, pane_id: PaneId) -> bool {
+        self.addressed.contains(&pane_id)
+    }
+}
+
+impl Drop for Attachments {
+    fn drop(&mut self) {
+        for screen in self.screens.values() {
+            screen.stop.store(true, Ordering::Relaxed);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bridge::FakePaneBridge;
+    use crate::secure::FrameWriter;
+    use std::sync::Mutex;
+
+    struct Discard;
+
+    impl FrameWriter for Discard {
+        fn write_frame(&mut self, _typ: u8, _payload: &[u8]) -> anyhow::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn one_unaddressed_and_many_addressed() {
+        let bridge: Arc<dyn PaneBridge> = Arc::new(FakePaneBridge::new(vec![]));
+        let session = SessionHandle::new(Arc::new(Mutex::new(Discard)));
+        let mut attachments = Attachments::new();
+
+        attachments
+            .attach(&bridge, &session, 1, None, StreamMode::Raw, false)
+            .unwrap();
+        assert!(attachments
+            .attach(&bridge, &session, 2, None, StreamMode::Raw, false)
+            .is_err());
+        attachments
+            .attach(&bridge, &session, 2, None, StreamMode::Raw, true)
+            .unwrap();
+        attachments
+            .attach(&bridge, &session, 3, None, StreamMode::Cells, true)
+            .unwrap();
+        assert!(attachments
+            .attach(&bridge, &session, 3, None, StreamMode::Raw, true)
+            .is_err());
+
+        assert_eq!(attachments.input_target(), Some(1));
+        assert!(!attachments.accepts_addressed_input(1));
+        assert!(attachments.accepts_addressed_input(3));
+
+        assert!(attachments.detach(&session, 3));
+        assert!(!attachments.detach(&session, 3));
+        assert!(!attachments.accepts_addressed_input(3));
+        assert!(attachments.detach(&session, 1));
+        assert_eq!(attachments.input_target(), None);
+        assert!(!session.session().stop_output(1));
+        assert!(session.session().stop_output(2));
+    }
+}
@@ -2,10 +2,17 @@ use anyhow::{anyhow, Context};
 use mux::pane::PaneId;
 use mux::Mux;
 use serde::{Deserialize, Serialize};
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::{Arc, Mutex};
 use wezterm_term::TerminalSize;
 
 pub use lucidity_proto::protocol::PaneInfo;
+use crate::mux_ops::{on_main_thread, pick_tab};
+use lucidity_proto::protocol::{
+    ActivateTabRequest, CellRun, CellStyle, KeyEventRequest, LineUpdate, MouseEventRequest,
+    PaneSnapshot, ScreenUpdate, SnapshotCursor, SpawnRequest, SpawnedPane, SplitPaneRequest,
+    ZoomMode,
+};
 
 pub trait OutputSubscription: Send {
     fn recv_timeout(&self, timeout: std::time::Duration) -> anyhow::Result<Option<Arc<[u8]>>>;
@@ -15,8 +22,28 @@ pub trait PaneBridge: Send + Sync + 'static {
     fn list_panes(&self) -> anyhow::Result<Vec<PaneInfo>>;
     fn subscribe_output(&self, pane_id: PaneId) -> anyhow::Result<Box<dyn OutputSubscription>>;
     fn send_input(&self, pane_id: PaneId, bytes: &[u8]) -> anyhow::Result<()>;
+    /// Paste the way the GUI does, honoring the pane's bracketed paste mode
     fn send_paste(&self, pane_id: PaneId, text: &str) -> anyhow::Result<()>;
+    fn bracketed_paste(&self, pane_id: PaneId) -> anyhow::Result<bool>;
     fn resize(&self, pane_id: PaneId, rows: usize, cols: usize) -> anyhow::Result<()>;
+    fn key_event(&self, req: KeyEventRequest) -> anyhow::Result<()>;
+    fn mouse_event(&self, req: MouseEventRequest) -> anyhow::Result<()>;
+    /// Capture the screen and the last `scrollback` rows of history
+    fn snapshot(&self, pane_id: PaneId, scrollback: usize) -> anyhow::Result<PaneSnapshot>;
+    /// Lines changed since `since`, or the whole screen when `since` is None
+    fn screen_update(&self, pane_id: PaneId, since: Option<u64>) -> anyhow::Result<ScreenUpdate>;
+    fn spawn(&self, req: SpawnRequest) -> anyhow::Result<SpawnedPane>;
+    fn split_pane(&self, req: SplitPaneRequest) -> anyhow::Result<SpawnedPane>;
+    fn kill_pane(&self, pane_id: PaneId) -> anyhow::Result<()>;
+    fn activate_tab(&self, req: ActivateTabRequest) -> anyhow::Result<()>;
+    fn activate_pane(&self, pane_id: PaneId) -> anyhow::Result<()>;
+    fn zoom_pane(&self, pane_id: PaneId, mode: ZoomMode) -> anyhow::Result<()>;
+    fn rename_workspace(
+        &self,
+        workspace: Option<String>,
+        pane_id: Option<PaneId>,
+        new_workspace: String,
+    ) -> anyhow::Result<()>;
 }
 
 struct MuxOutputSubscription {
@@ -47,9 +74,19 @@ impl PaneBridge for MuxPaneBridge {
         Ok(mux
             .iter_panes()
             .into_iter()
-            .map(|p| PaneInfo {
-                pane_id: p.pane_id(),
-                title: p.get_title(),
+            .map(|p| {
+                let window = mux.resolve_pane_id(p.pane_id());
+                PaneInfo {
+                    pane_id: p.pane_id(),
+                    title: p.get_title(),
+                    domain: mux
+                        .get_domain(p.domain_id())
+                        .map(|domain| domain.domain_name().to_string()),
+                    workspace: window.and_then(|(_domain_id, window_id, _tab_id)| {
+                        mux.get_window(window_id)
+                            .map(|window| window.get_workspace().to_string())
+                    }),
+                }
             })
             .collect())
     }
@@ -75,9 +112,19 @@ impl PaneBridge for MuxPaneBridge {
     }
 
     fn send_paste(&self, pane_id: PaneId, text: &str) -> anyhow::Result<()> {
-        // For now, simple input injection. 
-        // TODO: Bracketed paste if possible?
-        self.send_input(pane_id, text.as_bytes())
+        let mux = Mux::get();
+        let pane = mux
+            .get_pane(pane_id)
+            .ok_or_else(|| anyhow!("no such pane: {pane_id}"))?;
+        pane.send_paste(text)
+    }
+
+    fn bracketed_paste(&self, pane_id: PaneId) -> anyhow::Result<bool> {
+        let mux = Mux::get();
+        let pane = mux
+            .get_pane(pane_id)
+            .ok_or_else(|| anyhow!("no such pane: {pane_id}"))?;
+        Ok(pane.is_bracketed_paste_enabled())
     }
 
     fn resize(&self, pane_id: PaneId, rows: usize, cols: usize) -> anyhow::Result<()> {
@@ -98,23 +145,167 @@ impl PaneBridge for MuxPaneBridge {
         pane.resize(size)?; 
         Ok(())
     }
+
+    fn key_event(&self, req: KeyEventRequest) -> anyhow::Result<()> {
+        let mut pane = Mux::get()
+            .get_pane(req.pane_id)
+            .ok_or_else(|| anyhow!("no such pane: {}", req.pane_id))?;
+        crate::input::send_key(&mut pane, &req)
+    }
+
+    fn mouse_event(&self, req: MouseEventRequest) -> anyhow::Result<()> {
+        let mut pane = Mux::get()
+            .get_pane(req.pane_id)
+            .ok_or_else(|| anyhow!("no such pane: {}", req.pane_id))?;
+        crate::input::send_mouse(&mut pane, &req)
+    }
+
+    fn snapshot(&self, pane_id: PaneId, scrollback: usize) -> anyhow::Result<PaneSnapshot> {
+        let mux = Mux::get();
+        let pane = mux
+            .get_pane(pane_id)
+            .ok_or_else(|| anyhow!("no such pane: {pane_id}"))?;
+        Ok(crate::snapshot::capture(&*pane, scrollback))
+    }
+
+    fn screen_update(&self, pane_id: PaneId, since: Option<u64>) -> anyhow::Result<ScreenUpdate> {
+        let mux = Mux::get();
+        let pane = mux
+            .get_pane(pane_id)
+            .ok_or_else(|| anyhow!("no such pane: {pane_id}"))?;
+        Ok(crate::cells::capture_update(&*pane, since.map(|s| s as usize)))
+    }
+
+    fn spawn(&self, req: SpawnRequest) -> anyhow::Result<SpawnedPane> {
+        on_main_thread(move || crate::mux_ops::spawn(req))
+    }
+
+    fn split_pane(&self, req: SplitPaneRequest) -> anyhow::Result<SpawnedPane> {
+        on_main_thread(move || crate::mux_ops::split_pane(req))
+    }
+
+    fn kill_pane(&self, pane_id: PaneId) -> anyhow::Result<()> {
+        on_main_thread(move || async move { crate::mux_ops::kill_pane(pane_id) })
+    }
+
+    fn activate_tab(&self, req: ActivateTabRequest) -> anyhow::Result<()> {
+        on_main_thread(move || async move { crate::mux_ops::activate_tab(req) })
+    }
+
+    fn activate_pane(&self, pane_id: PaneId) -> anyhow::Result<()> {
+        on_main_thread(move || async move { crate::mux_ops::activate_pane(pane_id) })
+    }
+
+    fn zoom_pane(&self, pane_id: PaneId, mode: ZoomMode) -> anyhow::Result<()> {
+        on_main_thread(move || async move { crate::mux_ops::zoom_pane(pane_id, mode) })
+    }
+
+    fn rename_workspace(
+        &self,
+        workspace: Option<String>,
+        pane_id: Option<PaneId>,
+        new_workspace: String,
+    ) -> anyhow::Result<()> {
+        on_main_thread(move || async move {
+            crate::mux_ops::rename_workspace(workspace, pane_id, &new_workspace)
+        })
+    }
+}
+
+/// A tab in `FakePaneBridge`'s stand-in for the mux
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FakeTab {
+    pub tab_id: usize,
+    pub window_id: usize,
+    pub workspace: String,
+    pub panes: Vec<PaneId>,
+    pub active_pane: PaneId,
+    pub zoomed: bool,
+}
+
+#[derive(Default)]
+struct FakeLayout {
+    tabs: Vec<FakeTab>,
+    next_id: usize,
+    focused: Option<PaneId>,
+}
+
+impl FakeLayout {
+    fn new(panes: &[PaneInfo]) -> Self {
+        let mut layout = Self {
+            next_id: panes.iter().map(|p| p.pane_id + 1).max().unwrap_or(0),
+            ..Default::default()
+        };
+        for pane in panes {
+            let tab_id = layout.alloc_id();
+            layout.tabs.push(FakeTab {
+                tab_id,
+                window_id: 0,
+                workspace: mux::DEFAULT_WORKSPACE.to_string(),
+                panes: vec![pane.pane_id],
+                active_pane: pane.pane_id,
+                zoomed: false,
+            });
+        }
+        layout.focused = panes.first().map(|p| p.pane_id);
+        layout
+    }
+
+    fn alloc_id(&mut self) -> usize {
+        let id = self.next_id;
+        self.next_id += 1;
+        id
+    }
+
+    fn tab_index_of(&self, pane_id: PaneId) -> anyhow::Result<usize> {
+        self.tabs
+            .iter()
+            .position(|tab| tab.panes.contains(&pane_id))
+            .ok_or_else(|| anyhow!("no such pane: {pane_id}"))
+    }
+
+    fn resolve(&self, pane_id: Option<PaneId>) -> anyhow::Result<PaneId> {
+        pane_id
+            .or(self.focused)
+            .ok_or_else(|| anyhow!("no pane_id given and no pane is focused"))
+    }
 }
 
 pub struct FakePaneBridge {
     panes: Mutex<Vec<PaneInfo>>,
     out: Mutex<std::collections::HashMap<PaneId, crossbeam::channel::Sender<Arc<[u8]>>>>,
     inputs: Mutex<Vec<(PaneId, Vec<u8>)>>,
+    pastes: Mutex<Vec<(PaneId, String)>>,
+    bracketed: Mutex<std::collections::HashSet<PaneId>>,
+    key_events: Mutex<Vec<KeyEventRequest>>,
+    mouse_events: Mutex<Vec<MouseEventRequest>>,
+    screens: Mutex<std::collections::HashMap<PaneId, Vec<String>>>,
+    screen_seqno: AtomicU64,
+    layout: Mutex<FakeLayout>,
 }
 
 impl FakePaneBridge {
     pub fn new(panes: Vec<PaneInfo>) -> Self {
         Self {
+            layout: Mutex::new(FakeLayout::new(&panes)),
             panes: Mutex::new(panes),
             out: Mutex::new(std::collections::HashMap::new()),
             inputs: Mutex::new(Vec::new()),
+            pastes: Mutex::new(Vec::new()),
+            bracketed: Mutex::new(std::collections::HashSet::new()),
+            key_events: Mutex::new(Vec::new()),
+            mouse_events: Mutex::new(Vec::new()),
+            screens: Mutex::new(std::collections::HashMap::new()),
+            screen_seqno: AtomicU64::new(1),
         }
     }
 
+    /// Set the lines returned by `snapshot` for a pane
+    pub fn set_screen(&self, pane_id: PaneId, lines: Vec<String>) {
+        self.screens.lock().unwrap().insert(pane_id, lines);
+        self.screen_seqno.fetch_add(1, Ordering::SeqCst);
+    }
+
     pub fn emit_output(&self, pane_id: PaneId, bytes: &[u8]) {
         if let Some(tx) = self.out.lock().unwrap().get(&pane_id) {
             let _ = tx.try_send(Arc::from(bytes));
@@ -124,6 +315,46 @@ impl FakePaneBridge {
     pub fn take_inputs(&self) -> Vec<(PaneId, Vec<u8>)> {
         std::mem::take(&mut *self.inputs.lock().unwrap())
     }
+
+    pub fn take_pastes(&self) -> Vec<(PaneId, String)> {
+        std::mem::take(&mut *self.pastes.lock().unwrap())
+    }
+
+    pub fn set_bracketed_paste(&self, pane_id: PaneId, enabled: bool) {
+        let mut bracketed = self.bracketed.lock().unwrap();
+        if enabled {
+            bracketed.insert(pane_id);
+        } else {
+            bracketed.remove(&pane_id);
+        }
+    }
+
+    pub fn take_key_events(&self) -> Vec<KeyEventRequest> {
+        std::mem::take(&mut *self.key_events.lock().unwrap())
+    }
+
+    pub fn take_mouse_events(&self) -> Vec<MouseEventRequest> {
+        std::mem::take(&mut *self.mouse_events.lock().unwrap())
+    }
+
+    /// Each pane starts in a tab of its own in window 0; the mux
+    /// operations rearrange them from there.
+    pub fn tabs(&self) -> Vec<FakeTab> {
+        self.layout.lock().unwrap().tabs.clone()
+    }
+
+    pub fn focused_pane(&self) -> Option<PaneId> {
+        self.layout.lock().unwrap().focused
+    }
+
+    fn add_pane(&self, pane_id: PaneId) {
+        self.panes.lock().unwrap().push(PaneInfo {
+            pane_id,
+            title: format!("pane {pane_id}"),
+            domain: None,
+            workspace: None,
+        });
+    }
 }
 
 struct FakeOutputSubscription {
@@ -161,10 +392,247 @@ impl PaneBridge for FakePaneBridge {
     }
 
     fn send_paste(&self, pane_id: PaneId, text: &str) -> anyhow::Result<()> {
-        self.send_input(pane_id, text.as_bytes())
+        self.pastes.lock().unwrap().push((pane_id, text.to_string()));
+        Ok(())
+    }
+
+    fn bracketed_paste(&self, pane_id: PaneId) -> anyhow::Result<bool> {
+        Ok(self.bracketed.lock().unwrap().contains(&pane_id))
     }
 
     fn resize(&self, _pane_id: PaneId, _rows: usize, _cols: usize) -> anyhow::Result<()> {
         Ok(())
     }
+
+    fn key_event(&self, req: KeyEventRequest) -> anyhow::Result<()> {
+        self.key_events.lock().unwrap().push(req);
+        Ok(())
+    }
+
+    fn mouse_event(&self, req: MouseEventRequest) -> anyhow::Result<()> {
+        self.mouse_events.lock().unwrap().push(req);
+        Ok(())
+    }
+
+    fn snapshot(&self, pane_id: PaneId, _scrollback: usize) -> anyhow::Result<PaneSnapshot> {
+        let lines = self
+            .screens
+            .lock()
+            .unwrap()
+            .get(&pane_id)
+            .cloned()
+            .unwrap_or_default();
+        Ok(PaneSnapshot {
+            pane_id,
+            rows: lines.len(),
+            cols: lines.iter().map(|l| l.len()).max().unwrap_or(0),
+            cursor: SnapshotCursor {
+                x: 0,
+                y: lines.len().saturating_sub(1),
+                visible: true,
+            },
+            lines,
+            scrollback_rows: 0,
+            alt_screen: false,
+            bracketed_paste: false,
+            mouse_grabbed: false,
+        })
+    }
+
+    fn screen_update(&self, pane_id: PaneId, since: Option<u64>) -> anyhow::Result<ScreenUpdate> {
+        let seqno = self.screen_seqno.load(Ordering::SeqCst);
+        let screen = self
+            .screens
+            .lock()
+            .unwrap()
+            .get(&pane_id)
+            .cloned()
+            .unwrap_or_default();
+        // The fake has no per-line seqnos, so any change resends every line
+        let lines = if since == Some(seqno) {
+            vec![]
+        } else {
+            screen
+                .iter()
+                .enumerate()
+                .map(|(row, text)| LineUpdate {
+                    row: row as i64,
+                    runs: vec![CellRun {
+                        col: 0,
+                        text: text.clone(),
+                        style: CellStyle::default(),
+                    }],
+                })
+                .collect()
+        };
+        Ok(ScreenUpdate {
+            pane_id,
+            seqno,
+            full: since.is_none(),
+            top: 0,
+            rows: screen.len(),
+            cols: screen.iter().map(|l| l.len()).max().unwrap_or(0),
+            lines,
+            cursor: SnapshotCursor {
+                x: 0,
+                y: screen.len().saturating_sub(1),
+                visible: true,
+            },
+            alt_screen: false,
+            bracketed_paste: false,
+            mouse_grabbed: false,
+        })
+    }
+
+    fn spawn(&self, req: SpawnRequest) -> anyhow::Result<SpawnedPane> {
+        let mut layout = self.layout.lock().unwrap();
+        let (window_id, workspace) = match (req.new_window, req.window_id) {
+            (false, Some(window_id)) => (window_id, mux::DEFAULT_WORKSPACE.to_string()),
+            (false, None) => {
+                let pane_id = layout.resolve(req.pane_id)?;
+                let tab = &layout.tabs[layout.tab_index_of(pane_id)?];
+                (tab.window_id, tab.workspace.clone())
+            }
+            (true, _) => (
+                layout.alloc_id(),
+                req.workspace
+                    .unwrap_or_else(|| mux::DEFAULT_WORKSPACE.to_string()),
+            ),
+        };
+        let tab_id = layout.alloc_id();
+        let pane_id = layout.alloc_id();
+        layout.tabs.push(FakeTab {
+            tab_id,
+            window_id,
+            workspace,
+            panes: vec![pane_id],
+            active_pane: pane_id,
+            zoomed: false,
+        });
+        drop(layout);
+        self.add_pane(pane_id);
+        Ok(SpawnedPane {
+            pane_id,
+            tab_id,
+            window_id,
+        })
+    }
+
+    fn split_pane(&self, req: SplitPaneRequest) -> anyhow::Result<SpawnedPane> {
+        let mut layout = self.layout.lock().unwrap();
+        let idx = layout.tab_index_of(req.pane_id)?;
+        let pane_id = match req.move_pane_id {
+            Some(moved) => {
+                let from = layout.tab_index_of(moved)?;
+                layout.tabs[from].panes.retain(|&p| p != moved);
+                moved
+            }
+            None => layout.alloc_id(),
+        };
+        let tab = &mut layout.tabs[idx];
+        tab.panes.push(pane_id);
+        tab.zoomed = false;
+        let spawned = SpawnedPane {
+            pane_id,
+            tab_id: tab.tab_id,
+            window_id: tab.window_id,
+        };
+        layout.tabs.retain(|tab| !tab.panes.is_empty());
+        drop(layout);
+        if req.move_pane_id.is_none() {
+            self.add_pane(pane_id);
+        }
+        Ok(spawned)
+    }
+
+    fn kill_pane(&self, pane_id: PaneId) -> anyhow::Result<()> {
+        let mut layout = self.layout.lock().unwrap();
+        let idx = layout.tab_index_of(pane_id)?;
+        let tab = &mut layout.tabs[idx];
+        tab.panes.retain(|&p| p != pane_id);
+        if let Some(&first) = tab.panes.first() {
+            if tab.active_pane == pane_id {
+                tab.active_pane = first;
+                tab.zoomed = false;
+            }
+        } else {
+            layout.tabs.remove(idx);
+        }
+        if layout.focused == Some(pane_id) {
+            layout.focused = None;
+        }
+        drop(layout);
+        self.panes.lock().unwrap().retain(|p| p.pane_id != pane_id);
+        Ok(())
+    }
+
+    fn activate_tab(&self, req: ActivateTabRequest) -> anyhow::Result<()> {
+        let layout = self.layout.lock().unwrap();
+        let tab_id = match req.tab_id {
+            Some(tab_id) => tab_id,
+            None => {
+                let pane_id = layout.resolve(req.pane_id)?;
+                let current = &layout.tabs[layout.tab_index_of(pane_id)?];
+                let tabs: Vec<usize> = layout
+                    .tabs
+                    .iter()
+                    .filter(|tab| tab.window_id == current.window_id)
+                    .map(|tab| tab.tab_id)
+                    .collect();
+                pick_tab(&tabs, current.tab_id, &req)?
+            }
+        };
+        let pane_id = layout
+            .tabs
+            .iter()
+            .find(|tab| tab.tab_id == tab_id)
+            .map(|tab| tab.active_pane)
+            .ok_or_else(|| anyhow!("no such tab: {tab_id}"))?;
+        drop(layout);
+        self.activate_pane(pane_id)
+    }
+
+    fn activate_pane(&self, pane_id: PaneId) -> anyhow::Result<()> {
+        let mut layout = self.layout.lock().unwrap();
+        let idx = layout.tab_index_of(pane_id)?;
+        layout.tabs[idx].active_pane = pane_id;
+        layout.focused = Some(pane_id);
+        Ok(())
+    }
+
+    fn zoom_pane(&self, pane_id: PaneId, mode: ZoomMode) -> anyhow::Result<()> {
+        let mut layout = self.layout.lock().unwrap();
+        let idx = layout.tab_index_of(pane_id)?;
+        let tab = &mut layout.tabs[idx];
+        let is_zoomed = tab.zoomed && tab.active_pane == pane_id;
+        tab.zoomed = match mode {
+            ZoomMode::Zoom => true,
+            ZoomMode::Unzoom => false,
+            ZoomMode::Toggle => !is_zoomed,
+        };
+        if tab.zoomed {
+            tab.active_pane = pane_id;
+        }
+        Ok(())
+    }
+
+    fn rename_workspace(
+        &self,
+        workspace: Option<String>,
+        pane_id: Option<PaneId>,
+        new_workspace: String,
+    ) -> anyhow::Result<()> {
+        let mut layout = self.layout.lock().unwrap();
+        let old = match workspace {
+            Some(workspace) => workspace,
+            None => {
+                let pane_id = layout.resolve(pane_id)?;
+                layout.tabs[layout.tab_index_of(pane_id)?].workspace.clone()
+            }
+        };
+        for tab in layout.tabs.iter_mut().filter(|tab| tab.workspace == old) {
+            tab.workspace = new_workspace.clone();
+        }
+        Ok(())
+    }
 }
@@ -0,0 +1,312 @@
+//! Cell-mode streaming: instead of raw PTY bytes the client receives the
+//! lines that changed since the pane sequence number it last acknowledged.
+//!
+//! Only one update is in flight at a time. Changes that happen while the
+//! client is still applying an update are folded into the next one, so a
+//! slow link sees fewer, larger updates rather than a growing backlog.
+
+use crate::bridge::{OutputSubscription, PaneBridge};
+use crate::protocol::TYPE_SCREEN_UPDATE;
+use crate::resume::SharedWriter;
+use lucidity_proto::protocol::{
+    CellColor, CellRun, CellStyle, LineUpdate, ScreenUpdate, SnapshotCursor,
+};
+use mux::pane::{Pane, PaneId};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use termwiz::cell::{Blink, CellAttributes, Intensity, Underline};
+use termwiz::color::ColorAttribute;
+use termwiz::surface::{CursorVisibility, SequenceNo};
+use wezterm_term::Line;
+
+/// How long to wait after PTY output before diffing, so that the terminal
+/// has parsed it and bursts are coalesced
+const SETTLE_DELAY: Duration = Duration::from_millis(16);
+
+pub(crate) fn capture_update(pane: &dyn Pane, since: Option<SequenceNo>) -> ScreenUpdate {
+    // Read the seqno first: anything that changes while we copy lines is
+    // sent again in the next update.
+    let seqno = pane.get_current_seqno();
+    let dims = pane.get_dimensions();
+    let cursor = pane.get_cursor_position();
+    let top = dims.physical_top;
+    let screen = top..top + dims.viewport_rows as isize;
+
+    let mut lines = vec![];
+    let mut add_lines = |range| {
+        let (first, chunk) = pane.get_lines(range);
+        for (idx, line) in chunk.iter().enumerate() {
+            lines.push(line_update(first + idx as isize, line));
+        }
+    };
+    match since {
+        Some(since) => {
+            for range in pane.get_changed_since(screen, since).iter() {
+                add_lines(range.clone());
+            }
+        }
+        None => add_lines(screen),
+    }
+
+    ScreenUpdate {
+        pane_id: pane.pane_id(),
+        seqno: seqno as u64,
+        full: since.is_none(),
+        top: top as i64,
+        rows: dims.viewport_rows,
+        cols: dims.cols,
+        lines,
+        cursor: SnapshotCursor {
+            x: cursor.x,
+            y: (cursor.y - top).max(0) as usize,
+            visible: cursor.visibility == CursorVisibility::Visible,
+        },
+        alt_screen: pane.is_alt_screen_active(),
+        bracketed_paste: pane.is_bracketed_paste_enabled(),
+        mouse_grabbed: pane.is_mouse_grabbed(),
+    }
+}
+
+fn line_update(row: isize, line: &Line) -> LineUpdate {
+    let mut runs: Vec<CellRun> = vec![];
+    let mut wide = false;
+    for cell in line.visible_cells() {
+        let style = cell_style(cell.attrs());
+        match runs.last_mut() {
+            Some(run) if !wide && cell.width() == 1 && run.style == style => {
+                run.text.push_str(cell.str());
+            }
+            _ => runs.push(CellRun {
+                col: cell.cell_index(),
+                text: cell.str().to_string(),
+                style,
+            }),
+        }
+        wide = cell.width() > 1;
+    }
+
+    // Trailing blanks are implied
+    if let Some(run) = runs.last_mut() {
+        if run.style.is_default() {
+            run.text.truncate(run.text.trim_end_matches(' ').len());
+            if run.text.is_empty() {
+                runs.pop();
+            }
+        }
+    }
+
+    LineUpdate {
+        row: row as i64,
+        runs,
+    }
+}
+
+fn cell_style(attrs: &CellAttributes) -> CellStyle {
+    CellStyle {
+        fg: cell_color(attrs.foreground()),
+        bg: cell_color(attrs.background()),
+        bold: attrs.intensity() == Intensity::Bold,
+        dim: attrs.intensity() == Intensity::Half,
+        italic: attrs.italic(),
+        underline: attrs.underline() != Underline::None,
+        blink: attrs.blink() != Blink::None,
+        reverse: attrs.reverse(),
+        invisible: attrs.invisible(),
+        strikethrough: attrs.strikethrough(),
+        hyperlink: attrs.hyperlink().map(|link| link.uri().to_string()),
+    }
+}
+
+fn cell_color(color: ColorAttribute) -> Option<CellColor> {
+    match color {
+        ColorAttribute::TrueColorWithPaletteFallback(tc, _)
+        | ColorAttribute::TrueColorWithDefaultFallback(tc) => {
+            let (r, g, b, _) = tc.to_srgb_u8();
+            Some(CellColor::Rgb(r, g, b))
+        }
+        ColorAttribute::PaletteIndex(idx) => Some(CellColor::Palette(idx)),
+        ColorAttribute::Default => None,
+    }
+}
+
+/// The shape of the screen an update described. A change in any of these
+/// invalidates the stable row indices the client holds.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Sent {
+    seqno: u64,
+    rows: usize,
+    cols: usize,
+    alt_screen: bool,
+}
+
+impl Sent {
+    fn of(update: &ScreenUpdate) -> Self {
+        Self {
+            seqno: update.seqno,
+            rows: update.rows,
+            cols: update.cols,
+            alt_screen: update.alt_screen,
+        }
+    }
+
+    fn same_shape(&self, other: &Self) -> bool {
+        (self.rows, self.cols, self.alt_screen) == (other.rows, other.cols, other.alt_screen)
+    }
+}
+
+#[derive(Default)]
+struct StreamState {
+    acked: Option<Sent>,
+    in_flight: Option<Sent>,
+}
+
+/// Tracks what a cell-mode client has acknowledged for one pane.
+pub(crate) struct ScreenStream {
+    pane_id: PaneId,
+    state: Mutex<StreamState>,
+}
+
+impl ScreenStream {
+    pub fn new(pane_id: PaneId) -> Self {
+        Self {
+            pane_id,
+            state: Mutex::new(StreamState::default()),
+        }
+    }
+
+    pub fn pane_id(&self) -> PaneId {
+        self.pane_id
+    }
+
+    /// Record the client's `screen_ack`. Stale or unknown seqnos are ignored.
+    pub fn ack(&self, seqno: u64) {
+        let mut state = self.state.lock().unwrap();
+        if let Some(sent) = state.in_flight.filter(|sent| sent.seqno == seqno) {
+            state.acked = Some(sent);
+            state.in_flight = None;
+        }
+    }
+
+    /// The next update to send, or None while the previous one is
+    /// unacknowledged or nothing has changed.
+    pub fn next_update(&self, bridge: &dyn PaneBridge) -> anyhow::Result<Option<ScreenUpdate>> {
+        let mut state = self.state.lock().unwrap();
+        if state.in_flight.is_some() {
+            return Ok(None);
+        }
+
+        let update = match state.acked {
+            Some(acked) => {
+                let update = bridge.screen_update(self.pane_id, Some(acked.seqno))?;
+                if update.seqno == acked.seqno {
+                    return Ok(None);
+                }
+                if Sent::of(&update).same_shape(&acked) {
+                    update
+                } else {
+                    bridge.screen_update(self.pane_id, None)?
+                }
+            }
+            None => bridge.screen_update(self.pane_id, None)?,
+        };
+
+        state.in_flight = Some(Sent::of(&update));
+        Ok(Some(update))
+    }
+}
+
+/// Send updates for `stream` until `dead` is set or the client goes away.
+/// The output subscription is only used to learn that something changed.
+pub(crate) fn spawn_screen_pump(
+    bridge: Arc<dyn PaneBridge>,
+    stream: Arc<ScreenStream>,
+    sub: Box<dyn OutputSubscription>,
+    writer: SharedWriter,
+    dead: Arc<AtomicBool>,
+) {
+    std::thread::spawn(move || {
+        while !dead.load(Ordering::Relaxed) {
+            match sub.recv_timeout(Duration::from_millis(250)) {
+                Ok(Some(_)) => {
+                    std::thread::sleep(SETTLE_DELAY);
+                    while let Ok(Some(_)) = sub.recv_timeout(Duration::ZERO) {}
+                }
+                Ok(None) => {}
+                Err(_) => break,
+            }
+
+            let update = match stream.next_update(&*bridge) {
+                Ok(Some(update)) => update,
+                Ok(None) => continue,
+                Err(err) => {
+                    log::debug!("screen update for pane {}: {err:#}", stream.pane_id());
+                    break;
+                }
+            };
+            let payload = match serde_json::to_vec(&update) {
+                Ok(payload) => payload,
+                Err(_) => break,
+            };
+            let mut w = writer.lock().unwrap();
+            if w.write_frame(TYPE_SCREEN_UPDATE, &payload).is_err() {
+                break;
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bridge::FakePaneBridge;
+
+    #[test]
+    fn one_update_in_flight_until_acked() {
+        let bridge = FakePaneBridge::new(vec![]);
+        bridge.set_screen(1, vec!["$ ls".to_string()]);
+        let stream = ScreenStream::new(1);
+
+        let first = stream.next_update(&bridge).unwrap().unwrap();
+        assert!(first.full);
+        assert_eq!(first.lines.len(), 1);
+        assert!(stream.next_update(&bridge).unwrap().is_none());
+
+        stream.ack(first.seqno);
+        assert!(stream.next_update(&bridge).unwrap().is_none());
+
+        bridge.set_screen(1, vec!["$ ls".to_string(), "a b c".to_string()]);
+        let second = stream.next_update(&bridge).unwrap().unwrap();
+        assert!(second.seqno > first.seqno);
+    }
+
+    #[test]
+    fn stale_ack_is_ignored() {
+        let bridge = FakePaneBridge::new(vec![]);
+        bridge.set_screen(1, vec!["x".to_string()]);
+        let stream = ScreenStream::new(1);
+
+        let first = stream.next_update(&bridge).unwrap().unwrap();
+        stream.ack(first.seqno + 1);
+        assert!(stream.next_update(&bridge).unwrap().is_none());
+    }
+
+    #[test]
+    fn runs_split_on_style_and_trim_blanks() {
+        let mut bold = CellAttributes::default();
+        bold.set_intensity(Intensity::Bold);
+        let mut line = Line::from_text("ab", &bold, 0, None);
+        line.append_line(
+            Line::from_text("cd   ", &CellAttributes::default(), 0, None),
+            0,
+        );
+
+        let update = line_update(7, &line);
+        assert_eq!(update.row, 7);
+        assert_eq!(update.runs.len(), 2);
+        assert_eq!(update.runs[0].text, "ab");
+        assert!(update.runs[0].style.bold);
+        assert_eq!(update.runs[1].col, 2);
+        assert_eq!(update.runs[1].text, "cd");
+    }
+}
@@ -1,30 +1,200 @@
-use clipboard_win::{get_clipboard_string, formats};
-use std::thread;
-use std::time::Duration;
-use log::debug;
-
-pub fn start_clipboard_monitor<F>(callback: F) 
-where F: Fn(String) + Send + 'static 
-{
-    thread::spawn(move || {
-        let mut last_clipboard = String::new();
-        
-        loop {
-            thread::sleep(Duration::from_millis(1000));
-            
-            match get_clipboard_string() {
-                Ok(text) => {
-                    if !text.is_empty() && text != last_clipboard {
-                        debug!("Clipboard changed on host");
-                        last_clipboard = text.clone();
-                        callback(text);
-                    }
-                }
-                Err(_) => {
-                    // This often happens if the clipboard is empty or non-text
-                    // We just ignore it
-                }
+//! The host clipboard.
+//!
+//! Copies made in panes (OSC 52 and copy mode) reach the mux as
+//! `MuxNotification::AssignClipboard` and are pushed to the devices that
+//! asked for them with `subscribe_clipboard`, within each device's size
+//! limit. Devices set the host clipboard through a `ClipboardProvider`.
+
+use crate::registry::REGISTRY;
+use anyhow::{anyhow, ensure};
+use mux::{Mux, MuxNotification};
+use std::sync::{Arc, Mutex, OnceLock, RwLock};
+use wezterm_term::ClipboardSelection;
+
+/// The most a device may set, or ask to receive
+pub const MAX_CLIPBOARD_BYTES: usize = 1024 * 1024;
+
+/// What `subscribe_clipboard` allows when it gives no `max_bytes`
+pub const DEFAULT_CLIPBOARD_BYTES: usize = 64 * 1024;
+
+pub trait ClipboardProvider: Send + Sync {
+    fn set_text(&self, text: &str) -> anyhow::Result<()>;
+}
+
+/// Assigns the clipboard the way a pane's OSC 52 does, so whichever GUI is
+/// showing the mux, local or connected to a mux server, puts it on the
+/// system clipboard.
+pub struct MuxClipboard;
+
+impl ClipboardProvider for MuxClipboard {
+    fn set_text(&self, text: &str) -> anyhow::Result<()> {
+        let mux = Mux::try_get().ok_or_else(|| anyhow!("no mux"))?;
+        // The frontend only uses the pane for logging
+        let pane_id = crate::mux_ops::resolve_pane_id(&mux, None).unwrap_or(0);
+        mux.notify(MuxNotification::AssignClipboard {
+            pane_id,
+            selection: ClipboardSelection::Clipboard,
+            clipboard: Some(text.to_string()),
+        });
+        Ok(())
+    }
+}
+
+/// The Windows clipboard, for hosts running without a mux
+#[cfg(windows)]
+pub struct WindowsClipboard;
+
+#[cfg(windows)]
+impl ClipboardProvider for WindowsClipboard {
+    fn set_text(&self, text: &str) -> anyhow::Result<()> {
+        clipboard_win::set_clipboard_string(text)?;
+        Ok(())
+    }
+}
+
+/// Records what it is given, for tests
+#[derive(Default)]
+pub struct FakeClipboard {
+    texts: Mutex<Vec<String>>,
+}
+
+impl FakeClipboard {
+    pub fn take_texts(&self) -> Vec<String> {
+        std::mem::take(&mut *self.texts.lock().unwrap())
+    }
+}
+
+impl ClipboardProvider for FakeClipboard {
+    fn set_text(&self, text: &str) -> anyhow::Result<()> {
+        self.texts.lock().unwrap().push(text.to_string());
+        Ok(())
+    }
+}
+
+static CLIPBOARD_PROVIDER: OnceLock<RwLock<Option<Arc<dyn ClipboardProvider>>>> = OnceLock::new();
+
+fn clipboard_provider_lock() -> &'static RwLock<Option<Arc<dyn ClipboardProvider>>> {
+    CLIPBOARD_PROVIDER.get_or_init(|| RwLock::new(None))
+}
+
+/// Replace the platform's provider; `None` goes back to it
+pub fn set_clipboard_provider(provider: Option<Arc<dyn ClipboardProvider>>) {
+    *clipboard_provider_lock().write().unwrap() = provider;
+}
+
+fn clipboard_provider() -> Option<Arc<dyn ClipboardProvider>> {
+    if let Some(provider) = clipboard_provider_lock().read().unwrap().as_ref() {
+        return Some(Arc::clone(provider));
+    }
+    if Mux::try_get().is_some() {
+        return Some(Arc::new(MuxClipboard));
+    }
+    #[cfg(windows)]
+    return Some(Arc::new(WindowsClipboard));
+    #[cfg(not(windows))]
+    None
+}
+
+/// Text a device put on the clipboard, so that the `AssignClipboard` it
+/// causes is not pushed straight back out
+static FROM_DEVICE: Mutex<Option<String>> = Mutex::new(None);
+
+pub(crate) fn set_host_clipboard(text: String) -> anyhow::Result<()> {
+    ensure!(
+        text.len() <= MAX_CLIPBOARD_BYTES,
+        "clipboard text is {} bytes; the limit is {MAX_CLIPBOARD_BYTES}",
+        text.len()
+    );
+    let provider = clipboard_provider().ok_or_else(|| anyhow!("this host has no clipboard"))?;
+    *FROM_DEVICE.lock().unwrap() = Some(text.clone());
+    provider.set_text(&text)
+}
+
+/// The size limit to record for a `subscribe_clipboard`
+pub(crate) fn subscription_limit(max_bytes: Option<usize>) -> usize {
+    max_bytes
+        .unwrap_or(DEFAULT_CLIPBOARD_BYTES)
+        .min(MAX_CLIPBOARD_BYTES)
+}
+
+/// The text to push for `notification`, if it is a copy to the clipboard
+/// that did not come from a device
+fn copied_text(notification: MuxNotification) -> Option<String> {
+    match notification {
+        // Copy mode assigns the primary selection too; one push is enough
+        MuxNotification::AssignClipboard {
+            selection: ClipboardSelection::Clipboard,
+            clipboard: Some(text),
+            ..
+        } => {
+            let mut from_device = FROM_DEVICE.lock().unwrap();
+            if from_device.as_deref() == Some(text.as_str()) {
+                *from_device = None;
+                None
+            } else {
+                Some(text)
             }
         }
+        _ => None,
+    }
+}
+
+/// Push pane copies to subscribed devices. Does nothing if there is no mux
+/// in this process.
+pub(crate) fn start_clipboard_forwarding() {
+    let Some(mux) = Mux::try_get() else {
+        log::debug!("no mux; not forwarding clipboard copies");
+        return;
+    };
+    mux.subscribe(|notification| {
+        if let Some(text) = copied_text(notification) {
+            REGISTRY.broadcast_clipboard(text);
+        }
+        true
     });
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn copy(selection: ClipboardSelection, text: &str) -> MuxNotification {
+        MuxNotification::AssignClipboard {
+            pane_id: 1,
+            selection,
+            clipboard: Some(text.to_string()),
+        }
+    }
+
+    #[test]
+    fn device_copies_are_not_echoed() {
+        let fake = Arc::new(FakeClipboard::default());
+        set_clipboard_provider(Some(fake.clone()));
+
+        assert!(set_host_clipboard("x".repeat(MAX_CLIPBOARD_BYTES + 1)).is_err());
+        set_host_clipboard("from phone".to_string()).unwrap();
+        assert_eq!(fake.take_texts(), vec!["from phone".to_string()]);
+
+        assert_eq!(
+            copied_text(copy(ClipboardSelection::Clipboard, "from phone")),
+            None
+        );
+        // The same text copied again later on the desktop is a new copy
+        assert_eq!(
+            copied_text(copy(ClipboardSelection::Clipboard, "from phone")),
+            Some("from phone".to_string())
+        );
+        assert_eq!(
+            copied_text(copy(ClipboardSelection::PrimarySelection, "vim")),
+            None
+        );
+        set_clipboard_provider(None);
+    }
+
+    #[test]
+    fn subscription_limits_are_capped() {
+        assert_eq!(subscription_limit(None), DEFAULT_CLIPBOARD_BYTES);
+        assert_eq!(subscription_limit(Some(10)), 10);
+        assert_eq!(subscription_limit(Some(usize::MAX)), MAX_CLIPBOARD_BYTES);
+    }
+}
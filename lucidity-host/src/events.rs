@@ -0,0 +1,247 @@
+//! Mux notifications pushed to clients as `event` messages.
+//!
+//! `Mux::notify` calls subscribers while holding its subscriber lock, and
+//! sometimes while the caller holds other mux locks too, so the subscriber
+//! only decides whether a notification is interesting and leaves anything
+//! that has to look at the mux to a task on the main thread.
+
+use crate::registry::REGISTRY;
+use lucidity_proto::protocol::MuxEvent;
+use mux::client::ClientId;
+use mux::pane::PaneId;
+use mux::{Mux, MuxNotification};
+use std::sync::Arc;
+use wezterm_term::Alert;
+
+/// Which events a client asked for. Empty lists mean everything.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct EventFilter {
+    pub events: Vec<String>,
+    pub panes: Vec<usize>,
+    /// Drop events that aren't about a pane, such as tab and window titles
+    /// and workspace names, for a device that may not see every pane
+    pub pane_events_only: bool,
+}
+
+impl EventFilter {
+    pub fn matches(&self, event: &MuxEvent) -> bool {
+        if !self.events.is_empty() && !self.events.iter().any(|name| name == event.name()) {
+            return false;
+        }
+        match event.pane_id() {
+            Some(pane_id) if !self.panes.is_empty() => self.panes.contains(&pane_id),
+            Some(_) => true,
+            None => !self.pane_events_only,
+        }
+    }
+}
+
+/// What translating a notification needs to know about the mux
+trait MuxLookup {
+    fn pane_title(&self, pane_id: PaneId) -> Option<String>;
+    fn active_workspace(&self, client_id: &Arc<ClientId>) -> String;
+}
+
+impl MuxLookup for Mux {
+    fn pane_title(&self, pane_id: PaneId) -> Option<String> {
+        self.get_pane(pane_id).map(|pane| pane.get_title())
+    }
+
+    fn active_workspace(&self, client_id: &Arc<ClientId>) -> String {
+        self.active_workspace_for_client(client_id)
+    }
+}
+
+/// Whether `translate` can turn `notification` into an event. Called with
+/// the mux locked, so it must not look anything up.
+fn is_event(notification: &MuxNotification) -> bool {
+    match notification {
+        MuxNotification::PaneAdded(_)
+        | MuxNotification::PaneRemoved(_)
+        | MuxNotification::PaneFocused(_)
+        | MuxNotification::TabAddedToWindow { .. }
+        | MuxNotification::TabTitleChanged { .. }
+        | MuxNotification::WindowCreated(_)
+        | MuxNotification::WindowRemoved(_)
+        | MuxNotification::WindowTitleChanged { .. }
+        | MuxNotification::ActiveWorkspaceChanged(_)
+        | MuxNotification::WorkspaceRenamed { .. } => true,
+        MuxNotification::Alert { alert, .. } => matches!(
+            alert,
+            Alert::Bell
+                | Alert::ToastNotification { .. }
+                | Alert::IconTitleChanged(_)
+                | Alert::WindowTitleChanged(_)
+        ),
+        _ => false,
+    }
+}
+
+fn translate(lookup: &dyn MuxLookup, notification: MuxNotification) -> Option<MuxEvent> {
+    Some(match notification {
+        MuxNotification::PaneAdded(pane_id) => MuxEvent::PaneAdded {
+            pane_id,
+            title: lookup.pane_title(pane_id).unwrap_or_default(),
+        },
+        MuxNotification::PaneRemoved(pane_id) => MuxEvent::PaneRemoved { pane_id },
+        MuxNotification::PaneFocused(pane_id) => MuxEvent::PaneFocused { pane_id },
+        MuxNotification::TabAddedToWindow { tab_id, window_id } => {
+            MuxEvent::TabAdded { tab_id, window_id }
+        }
+        MuxNotification::TabTitleChanged { tab_id, title } => {
+            MuxEvent::TabTitleChanged { tab_id, title }
+        }
+        MuxNotification::WindowCreated(window_id) => MuxEvent::WindowCreated { window_id },
+        MuxNotification::WindowRemoved(window_id) => MuxEvent::WindowRemoved { window_id },
+        MuxNotification::WindowTitleChanged { window_id, title } => {
+            MuxEvent::WindowTitleChanged { window_id, title }
+        }
+        MuxNotification::ActiveWorkspaceChanged(client_id) => MuxEvent::ActiveWorkspaceChanged {
+            workspace: lookup.active_workspace(&client_id),
+        },
+        MuxNotification::WorkspaceRenamed {
+            old_workspace,
+            new_workspace,
+        } => MuxEvent::WorkspaceRenamed {
+            old_workspace,
+            new_workspace,
+        },
+        MuxNotification::Alert { pane_id, alert } => match alert {
+            Alert::Bell => MuxEvent::Bell { pane_id },
+            Alert::ToastNotification { title, body, .. } => MuxEvent::Toast {
+                pane_id,
+                title,
+                body,
+            },
+            // The pane title is derived from these, so report what it
+            // became rather than the raw escape payload
+            Alert::IconTitleChanged(_) | Alert::WindowTitleChanged(_) => {
+                MuxEvent::PaneTitleChanged {
+                    pane_id,
+                    title: lookup.pane_title(pane_id)?,
+                }
+            }
+            _ => return None,
+        },
+        _ => return None,
+    })
+}
+
+/// Forward mux notifications to subscribed clients. Does nothing if there
+/// is no mux in this process.
+pub(crate) fn start_mux_events() {
+    let Some(mux) = Mux::try_get() else {
+        log::debug!("no mux; not forwarding mux events");
+        return;
+    };
+    mux.subscribe(|notification| {
+        if is_event(&notification) && REGISTRY.has_event_subscribers() {
+            promise::spawn::spawn_into_main_thread(async move {
+                let Some(mux) = Mux::try_get() else { return };
+                if let Some(event) = translate(&*mux, notification) {
+                    REGISTRY.broadcast_event(event);
+                }
+            })
+            .detach();
+        }
+        true
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Titles;
+
+    impl MuxLookup for Titles {
+        fn pane_title(&self, pane_id: PaneId) -> Option<String> {
+            (pane_id == 1).then(|| "vim".to_string())
+        }
+
+        fn active_workspace(&self, _client_id: &Arc<ClientId>) -> String {
+            "default".to_string()
+        }
+    }
+
+    #[test]
+    fn translates_titles_and_skips_noise() {
+        assert!(!is_event(&MuxNotification::PaneOutput(1)));
+        assert!(!is_event(&MuxNotification::Alert {
+            pane_id: 1,
+            alert: Alert::OutputSinceFocusLost,
+        }));
+
+        let title = MuxNotification::Alert {
+            pane_id: 1,
+            alert: Alert::WindowTitleChanged("ignored".to_string()),
+        };
+        assert!(is_event(&title));
+        assert_eq!(
+            translate(&Titles, title),
+            Some(MuxEvent::PaneTitleChanged {
+                pane_id: 1,
+                title: "vim".to_string(),
+            })
+        );
+        // The pane went away before the task ran
+        let gone = MuxNotification::Alert {
+            pane_id: 2,
+            alert: Alert::IconTitleChanged(None),
+        };
+        assert_eq!(translate(&Titles, gone), None);
+        assert_eq!(
+            translate(&Titles, MuxNotification::PaneAdded(2)),
+            Some(MuxEvent::PaneAdded {
+                pane_id: 2,
+                title: String::new(),
+            })
+        );
+    }
+
+    #[test]
+    fn filter_limits_names_and_panes() {
+        let bell = MuxEvent::Bell { pane_id: 3 };
+        let window = MuxEvent::WindowCreated { window_id: 0 };
+        assert!(EventFilter::default().matches(&bell));
+
+        let filter = EventFilter {
+            events: vec![],
+            panes: vec![1],
+            pane_events_only: false,
+        };
+        assert!(!filter.matches(&bell));
+        assert!(filter.matches(&window));
+
+        let filter = EventFilter {
+            events: vec!["bell".to_string()],
+            panes: vec![],
+            pane_events_only: false,
+        };
+        assert!(filter.matches(&bell));
+        assert!(!filter.matches(&window));
+    }
+
+    #[test]
+    fn restricted_filters_drop_events_about_other_things() {
+        let filter = EventFilter {
+            events: vec![],
+            panes: vec![1],
+            pane_events_only: true,
+        };
+        assert!(filter.matches(&MuxEvent::Bell { pane_id: 1 }));
+        assert!(!filter.matches(&MuxEvent::Bell { pane_id: 2 }));
+        assert!(!filter.matches(&MuxEvent::WindowTitleChanged {
+            window_id: 0,
+            title: "secret".to_string(),
+        }));
+        assert!(!filter.matches(&MuxEvent::TabTitleChanged {
+            tab_id: 0,
+            title: "secret".to_string(),
+        }));
+        assert!(!filter.matches(&MuxEvent::WorkspaceRenamed {
+            old_workspace: "default".to_string(),
+            new_workspace: "secret".to_string(),
+        }));
+    }
+}
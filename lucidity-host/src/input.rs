@@ -0,0 +1,248 @@
+//! Key and mouse events from clients, delivered through the pane's own
+//! input encoding so that application cursor keys, mouse reporting modes
+//! and the like come out the way they would from a local keyboard.
+
+use lucidity_proto::protocol::{
+    Key, KeyAction, KeyEventRequest, KeyMods, MouseButton, MouseEventKind, MouseEventRequest,
+};
+use mux::pane::Pane;
+use std::sync::Arc;
+use wezterm_term::{KeyCode, KeyModifiers, MouseEvent};
+
+/// Where key and mouse events go: a pane, or a bare terminal in tests
+pub(crate) trait InputTarget {
+    fn key_down(&mut self, key: KeyCode, mods: KeyModifiers) -> anyhow::Result<()>;
+    fn key_up(&mut self, key: KeyCode, mods: KeyModifiers) -> anyhow::Result<()>;
+    fn mouse_event(&mut self, event: MouseEvent) -> anyhow::Result<()>;
+}
+
+impl InputTarget for Arc<dyn Pane> {
+    fn key_down(&mut self, key: KeyCode, mods: KeyModifiers) -> anyhow::Result<()> {
+        Pane::key_down(&**self, key, mods)
+    }
+
+    fn key_up(&mut self, key: KeyCode, mods: KeyModifiers) -> anyhow::Result<()> {
+        Pane::key_up(&**self, key, mods)
+    }
+
+    fn mouse_event(&mut self, event: MouseEvent) -> anyhow::Result<()> {
+        Pane::mouse_event(&**self, event)
+    }
+}
+
+pub(crate) fn send_key(target: &mut dyn InputTarget, req: &KeyEventRequest) -> anyhow::Result<()> {
+    let key = key_code(req.key);
+    let mods = modifiers(req.mods);
+    if matches!(req.action, KeyAction::Press | KeyAction::Tap) {
+        target.key_down(key, mods)?;
+    }
+    if matches!(req.action, KeyAction::Release | KeyAction::Tap) {
+        target.key_up(key, mods)?;
+    }
+    Ok(())
+}
+
+pub(crate) fn send_mouse(
+    target: &mut dyn InputTarget,
+    req: &MouseEventRequest,
+) -> anyhow::Result<()> {
+    let event = |kind| MouseEvent {
+        kind,
+        x: req.x,
+        y: req.y,
+        x_pixel_offset: 0,
+        y_pixel_offset: 0,
+        button: mouse_button(req.button),
+        modifiers: modifiers(req.mods),
+    };
+    use wezterm_term::MouseEventKind as Kind;
+    match req.kind {
+        MouseEventKind::Press => target.mouse_event(event(Kind::Press)),
+        MouseEventKind::Release => target.mouse_event(event(Kind::Release)),
+        MouseEventKind::Move => target.mouse_event(event(Kind::Move)),
+        MouseEventKind::Click => {
+            target.mouse_event(event(Kind::Press))?;
+            target.mouse_event(event(Kind::Release))
+        }
+    }
+}
+
+fn key_code(key: Key) -> KeyCode {
+    match key {
+        Key::Char(c) => KeyCode::Char(c),
+        Key::Function(n) => KeyCode::Function(n),
+        Key::Enter => KeyCode::Enter,
+        Key::Tab => KeyCode::Tab,
+        Key::Backspace => KeyCode::Backspace,
+        Key::Escape => KeyCode::Escape,
+        Key::Insert => KeyCode::Insert,
+        Key::Delete => KeyCode::Delete,
+        Key::Home => KeyCode::Home,
+        Key::End => KeyCode::End,
+        Key::PageUp => KeyCode::PageUp,
+        Key::PageDown => KeyCode::PageDown,
+        Key::UpArrow => KeyCode::UpArrow,
+        Key::DownArrow => KeyCode::DownArrow,
+        Key::LeftArrow => KeyCode::LeftArrow,
+        Key::RightArrow => KeyCode::RightArrow,
+    }
+}
+
+fn modifiers(mods: KeyMods) -> KeyModifiers {
+    let mut out = KeyModifiers::NONE;
+    out.set(KeyModifiers::SHIFT, mods.shift);
+    out.set(KeyModifiers::ALT, mods.alt);
+    out.set(KeyModifiers::CTRL, mods.ctrl);
+    out.set(KeyModifiers::SUPER, mods.super_);
+    out
+}
+
+fn mouse_button(button: MouseButton) -> wezterm_term::MouseButton {
+    use wezterm_term::MouseButton as B;
+    // The terminal sends one report per wheel event whatever the count
+    match button {
+        MouseButton::Left => B::Left,
+        MouseButton::Middle => B::Middle,
+        MouseButton::Right => B::Right,
+        MouseButton::WheelUp => B::WheelUp(1),
+        MouseButton::WheelDown => B::WheelDown(1),
+        MouseButton::WheelLeft => B::WheelLeft(1),
+        MouseButton::WheelRight => B::WheelRight(1),
+        MouseButton::None => B::None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use std::sync::Mutex;
+    use std::time::{Duration, Instant};
+    use wezterm_term::color::ColorPalette;
+    use wezterm_term::{Terminal, TerminalConfiguration, TerminalSize, TerminalState};
+
+    #[derive(Debug)]
+    struct Config;
+
+    impl TerminalConfiguration for Config {
+        fn color_palette(&self) -> ColorPalette {
+            ColorPalette::default()
+        }
+    }
+
+    #[derive(Clone, Default)]
+    struct Sent(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Sent {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Sent {
+        /// The terminal writes from a thread of its own, so wait for
+        /// `expected` to arrive
+        fn expect(&self, expected: &str) {
+            let deadline = Instant::now() + Duration::from_secs(5);
+            while self.0.lock().unwrap().len() < expected.len() && Instant::now() < deadline {
+                std::thread::sleep(Duration::from_millis(5));
+            }
+            let sent = std::mem::take(&mut *self.0.lock().unwrap());
+            assert_eq!(String::from_utf8(sent).unwrap(), expected);
+        }
+    }
+
+    impl InputTarget for Terminal {
+        fn key_down(&mut self, key: KeyCode, mods: KeyModifiers) -> anyhow::Result<()> {
+            TerminalState::key_down(self, key, mods)
+        }
+
+        fn key_up(&mut self, key: KeyCode, mods: KeyModifiers) -> anyhow::Result<()> {
+            TerminalState::key_up(self, key, mods)
+        }
+
+        fn mouse_event(&mut self, event: MouseEvent) -> anyhow::Result<()> {
+            TerminalState::mouse_event(self, event)
+        }
+    }
+
+    fn terminal() -> (Terminal, Sent) {
+        let sent = Sent::default();
+        let term = Terminal::new(
+            TerminalSize {
+                rows: 24,
+                cols: 80,
+                pixel_width: 640,
+                pixel_height: 384,
+                dpi: 0,
+            },
+            Arc::new(Config),
+            "Lucidity",
+            "test",
+            Box::new(sent.clone()),
+        );
+        (term, sent)
+    }
+
+    fn key(key: Key, mods: KeyMods) -> KeyEventRequest {
+        KeyEventRequest {
+            pane_id: 0,
+            key,
+            mods,
+            action: KeyAction::Tap,
+        }
+    }
+
+    #[test]
+    fn keys_follow_the_terminal_modes() {
+        let (mut term, sent) = terminal();
+        send_key(&mut term, &key(Key::UpArrow, KeyMods::default())).unwrap();
+        sent.expect("\x1b[A");
+
+        // Application cursor keys, as set by vim and less
+        term.advance_bytes(b"\x1b[?1h");
+        send_key(&mut term, &key(Key::UpArrow, KeyMods::default())).unwrap();
+        sent.expect("\x1bOA");
+
+        let ctrl = KeyMods {
+            ctrl: true,
+            ..Default::default()
+        };
+        send_key(&mut term, &key(Key::Char('c'), ctrl)).unwrap();
+        sent.expect("\x03");
+    }
+
+    #[test]
+    fn taps_and_wheel_become_mouse_reports() {
+        let (mut term, sent) = terminal();
+        let click = MouseEventRequest {
+            pane_id: 0,
+            kind: MouseEventKind::Click,
+            button: MouseButton::Left,
+            x: 4,
+            y: 2,
+            mods: KeyMods::default(),
+        };
+        // Nothing is reported until the application asks for it
+        send_mouse(&mut term, &click).unwrap();
+        send_key(&mut term, &key(Key::Char('x'), KeyMods::default())).unwrap();
+        sent.expect("x");
+
+        term.advance_bytes(b"\x1b[?1000h\x1b[?1006h");
+        send_mouse(&mut term, &click).unwrap();
+        sent.expect("\x1b[<0;5;3M\x1b[<0;5;3m");
+
+        let wheel = MouseEventRequest {
+            kind: MouseEventKind::Press,
+            button: MouseButton::WheelDown,
+            ..click
+        };
+        send_mouse(&mut term, &wheel).unwrap();
+        sent.expect("\x1b[<65;5;3M");
+    }
+}
@@ -1,20 +1,43 @@
+mod attach;
 mod bridge;
+mod cells;
+mod events;
+mod input;
+mod mdns;
+mod mux_ops;
+mod natpmp;
 mod p2p;
 mod pairing_api;
+mod paste;
+mod punch;
 mod protocol;
 mod clipboard;
 mod registry;
 mod relay_client;
+mod resume;
+mod secure;
 mod server;
+mod session;
+mod snapshot;
 
-pub use bridge::{FakePaneBridge, MuxPaneBridge, PaneBridge, PaneInfo};
+pub use bridge::{FakePaneBridge, FakeTab, MuxPaneBridge, PaneBridge, PaneInfo};
+pub use clipboard::{set_clipboard_provider, ClipboardProvider, FakeClipboard, MuxClipboard};
 pub use pairing_api::{
-    current_pairing_payload, handle_pairing_submit, list_trusted_devices, revoke_device,
-    load_or_create_host_keypair, set_pairing_approver, pairing_payload_with_p2p,
-    PairingApproval, PairingApprover,
+    current_pairing_payload, handle_pairing_submit, invalidate_pairing_tokens,
+    list_trusted_devices, revoke_device, load_or_create_host_keypair, set_pairing_approver,
+    pairing_payload_with_p2p, short_code_state, start_short_code, PairingApproval,
+    PairingApprover, ShortCodeState,
 };
-pub use protocol::{TYPE_JSON, TYPE_PANE_INPUT, TYPE_PANE_OUTPUT};
-pub use server::{autostart_in_process, serve_blocking, serve_blocking_with_limit, HostConfig};
-pub use p2p::{ExternalConnectionInfo, P2PConnectivity};
-pub use relay_client::{RelayClient, RelayStatus};
+pub use protocol::{
+    TYPE_JSON, TYPE_PANE_INPUT, TYPE_PANE_INPUT_ADDRESSED, TYPE_PANE_OUTPUT,
+    TYPE_PANE_OUTPUT_ADDRESSED, TYPE_PANE_SNAPSHOT, TYPE_SCREEN_UPDATE, TYPE_SEALED,
+};
+pub use server::{
+    autostart_in_process, serve_blocking, serve_blocking_with_limit, set_relay_enabled, shutdown,
+    HostConfig,
+};
+pub use mdns::{browse_hosts, find_host, Advertisement, DiscoveredHost};
+pub use p2p::{ExternalConnectionInfo, MappingRefresh, P2PConnectivity, PortMapping};
+pub use punch::{connect_direct, DirectConnection, PunchSocket};
+pub use relay_client::{connect_mobile, RelayClient, RelayStatus};
 
@@ -0,0 +1,212 @@
+//! LAN discovery over mDNS/DNS-SD, so that a paired device can find its
+//! host after the address it was given at pairing time goes stale.
+//!
+//! The host advertises a `_lucidity._tcp` service named after its relay_id,
+//! with the relay_id and its key's fingerprint in the TXT record. Anyone on
+//! the LAN can advertise anything, so a host found this way must still
+//! prove its key when the device connects.
+
+use anyhow::{Context, Result};
+use lucidity_pairing::{PairingPayload, PublicKey};
+use mdns_sd::{ResolvedService, ServiceDaemon, ServiceEvent, ServiceInfo};
+use std::net::{IpAddr, SocketAddr};
+use std::time::{Duration, Instant};
+
+pub const SERVICE_TYPE: &str = "_lucidity._tcp.local.";
+
+const TXT_RELAY_ID: &str = "relay_id";
+const TXT_FINGERPRINT: &str = "key_sha256";
+
+/// The host's service, advertised until this is dropped
+pub struct Advertisement {
+    daemon: ServiceDaemon,
+    fullname: String,
+}
+
+impl Advertisement {
+    /// Advertise the host with `public_key`, listening on `port` on every
+    /// interface
+    pub fn new(public_key: &PublicKey, port: u16) -> Result<Self> {
+        let relay_id = PairingPayload::derive_relay_id(public_key);
+        let fingerprint = public_key.fingerprint();
+        let host_name = format!("lucidity-{}.local.", &fingerprint[..16]);
+        let properties = [
+            (TXT_RELAY_ID, relay_id.as_str()),
+            (TXT_FINGERPRINT, fingerprint.as_str()),
+        ];
+        let info = ServiceInfo::new(
+            SERVICE_TYPE,
+            &relay_id,
+            &host_name,
+            "",
+            port,
+            &properties[..],
+        )?
+        .enable_addr_auto();
+        let fullname = info.get_fullname().to_string();
+
+        let daemon = ServiceDaemon::new().context("starting mDNS")?;
+        daemon.register(info).context("registering mDNS service")?;
+        Ok(Self { daemon, fullname })
+    }
+}
+
+impl Drop for Advertisement {
+    fn drop(&mut self) {
+        // Say goodbye, so browsers forget us now rather than when the
+        // records expire
+        if let Ok(done) = self.daemon.unregister(&self.fullname) {
+            let _ = done.recv_timeout(Duration::from_secs(1));
+        }
+        let _ = self.daemon.shutdown();
+    }
+}
+
+/// A host found on the LAN
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DiscoveredHost {
+    pub relay_id: String,
+    /// `PublicKey::fingerprint` of the host's key
+    pub fingerprint: String,
+    /// Where the host is listening, IPv4 first
+    pub addrs: Vec<SocketAddr>,
+}
+
+impl DiscoveredHost {
+    fn from_resolved(service: &ResolvedService) -> Option<Self> {
+        let relay_id = service.get_property_val_str(TXT_RELAY_ID)?;
+        let fingerprint = service.get_property_val_str(TXT_FINGERPRINT)?;
+        let mut ips: Vec<IpAddr> = service
+            .get_addresses()
+            .iter()
+            .map(|ip| ip.to_ip_addr())
+            // Link-local IPv6 needs a scope that a SocketAddr string can't carry
+            .filter(|ip| !matches!(ip, IpAddr::V6(v6) if v6.is_unicast_link_local()))
+            .collect();
+        ips.sort_by_key(|ip| (ip.is_ipv6(), *ip));
+        Some(Self {
+            relay_id: relay_id.to_string(),
+            fingerprint: fingerprint.to_ascii_lowercase(),
+            addrs: ips
+                .into_iter()
+                .map(|ip| SocketAddr::new(ip, service.get_port()))
+                .collect(),
+        })
+    }
+
+    /// Whether this claims to be the host with `public_key`
+    pub fn is(&self, public_key: &PublicKey) -> bool {
+        self.relay_id == PairingPayload::derive_relay_id(public_key)
+            && self.fingerprint == public_key.fingerprint()
+    }
+}
+
+/// Browse until `timeout`, or until `done` says the host just found is the
+/// one wanted, returning every host found
+fn browse_until(
+    timeout: Duration,
+    done: impl Fn(&DiscoveredHost) -> bool,
+) -> Result<Vec<DiscoveredHost>> {
+    let daemon = ServiceDaemon::new().context("starting mDNS")?;
+    let events = daemon.browse(SERVICE_TYPE).context("browsing mDNS")?;
+    let deadline = Instant::now() + timeout;
+    let mut hosts: Vec<DiscoveredHost> = vec![];
+    while let Ok(event) = events.recv_deadline(deadline) {
+        let ServiceEvent::ServiceResolved(service) = event else {
+            continue;
+        };
+        let Some(host) = DiscoveredHost::from_resolved(&service) else {
+            log::debug!("Ignoring {}: no relay_id or key", service.get_fullname());
+            continue;
+        };
+        let found = done(&host);
+        match hosts
+            .iter_mut()
+            .find(|h| h.relay_id == host.relay_id && h.fingerprint == host.fingerprint)
+        {
+            Some(existing) => *existing = host,
+            None => hosts.push(host),
+        }
+        if found {
+            break;
+        }
+    }
+    let _ = daemon.shutdown();
+    Ok(hosts)
+}
+
+/// Every host that answers within `timeout`
+pub fn browse_hosts(timeout: Duration) -> Result<Vec<DiscoveredHost>> {
+    browse_until(timeout, |_| false)
+}
+
+/// The host with `public_key`, if it answers within `timeout`
+pub fn find_host(public_key: &PublicKey, timeout: Duration) -> Result<Option<DiscoveredHost>> {
+    let hosts = browse_until(timeout, |host| host.is(public_key))?;
+    Ok(hosts.into_iter().find(|host| host.is(public_key)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use lucidity_pairing::Keypair;
+
+    fn resolved(properties: &[(&str, &str)]) -> ResolvedService {
+        ServiceInfo::new(
+            SERVICE_TYPE,
+            "instance",
+            "lucidity-test.local.",
+            "fe80::1,2001:db8::5,192.168.1.20",
+            9797,
+            properties,
+        )
+        .unwrap()
+        .as_resolved_service()
+    }
+
+    #[test]
+    fn hosts_are_recognized_by_relay_id_and_key() {
+        let key = Keypair::generate().public_key();
+        let relay_id = PairingPayload::derive_relay_id(&key);
+        let fingerprint = key.fingerprint();
+
+        let host = DiscoveredHost::from_resolved(&resolved(&[
+            (TXT_RELAY_ID, &relay_id),
+            (TXT_FINGERPRINT, &fingerprint),
+        ]))
+        .unwrap();
+        assert_eq!(
+            host.addrs,
+            vec![
+                "192.168.1.20:9797".parse::<SocketAddr>().unwrap(),
+                "[2001:db8::5]:9797".parse().unwrap(),
+            ]
+        );
+        assert!(host.is(&key));
+        assert!(!host.is(&Keypair::generate().public_key()));
+
+        // Someone else's key under our relay_id is not us
+        let impostor = DiscoveredHost::from_resolved(&resolved(&[
+            (TXT_RELAY_ID, &relay_id),
+            (
+                TXT_FINGERPRINT,
+                &Keypair::generate().public_key().fingerprint(),
+            ),
+        ]))
+        .unwrap();
+        assert!(!impostor.is(&key));
+
+        assert!(DiscoveredHost::from_resolved(&resolved(&[(TXT_RELAY_ID, &relay_id)])).is_none());
+    }
+
+    #[test]
+    fn advertised_hosts_can_be_found() {
+        let key = Keypair::generate().public_key();
+        let advertisement = Advertisement::new(&key, 9797).unwrap();
+        let host = find_host(&key, Duration::from_secs(5))
+            .unwrap()
+            .expect("host not found");
+        assert!(host.addrs.iter().all(|addr| addr.port() == 9797));
+        drop(advertisement);
+    }
+}
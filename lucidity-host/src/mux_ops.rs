@@ -0,0 +1,375 @@
+//! Mux control operations: spawn, split, kill, activate, zoom and rename.
+//!
+//! The `MuxPaneBridge` implementations follow the matching `wezterm cli`
+//! subcommand and the mux server handler behind it, and run on the main
+//! thread like those handlers do.
+
+use crate::bridge::PaneBridge;
+use anyhow::{anyhow, bail, ensure};
+use config::keyassignment::SpawnTabDomain;
+use lucidity_proto::protocol::{
+    ActivateTabRequest, JsonRequest, JsonResponse, SpawnRequest, SpawnedPane, SplitPaneRequest,
+    SplitSide, SplitSize, ZoomMode,
+};
+use mux::domain::SplitSource;
+use mux::pane::{Pane, PaneId};
+use mux::tab::{self, SplitDirection, SplitRequest, TabId};
+use mux::{Mux, MuxNotification};
+use portable_pty::CommandBuilder;
+use std::ffi::OsString;
+use std::future::Future;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Answer a mux control request. A failed operation is reported to the
+/// client rather than ending the connection.
+pub(crate) fn handle_request(bridge: &dyn PaneBridge, req: JsonRequest) -> JsonResponse {
+    let result = match req {
+        JsonRequest::Spawn(req) => bridge.spawn(req).map(JsonResponse::Spawned),
+        JsonRequest::SplitPane(req) => bridge.split_pane(req).map(JsonResponse::Spawned),
+        JsonRequest::KillPane { pane_id } => bridge.kill_pane(pane_id).map(|()| JsonResponse::Ok),
+        JsonRequest::ActivateTab(req) => bridge.activate_tab(req).map(|()| JsonResponse::Ok),
+        JsonRequest::ActivatePane { pane_id } => {
+            bridge.activate_pane(pane_id).map(|()| JsonResponse::Ok)
+        }
+        JsonRequest::ZoomPane { pane_id, mode } => {
+            bridge.zoom_pane(pane_id, mode).map(|()| JsonResponse::Ok)
+        }
+        JsonRequest::RenameWorkspace {
+            workspace,
+            pane_id,
+            new_workspace,
+        } => bridge
+            .rename_workspace(workspace, pane_id, new_workspace)
+            .map(|()| JsonResponse::Ok),
+        other => Err(anyhow!("not a mux operation: {other:?}")),
+    };
+    result.unwrap_or_else(|err| JsonResponse::Error {
+        message: format!("{err:#}"),
+    })
+}
+
+/// How long to wait for the main thread to run an operation
+const MAIN_THREAD_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Run `f` on the main thread and wait for its result.
+pub(crate) fn on_main_thread<T, F, Fut>(f: F) -> anyhow::Result<T>
+where
+    F: FnOnce() -> Fut + Send + 'static,
+    Fut: Future<Output = anyhow::Result<T>> + 'static,
+    T: Send + 'static,
+{
+    let (tx, rx) = crossbeam::channel::bounded(1);
+    promise::spawn::spawn_into_main_thread(async move {
+        promise::spawn::spawn(async move {
+            tx.send(f().await).ok();
+        })
+        .detach();
+    })
+    .detach();
+    rx.recv_timeout(MAIN_THREAD_TIMEOUT)
+        .map_err(|_| anyhow!("timed out waiting for the main thread"))?
+}
+
+/// `pane_id`, or the pane focused most recently by any client, which is
+/// what `wezterm cli` uses when `--pane-id` is omitted.
+pub(crate) fn resolve_pane_id(mux: &Mux, pane_id: Option<PaneId>) -> anyhow::Result<PaneId> {
+    if let Some(pane_id) = pane_id {
+        return Ok(pane_id);
+    }
+    mux.iter_clients()
+        .into_iter()
+        .filter(|client| client.focused_pane_id.is_some())
+        .max_by_key(|client| client.last_input)
+        .and_then(|client| client.focused_pane_id)
+        .ok_or_else(|| anyhow!("no pane_id given and no pane is focused"))
+}
+
+fn get_pane(mux: &Mux, pane_id: PaneId) -> anyhow::Result<Arc<dyn Pane>> {
+    mux.get_pane(pane_id)
+        .ok_or_else(|| anyhow!("no such pane: {pane_id}"))
+}
+
+fn command_builder(argv: Vec<String>) -> Option<CommandBuilder> {
+    if argv.is_empty() {
+        None
+    } else {
+        Some(CommandBuilder::from_argv(
+            argv.into_iter().map(OsString::from).collect(),
+        ))
+    }
+}
+
+pub(crate) async fn spawn(req: SpawnRequest) -> anyhow::Result<SpawnedPane> {
+    let mux = Mux::get();
+    let window_id = if req.new_window {
+        None
+    } else {
+        match req.window_id {
+            Some(window_id) => Some(window_id),
+            None => {
+                let pane_id = resolve_pane_id(&mux, req.pane_id)?;
+                mux.resolve_pane_id(pane_id)
+                    .map(|(_domain_id, window_id, _tab_id)| window_id)
+            }
+        }
+    };
+
+    let config = config::configuration();
+    let workspace = req
+        .workspace
+        .or_else(|| config.default_workspace.clone())
+        .unwrap_or_else(|| mux::DEFAULT_WORKSPACE.to_string());
+    let domain = match req.domain_name {
+        Some(name) => SpawnTabDomain::DomainName(name),
+        None => SpawnTabDomain::DefaultDomain,
+    };
+
+    let (tab, pane, window_id) = mux
+        .spawn_tab_or_window(
+            window_id,
+            domain,
+            command_builder(req.command),
+            req.cwd,
+            config.initial_size(0, None),
+            None,
+            workspace,
+            None,
+        )
+        .await?;
+
+    Ok(SpawnedPane {
+        pane_id: pane.pane_id(),
+        tab_id: tab.tab_id(),
+        window_id,
+    })
+}
+
+pub(crate) async fn split_pane(req: SplitPaneRequest) -> anyhow::Result<SpawnedPane> {
+    let mux = Mux::get();
+    let (_domain_id, window_id, tab_id) = mux
+        .resolve_pane_id(req.pane_id)
+        .ok_or_else(|| anyhow!("no such pane: {}", req.pane_id))?;
+
+    let (direction, target_is_second) = match req.side {
+        SplitSide::Left => (SplitDirection::Horizontal, false),
+        SplitSide::Right => (SplitDirection::Horizontal, true),
+        SplitSide::Top => (SplitDirection::Vertical, false),
+        SplitSide::Bottom => (SplitDirection::Vertical, true),
+    };
+    let size = match req.size {
+        Some(SplitSize::Cells(cells)) => tab::SplitSize::Cells(cells),
+        Some(SplitSize::Percent(percent)) => tab::SplitSize::Percent(percent),
+        None => tab::SplitSize::Percent(50),
+    };
+    let source = match req.move_pane_id {
+        Some(pane_id) => SplitSource::MovePane(pane_id),
+        None => SplitSource::Spawn {
+            command: command_builder(req.command),
+            command_dir: req.cwd,
+        },
+    };
+
+    let (pane, _size) = mux
+        .split_pane(
+            req.pane_id,
+            SplitRequest {
+                direction,
+                target_is_second,
+                size,
+                top_level: req.top_level,
+            },
+            source,
+            SpawnTabDomain::CurrentPaneDomain,
+        )
+        .await?;
+
+    Ok(SpawnedPane {
+        pane_id: pane.pane_id(),
+        tab_id,
+        window_id,
+    })
+}
+
+pub(crate) fn kill_pane(pane_id: PaneId) -> anyhow::Result<()> {
+    let mux = Mux::get();
+    let pane = get_pane(&mux, pane_id)?;
+    pane.kill();
+    mux.remove_pane(pane_id);
+    Ok(())
+}
+
+/// Make `pane_id` the active pane of its tab and its tab the active tab of
+/// its window.
+pub(crate) fn activate_pane(pane_id: PaneId) -> anyhow::Result<()> {
+    let mux = Mux::get();
+    let pane = get_pane(&mux, pane_id)?;
+    let (_domain_id, window_id, tab_id) = mux
+        .resolve_pane_id(pane_id)
+        .ok_or_else(|| anyhow!("pane {pane_id} is not in a tab"))?;
+    {
+        let mut window = mux
+            .get_window_mut(window_id)
+            .ok_or_else(|| anyhow!("no such window: {window_id}"))?;
+        let tab_idx = window
+            .idx_by_id(tab_id)
+            .ok_or_else(|| anyhow!("tab {tab_id} is not in window {window_id}"))?;
+        window.save_and_then_set_active(tab_idx);
+    }
+    let tab = mux
+        .get_tab(tab_id)
+        .ok_or_else(|| anyhow!("no such tab: {tab_id}"))?;
+    tab.set_active_pane(&pane);
+    mux.notify(MuxNotification::PaneFocused(pane_id));
+    Ok(())
+}
+
+pub(crate) fn activate_tab(req: ActivateTabRequest) -> anyhow::Result<()> {
+    let mux = Mux::get();
+    let tab_id = match req.tab_id {
+        Some(tab_id) => tab_id,
+        None => {
+            let pane_id = resolve_pane_id(&mux, req.pane_id)?;
+            let (_domain_id, window_id, current) = mux
+                .resolve_pane_id(pane_id)
+                .ok_or_else(|| anyhow!("pane {pane_id} is not in a tab"))?;
+            let tabs: Vec<TabId> = mux
+                .get_window(window_id)
+                .ok_or_else(|| anyhow!("no such window: {window_id}"))?
+                .iter()
+                .map(|tab| tab.tab_id())
+                .collect();
+            pick_tab(&tabs, current, &req)?
+        }
+    };
+    let pane = mux
+        .get_tab(tab_id)
+        .and_then(|tab| tab.get_active_pane())
+        .ok_or_else(|| anyhow!("no active pane in tab {tab_id}"))?;
+    activate_pane(pane.pane_id())
+}
+
+/// Choose the tab for an `activate_tab` without a `tab_id`, given the tabs of
+/// the window and the current one. Same rules as `wezterm cli activate-tab`.
+pub(crate) fn pick_tab(
+    tabs: &[TabId],
+    current: TabId,
+    req: &ActivateTabRequest,
+) -> anyhow::Result<TabId> {
+    let max = tabs.len();
+    ensure!(max > 0, "window has no tabs");
+
+    if let Some(tab_index) = req.tab_index {
+        let idx = if tab_index < 0 {
+            max.saturating_sub(tab_index.unsigned_abs())
+        } else {
+            tab_index as usize
+        };
+        tabs.get(idx)
+            .copied()
+            .ok_or_else(|| anyhow!("tab index {tab_index} is invalid"))
+    } else if let Some(delta) = req.tab_relative {
+        let active = tabs
+            .iter()
+            .position(|&tab_id| tab_id == current)
+            .ok_or_else(|| anyhow!("current tab is not in its window"))?
+            as isize;
+        let tab = active + delta;
+        let idx = if req.no_wrap {
+            tab.clamp(0, max as isize - 1)
+        } else {
+            tab.rem_euclid(max as isize)
+        };
+        Ok(tabs[idx as usize])
+    } else {
+        bail!("activate_tab needs tab_id, tab_index or tab_relative")
+    }
+}
+
+pub(crate) fn zoom_pane(pane_id: PaneId, mode: ZoomMode) -> anyhow::Result<()> {
+    let mux = Mux::get();
+    let pane = get_pane(&mux, pane_id)?;
+    let (_domain_id, _window_id, tab_id) = mux
+        .resolve_pane_id(pane_id)
+        .ok_or_else(|| anyhow!("pane {pane_id} is not in a tab"))?;
+    let tab = mux
+        .get_tab(tab_id)
+        .ok_or_else(|| anyhow!("no such tab: {tab_id}"))?;
+
+    let is_zoomed = tab.get_zoomed_pane().map(|p| p.pane_id()) == Some(pane_id);
+    let zoomed = match mode {
+        ZoomMode::Zoom => true,
+        ZoomMode::Unzoom => false,
+        ZoomMode::Toggle => !is_zoomed,
+    };
+    if zoomed == is_zoomed {
+        return Ok(());
+    }
+    tab.set_zoomed(false);
+    if zoomed {
+        tab.set_active_pane(&pane);
+        tab.set_zoomed(true);
+    }
+    Ok(())
+}
+
+pub(crate) fn rename_workspace(
+    workspace: Option<String>,
+    pane_id: Option<PaneId>,
+    new_workspace: &str,
+) -> anyhow::Result<()> {
+    let mux = Mux::get();
+    let old_workspace = match workspace {
+        Some(workspace) => workspace,
+        None => {
+            let pane_id = resolve_pane_id(&mux, pane_id)?;
+            let (_domain_id, window_id, _tab_id) = mux
+                .resolve_pane_id(pane_id)
+                .ok_or_else(|| anyhow!("pane {pane_id} is not in a tab"))?;
+            mux.get_window(window_id)
+                .ok_or_else(|| anyhow!("no such window: {window_id}"))?
+                .get_workspace()
+                .to_string()
+        }
+    };
+    mux.rename_workspace(&old_workspace, new_workspace);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn by_index(tab_index: isize) -> ActivateTabRequest {
+        ActivateTabRequest {
+            tab_index: Some(tab_index),
+            ..Default::default()
+        }
+    }
+
+    fn relative(delta: isize, no_wrap: bool) -> ActivateTabRequest {
+        ActivateTabRequest {
+            tab_relative: Some(delta),
+            no_wrap,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn tab_index_counts_from_either_end() {
+        let tabs = [10, 11, 12];
+        assert_eq!(pick_tab(&tabs, 10, &by_index(1)).unwrap(), 11);
+        assert_eq!(pick_tab(&tabs, 10, &by_index(-1)).unwrap(), 12);
+        assert!(pick_tab(&tabs, 10, &by_index(3)).is_err());
+    }
+
+    #[test]
+    fn tab_relative_wraps_unless_told_not_to() {
+        let tabs = [10, 11, 12];
+        assert_eq!(pick_tab(&tabs, 12, &relative(1, false)).unwrap(), 10);
+        assert_eq!(pick_tab(&tabs, 10, &relative(-1, false)).unwrap(), 12);
+        assert_eq!(pick_tab(&tabs, 12, &relative(1, true)).unwrap(), 12);
+        assert_eq!(pick_tab(&tabs, 10, &relative(-5, true)).unwrap(), 10);
+        assert!(pick_tab(&tabs, 10, &ActivateTabRequest::default()).is_err());
+    }
+}
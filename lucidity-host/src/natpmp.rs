@@ -0,0 +1,485 @@
+//! NAT-PMP (RFC 6886) and PCP (RFC 6887) port mapping
+//!
+//! Routers that don't speak UPnP often speak one of these instead. Both
+//! are small UDP protocols spoken to the default gateway on port 5351;
+//! PCP is tried first, and a gateway that only knows NAT-PMP says so by
+//! answering in NAT-PMP's version.
+//!
+//! Mappings are leases: `Mapping::renew` them before half of `lifetime`
+//! has passed, and `Mapping::delete` them when done.
+
+use anyhow::{Context, Result};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket};
+use std::time::Duration;
+
+/// The port NAT-PMP and PCP gateways listen on
+pub const GATEWAY_PORT: u16 = 5351;
+
+const NAT_PMP_VERSION: u8 = 0;
+const PCP_VERSION: u8 = 2;
+
+const NAT_PMP_OP_EXTERNAL_ADDRESS: u8 = 0;
+const NAT_PMP_OP_MAP_TCP: u8 = 2;
+const PCP_OP_MAP: u8 = 1;
+/// Set in the opcode of responses
+const RESPONSE: u8 = 0x80;
+
+/// Result code both protocols use for a version they don't speak
+const UNSUPPORTED_VERSION: u16 = 1;
+const IPPROTO_TCP: u8 = 6;
+
+/// The first retransmission comes after this, doubling for each one after
+const INITIAL_TIMEOUT: Duration = Duration::from_millis(250);
+const ATTEMPTS: u32 = 4;
+
+/// Which protocol a mapping was made with
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Protocol {
+    NatPmp,
+    Pcp,
+}
+
+/// A TCP port mapped on a gateway
+#[derive(Debug, Clone)]
+pub struct Mapping {
+    gateway: SocketAddr,
+    protocol: Protocol,
+    /// Identifies the mapping to a PCP gateway across renewals
+    nonce: [u8; 12],
+    requested_lifetime: Duration,
+    pub internal_port: u16,
+    pub external_port: u16,
+    /// The gateway's external address, if it told us
+    pub external_ip: Option<Ipv4Addr>,
+    /// How long the gateway granted the mapping for
+    pub lifetime: Duration,
+}
+
+/// Map TCP `internal_port` on this machine through `gateway`, asking for
+/// the same port outside and a lease of `lifetime`
+pub fn map_port(gateway: SocketAddr, internal_port: u16, lifetime: Duration) -> Result<Mapping> {
+    let mut mapping = Mapping {
+        gateway,
+        protocol: Protocol::Pcp,
+        nonce: new_nonce(),
+        requested_lifetime: lifetime,
+        internal_port,
+        external_port: internal_port,
+        external_ip: None,
+        lifetime,
+    };
+    match mapping.request(lifetime) {
+        Err(e) if is_unsupported_version(&e) => {
+            log::debug!("{} doesn't speak PCP; trying NAT-PMP", gateway);
+            mapping.protocol = Protocol::NatPmp;
+            mapping.request(lifetime)?;
+            mapping.external_ip = nat_pmp_external_address(gateway)
+                .map_err(|e| log::debug!("NAT-PMP external address: {:#}", e))
+                .ok();
+        }
+        result => result?,
+    }
+    log::info!(
+        "{:?} port mapping created: external:{} -> local:{} for {:?}",
+        mapping.protocol,
+        mapping.external_port,
+        internal_port,
+        mapping.lifetime
+    );
+    Ok(mapping)
+}
+
+impl Mapping {
+    pub fn protocol(&self) -> Protocol {
+        self.protocol
+    }
+
+    /// Extend the lease, keeping the same external port if the gateway
+    /// allows
+    pub fn renew(&mut self) -> Result<()> {
+        self.request(self.requested_lifetime)
+    }
+
+    /// Remove the mapping from the gateway
+    pub fn delete(&mut self) -> Result<()> {
+        self.request(Duration::ZERO)
+    }
+
+    fn request(&mut self, lifetime: Duration) -> Result<()> {
+        let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?;
+        socket
+            .connect(self.gateway)
+            .with_context(|| format!("connecting to gateway {}", self.gateway))?;
+        let lifetime_secs = lifetime.as_secs().min(u32::MAX as u64) as u32;
+        // Deleting a NAT-PMP mapping asks for external port 0
+        let suggested_port = if lifetime.is_zero() && self.protocol == Protocol::NatPmp {
+            0
+        } else {
+            self.external_port
+        };
+
+        let response = match self.protocol {
+            Protocol::Pcp => {
+                let client_ip = match socket.local_addr()?.ip() {
+                    IpAddr::V4(ip) => ip,
+                    IpAddr::V6(_) => anyhow::bail!("PCP over IPv6 is not supported"),
+                };
+                let request = pcp_map_request(
+                    client_ip,
+                    &self.nonce,
+                    self.internal_port,
+                    suggested_port,
+                    self.external_ip.unwrap_or(Ipv4Addr::UNSPECIFIED),
+                    lifetime_secs,
+                );
+                exchange(&socket, &request, PCP_OP_MAP)?
+            }
+            Protocol::NatPmp => {
+                let request =
+                    nat_pmp_map_request(self.internal_port, suggested_port, lifetime_secs);
+                exchange(&socket, &request, NAT_PMP_OP_MAP_TCP)?
+            }
+        };
+
+        let granted = match self.protocol {
+            Protocol::Pcp => parse_pcp_map_response(&response, &self.nonce)?,
+            Protocol::NatPmp => parse_nat_pmp_map_response(&response)?,
+        };
+        if !lifetime.is_zero() {
+            self.external_port = granted.external_port;
+            self.lifetime = Duration::from_secs(granted.lifetime_secs as u64);
+            if granted.external_ip.is_some() {
+                self.external_ip = granted.external_ip;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The default gateway, where NAT-PMP and PCP servers listen, for a
+/// machine whose address is `local_ip`
+pub fn default_gateway(local_ip: Ipv4Addr) -> Ipv4Addr {
+    #[cfg(target_os = "linux")]
+    if let Some(gateway) = std::fs::read_to_string("/proc/net/route")
+        .ok()
+        .and_then(|routes| parse_proc_net_route(&routes))
+    {
+        return gateway;
+    }
+    // Most home networks put the router at .1
+    let [a, b, c, _] = local_ip.octets();
+    Ipv4Addr::new(a, b, c, 1)
+}
+
+/// The gateway of the default route in the kernel's routing table
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+fn parse_proc_net_route(routes: &str) -> Option<Ipv4Addr> {
+    const RTF_GATEWAY: u32 = 0x2;
+    routes.lines().skip(1).find_map(|line| {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        let (destination, gateway, flags) = (fields.get(1)?, fields.get(2)?, fields.get(3)?);
+        let flags = u32::from_str_radix(flags, 16).ok()?;
+        if *destination != "00000000" || flags & RTF_GATEWAY == 0 {
+            return None;
+        }
+        // Addresses are in network order, printed as a native-endian u32
+        let gateway = u32::from_str_radix(gateway, 16).ok()?;
+        Some(Ipv4Addr::from(gateway.to_le_bytes()))
+    })
+}
+
+#[derive(Debug)]
+struct UnsupportedVersion;
+
+impl std::fmt::Display for UnsupportedVersion {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "gateway does not support this protocol version")
+    }
+}
+
+impl std::error::Error for UnsupportedVersion {}
+
+fn is_unsupported_version(e: &anyhow::Error) -> bool {
+    e.downcast_ref::<UnsupportedVersion>().is_some()
+}
+
+fn new_nonce() -> [u8; 12] {
+    let mut nonce = [0u8; 12];
+    nonce.copy_from_slice(&uuid::Uuid::new_v4().as_bytes()[..12]);
+    nonce
+}
+
+/// Send `request` until a response to `opcode` comes back, retransmitting
+/// with a doubling timeout
+fn exchange(socket: &UdpSocket, request: &[u8], opcode: u8) -> Result<Vec<u8>> {
+    let mut timeout = INITIAL_TIMEOUT;
+    let mut buf = [0u8; 1100];
+    for _ in 0..ATTEMPTS {
+        socket.send(request)?;
+        socket.set_read_timeout(Some(timeout))?;
+        loop {
+            let n = match socket.recv(&mut buf) {
+                Ok(n) => n,
+                Err(e)
+                    if matches!(
+                        e.kind(),
+                        std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut
+                    ) =>
+                {
+                    break
+                }
+                Err(e) => return Err(e).context("reading from gateway"),
+            };
+            let response = &buf[..n];
+            if response.len() < 4 {
+                continue;
+            }
+            // A NAT-PMP gateway answers PCP in its own version
+            if response[0] != request[0] {
+                if response[0] == NAT_PMP_VERSION
+                    && u16::from_be_bytes([response[2], response[3]]) == UNSUPPORTED_VERSION
+                {
+                    return Err(UnsupportedVersion.into());
+                }
+                continue;
+            }
+            if response[1] == opcode | RESPONSE {
+                return Ok(response.to_vec());
+            }
+        }
+        timeout *= 2;
+    }
+    anyhow::bail!("no response from gateway")
+}
+
+fn nat_pmp_map_request(internal_port: u16, external_port: u16, lifetime_secs: u32) -> Vec<u8> {
+    let mut request = vec![NAT_PMP_VERSION, NAT_PMP_OP_MAP_TCP, 0, 0];
+    request.extend_from_slice(&internal_port.to_be_bytes());
+    request.extend_from_slice(&external_port.to_be_bytes());
+    request.extend_from_slice(&lifetime_secs.to_be_bytes());
+    request
+}
+
+fn nat_pmp_external_address(gateway: SocketAddr) -> Result<Ipv4Addr> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?;
+    socket.connect(gateway)?;
+    let response = exchange(
+        &socket,
+        &[NAT_PMP_VERSION, NAT_PMP_OP_EXTERNAL_ADDRESS],
+        NAT_PMP_OP_EXTERNAL_ADDRESS,
+    )?;
+    anyhow::ensure!(response.len() >= 12, "short NAT-PMP response");
+    check_result("NAT-PMP", u16::from_be_bytes([response[2], response[3]]))?;
+    Ok(Ipv4Addr::new(
+        response[8],
+        response[9],
+        response[10],
+        response[11],
+    ))
+}
+
+struct Granted {
+    external_port: u16,
+    external_ip: Option<Ipv4Addr>,
+    lifetime_secs: u32,
+}
+
+fn parse_nat_pmp_map_response(response: &[u8]) -> Result<Granted> {
+    anyhow::ensure!(response.len() >= 16, "short NAT-PMP response");
+    check_result("NAT-PMP", u16::from_be_bytes([response[2], response[3]]))?;
+    Ok(Granted {
+        external_port: u16::from_be_bytes([response[10], response[11]]),
+        external_ip: None,
+        lifetime_secs: u32::from_be_bytes([response[12], response[13], response[14], response[15]]),
+    })
+}
+
+fn pcp_map_request(
+    client_ip: Ipv4Addr,
+    nonce: &[u8; 12],
+    internal_port: u16,
+    external_port: u16,
+    external_ip: Ipv4Addr,
+    lifetime_secs: u32,
+) -> Vec<u8> {
+    let mut request = vec![PCP_VERSION, PCP_OP_MAP, 0, 0];
+    request.extend_from_slice(&lifetime_secs.to_be_bytes());
+    request.extend_from_slice(&client_ip.to_ipv6_mapped().octets());
+    request.extend_from_slice(nonce);
+    request.extend_from_slice(&[IPPROTO_TCP, 0, 0, 0]);
+    request.extend_from_slice(&internal_port.to_be_bytes());
+    request.extend_from_slice(&external_port.to_be_bytes());
+    request.extend_from_slice(&external_ip.to_ipv6_mapped().octets());
+    request
+}
+
+fn parse_pcp_map_response(response: &[u8], nonce: &[u8; 12]) -> Result<Granted> {
+    anyhow::ensure!(response.len() >= 60, "short PCP response");
+    check_result("PCP", response[3] as u16)?;
+    anyhow::ensure!(
+        &response[24..36] == nonce,
+        "PCP response is for another mapping"
+    );
+    let external_ip: [u8; 16] = response[44..60].try_into().unwrap();
+    Ok(Granted {
+        external_port: u16::from_be_bytes([response[42], response[43]]),
+        external_ip: Ipv6Addr::from(external_ip).to_ipv4_mapped(),
+        lifetime_secs: u32::from_be_bytes([response[4], response[5], response[6], response[7]]),
+    })
+}
+
+fn check_result(protocol: &str, code: u16) -> Result<()> {
+    match code {
+        0 => Ok(()),
+        UNSUPPORTED_VERSION => Err(UnsupportedVersion.into()),
+        2 => anyhow::bail!("{} mapping not authorized by the gateway", protocol),
+        code => anyhow::bail!(
+            "{} mapping refused by the gateway: result code {}",
+            protocol,
+            code
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc;
+
+    const EXTERNAL_IP: Ipv4Addr = Ipv4Addr::new(203, 0, 113, 7);
+
+    /// A gateway on localhost that grants every mapping on port 40000 for
+    /// two minutes. Each request is sent on the returned channel.
+    fn fake_gateway(speaks_pcp: bool) -> (SocketAddr, mpsc::Receiver<Vec<u8>>) {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        socket
+            .set_read_timeout(Some(Duration::from_secs(10)))
+            .unwrap();
+        let addr = socket.local_addr().unwrap();
+        let (tx, rx) = mpsc::channel();
+        std::thread::spawn(move || {
+            let mut buf = [0u8; 1100];
+            while let Ok((n, from)) = socket.recv_from(&mut buf) {
+                let request = buf[..n].to_vec();
+                let response = match (request[0], request[1]) {
+                    (PCP_VERSION, PCP_OP_MAP) if speaks_pcp => {
+                        let mut response = vec![PCP_VERSION, PCP_OP_MAP | RESPONSE, 0, 0];
+                        response.extend_from_slice(&request[4..8]);
+                        response.extend_from_slice(&[0; 16]);
+                        response.extend_from_slice(&request[24..42]);
+                        response.extend_from_slice(&40000u16.to_be_bytes());
+                        response.extend_from_slice(&EXTERNAL_IP.to_ipv6_mapped().octets());
+                        if request[4..8] != [0; 4] {
+                            response[4..8].copy_from_slice(&120u32.to_be_bytes());
+                        }
+                        response
+                    }
+                    (PCP_VERSION, op) => {
+                        vec![NAT_PMP_VERSION, op | RESPONSE, 0, 1, 0, 0, 0, 0]
+                    }
+                    (NAT_PMP_VERSION, NAT_PMP_OP_EXTERNAL_ADDRESS) => {
+                        let mut response = vec![NAT_PMP_VERSION, RESPONSE, 0, 0, 0, 0, 0, 0];
+                        response.extend_from_slice(&EXTERNAL_IP.octets());
+                        response
+                    }
+                    (NAT_PMP_VERSION, NAT_PMP_OP_MAP_TCP) => {
+                        let mut response = vec![
+                            NAT_PMP_VERSION,
+                            NAT_PMP_OP_MAP_TCP | RESPONSE,
+                            0,
+                            0,
+                            0,
+                            0,
+                            0,
+                            0,
+                        ];
+                        response.extend_from_slice(&request[4..6]);
+                        let deleting = request[8..12] == [0; 4];
+                        let port: u16 = if deleting { 0 } else { 40000 };
+                        response.extend_from_slice(&port.to_be_bytes());
+                        let lifetime: u32 = if deleting { 0 } else { 120 };
+                        response.extend_from_slice(&lifetime.to_be_bytes());
+                        response
+                    }
+                    _ => continue,
+                };
+                socket.send_to(&response, from).unwrap();
+                tx.send(request).unwrap();
+            }
+        });
+        (addr, rx)
+    }
+
+    fn lifetime_of(request: &[u8]) -> u32 {
+        let at = if request[0] == PCP_VERSION { 4 } else { 8 };
+        u32::from_be_bytes(request[at..at + 4].try_into().unwrap())
+    }
+
+    #[test]
+    fn pcp_mappings_are_made_renewed_and_deleted() {
+        let (gateway, requests) = fake_gateway(true);
+        let mut mapping = map_port(gateway, 9797, Duration::from_secs(3600)).unwrap();
+        assert_eq!(mapping.protocol(), Protocol::Pcp);
+        assert_eq!(mapping.external_port, 40000);
+        assert_eq!(mapping.external_ip, Some(EXTERNAL_IP));
+        assert_eq!(mapping.lifetime, Duration::from_secs(120));
+        let created = requests.recv().unwrap();
+        assert_eq!(lifetime_of(&created), 3600);
+        assert_eq!(u16::from_be_bytes([created[40], created[41]]), 9797);
+
+        // Renewals ask for the same mapping again
+        mapping.renew().unwrap();
+        let renewed = requests.recv().unwrap();
+        assert_eq!(renewed[24..36], created[24..36]);
+        assert_eq!(u16::from_be_bytes([renewed[42], renewed[43]]), 40000);
+        assert_eq!(lifetime_of(&renewed), 3600);
+
+        mapping.delete().unwrap();
+        let deleted = requests.recv().unwrap();
+        assert_eq!(deleted[24..36], created[24..36]);
+        assert_eq!(lifetime_of(&deleted), 0);
+    }
+
+    #[test]
+    fn nat_pmp_is_used_when_the_gateway_does_not_speak_pcp() {
+        let (gateway, requests) = fake_gateway(false);
+        let mut mapping = map_port(gateway, 9797, Duration::from_secs(3600)).unwrap();
+        assert_eq!(mapping.protocol(), Protocol::NatPmp);
+        assert_eq!(mapping.external_port, 40000);
+        assert_eq!(mapping.external_ip, Some(EXTERNAL_IP));
+        assert_eq!(mapping.lifetime, Duration::from_secs(120));
+        assert_eq!(requests.recv().unwrap()[0], PCP_VERSION);
+        assert_eq!(lifetime_of(&requests.recv().unwrap()), 3600);
+        assert_eq!(requests.recv().unwrap()[1], NAT_PMP_OP_EXTERNAL_ADDRESS);
+
+        mapping.renew().unwrap();
+        let renewed = requests.recv().unwrap();
+        assert_eq!(u16::from_be_bytes([renewed[6], renewed[7]]), 40000);
+
+        mapping.delete().unwrap();
+        let deleted = requests.recv().unwrap();
+        assert_eq!(u16::from_be_bytes([deleted[6], deleted[7]]), 0);
+        assert_eq!(lifetime_of(&deleted), 0);
+        // The lease is still on record for the caller
+        assert_eq!(mapping.external_port, 40000);
+    }
+
+    #[test]
+    fn silent_gateways_time_out() {
+        let silent = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let gateway = silent.local_addr().unwrap();
+        assert!(map_port(gateway, 9797, Duration::from_secs(3600)).is_err());
+    }
+
+    #[test]
+    fn default_route_is_read_from_the_routing_table() {
+        let routes = "Iface\tDestination\tGateway \tFlags\tRefCnt\tUse\tMetric\tMask\n\
+                      eth0\t0002A8C0\t00000000\t0001\t0\t0\t0\t00FFFFFF\n\
+                      eth0\t00000000\t0102A8C0\t0003\t0\t0\t100\t00000000\n";
+        assert_eq!(
+            parse_proc_net_route(routes),
+            Some(Ipv4Addr::new(192, 168, 2, 1))
+        );
+        assert_eq!(parse_proc_net_route(""), None);
+    }
+}
@@ -1,12 +1,23 @@
-//! UPnP Port Mapping and Public IP Discovery
+//! Port Mapping and Public IP Discovery
 //!
 //! Enables zero-config remote access by:
-//! 1. Automatically opening an external port via UPnP
-//! 2. Discovering the public IP address
+//! 1. Automatically opening an external port via UPnP, PCP or NAT-PMP
+//! 2. Discovering the public IP address via STUN, the gateway or HTTP
 //! 3. Providing connection info for remote clients
+//!
+//! Each step degrades on its own: without a port mapping the public
+//! address is still discovered, for hosts that are forwarded by hand, and
+//! whatever addresses are found are offered even if others aren't. An
+//! IPv6-only host, or one whose public address can't be discovered,
+//! still offers the rest.
+//!
+//! When listening on IPv6 too, the host's global IPv6 address is offered
+//! as well. It needs no mapping, only a firewall that lets it through.
 
+use crate::natpmp;
 use anyhow::{Context, Result};
-use std::net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4};
+use lucidity_pairing::{CandidateKind, ConnectionCandidate};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
 use std::sync::{Arc, RwLock};
 use std::time::Duration;
 use stun::message::{BINDING_REQUEST, Message};
@@ -16,33 +27,89 @@ use tokio::net::UdpSocket;
 /// External connection info for remote access
 #[derive(Debug, Clone)]
 pub struct ExternalConnectionInfo {
-    /// Local IP address on LAN
-    pub local_ip: Ipv4Addr,
-    /// Public IP address
-    pub public_ip: Ipv4Addr,
-    /// External port mapped via UPnP
+    /// Local IPv4 address on the LAN, if the host has one
+    pub local_ip: Option<Ipv4Addr>,
+    /// Public IPv4 address, if it could be discovered
+    pub public_ip: Option<Ipv4Addr>,
+    /// External port mapped on the gateway, or the local port if none is
     pub external_port: u16,
     /// Local port being forwarded to
     pub local_port: u16,
-    /// Whether UPnP mapping is active
-    pub upnp_active: bool,
+    /// How the external port was mapped, if it was
+    pub port_mapping: Option<PortMapping>,
+    /// Global IPv6 address, if the host has one and listens on IPv6
+    pub global_ipv6: Option<Ipv6Addr>,
+}
+
+/// How a port mapping was made
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PortMapping {
+    Upnp,
+    Pcp,
+    NatPmp,
 }
 
 impl ExternalConnectionInfo {
-    pub fn socket_addr(&self) -> SocketAddrV4 {
-        SocketAddrV4::new(self.public_ip, self.external_port)
+    pub fn socket_addr(&self) -> Option<SocketAddrV4> {
+        self.public_ip
+            .map(|ip| SocketAddrV4::new(ip, self.external_port))
     }
     
-    pub fn lan_addr(&self) -> SocketAddrV4 {
-        SocketAddrV4::new(self.local_ip, self.local_port)
+    pub fn lan_addr(&self) -> Option<SocketAddrV4> {
+        self.local_ip
+            .map(|ip| SocketAddrV4::new(ip, self.local_port))
+    }
+
+    pub fn ipv6_addr(&self) -> Option<SocketAddrV6> {
+        self.global_ipv6
+            .map(|ip| SocketAddrV6::new(ip, self.local_port, 0, 0))
+    }
+
+    /// Every address a phone might reach the host at, for the pairing
+    /// payload
+    pub fn candidates(&self) -> Vec<ConnectionCandidate> {
+        let mut candidates = vec![];
+        if let Some(addr) = self.lan_addr() {
+            candidates.push(ConnectionCandidate::new(CandidateKind::Lan, addr.into()));
+        }
+        if let Some(addr) = self.ipv6_addr() {
+            candidates.push(ConnectionCandidate::new(CandidateKind::Ipv6, addr.into()));
+        }
+        if let Some(addr) = self.socket_addr() {
+            let external = if self.port_mapping.is_some() {
+                CandidateKind::Mapped
+            } else {
+                CandidateKind::Public
+            };
+            candidates.push(ConnectionCandidate::new(external, addr.into()));
+        }
+        candidates
     }
 }
 
-/// Manages UPnP port mapping and public IP discovery
+/// Lease requested for port mappings
+const MAPPING_LIFETIME: Duration = Duration::from_secs(3600);
+
+const STUN_SERVER: &str = "stun.l.google.com:19302";
+const STUN_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// A gateway holding a port mapping for us
+#[derive(Clone)]
+enum Mapper {
+    Upnp {
+        gateway: igd::Gateway,
+        external_port: u16,
+    },
+    NatPmp(natpmp::Mapping),
+}
+
+/// Manages port mapping and public IP discovery
 pub struct P2PConnectivity {
     local_port: u16,
+    /// Whether the host accepts connections over IPv6
+    ipv6: bool,
     external_info: Arc<RwLock<Option<ExternalConnectionInfo>>>,
-    gateway: Option<igd::Gateway>,
+    mapper: Option<Mapper>,
 }
 
 impl P2PConnectivity {
@@ -50,55 +117,85 @@ impl P2PConnectivity {
     pub fn new(local_port: u16) -> Self {
         Self {
             local_port,
+            ipv6: false,
             external_info: Arc::new(RwLock::new(None)),
-            gateway: None,
+            mapper: None,
         }
     }
 
-    /// Initialize UPnP and discover public IP
-    /// Call this once at startup
-    pub fn initialize(&mut self) -> Result<ExternalConnectionInfo> {
-        log::info!("Initializing P2P connectivity...");
+    /// Offer the host's global IPv6 address, for hosts listening on IPv6
+    pub fn set_ipv6(&mut self, ipv6: bool) {
+        self.ipv6 = ipv6;
+    }
 
-        // Step 1: Discover UPnP gateway
-        let gateway = self.discover_gateway()?;
-        self.gateway = Some(gateway.clone());
+    /// Map a port and discover the public IP, publishing whichever
+    /// addresses are found. Call this once at startup.
+    pub fn initialize(&mut self) -> ExternalConnectionInfo {
+        log::info!("Initializing P2P connectivity...");
 
-        // Step 2: Get local IP
-        let local_ip = self.get_local_ip()?;
+        // Step 1: Get local IP
+        let local_ip = match self.get_local_ip() {
+            Ok(ip) => Some(ip),
+            Err(e) => {
+                log::info!("No LAN IPv4 address: {:#}", e);
+                None
+            }
+        };
 
-        // Step 3: Request port mapping
-        let external_port = self.request_port_mapping(&gateway, local_ip)?;
+        // Step 2: Map a port, by whichever protocol the gateway speaks
+        self.mapper = local_ip.and_then(|ip| self.map_port(ip));
+        let (external_port, port_mapping, gateway_ip) = match &self.mapper {
+            Some(Mapper::Upnp { external_port, .. }) => {
+                (*external_port, Some(PortMapping::Upnp), None)
+            }
+            Some(Mapper::NatPmp(mapping)) => {
+                let kind = match mapping.protocol() {
+                    natpmp::Protocol::Pcp => PortMapping::Pcp,
+                    natpmp::Protocol::NatPmp => PortMapping::NatPmp,
+                };
+                (mapping.external_port, Some(kind), mapping.external_ip)
+            }
+            None => {
+                log::warn!(
+                    "No port mapping; port {} is only reachable from outside if forwarded by hand",
+                    self.local_port
+                );
+                (self.local_port, None, None)
+            }
+        };
 
-        // Step 4: Discover public IP (Try STUN first, fallback to HTTP)
-        let public_addr = self.discover_public_addr_via_stun().ok();
-        
-        let (public_ip, external_port) = if let Some(addr) = public_addr {
-            (match addr.ip() {
-                IpAddr::V4(ip) => ip,
-                IpAddr::V6(_) => self.discover_public_ip()?, // Fallback to HTTP for IP
-            }, addr.port())
-        } else {
-            (self.discover_public_ip()?, external_port)
+        // Step 3: Discover public IP (STUN, then the gateway, then HTTP)
+        let public_ip = match Self::discover_public_ip(gateway_ip) {
+            Ok(ip) => Some(ip),
+            Err(e) => {
+                log::warn!("No public IPv4 address: {:#}", e);
+                None
+            }
         };
 
-        let info = ExternalConnectionInfo {
+        // Step 4: Find a global IPv6 address, which needs no mapping
+        let global_ipv6 = self.get_global_ipv6();
+
+        self.publish(ExternalConnectionInfo {
             local_ip,
             public_ip,
             external_port,
             local_port: self.local_port,
-            upnp_active: true,
-        };
-
-        log::info!(
-            "P2P connectivity ready: {}:{} -> local:{}",
-            public_ip,
-            external_port,
-            self.local_port
-        );
+            port_mapping,
+            global_ipv6,
+        })
+    }
 
+    /// Make `info` what `get_external_info` returns
+    fn publish(&self, info: ExternalConnectionInfo) -> ExternalConnectionInfo {
+        let addrs: Vec<String> = info.candidates().into_iter().map(|c| c.addr).collect();
+        if addrs.is_empty() {
+            log::warn!("No addresses found for direct connections; phones can still use the relay");
+        } else {
+            log::info!("P2P connectivity ready: {}", addrs.join(", "));
+        }
         *self.external_info.write().unwrap() = Some(info.clone());
-        Ok(info)
+        info
     }
 
     /// Get current external connection info
@@ -119,16 +216,59 @@ impl P2PConnectivity {
             .context("Failed to discover UPnP gateway. Your router may not support UPnP or it may be disabled.")
     }
 
+    /// Map the local port through the gateway, trying UPnP, then PCP and
+    /// NAT-PMP
+    fn map_port(&self, local_ip: Ipv4Addr) -> Option<Mapper> {
+        match self
+            .discover_gateway()
+            .and_then(|gateway| Ok((self.request_port_mapping(&gateway, local_ip)?, gateway)))
+        {
+            Ok((external_port, gateway)) => {
+                return Some(Mapper::Upnp {
+                    gateway,
+                    external_port,
+                })
+            }
+            Err(e) => log::info!("UPnP unavailable: {:#}", e),
+        }
+
+        let gateway = SocketAddr::from((natpmp::default_gateway(local_ip), natpmp::GATEWAY_PORT));
+        match natpmp::map_port(gateway, self.local_port, MAPPING_LIFETIME) {
+            Ok(mapping) => Some(Mapper::NatPmp(mapping)),
+            Err(e) => {
+                log::info!("PCP and NAT-PMP unavailable at {}: {:#}", gateway, e);
+                None
+            }
+        }
+    }
+
     /// Get local IP address
     fn get_local_ip(&self) -> Result<Ipv4Addr> {
-        // Use a UDP socket trick to get the local IP
-        let socket = std::net::UdpSocket::bind("0.0.0.0:0")?;
-        socket.connect("8.8.8.8:80")?;
-        let local_addr = socket.local_addr()?;
-        
-        match local_addr.ip() {
-            IpAddr::V4(ip) => Ok(ip),
-            IpAddr::V6(_) => anyhow::bail!("IPv6 not supported yet"),
+        local_ipv4()
+    }
+
+    /// Get the global IPv6 address this host would reach the internet
+    /// from, if it listens on IPv6 and has one
+    fn get_global_ipv6(&self) -> Option<Ipv6Addr> {
+        Self::global_ipv6(self.ipv6)
+    }
+
+    fn global_ipv6(ipv6: bool) -> Option<Ipv6Addr> {
+        if !ipv6 {
+            return None;
+        }
+        // The same UDP socket trick as for IPv4; nothing is sent
+        let socket = std::net::UdpSocket::bind("[::]:0").ok()?;
+        socket.connect("[2001:4860:4860::8888]:80").ok()?;
+        match socket.local_addr().ok()?.ip() {
+            IpAddr::V6(ip) if is_global_ipv6(ip) => {
+                log::info!("Global IPv6 address: {}", ip);
+                Some(ip)
+            }
+            ip => {
+                log::debug!("No global IPv6 address (would send from {})", ip);
+                None
+            }
         }
     }
 
@@ -155,7 +295,7 @@ impl P2PConnectivity {
                 igd::PortMappingProtocol::TCP,
                 try_port,
                 local_addr,
-                3600, // 1 hour lease
+                MAPPING_LIFETIME.as_secs() as u32,
                 "Lucidity Terminal",
             ) {
                 Ok(()) => {
@@ -178,35 +318,30 @@ impl P2PConnectivity {
 
     /// Discover public IP and port via STUN
     #[tokio::main(flavor = "current_thread")]
-    async fn discover_public_addr_via_stun(&self) -> Result<SocketAddr> {
+    async fn discover_public_addr_via_stun() -> Result<SocketAddr> {
         log::debug!("Discovering public address via STUN...");
-        
-        let stun_server = "stun.l.google.com:19302";
         let socket = UdpSocket::bind("0.0.0.0:0").await?;
-        socket.connect(stun_server).await?;
-
-        let mut msg = Message::new();
-        msg.build(&[Box::new(BINDING_REQUEST)])?;
-
-        socket.send(&msg.raw).await?;
-
-        let mut buf = [0u8; 1024];
-        let (n, _) = tokio::time::timeout(Duration::from_secs(3), socket.recv_from(&mut buf)).await??;
-
-        let mut response = Message::new();
-        response.raw = buf[..n].to_vec();
-        response.decode()?;
-
-        let mut xor_addr = XorMappedAddress::default();
-        xor_addr.get_from_as(&response, stun::attributes::ATTR_XORMAPPED_ADDRESS)?;
-
-        let socket_addr = SocketAddr::new(xor_addr.ip, xor_addr.port);
+        let socket_addr = stun_binding(&socket).await?;
         log::info!("Discovered public address via STUN: {}", socket_addr);
         Ok(socket_addr)
     }
 
-    /// Discover public IP address
-    fn discover_public_ip(&self) -> Result<Ipv4Addr> {
+    /// Discover public IP address, via STUN if possible, then the address
+    /// the gateway reported, then HTTP
+    fn discover_public_ip(gateway_ip: Option<Ipv4Addr>) -> Result<Ipv4Addr> {
+        match Self::discover_public_addr_via_stun() {
+            Ok(SocketAddr::V4(addr)) => return Ok(*addr.ip()),
+            Ok(addr) => log::debug!("STUN gave an IPv6 address: {}", addr),
+            Err(e) => log::debug!("STUN failed: {:#}", e),
+        }
+        if let Some(ip) = gateway_ip {
+            log::info!("Public IP: {} (via the gateway)", ip);
+            return Ok(ip);
+        }
+        Self::discover_public_ip_via_http()
+    }
+
+    fn discover_public_ip_via_http() -> Result<Ipv4Addr> {
         log::debug!("Discovering public IP...");
 
         // Try multiple services for reliability
@@ -217,7 +352,7 @@ impl P2PConnectivity {
         ];
 
         for service in services {
-            match self.fetch_public_ip(service) {
+            match Self::fetch_public_ip(service) {
                 Ok(ip) => {
                     log::info!("Public IP: {} (via {})", ip, service);
                     return Ok(ip);
@@ -232,7 +367,7 @@ impl P2PConnectivity {
         anyhow::bail!("Failed to discover public IP from any service")
     }
 
-    fn fetch_public_ip(&self, url: &str) -> Result<Ipv4Addr> {
+    fn fetch_public_ip(url: &str) -> Result<Ipv4Addr> {
         let client = reqwest::blocking::Client::builder()
             .timeout(Duration::from_secs(5))
             .build()?;
@@ -245,50 +380,140 @@ impl P2PConnectivity {
             .context(format!("Invalid IP response: {}", ip_str))
     }
 
-    /// Refresh the port mapping and check for IP changes
-    pub fn refresh_mapping(&mut self) -> Result<()> {
-        if let Some(gateway) = &self.gateway {
-            if let Some(mut info) = self.get_external_info() {
-                // Check if public IP changed
-                match self.discover_public_ip() {
-                    Ok(new_ip) => {
-                        if new_ip != info.public_ip {
-                            log::info!("Public IP changed: {} -> {}", info.public_ip, new_ip);
-                            info.public_ip = new_ip;
-                            *self.external_info.write().unwrap() = Some(info.clone());
-                        }
-                    }
-                    Err(e) => log::warn!("Failed to check public IP during refresh: {}", e),
-                }
+    /// How often to call `refresh_mapping`: halfway through the lease
+    pub fn refresh_interval(&self) -> Duration {
+        match &self.mapper {
+            Some(Mapper::NatPmp(mapping)) => mapping.lifetime / 2,
+            _ => MAPPING_LIFETIME / 2,
+        }
+    }
 
-                let local_ip = self.get_local_ip()?;
-                let local_addr = SocketAddrV4::new(local_ip, self.local_port);
+    /// Refresh the port mapping and check for IP changes. A host that
+    /// shares its `P2PConnectivity` uses `start_refresh` and
+    /// `finish_refresh` instead, so it isn't held during the round trips.
+    pub fn refresh_mapping(&mut self) -> Result<()> {
+        let Some(mut refresh) = self.start_refresh() else {
+            return Ok(());
+        };
+        let result = refresh.run();
+        self.finish_refresh(refresh);
+        result
+    }
 
-                gateway.add_port(
-                    igd::PortMappingProtocol::TCP,
-                    info.external_port,
-                    local_addr,
-                    3600,
-                    "Lucidity Terminal",
-                )?;
+    /// What a refresh needs, to `run` without holding `self`. None until
+    /// `initialize` has run.
+    pub fn start_refresh(&self) -> Option<MappingRefresh> {
+        Some(MappingRefresh {
+            local_port: self.local_port,
+            ipv6: self.ipv6,
+            info: self.get_external_info()?,
+            mapper: self.mapper.clone(),
+        })
+    }
 
-                log::debug!("Refreshed UPnP mapping");
-            }
+    /// Keep what a refresh found. If `cleanup` removed the mapping in the
+    /// meantime, the renewed lease is left to lapse.
+    pub fn finish_refresh(&mut self, refresh: MappingRefresh) {
+        if self.mapper.is_some() {
+            self.mapper = refresh.mapper;
         }
-        Ok(())
+        *self.external_info.write().unwrap() = Some(refresh.info);
     }
 
     /// Remove the port mapping (call on shutdown)
-    pub fn cleanup(&self) {
-        if let Some(gateway) = &self.gateway {
-            if let Some(info) = self.get_external_info() {
-                if let Err(e) = gateway.remove_port(igd::PortMappingProtocol::TCP, info.external_port) {
+    pub fn cleanup(&mut self) {
+        match self.mapper.take() {
+            Some(Mapper::Upnp {
+                gateway,
+                external_port,
+            }) => {
+                if let Err(e) = gateway.remove_port(igd::PortMappingProtocol::TCP, external_port) {
                     log::warn!("Failed to remove UPnP mapping: {}", e);
                 } else {
                     log::info!("Removed UPnP port mapping");
                 }
             }
+            Some(Mapper::NatPmp(mut mapping)) => {
+                if let Err(e) = mapping.delete() {
+                    log::warn!("Failed to remove {:?} mapping: {:#}", mapping.protocol(), e);
+                } else {
+                    log::info!("Removed {:?} port mapping", mapping.protocol());
+                }
+            }
+            None => {}
+        }
+    }
+}
+
+/// A refresh of `P2PConnectivity`'s addresses and port mapping, taken
+/// from it by `start_refresh` and handed back to `finish_refresh`
+pub struct MappingRefresh {
+    local_port: u16,
+    ipv6: bool,
+    info: ExternalConnectionInfo,
+    mapper: Option<Mapper>,
+}
+
+impl MappingRefresh {
+    /// Check the public, LAN and IPv6 addresses and renew the mapping,
+    /// which takes round trips to STUN and the gateway
+    pub fn run(&mut self) -> Result<()> {
+        let info = &mut self.info;
+
+        // Check if public IP changed
+        let gateway_ip = match &self.mapper {
+            Some(Mapper::NatPmp(mapping)) => mapping.external_ip,
+            _ => None,
+        };
+        match P2PConnectivity::discover_public_ip(gateway_ip) {
+            Ok(new_ip) => {
+                match info.public_ip {
+                    Some(old_ip) if old_ip == new_ip => {}
+                    Some(old_ip) => log::info!("Public IP changed: {} -> {}", old_ip, new_ip),
+                    None => log::info!("Public IP found: {}", new_ip),
+                }
+                info.public_ip = Some(new_ip);
+            }
+            Err(e) => log::warn!("Failed to check public IP during refresh: {}", e),
+        }
+        // Temporary IPv6 addresses are replaced every day or so
+        info.global_ipv6 = P2PConnectivity::global_ipv6(self.ipv6);
+        info.local_ip = local_ipv4().ok();
+
+        match &mut self.mapper {
+            Some(Mapper::Upnp {
+                gateway,
+                external_port,
+            }) => info
+                .local_ip
+                .context("No LAN IPv4 address to map to")
+                .and_then(|local_ip| {
+                    gateway
+                        .add_port(
+                            igd::PortMappingProtocol::TCP,
+                            *external_port,
+                            SocketAddrV4::new(local_ip, self.local_port),
+                            MAPPING_LIFETIME.as_secs() as u32,
+                            "Lucidity Terminal",
+                        )
+                        .context("Failed to refresh UPnP mapping")
+                })?,
+            Some(Mapper::NatPmp(mapping)) => {
+                mapping.renew()?;
+                if mapping.external_port != info.external_port {
+                    log::info!(
+                        "Mapped port changed: {} -> {}",
+                        info.external_port,
+                        mapping.external_port
+                    );
+                    info.external_port = mapping.external_port;
+                }
+            }
+            None => {}
         }
+
+        log::debug!("Refreshed port mapping");
+        Ok(())
     }
 }
 
@@ -298,6 +523,58 @@ impl Drop for P2PConnectivity {
     }
 }
 
+/// The LAN address this host would reach the internet from
+pub(crate) fn local_ipv4() -> Result<Ipv4Addr> {
+    // Use a UDP socket trick to get the local IP
+    let socket = std::net::UdpSocket::bind("0.0.0.0:0")?;
+    socket.connect("8.8.8.8:80")?;
+    let local_addr = socket.local_addr()?;
+
+    match local_addr.ip() {
+        IpAddr::V4(ip) => Ok(ip),
+        IpAddr::V6(_) => anyhow::bail!("IPv6 not supported yet"),
+    }
+}
+
+/// Ask `STUN_SERVER` where `socket`'s packets appear to come from
+pub(crate) async fn stun_binding(socket: &UdpSocket) -> Result<SocketAddr> {
+    let server = tokio::net::lookup_host(STUN_SERVER)
+        .await?
+        .find(SocketAddr::is_ipv4)
+        .context("STUN server has no IPv4 address")?;
+
+    let mut msg = Message::new();
+    msg.build(&[Box::new(BINDING_REQUEST)])?;
+    socket.send_to(&msg.raw, server).await?;
+
+    // The socket may be in use for other things, so skip anything else
+    let mut buf = [0u8; 1024];
+    let n = tokio::time::timeout(STUN_TIMEOUT, async {
+        loop {
+            let (n, from) = socket.recv_from(&mut buf).await?;
+            if from == server {
+                return anyhow::Ok(n);
+            }
+        }
+    })
+    .await
+    .context("STUN server did not answer")??;
+
+    let mut response = Message::new();
+    response.raw = buf[..n].to_vec();
+    response.decode()?;
+
+    let mut xor_addr = XorMappedAddress::default();
+    xor_addr.get_from_as(&response, stun::attributes::ATTR_XORMAPPED_ADDRESS)?;
+    Ok(SocketAddr::new(xor_addr.ip, xor_addr.port))
+}
+
+/// Whether `ip` is a global unicast address (2000::/3), as opposed to
+/// link-local, unique local or loopback
+fn is_global_ipv6(ip: Ipv6Addr) -> bool {
+    ip.segments()[0] & 0xe000 == 0x2000
+}
+
 /// Simple random u16 for port selection
 fn rand_u16() -> u16 {
     use std::time::{SystemTime, UNIX_EPOCH};
@@ -321,4 +598,124 @@ mod tests {
         let ip = ip.unwrap();
         assert!(!ip.is_loopback(), "Got loopback address");
     }
+
+    #[test]
+    fn candidates_cover_ipv4_and_ipv6() {
+        let mut info = ExternalConnectionInfo {
+            local_ip: Some(Ipv4Addr::new(192, 168, 1, 5)),
+            public_ip: Some(Ipv4Addr::new(203, 0, 113, 5)),
+            external_port: 40000,
+            local_port: 9797,
+            port_mapping: Some(PortMapping::Pcp),
+            global_ipv6: Some("2001:db8::5".parse().unwrap()),
+        };
+        let candidates: Vec<(CandidateKind, String)> = info
+            .candidates()
+            .into_iter()
+            .map(|c| (c.kind, c.addr))
+            .collect();
+        assert_eq!(
+            candidates,
+            vec![
+                (CandidateKind::Lan, "192.168.1.5:9797".to_string()),
+                (CandidateKind::Ipv6, "[2001:db8::5]:9797".to_string()),
+                (CandidateKind::Mapped, "203.0.113.5:40000".to_string()),
+            ]
+        );
+
+        info.port_mapping = None;
+        info.global_ipv6 = None;
+        let kinds: Vec<CandidateKind> = info.candidates().into_iter().map(|c| c.kind).collect();
+        assert_eq!(kinds, vec![CandidateKind::Lan, CandidateKind::Public]);
+    }
+
+    #[test]
+    fn ipv6_only_hosts_publish_their_ipv6_address() {
+        let p2p = P2PConnectivity::new(9797);
+        p2p.publish(ExternalConnectionInfo {
+            local_ip: None,
+            public_ip: None,
+            external_port: 9797,
+            local_port: 9797,
+            port_mapping: None,
+            global_ipv6: Some("2001:db8::5".parse().unwrap()),
+        });
+        let candidates: Vec<(CandidateKind, String)> = p2p
+            .get_external_info()
+            .unwrap()
+            .candidates()
+            .into_iter()
+            .map(|c| (c.kind, c.addr))
+            .collect();
+        assert_eq!(
+            candidates,
+            vec![(CandidateKind::Ipv6, "[2001:db8::5]:9797".to_string())]
+        );
+    }
+
+    #[test]
+    fn hosts_without_a_public_ip_publish_the_rest() {
+        let p2p = P2PConnectivity::new(9797);
+        p2p.publish(ExternalConnectionInfo {
+            local_ip: Some(Ipv4Addr::new(192, 168, 1, 5)),
+            public_ip: None,
+            external_port: 40000,
+            local_port: 9797,
+            port_mapping: Some(PortMapping::Upnp),
+            global_ipv6: Some("2001:db8::5".parse().unwrap()),
+        });
+        let kinds: Vec<CandidateKind> = p2p
+            .get_external_info()
+            .unwrap()
+            .candidates()
+            .into_iter()
+            .map(|c| c.kind)
+            .collect();
+        assert_eq!(kinds, vec![CandidateKind::Lan, CandidateKind::Ipv6]);
+
+        // Nothing at all still publishes, for refreshes to fill in
+        p2p.publish(ExternalConnectionInfo {
+            local_ip: None,
+            public_ip: None,
+            external_port: 9797,
+            local_port: 9797,
+            port_mapping: None,
+            global_ipv6: None,
+        });
+        assert!(p2p.get_external_info().unwrap().candidates().is_empty());
+    }
+
+    #[test]
+    fn only_global_ipv6_addresses_are_offered() {
+        assert!(is_global_ipv6("2001:db8::1".parse().unwrap()));
+        assert!(is_global_ipv6("2a02:8070::1".parse().unwrap()));
+        assert!(!is_global_ipv6("fe80::1".parse().unwrap()));
+        assert!(!is_global_ipv6("fd00::1".parse().unwrap()));
+        assert!(!is_global_ipv6(Ipv6Addr::LOCALHOST));
+    }
+
+    #[test]
+    fn refreshes_hand_back_what_they_found() {
+        let mut p2p = P2PConnectivity::new(9797);
+        assert!(p2p.start_refresh().is_none());
+        p2p.publish(ExternalConnectionInfo {
+            local_ip: Some(Ipv4Addr::new(192, 168, 1, 5)),
+            public_ip: None,
+            external_port: 9797,
+            local_port: 9797,
+            port_mapping: None,
+            global_ipv6: None,
+        });
+
+        // What `run` would find, without the network
+        let mut refresh = p2p.start_refresh().unwrap();
+        refresh.info.public_ip = Some(Ipv4Addr::new(203, 0, 113, 5));
+        assert_eq!(p2p.get_external_info().unwrap().public_ip, None);
+
+        p2p.finish_refresh(refresh);
+        assert_eq!(
+            p2p.get_external_info().unwrap().public_ip,
+            Some(Ipv4Addr::new(203, 0, 113, 5))
+        );
+    }
 }
@@ -1,22 +1,48 @@
 use anyhow::Context;
 use lucidity_pairing::{
-    DeviceTrustStore, Keypair, KeypairStore, PairingPayload, PairingRequest, PairingResponse,
-    PublicKey, Signature, TrustedDevice,
+    ConnectionCandidate, DeviceScopes, DeviceTrustStore, Keypair, KeypairStore, PairingPayload,
+    PairingRequest, PairingResponse, PublicKey, ShortCode, ShortCodeChallenge, ShortCodeConfirm,
+    ShortCodeHello, ShortCodeHost, Signature, TrustedDevice,
 };
 use std::path::PathBuf;
-use std::sync::{Arc, OnceLock, RwLock};
+use std::sync::{Arc, Mutex, OnceLock, RwLock};
+use std::time::{Duration, Instant};
+
+/// How long a pairing token lasts, as long as the QR code it's in
+const PAIRING_TOKEN_TTL: Duration = Duration::from_secs(300);
+
+/// Tokens in pairing payloads that haven't been used yet, with when they
+/// were issued
+static PAIRING_TOKENS: Mutex<Vec<(String, Instant)>> = Mutex::new(Vec::new());
+
+/// The short pairing code being shown, if any
+static SHORT_CODE: Mutex<Option<ShortCodeHost>> = Mutex::new(None);
+
+/// Held by tests that use the pairing statics, since tests run in parallel
+#[cfg(test)]
+pub(crate) fn lock_pairing_state() -> std::sync::MutexGuard<'static, ()> {
+    static LOCK: Mutex<()> = Mutex::new(());
+    LOCK.lock().unwrap_or_else(|e| e.into_inner())
+}
 
 #[derive(Debug, Clone)]
 pub struct PairingApproval {
     pub approved: bool,
     pub reason: Option<String>,
+    /// What an approved device may do
+    pub scopes: DeviceScopes,
 }
 
 impl PairingApproval {
     pub fn approved() -> Self {
+        Self::approved_with(DeviceScopes::full())
+    }
+
+    pub fn approved_with(scopes: DeviceScopes) -> Self {
         Self {
             approved: true,
             reason: None,
+            scopes,
         }
     }
 
@@ -24,6 +50,7 @@ impl PairingApproval {
         Self {
             approved: false,
             reason: Some(reason.into()),
+            scopes: DeviceScopes::view_only(),
         }
     }
 }
@@ -69,29 +96,153 @@ pub fn load_or_create_host_keypair() -> anyhow::Result<Keypair> {
     store.load_or_generate()
 }
 
+fn issue_pairing_token() -> String {
+    let token = format!(
+        "{}{}",
+        uuid::Uuid::new_v4().simple(),
+        uuid::Uuid::new_v4().simple()
+    );
+    let mut tokens = PAIRING_TOKENS.lock().unwrap();
+    tokens.retain(|(_, issued)| issued.elapsed() < PAIRING_TOKEN_TTL);
+    tokens.push((token.clone(), Instant::now()));
+    token
+}
+
+/// Forget every pairing token that hasn't been used, and the short code,
+/// so that no QR code or code already shown can pair a device
+pub fn invalidate_pairing_tokens() {
+    PAIRING_TOKENS.lock().unwrap().clear();
+    SHORT_CODE.lock().unwrap().take();
+}
+
+/// Start pairing by short code, returning the code to show. Any code
+/// shown before stops working.
+pub fn start_short_code() -> anyhow::Result<ShortCode> {
+    let keypair = load_or_create_host_keypair()?;
+    let host = ShortCodeHost::new(keypair.public_key());
+    let code = host.code().clone();
+    *SHORT_CODE.lock().unwrap() = Some(host);
+    Ok(code)
+}
+
+/// Where the short code being shown stands
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ShortCodeState {
+    /// No code is being shown, or it has paired a phone
+    None,
+    /// The code can still pair a phone, with this many hellos
+    Live { attempts_left: u32 },
+    /// Hellos that didn't pair a phone used up the code. Anyone who can
+    /// reach the host can send them, so this may be someone guessing.
+    UsedUp,
+    /// The code outlived `CODE_TTL_SECS`
+    Expired,
+}
+
+pub fn short_code_state() -> ShortCodeState {
+    match SHORT_CODE.lock().unwrap().as_ref() {
+        None => ShortCodeState::None,
+        Some(host) if host.attempts_left() == 0 => ShortCodeState::UsedUp,
+        Some(host) if !host.is_valid() => ShortCodeState::Expired,
+        Some(host) => ShortCodeState::Live {
+            attempts_left: host.attempts_left(),
+        },
+    }
+}
+
+/// Answer a phone starting to pair with the short code. Each hello uses
+/// up one of the code's attempts.
+pub(crate) fn handle_short_code_hello(
+    hello: &ShortCodeHello,
+) -> anyhow::Result<ShortCodeChallenge> {
+    let mut short_code = SHORT_CODE.lock().unwrap();
+    let host = short_code
+        .as_mut()
+        .context("no pairing code is being shown")?;
+    host.respond(hello)
+}
+
+/// Check a phone's short code confirmation and ask the host's user to
+/// approve it, as for a scanned QR code. The code takes the place of the
+/// pairing token, so the request carries none.
+pub(crate) fn handle_short_code_confirm(
+    confirm: &ShortCodeConfirm,
+) -> anyhow::Result<PairingResponse> {
+    let approver = match get_pairing_approver() {
+        Some(a) => a,
+        None => {
+            return Ok(PairingResponse::rejected(
+                "pairing approval UI not available (GUI not running?)",
+            ));
+        }
+    };
+    let request = {
+        let mut short_code = SHORT_CODE.lock().unwrap();
+        let host = short_code
+            .as_mut()
+            .context("no pairing code is being shown")?;
+        let request = host.finish(confirm)?;
+        // A code pairs one phone
+        short_code.take();
+        request
+    };
+
+    let db_path = device_trust_db_path();
+    let store = DeviceTrustStore::open(&db_path)
+        .with_context(|| format!("opening trust store {}", db_path.display()))?;
+    approve_and_trust(&store, &*approver, &request)
+}
+
+/// Use up `token`, which must be one we issued that hasn't expired or been
+/// used before. Only tokens we issued are recorded in the store, so that
+/// peers can't fill it with made-up ones before authenticating.
+fn redeem_pairing_token(store: &DeviceTrustStore, token: Option<&str>) -> anyhow::Result<()> {
+    let token = token.context("pairing request has no pairing token; scan a new QR code")?;
+    let issued = {
+        let mut tokens = PAIRING_TOKENS.lock().unwrap();
+        let found = tokens.iter().position(|(t, _)| t == token);
+        found.map(|i| tokens.swap_remove(i).1)
+    };
+    match issued {
+        Some(issued) if issued.elapsed() < PAIRING_TOKEN_TTL => {}
+        Some(_) => anyhow::bail!("pairing token has expired; scan a new QR code"),
+        None if store.is_pairing_token_used(token)? => {
+            anyhow::bail!("pairing token has already been used; scan a new QR code")
+        }
+        None => anyhow::bail!("pairing token is not valid; scan a new QR code"),
+    }
+    if !store.use_pairing_token(token, chrono::Utc::now().timestamp())? {
+        anyhow::bail!("pairing token has already been used; scan a new QR code");
+    }
+    Ok(())
+}
+
 /// Create pairing payload without connection info (basic mode)
 pub fn current_pairing_payload() -> anyhow::Result<PairingPayload> {
     let keypair = load_or_create_host_keypair()?;
-    Ok(PairingPayload::new(keypair.public_key()))
+    let mut payload = PairingPayload::new(keypair.public_key());
+    payload.pairing_token = Some(issue_pairing_token());
+    Ok(payload)
 }
 
 /// Create pairing payload with P2P connection info
-/// This includes LAN and external addresses for zero-config remote access
+/// This includes LAN, IPv6 and external addresses for zero-config remote access
 pub fn pairing_payload_with_p2p(
-    lan_addr: Option<String>,
-    external_addr: Option<String>,
+    candidates: Vec<ConnectionCandidate>,
 ) -> anyhow::Result<PairingPayload> {
     let keypair = load_or_create_host_keypair()?;
     let relay_url = std::env::var("LUCIDITY_RELAY_URL").ok();
     let relay_secret = std::env::var("LUCIDITY_RELAY_SECRET").ok();
     
-    Ok(PairingPayload::with_connection_info(
+    let mut payload = PairingPayload::with_candidates(
         keypair.public_key(),
-        lan_addr,
-        external_addr,
+        candidates,
         relay_url,
         relay_secret,
-    ))
+    );
+    payload.relay_cert_sha256 = crate::relay_client::relay_cert_sha256();
+    payload.pairing_token = Some(issue_pairing_token());
+    Ok(payload)
 }
 
 pub fn handle_pairing_submit(req: PairingRequest) -> anyhow::Result<PairingResponse> {
@@ -109,7 +260,23 @@ pub fn handle_pairing_submit(req: PairingRequest) -> anyhow::Result<PairingRespo
         }
     };
 
-    let approval = approver.approve_pairing(&req)?;
+    // Use the token up before asking, so that a copy of the request
+    // submitted meanwhile is refused
+    let db_path = device_trust_db_path();
+    let store = DeviceTrustStore::open(&db_path)
+        .with_context(|| format!("opening trust store {}", db_path.display()))?;
+    redeem_pairing_token(&store, req.pairing_token.as_deref())?;
+    approve_and_trust(&store, &*approver, &req)
+}
+
+/// Ask the host's user whether to trust the device making `req`, and add
+/// it to `store` with the scopes they chose if they do
+fn approve_and_trust(
+    store: &DeviceTrustStore,
+    approver: &dyn PairingApprover,
+    req: &PairingRequest,
+) -> anyhow::Result<PairingResponse> {
+    let approval = approver.approve_pairing(req)?;
     if !approval.approved {
         return Ok(PairingResponse::rejected(
             approval
@@ -118,10 +285,6 @@ pub fn handle_pairing_submit(req: PairingRequest) -> anyhow::Result<PairingRespo
         ));
     }
 
-    let db_path = device_trust_db_path();
-    let store = DeviceTrustStore::open(&db_path)
-        .with_context(|| format!("opening trust store {}", db_path.display()))?;
-
     let now = chrono::Utc::now().timestamp();
     store.add_device(&TrustedDevice {
         public_key: req.mobile_public_key.clone(),
@@ -129,7 +292,9 @@ pub fn handle_pairing_submit(req: PairingRequest) -> anyhow::Result<PairingRespo
         device_name: req.device_name.clone(),
         paired_at: now,
         last_seen: Some(now),
+        scopes: approval.scopes,
     })?;
+    invalidate_pairing_tokens();
 
     Ok(PairingResponse::approved())
 }
@@ -141,11 +306,14 @@ pub fn list_trusted_devices() -> anyhow::Result<Vec<TrustedDevice>> {
     store.list_devices()
 }
 
+/// Verify a device's signature over the auth `challenge` and return the
+/// trusted device. The challenge is either the bare nonce (legacy clients) or
+/// `secure::device_auth_message` when the device offers a key exchange.
 pub fn verify_device_auth(
     public_key_b64: &str,
     signature_b64: &str,
-    nonce: &str,
-) -> anyhow::Result<()> {
+    challenge: &[u8],
+) -> anyhow::Result<TrustedDevice> {
     let db_path = device_trust_db_path();
     let store = DeviceTrustStore::open(&db_path)
         .with_context(|| format!("opening trust store {}", db_path.display()))?;
@@ -154,22 +322,22 @@ pub fn verify_device_auth(
         .map_err(|_| anyhow::anyhow!("invalid public key format"))?;
 
     // Must be a trusted device
-    if !store.is_trusted(&public_key)? {
+    let Some(device) = store.get_device(&public_key)? else {
         anyhow::bail!("device not trusted (pair first)");
-    }
+    };
 
     let signature = Signature::from_base64(signature_b64)
         .map_err(|_| anyhow::anyhow!("invalid signature format"))?;
 
-    // Verify signature of the nonce
-    public_key.verify(nonce.as_bytes(), &signature)
+    // Verify signature of the challenge
+    public_key.verify(challenge, &signature)
         .map_err(|_| anyhow::anyhow!("invalid signature"))?;
 
     // Update statistics
     let now = chrono::Utc::now().timestamp();
     store.update_last_seen(&public_key, now)?;
 
-    Ok(())
+    Ok(device)
 }
 
 pub fn revoke_device(public_key_b64: &str) -> anyhow::Result<()> {
@@ -183,3 +351,25 @@ pub fn revoke_device(public_key_b64: &str) -> anyhow::Result<()> {
     store.remove_device(&public_key)?;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_issued_tokens_are_recorded() {
+        let _state = lock_pairing_state();
+        let store = DeviceTrustStore::in_memory().unwrap();
+        let made_up = uuid::Uuid::new_v4().simple().to_string();
+        assert!(redeem_pairing_token(&store, Some(&made_up)).is_err());
+        assert!(!store.is_pairing_token_used(&made_up).unwrap());
+        assert_eq!(store.count_used_pairing_tokens().unwrap(), 0);
+
+        let token = issue_pairing_token();
+        redeem_pairing_token(&store, Some(&token)).unwrap();
+        assert!(store.is_pairing_token_used(&token).unwrap());
+        let replay = redeem_pairing_token(&store, Some(&token)).unwrap_err();
+        assert!(replay.to_string().contains("already been used"));
+        assert_eq!(store.count_used_pairing_tokens().unwrap(), 1);
+    }
+}
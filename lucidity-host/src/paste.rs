@@ -0,0 +1,153 @@
+//! Pastes from devices.
+//!
+//! The text reaches the pane through `Pane::send_paste`, as a paste in the
+//! GUI does, so it is bracketed when the application enabled bracketed
+//! paste and has its newlines canonicalized otherwise. Before that a
+//! device's paste is limited in size and stripped of control characters,
+//! and a paste with line breaks into a pane without bracketed paste, where
+//! the shell would run each line as it arrives, waits for the device to
+//! confirm it.
+
+use crate::bridge::PaneBridge;
+use anyhow::{anyhow, ensure};
+use lucidity_proto::protocol::JsonResponse;
+use mux::pane::PaneId;
+use uuid::Uuid;
+
+/// The largest paste a device may send
+pub const MAX_PASTE_BYTES: usize = 1024 * 1024;
+
+struct PendingPaste {
+    paste_id: String,
+    pane_id: PaneId,
+    text: String,
+}
+
+/// A session's paste waiting for confirmation. A new paste that needs
+/// confirming replaces the one waiting.
+#[derive(Default)]
+pub(crate) struct PasteGuard {
+    pending: Option<PendingPaste>,
+}
+
+impl PasteGuard {
+    /// Paste `text` into `pane_id`, or hold it and return the
+    /// `paste_pending` asking the device to confirm it
+    pub fn paste(
+        &mut self,
+        bridge: &dyn PaneBridge,
+        pane_id: PaneId,
+        text: &str,
+    ) -> anyhow::Result<Option<JsonResponse>> {
+        ensure!(
+            text.len() <= MAX_PASTE_BYTES,
+            "paste is {} bytes; the limit is {MAX_PASTE_BYTES}",
+            text.len()
+        );
+        let text = strip_controls(text);
+        if !text.contains(['\r', '\n']) || bridge.bracketed_paste(pane_id)? {
+            return bridge.send_paste(pane_id, &text).map(|()| None);
+        }
+        let paste_id = Uuid::new_v4().to_string();
+        let resp = JsonResponse::PastePending {
+            pane_id,
+            paste_id: paste_id.clone(),
+            lines: text.lines().count(),
+            bytes: text.len(),
+        };
+        self.pending = Some(PendingPaste {
+            paste_id,
+            pane_id,
+            text,
+        });
+        Ok(Some(resp))
+    }
+
+    pub fn confirm(&mut self, bridge: &dyn PaneBridge, paste_id: &str) -> anyhow::Result<()> {
+        let pending = self
+            .take(paste_id)
+            .ok_or_else(|| anyhow!("no paste {paste_id} is waiting"))?;
+        bridge.send_paste(pending.pane_id, &pending.text)
+    }
+
+    pub fn cancel(&mut self, paste_id: &str) {
+        self.take(paste_id);
+    }
+
+    fn take(&mut self, paste_id: &str) -> Option<PendingPaste> {
+        if self.pending.as_ref()?.paste_id == paste_id {
+            self.pending.take()
+        } else {
+            None
+        }
+    }
+}
+
+/// Drop control characters other than tab and line breaks, so a paste
+/// can't carry escape sequences or signal the foreground process
+fn strip_controls(text: &str) -> String {
+    text.chars()
+        .filter(|&c| !c.is_control() || matches!(c, '\t' | '\r' | '\n'))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bridge::FakePaneBridge;
+
+    #[test]
+    fn single_lines_go_straight_through() {
+        let bridge = FakePaneBridge::new(vec![]);
+        let mut guard = PasteGuard::default();
+        let resp = guard
+            .paste(&bridge, 1, "echo \x1b[201~hi\x03\u{9b}2J\tthere")
+            .unwrap();
+        assert!(resp.is_none());
+        assert_eq!(
+            bridge.take_pastes(),
+            vec![(1, "echo [201~hi2J\tthere".to_string())]
+        );
+
+        let huge = "x".repeat(MAX_PASTE_BYTES + 1);
+        assert!(guard.paste(&bridge, 1, &huge).is_err());
+        assert_eq!(bridge.take_pastes(), vec![]);
+    }
+
+    #[test]
+    fn line_breaks_wait_for_confirmation_unless_bracketed() {
+        let bridge = FakePaneBridge::new(vec![]);
+        let mut guard = PasteGuard::default();
+        let paste_id = match guard.paste(&bridge, 1, "cd /\nrm -rf tmp\n").unwrap() {
+            Some(JsonResponse::PastePending {
+                pane_id: 1,
+                paste_id,
+                lines: 2,
+                bytes: 16,
+            }) => paste_id,
+            other => panic!("expected paste_pending, got {other:?}"),
+        };
+        assert_eq!(bridge.take_pastes(), vec![]);
+
+        assert!(guard.confirm(&bridge, "someone else's").is_err());
+        guard.confirm(&bridge, &paste_id).unwrap();
+        assert_eq!(
+            bridge.take_pastes(),
+            vec![(1, "cd /\nrm -rf tmp\n".to_string())]
+        );
+        // Each confirmation sends the paste once
+        assert!(guard.confirm(&bridge, &paste_id).is_err());
+
+        let Some(JsonResponse::PastePending { paste_id, .. }) =
+            guard.paste(&bridge, 1, "a\nb").unwrap()
+        else {
+            panic!("expected paste_pending");
+        };
+        guard.cancel(&paste_id);
+        assert!(guard.confirm(&bridge, &paste_id).is_err());
+
+        bridge.set_bracketed_paste(1, true);
+        assert!(guard.paste(&bridge, 1, "a\nb").unwrap().is_none());
+        assert_eq!(bridge.take_pastes(), vec![(1, "a\nb".to_string())]);
+    }
+}
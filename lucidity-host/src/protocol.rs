@@ -1,3 +1,14 @@
 pub const TYPE_JSON: u8 = 1;
 pub const TYPE_PANE_OUTPUT: u8 = 2;
 pub const TYPE_PANE_INPUT: u8 = 3;
+/// A frame sealed with the session keys agreed during authentication
+pub const TYPE_SEALED: u8 = 4;
+/// JSON `PaneSnapshot` sent after `AttachOk` and before live output
+pub const TYPE_PANE_SNAPSHOT: u8 = 5;
+/// JSON `ScreenUpdate` for clients attached in cell mode
+pub const TYPE_SCREEN_UPDATE: u8 = 6;
+/// Raw output for one of several attached panes: u32 LE pane id, then bytes
+pub const TYPE_PANE_OUTPUT_ADDRESSED: u8 = 7;
+/// Input for one of several attached panes, framed like
+/// `TYPE_PANE_OUTPUT_ADDRESSED`
+pub const TYPE_PANE_INPUT_ADDRESSED: u8 = 8;
@@ -0,0 +1,466 @@
+//! Direct connections over UDP, punched through NATs with the relay's help.
+//!
+//! A device connected through the relay may offer to go direct: it sends a
+//! `PunchOffer` listing the UDP addresses it can be reached at, as learned
+//! from STUN, and the host answers with a `PunchAnswer` listing its own and
+//! the fingerprint of the certificate it will present. Both sides then send
+//! to each other's addresses at once. Behind NATs that give each socket one
+//! public port whoever it talks to ("cone" NATs), that opens a path both
+//! ways, and the device connects to the host over QUIC.
+//!
+//! The host opens one stream on the connection and runs the session on it
+//! just as over TCP. The fingerprint came by way of the relay, so like a
+//! relayed session this one must be end-to-end encrypted.
+//!
+//! Answering sends UDP wherever the offer says, so the host only answers
+//! devices that have authenticated on their relayed session, one offer at
+//! a time, and punches towards at most `MAX_PUNCH_CANDIDATES` single hosts.
+
+use crate::bridge::PaneBridge;
+use crate::p2p::{local_ipv4, stun_binding};
+use crate::server::serve_stream;
+use crate::session::TransportPolicy;
+use anyhow::{bail, Context, Result};
+use futures_util::future::{select_ok, BoxFuture};
+use futures_util::FutureExt;
+use lucidity_pairing::{cert_fingerprint, cert_matches};
+use lucidity_proto::relay::MAX_PUNCH_CANDIDATES;
+use quinn::crypto::rustls::{QuicClientConfig, QuicServerConfig};
+use quinn::{
+    Connection, Endpoint, EndpointConfig, RecvStream, SendStream, TokioRuntime, TransportConfig,
+};
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::crypto::CryptoProvider;
+use rustls::pki_types::{CertificateDer, PrivatePkcs8KeyDer, ServerName, UnixTime};
+use rustls::{DigitallySignedStruct, SignatureScheme};
+use std::future::Future;
+use std::net::{IpAddr, SocketAddr, UdpSocket};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Protocol named in the QUIC handshake
+const ALPN: &[u8] = b"lucidity/1";
+/// Name the device asks for; the certificate is pinned instead
+const SERVER_NAME: &str = "lucidity";
+/// What is sent to open the NAT on the way out
+const PUNCH_PACKET: &[u8] = b"lucidity-punch";
+const PUNCH_INTERVAL: Duration = Duration::from_millis(100);
+/// How long either side keeps trying before staying on the relay
+const PUNCH_TIMEOUT: Duration = Duration::from_secs(10);
+/// How often an idle connection is pinged, to keep it and the NAT
+/// mappings under it alive
+const KEEPALIVE: Duration = Duration::from_secs(10);
+
+fn crypto_provider() -> Arc<CryptoProvider> {
+    Arc::new(rustls::crypto::ring::default_provider())
+}
+
+fn transport_config() -> Arc<TransportConfig> {
+    let mut transport = TransportConfig::default();
+    transport.keep_alive_interval(Some(KEEPALIVE));
+    Arc::new(transport)
+}
+
+/// A UDP socket to punch from, and the addresses it may be reached at
+pub struct PunchSocket {
+    socket: UdpSocket,
+    candidates: Vec<SocketAddr>,
+}
+
+impl PunchSocket {
+    /// Bind a socket, and learn its LAN and public addresses
+    pub async fn bind() -> Result<Self> {
+        let socket = tokio::net::UdpSocket::bind("0.0.0.0:0").await?;
+        let port = socket.local_addr()?.port();
+        let mut candidates = vec![];
+        // The public address first; if it's the LAN address, we aren't
+        // behind a NAT at all
+        match stun_binding(&socket).await {
+            Ok(public) => candidates.push(public),
+            Err(e) => log::debug!("No public UDP address: {:#}", e),
+        }
+        match local_ipv4() {
+            Ok(ip) => {
+                let lan = SocketAddr::new(ip.into(), port);
+                if !candidates.contains(&lan) {
+                    candidates.push(lan);
+                }
+            }
+            Err(e) => log::debug!("No LAN address: {:#}", e),
+        }
+        if candidates.is_empty() {
+            bail!("this host has no address to punch from");
+        }
+        Ok(Self {
+            socket: socket.into_std()?,
+            candidates,
+        })
+    }
+
+    /// A socket reached only over loopback, so that tests don't depend on
+    /// a STUN server or the network
+    #[cfg(test)]
+    fn loopback() -> Result<Self> {
+        let socket = UdpSocket::bind("127.0.0.1:0")?;
+        socket.set_nonblocking(true)?;
+        Ok(Self {
+            candidates: vec![socket.local_addr()?],
+            socket,
+        })
+    }
+
+    /// Where the peer should send to, best first
+    pub fn candidates(&self) -> Vec<String> {
+        self.candidates.iter().map(|a| a.to_string()).collect()
+    }
+
+    /// Keep sending to `targets` from the socket, until dropped
+    fn punch(&self, targets: Vec<SocketAddr>) -> Result<impl Future<Output = ()>> {
+        let socket = tokio::net::UdpSocket::from_std(self.socket.try_clone()?)?;
+        Ok(async move {
+            let mut interval = tokio::time::interval(PUNCH_INTERVAL);
+            loop {
+                interval.tick().await;
+                for target in &targets {
+                    // Unreachable candidates are expected
+                    socket.send_to(PUNCH_PACKET, target).await.ok();
+                }
+            }
+        })
+    }
+
+    fn into_endpoint(self, server: Option<quinn::ServerConfig>) -> Result<Endpoint> {
+        Ok(Endpoint::new(
+            EndpointConfig::default(),
+            server,
+            self.socket,
+            Arc::new(TokioRuntime),
+        )?)
+    }
+}
+
+/// Whether punch packets may go to `addr`: one host, on a real port
+fn punchable(addr: &SocketAddr) -> bool {
+    let ip = addr.ip();
+    addr.port() != 0
+        && !ip.is_unspecified()
+        && !ip.is_multicast()
+        && !matches!(ip, IpAddr::V4(v4) if v4.is_broadcast())
+}
+
+/// The first `MAX_PUNCH_CANDIDATES` candidates that parse and may be
+/// punched towards
+fn parse_candidates(candidates: &[String]) -> Vec<SocketAddr> {
+    candidates
+        .iter()
+        .take(MAX_PUNCH_CANDIDATES)
+        .filter_map(|c| match c.parse() {
+            Ok(addr) if punchable(&addr) => Some(addr),
+            _ => {
+                log::debug!("Ignoring bad punch candidate {:?}", c);
+                None
+            }
+        })
+        .collect()
+}
+
+fn server_config() -> Result<(quinn::ServerConfig, String)> {
+    let cert = rcgen::generate_simple_self_signed(vec![SERVER_NAME.to_string()])?;
+    let der = cert.serialize_der()?;
+    let fingerprint = cert_fingerprint(&der);
+    let key = PrivatePkcs8KeyDer::from(cert.serialize_private_key_der());
+    let mut tls = rustls::ServerConfig::builder_with_provider(crypto_provider())
+        .with_protocol_versions(&[&rustls::version::TLS13])?
+        .with_no_client_auth()
+        .with_single_cert(vec![CertificateDer::from(der)], key.into())?;
+    tls.alpn_protocols = vec![ALPN.to_vec()];
+    let mut config = quinn::ServerConfig::with_crypto(Arc::new(QuicServerConfig::try_from(tls)?));
+    config.transport_config(transport_config());
+    Ok((config, fingerprint))
+}
+
+/// The host's side of an offer: the candidates and certificate fingerprint
+/// to answer with, and a future that punches towards `offered` and serves
+/// the device once it connects
+pub(crate) async fn answer(
+    offered: &[String],
+    bridge: Arc<dyn PaneBridge>,
+) -> Result<(Vec<String>, String, impl Future<Output = ()>)> {
+    let targets = parse_candidates(offered);
+    if targets.is_empty() {
+        bail!("no usable candidates offered");
+    }
+    answer_from(PunchSocket::bind().await?, targets, bridge)
+}
+
+/// `answer`, punching from `socket` towards `targets`
+fn answer_from(
+    socket: PunchSocket,
+    targets: Vec<SocketAddr>,
+    bridge: Arc<dyn PaneBridge>,
+) -> Result<(Vec<String>, String, impl Future<Output = ()>)> {
+    let candidates = socket.candidates();
+    let (server, fingerprint) = server_config()?;
+    let punch = socket.punch(targets)?;
+    let endpoint = socket.into_endpoint(Some(server))?;
+
+    let serve = async move {
+        let accepted = tokio::select! {
+            accepted = tokio::time::timeout(PUNCH_TIMEOUT, endpoint.accept()) => accepted,
+            () = punch => unreachable!(),
+        };
+        // One device per offer
+        endpoint.set_server_config(None);
+        let connection = match accepted {
+            Ok(Some(incoming)) => incoming.await,
+            _ => {
+                log::info!("No direct connection within {:?}", PUNCH_TIMEOUT);
+                return;
+            }
+        };
+        match connection {
+            Ok(connection) => {
+                let peer = connection.remote_address();
+                log::info!("Direct connection from {}", peer);
+                if let Err(e) = serve_connection(connection, bridge).await {
+                    log::info!("Direct connection from {} ended: {:#}", peer, e);
+                }
+            }
+            Err(e) => log::info!("Direct connection failed: {}", e),
+        }
+        endpoint.wait_idle().await;
+    };
+    Ok((candidates, fingerprint, serve))
+}
+
+async fn serve_connection(connection: Connection, bridge: Arc<dyn PaneBridge>) -> Result<()> {
+    // The host opens the stream, since it speaks first
+    let (send, recv) = connection.open_bi().await?;
+    let policy = TransportPolicy {
+        trusted: false,
+        require_encryption: true,
+    };
+    let result = serve_stream(recv, send, bridge, policy).await;
+    connection.close(0u32.into(), b"session ended");
+    result
+}
+
+/// Accepts only the certificate whose fingerprint came in the answer
+#[derive(Debug)]
+struct PinnedCert {
+    cert_sha256: String,
+    provider: Arc<CryptoProvider>,
+}
+
+impl ServerCertVerifier for PinnedCert {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> std::result::Result<ServerCertVerified, rustls::Error> {
+        if cert_matches(end_entity, &self.cert_sha256) {
+            Ok(ServerCertVerified::assertion())
+        } else {
+            Err(rustls::Error::InvalidCertificate(
+                rustls::CertificateError::ApplicationVerificationFailure,
+            ))
+        }
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> std::result::Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> std::result::Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.provider
+            .signature_verification_algorithms
+            .supported_schemes()
+    }
+}
+
+fn client_config(cert_sha256: &str) -> Result<quinn::ClientConfig> {
+    let provider = crypto_provider();
+    let verifier = PinnedCert {
+        cert_sha256: cert_sha256.to_string(),
+        provider: provider.clone(),
+    };
+    let mut tls = rustls::ClientConfig::builder_with_provider(provider)
+        .with_protocol_versions(&[&rustls::version::TLS13])?
+        .dangerous()
+        .with_custom_certificate_verifier(Arc::new(verifier))
+        .with_no_client_auth();
+    tls.alpn_protocols = vec![ALPN.to_vec()];
+    let mut config = quinn::ClientConfig::new(Arc::new(QuicClientConfig::try_from(tls)?));
+    config.transport_config(transport_config());
+    Ok(config)
+}
+
+/// A device's direct connection to its host. The session runs on `send`
+/// and `recv` as it would on a TCP stream.
+pub struct DirectConnection {
+    pub send: SendStream,
+    pub recv: RecvStream,
+    connection: Connection,
+    // Kept for as long as the connection is in use
+    _endpoint: Endpoint,
+}
+
+impl DirectConnection {
+    pub fn remote_address(&self) -> SocketAddr {
+        self.connection.remote_address()
+    }
+}
+
+/// The device's side: having offered `socket`'s candidates and been
+/// answered with the host's, punch towards them and connect to whichever
+/// answers first
+pub async fn connect_direct(
+    socket: PunchSocket,
+    answered: &[String],
+    cert_sha256: &str,
+) -> Result<DirectConnection> {
+    let targets = parse_candidates(answered);
+    if targets.is_empty() {
+        bail!("the host offered no candidates");
+    }
+    let punch = socket.punch(targets.clone())?;
+    let mut endpoint = socket.into_endpoint(None)?;
+    endpoint.set_default_client_config(client_config(cert_sha256)?);
+
+    let attempts: Vec<BoxFuture<'static, Result<Connection>>> = targets
+        .iter()
+        .map(|target| {
+            let connecting = endpoint.connect(*target, SERVER_NAME);
+            async move { Ok(connecting?.await?) }.boxed()
+        })
+        .collect();
+    let connection = tokio::select! {
+        connected = tokio::time::timeout(PUNCH_TIMEOUT, select_ok(attempts)) => {
+            let (connection, _) = connected
+                .map_err(|_| anyhow::anyhow!("no direct connection within {:?}", PUNCH_TIMEOUT))?
+                .context("connecting directly")?;
+            connection
+        }
+        () = punch => unreachable!(),
+    };
+    let (send, recv) = connection.accept_bi().await?;
+    Ok(DirectConnection {
+        send,
+        recv,
+        connection,
+        _endpoint: endpoint,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::TYPE_JSON;
+    use crate::FakePaneBridge;
+    use lucidity_proto::frame::{encode_frame, FrameDecoder};
+
+    /// A device's socket, and the host's answer to its offer, all on
+    /// loopback
+    fn loopback_offer() -> (PunchSocket, Vec<String>, String) {
+        let device = PunchSocket::loopback().unwrap();
+        let host = PunchSocket::loopback().unwrap();
+        let (candidates, cert_sha256, serve) = answer_from(
+            host,
+            parse_candidates(&device.candidates()),
+            Arc::new(FakePaneBridge::new(vec![])),
+        )
+        .unwrap();
+        tokio::spawn(serve);
+        (device, candidates, cert_sha256)
+    }
+
+    #[tokio::test]
+    async fn only_the_answered_certificate_is_accepted() {
+        let (device, candidates, cert_sha256) = loopback_offer();
+        assert_eq!(cert_sha256.len(), 64);
+        let impostor = "00".repeat(32);
+        let refused = connect_direct(device, &candidates, &impostor).await;
+        assert!(refused.is_err());
+
+        let (device, candidates, cert_sha256) = loopback_offer();
+        let mut direct = connect_direct(device, &candidates, &cert_sha256)
+            .await
+            .unwrap();
+        let list_req = serde_json::to_vec(&serde_json::json!({ "op": "list_panes" })).unwrap();
+        direct
+            .send
+            .write_all(&encode_frame(TYPE_JSON, &list_req))
+            .await
+            .unwrap();
+        // The session runs as over TCP: challenged at once, then
+        // challenged again and refused until the device authenticates
+        let mut decoder = FrameDecoder::new();
+        let mut buf = [0u8; 4096];
+        let mut ops = vec![];
+        while ops.len() < 3 {
+            if let Some(frame) = decoder.next_frame().unwrap() {
+                let v: serde_json::Value = serde_json::from_slice(&frame.payload).unwrap();
+                ops.push(v["op"].as_str().unwrap().to_string());
+                continue;
+            }
+            let n = direct.recv.read(&mut buf).await.unwrap().unwrap();
+            decoder.push(&buf[..n]);
+        }
+        assert_eq!(ops, ["auth_challenge", "auth_challenge", "error"]);
+    }
+
+    #[test]
+    fn candidates_are_limited_to_single_hosts() {
+        let offered: Vec<String> = [
+            "203.0.113.7:4000",
+            "[2001:db8::1]:4000",
+            "not an address",
+            "0.0.0.0:4000",
+            "[::]:4000",
+            "224.0.0.1:4000",
+            "[ff02::1]:4000",
+            "255.255.255.255:4000",
+            "203.0.113.7:0",
+        ]
+        .iter()
+        .map(|c| c.to_string())
+        .collect();
+        assert_eq!(
+            parse_candidates(&offered),
+            vec![
+                "203.0.113.7:4000".parse::<SocketAddr>().unwrap(),
+                "[2001:db8::1]:4000".parse().unwrap(),
+            ]
+        );
+
+        let many: Vec<String> = (1..=20).map(|i| format!("203.0.113.{i}:4000")).collect();
+        assert_eq!(parse_candidates(&many).len(), MAX_PUNCH_CANDIDATES);
+    }
+}
@@ -1,16 +1,46 @@
-use std::sync::{Arc, Mutex};
 use dashmap::DashMap;
 use tokio::sync::mpsc;
-use lucidity_proto::protocol::JsonResponse;
+use tokio::sync::mpsc::error::TrySendError;
+use lucidity_proto::protocol::{JsonResponse, MuxEvent};
 use once_cell::sync::Lazy;
 use log::debug;
+use uuid::Uuid;
 
+use crate::events::EventFilter;
+
+/// Identifies one connection; a device with two connections has two
 pub type ClientId = String;
 
+/// Pushes a connection's queue holds. Pushes to a connection that is this
+/// far behind are dropped.
+pub const PUSH_QUEUE: usize = 256;
+
 pub static REGISTRY: Lazy<ClientRegistry> = Lazy::new(|| ClientRegistry::new());
 
+struct Client {
+    tx: mpsc::Sender<JsonResponse>,
+    /// Set once the client sends `subscribe_events`
+    events: Option<EventFilter>,
+    /// Largest clipboard copy to push, once the client sends
+    /// `subscribe_clipboard`
+    clipboard_limit: Option<usize>,
+}
+
+impl Client {
+    fn push(&self, msg: JsonResponse) {
+        match self.tx.try_send(msg) {
+            Ok(()) => {}
+            Err(TrySendError::Full(msg)) => {
+                debug!("Dropping {:?} for a client that is behind", msg);
+            }
+            // The connection is going away and will unregister itself
+            Err(TrySendError::Closed(_)) => {}
+        }
+    }
+}
+
 pub struct ClientRegistry {
-    clients: DashMap<ClientId, mpsc::UnboundedSender<JsonResponse>>,
+    clients: DashMap<ClientId, Client>,
 }
 
 impl ClientRegistry {
@@ -20,9 +50,19 @@ impl ClientRegistry {
         }
     }
 
-    pub fn register(&self, id: ClientId, tx: mpsc::UnboundedSender<JsonResponse>) {
+    /// Register a connection for pushes until the returned guard is dropped
+    pub fn register(&'static self, tx: mpsc::Sender<JsonResponse>) -> Registration {
+        let id = Uuid::new_v4().to_string();
         debug!("Registering client {} for push notifications", id);
-        self.clients.insert(id, tx);
+        self.clients.insert(
+            id.clone(),
+            Client {
+                tx,
+                events: None,
+                clipboard_limit: None,
+            },
+        );
+        Registration { registry: self, id }
     }
 
     pub fn unregister(&self, id: &ClientId) {
@@ -30,13 +70,141 @@ impl ClientRegistry {
         self.clients.remove(id);
     }
 
-    pub fn broadcast(&self, msg: JsonResponse) {
-        for mut entry in self.clients.iter_mut() {
-            let tx = entry.value_mut();
-            if let Err(_) = tx.send(msg.clone()) {
-                // Client probably disconnected, but we let unregister handle it 
-                // or we could remove here too.
+    pub fn set_event_filter(&self, id: &ClientId, filter: Option<EventFilter>) {
+        if let Some(mut client) = self.clients.get_mut(id) {
+            client.events = filter;
+        }
+    }
+
+    pub fn set_clipboard_limit(&self, id: &ClientId, limit: Option<usize>) {
+        if let Some(mut client) = self.clients.get_mut(id) {
+            client.clipboard_limit = limit;
+        }
+    }
+
+    pub fn has_event_subscribers(&self) -> bool {
+        self.clients.iter().any(|client| client.events.is_some())
+    }
+
+    /// Send a clipboard copy to the clients that asked for copies this big
+    pub fn broadcast_clipboard(&self, text: String) {
+        for client in self.clients.iter() {
+            if client.clipboard_limit.is_some_and(|limit| text.len() <= limit) {
+                client.push(JsonResponse::ClipboardPush { text: text.clone() });
+            }
+        }
+    }
+
+    /// Send `event` to the clients whose filter accepts it
+    pub fn broadcast_event(&self, event: MuxEvent) {
+        for client in self.clients.iter() {
+            if client.events.as_ref().is_some_and(|filter| filter.matches(&event)) {
+                client.push(JsonResponse::Event {
+                    event: event.clone(),
+                });
             }
         }
     }
 }
+
+/// A connection's entry in the registry, removed on drop
+pub struct Registration {
+    registry: &'static ClientRegistry,
+    id: ClientId,
+}
+
+impl Registration {
+    pub fn set_event_filter(&self, filter: Option<EventFilter>) {
+        self.registry.set_event_filter(&self.id, filter);
+    }
+
+    pub fn set_clipboard_limit(&self, limit: Option<usize>) {
+        self.registry.set_clipboard_limit(&self.id, limit);
+    }
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        self.registry.unregister(&self.id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn events_reach_subscribed_clients_only() {
+        let registry: &'static ClientRegistry = Box::leak(Box::new(ClientRegistry::new()));
+        let (tx_a, mut rx_a) = mpsc::channel(8);
+        let (tx_b, mut rx_b) = mpsc::channel(8);
+        let a = registry.register(tx_a);
+        let _b = registry.register(tx_b);
+
+        assert!(!registry.has_event_subscribers());
+        registry.broadcast_event(MuxEvent::Bell { pane_id: 1 });
+        assert!(rx_a.try_recv().is_err());
+
+        a.set_event_filter(Some(EventFilter::default()));
+        assert!(registry.has_event_subscribers());
+        registry.broadcast_event(MuxEvent::Bell { pane_id: 1 });
+        assert!(matches!(
+            rx_a.try_recv(),
+            Ok(JsonResponse::Event {
+                event: MuxEvent::Bell { pane_id: 1 }
+            })
+        ));
+        assert!(rx_b.try_recv().is_err());
+
+        drop(a);
+        assert!(!registry.has_event_subscribers());
+    }
+
+    #[test]
+    fn clipboard_goes_to_opted_in_clients_within_their_limit() {
+        let registry: &'static ClientRegistry = Box::leak(Box::new(ClientRegistry::new()));
+        let (tx_a, mut rx_a) = mpsc::channel(8);
+        let (tx_b, mut rx_b) = mpsc::channel(8);
+        let a = registry.register(tx_a);
+        let b = registry.register(tx_b);
+        a.set_clipboard_limit(Some(4));
+
+        registry.broadcast_clipboard("hunter2".to_string());
+        assert!(rx_a.try_recv().is_err());
+        registry.broadcast_clipboard("ls".to_string());
+        assert!(matches!(
+            rx_a.try_recv(),
+            Ok(JsonResponse::ClipboardPush { text }) if text == "ls"
+        ));
+        assert!(rx_b.try_recv().is_err());
+
+        b.set_clipboard_limit(Some(1024));
+        b.set_clipboard_limit(None);
+        registry.broadcast_clipboard("ls".to_string());
+        assert!(rx_b.try_recv().is_err());
+    }
+
+    #[test]
+    fn pushes_to_a_client_that_is_behind_are_dropped() {
+        let registry: &'static ClientRegistry = Box::leak(Box::new(ClientRegistry::new()));
+        let (tx, mut rx) = mpsc::channel(2);
+        let a = registry.register(tx);
+        a.set_event_filter(Some(EventFilter::default()));
+
+        for pane_id in 0..5 {
+            registry.broadcast_event(MuxEvent::Bell { pane_id });
+        }
+        assert!(rx.try_recv().is_ok());
+        assert!(rx.try_recv().is_ok());
+        assert!(rx.try_recv().is_err());
+
+        // It gets pushes again once it catches up
+        registry.broadcast_event(MuxEvent::Bell { pane_id: 9 });
+        assert!(matches!(
+            rx.try_recv(),
+            Ok(JsonResponse::Event {
+                event: MuxEvent::Bell { pane_id: 9 }
+            })
+        ));
+    }
+}
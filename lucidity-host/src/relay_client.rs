@@ -1,28 +1,66 @@
 //! WebSocket Relay Client for lucidity-host
 //!
 //! Connects to a relay server when P2P (UPnP/STUN) fails.
-//! This provides a fallback connection path for mobile clients.
+//! This provides a fallback connection path for mobile clients, each of
+//! which gets a session of its own on the one relay connection. Mobiles
+//! may use it to set up a direct connection instead; see `punch`.
+//!
+//! `wss://` relays are checked against a pinned certificate fingerprint
+//! when one is configured, in place of the usual CA and hostname checks,
+//! and the desktop can present a client certificate of its own. The pin
+//! goes into pairing payloads, so that phones check the same certificate.
 
-use anyhow::{anyhow, Context, Result};
+use anyhow::{bail, Context, Result};
 use futures_util::{SinkExt, StreamExt};
 use log::{debug, error, info, warn};
-use lucidity_proto::frame::{encode_frame, Frame, FrameDecoder};
-use std::sync::Arc;
-use tokio::sync::{mpsc, Mutex};
-use tokio_tungstenite::{connect_async, tungstenite::protocol::Message};
-use url::Url;
-use serde::{Deserialize, Serialize};
-use uuid::Uuid;
-use std::time::Duration;
-
-use crate::bridge::{PaneBridge, PaneInfo};
-use crate::protocol::{TYPE_JSON, TYPE_PANE_INPUT, TYPE_PANE_OUTPUT};
-// Note: We might not need all logic from pairing_api if we just forward requests, 
-// but for V1 we implement the host logic here too.
-use crate::pairing_api::{
-    handle_pairing_submit, pairing_payload_with_p2p, list_trusted_devices, verify_device_auth, 
-    load_or_create_host_keypair
+use lucidity_pairing::{cert_fingerprint, cert_matches, normalize_fingerprint, Keypair};
+use lucidity_proto::relay::{
+    decode_session_data, encode_session_data, registration_message, RelayMessage,
 };
+use std::collections::HashMap;
+use std::future::Future;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+use tokio::net::TcpStream;
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::sync::{mpsc, watch, Mutex};
+use tokio::time::MissedTickBehavior;
+use tokio_tungstenite::{tungstenite::protocol::Message, MaybeTlsStream, WebSocketStream};
+use url::Url;
+
+use crate::bridge::PaneBridge;
+use crate::pairing_api::load_or_create_host_keypair;
+use crate::punch;
+use crate::server::OutboundQueue;
+use crate::session::{ChannelSource, SessionCore, TransportPolicy, INBOUND_CHUNKS};
+
+/// How often the relay is pinged
+const KEEPALIVE: Duration = Duration::from_secs(20);
+/// Keepalive intervals without a word from the relay before it is
+/// considered gone
+const DEAD_AFTER_KEEPALIVES: u32 = 3;
+/// Session data messages waiting for the relay connection before sessions
+/// have to wait. Each session also has an `OutboundQueue` of its own.
+const RELAY_DATA_MESSAGES: usize = 64;
+/// Bounds of the wait between reconnection attempts
+const MIN_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+/// The certificate pin of the relay connection the host keeps
+static HOST_RELAY_CERT_SHA256: RwLock<Option<String>> = RwLock::new(None);
+
+/// The fingerprint the host's relay certificate is pinned to, for pairing
+/// payloads. `LUCIDITY_RELAY_CERT_SHA256` until the relay client starts.
+pub(crate) fn relay_cert_sha256() -> Option<String> {
+    HOST_RELAY_CERT_SHA256
+        .read()
+        .unwrap()
+        .clone()
+        .or_else(|| RelayTls::from_env().cert_sha256)
+        .map(|pin| normalize_fingerprint(&pin))
+}
 
 /// Relay connection status
 #[derive(Debug, Clone, PartialEq)]
@@ -33,17 +71,246 @@ pub enum RelayStatus {
     Error(String),
 }
 
-pub use lucidity_proto::protocol::{JsonRequest, JsonResponse};
+/// Carry one session's queued frames to the relay as session data. Once
+/// the session is over, ask the relay to close the mobile too if it ended
+/// on the host's side, after its last frames.
+async fn forward_session(
+    session_id: String,
+    mut rx: mpsc::Receiver<Vec<u8>>,
+    queued: Arc<AtomicUsize>,
+    data: mpsc::Sender<Message>,
+    ended_here: Arc<AtomicBool>,
+) {
+    while let Some(bytes) = rx.recv().await {
+        let len = bytes.len();
+        let msg = Message::Binary(encode_session_data(&session_id, &bytes));
+        if data.send(msg).await.is_err() {
+            return;
+        }
+        queued.fetch_sub(len, Ordering::Relaxed);
+    }
+    if ended_here.load(Ordering::Relaxed) {
+        let close = RelayMessage::Close {
+            session_id,
+            reason: "session ended".to_string(),
+        };
+        if let Ok(text) = serde_json::to_string(&close) {
+            data.send(Message::Text(text)).await.ok();
+        }
+    }
+}
+
+/// How the relay's certificate is checked, and how the desktop identifies
+/// itself to the relay
+#[derive(Debug, Clone, Default)]
+struct RelayTls {
+    /// SHA-256 fingerprint the relay's certificate must have
+    cert_sha256: Option<String>,
+    /// PEM certificate and PKCS#8 key to present to the relay
+    client_cert: Option<(PathBuf, PathBuf)>,
+}
+
+impl RelayTls {
+    fn from_env() -> Self {
+        let client_cert = match (
+            std::env::var_os("LUCIDITY_RELAY_CLIENT_CERT"),
+            std::env::var_os("LUCIDITY_RELAY_CLIENT_KEY"),
+        ) {
+            (Some(cert), Some(key)) => Some((cert.into(), key.into())),
+            _ => None,
+        };
+        Self {
+            cert_sha256: std::env::var("LUCIDITY_RELAY_CERT_SHA256").ok(),
+            client_cert,
+        }
+    }
+
+    fn connector(&self) -> Result<native_tls::TlsConnector> {
+        let mut builder = native_tls::TlsConnector::builder();
+        if let Some((cert, key)) = &self.client_cert {
+            let cert =
+                std::fs::read(cert).with_context(|| format!("reading {}", cert.display()))?;
+            let key = std::fs::read(key).with_context(|| format!("reading {}", key.display()))?;
+            builder.identity(native_tls::Identity::from_pkcs8(&cert, &key)?);
+        }
+        if self.cert_sha256.is_some() {
+            // The pin stands in for the CA and hostname checks
+            builder
+                .danger_accept_invalid_certs(true)
+                .danger_accept_invalid_hostnames(true);
+        }
+        Ok(builder.build()?)
+    }
+
+    /// Open a WebSocket to `url`. The relay's certificate is checked
+    /// before anything, including the secret in `url`, is sent.
+    async fn open(&self, url: &Url) -> Result<WebSocketStream<MaybeTlsStream<TcpStream>>> {
+        let host = url.host_str().context("relay URL has no host")?;
+        let port = url
+            .port_or_known_default()
+            .context("relay URL has no port")?;
+        let tcp = TcpStream::connect((host, port)).await?;
+        tcp.set_nodelay(true).ok();
+        let stream = match url.scheme() {
+            "ws" => MaybeTlsStream::Plain(tcp),
+            "wss" => {
+                let connector = tokio_native_tls::TlsConnector::from(self.connector()?);
+                let stream = connector.connect(host, tcp).await?;
+                if let Some(pinned) = &self.cert_sha256 {
+                    let cert = stream
+                        .get_ref()
+                        .peer_certificate()?
+                        .context("relay presented no certificate")?
+                        .to_der()?;
+                    if !cert_matches(&cert, pinned) {
+                        bail!(
+                            "relay certificate {} is not the pinned {}",
+                            cert_fingerprint(&cert),
+                            pinned
+                        );
+                    }
+                }
+                MaybeTlsStream::NativeTls(stream)
+            }
+            scheme => bail!("unsupported relay URL scheme {}", scheme),
+        };
+        let (ws_stream, _) = tokio_tungstenite::client_async(url.as_str(), stream).await?;
+        Ok(ws_stream)
+    }
+}
+
+/// A device's connection to its host through the relay at `relay_url`.
+/// Frames travel in binary messages, as over TCP. With `cert_sha256`, a
+/// `wss://` relay must present that certificate, which is checked before
+/// `relay_secret` is sent.
+pub async fn connect_mobile(
+    relay_url: &str,
+    relay_id: &str,
+    relay_secret: Option<&str>,
+    cert_sha256: Option<&str>,
+) -> Result<WebSocketStream<MaybeTlsStream<TcpStream>>> {
+    let mut url = Url::parse(&format!("{}/mobile/{}", relay_url, relay_id))
+        .context("Invalid relay URL")?;
+    if let Some(secret) = relay_secret {
+        url.query_pairs_mut().append_pair("secret", secret);
+    }
+    let tls = RelayTls {
+        cert_sha256: cert_sha256.map(str::to_string),
+        client_cert: None,
+    };
+    tls.open(&url).await
+}
+
+/// Resolves once `enabled` turns false, or its sender goes away
+async fn disabled(enabled: &mut watch::Receiver<bool>) {
+    let _ = enabled.wait_for(|enabled| !*enabled).await;
+}
+
+/// Resolves once `enabled` is true, to false if its sender has gone away
+async fn wait_enabled(enabled: &mut watch::Receiver<bool>) -> bool {
+    if enabled.wait_for(|enabled| *enabled).await.is_err() {
+        return false;
+    }
+    // `wait_for` is satisfied by the last value even once the sender is gone
+    enabled.has_changed().is_ok()
+}
+
+fn send_control(outgoing: &mpsc::UnboundedSender<Message>, msg: &RelayMessage) {
+    match serde_json::to_string(msg) {
+        Ok(text) => {
+            outgoing.send(Message::Text(text)).ok();
+        }
+        Err(e) => error!("Failed to encode relay message: {}", e),
+    }
+}
+
+/// A mobile's session, as the relay connection sees it
+struct RelaySession {
+    /// Inbound frames for the session's thread. Dropping it ends the
+    /// session.
+    frames: mpsc::Sender<Vec<u8>>,
+    /// Set by the session once the device has authenticated and the key
+    /// exchange is done
+    secured: Arc<AtomicBool>,
+    /// Set while an offer to go direct is being answered
+    punching: Arc<AtomicBool>,
+}
+
+impl RelaySession {
+    fn new(frames: mpsc::Sender<Vec<u8>>) -> Self {
+        Self {
+            frames,
+            secured: Arc::new(AtomicBool::new(false)),
+            punching: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    /// Start answering an offer to go direct, unless the device hasn't
+    /// secured the session yet or an earlier offer is still being answered
+    fn start_punch(&self) -> Option<Punching> {
+        if !self.secured.load(Ordering::Acquire) || self.punching.swap(true, Ordering::AcqRel) {
+            return None;
+        }
+        Some(Punching(self.punching.clone()))
+    }
+}
+
+/// Held while a session's offer to go direct is answered and served
+struct Punching(Arc<AtomicBool>);
+
+impl Drop for Punching {
+    fn drop(&mut self) {
+        self.0.store(false, Ordering::Release);
+    }
+}
+
+/// Hand session data to its session without waiting. A session too far
+/// behind to take it is closed, rather than holding up every other session
+/// and the keepalive, which share this connection.
+fn deliver(
+    sessions: &mut HashMap<String, RelaySession>,
+    outgoing: &mpsc::UnboundedSender<Message>,
+    session_id: &str,
+    payload: &[u8],
+) {
+    let Some(session) = sessions.get(session_id) else {
+        return;
+    };
+    match session.frames.try_send(payload.to_vec()) {
+        Ok(()) => {}
+        Err(TrySendError::Full(_)) => {
+            warn!("Relay session {} is not keeping up; closing it", session_id);
+            sessions.remove(session_id);
+            send_control(
+                outgoing,
+                &RelayMessage::Close {
+                    session_id: session_id.to_string(),
+                    reason: "session is not keeping up".to_string(),
+                },
+            );
+        }
+        // The session has closed itself
+        Err(TrySendError::Closed(_)) => {
+            sessions.remove(session_id);
+        }
+    }
+}
 
 /// Client for connecting to the Lucidity relay server
 pub struct RelayClient {
     relay_url: String,
     relay_id: String,
     desktop_secret: Option<String>,
+    tls: RelayTls,
+    /// Signs the relay's registration challenge; the host key by default
+    keypair: Option<Arc<Keypair>>,
     bridge: Option<Arc<dyn PaneBridge>>,
     status: Arc<Mutex<RelayStatus>>,
     /// Channel to send outgoing messages to the relay
-    outgoing_tx: Option<mpsc::UnboundedSender<Vec<u8>>>,
+    outgoing_tx: Option<mpsc::UnboundedSender<Message>>,
+    keepalive: Duration,
+    /// Shortest and longest waits before reconnecting
+    backoff: (Duration, Duration),
 }
 
 impl RelayClient {
@@ -53,9 +320,13 @@ impl RelayClient {
             relay_url,
             relay_id,
             desktop_secret: std::env::var("LUCIDITY_RELAY_SECRET").ok(),
+            tls: RelayTls::from_env(),
+            keypair: None,
             bridge: None,
             status: Arc::new(Mutex::new(RelayStatus::Disconnected)),
             outgoing_tx: None,
+            keepalive: KEEPALIVE,
+            backoff: (MIN_BACKOFF, MAX_BACKOFF),
         }
     }
 
@@ -66,6 +337,38 @@ impl RelayClient {
         Some(Self::new(relay_url, relay_id))
     }
 
+    /// Register with `keypair` instead of the host key. The relay only
+    /// accepts the key `relay_id` was derived from.
+    pub fn set_keypair(&mut self, keypair: Keypair) {
+        self.keypair = Some(Arc::new(keypair));
+    }
+
+    /// Only accept a relay whose certificate has this SHA-256 fingerprint,
+    /// whoever signed it. `LUCIDITY_RELAY_CERT_SHA256` by default.
+    pub fn set_cert_sha256(&mut self, fingerprint: String) {
+        self.tls.cert_sha256 = Some(fingerprint);
+    }
+
+    /// Present this certificate to the relay, for relays that pin the
+    /// desktops they serve. The key must be PKCS#8.
+    /// `LUCIDITY_RELAY_CLIENT_CERT` and `LUCIDITY_RELAY_CLIENT_KEY` by
+    /// default.
+    pub fn set_client_cert(&mut self, cert_path: PathBuf, key_path: PathBuf) {
+        self.tls.client_cert = Some((cert_path, key_path));
+    }
+
+    /// Ping the relay this often, and give up on it after three intervals
+    /// without hearing from it
+    pub fn set_keepalive(&mut self, interval: Duration) {
+        self.keepalive = interval;
+    }
+
+    /// Wait at least `min` before reconnecting, doubling the wait after
+    /// each failure up to `max`
+    pub fn set_backoff(&mut self, min: Duration, max: Duration) {
+        self.backoff = (min, max);
+    }
+
     /// Set the pane bridge for handling incoming frames
     pub fn set_bridge(&mut self, bridge: Arc<dyn PaneBridge>) {
         self.bridge = Some(bridge);
@@ -86,24 +389,59 @@ impl RelayClient {
         &self.relay_id
     }
 
-    /// Send binary data to the relay (to be forwarded to mobile)
-    pub fn send(&self, data: Vec<u8>) -> Result<()> {
-        if let Some(tx) = &self.outgoing_tx {
-            tx.send(data).context("Failed to send to relay")?;
-        } else {
-            anyhow::bail!("Relay not connected");
-        }
+    /// Connect to relay server via WebSocket. The connection runs in the
+    /// background until it drops; use `run` to stay connected.
+    pub async fn connect(&mut self) -> Result<()> {
+        let connection = self.open_connection().await?;
+        tokio::spawn(connection);
         Ok(())
     }
 
-    /// Send a framed message to the relay
-    pub fn send_frame(&self, frame_type: u8, payload: &[u8]) -> Result<()> {
-        let frame_data = encode_frame(frame_type, payload);
-        self.send(frame_data)
+    /// Stay connected for as long as `enabled` is true, reconnecting with
+    /// exponential backoff whenever the connection drops or the relay stops
+    /// answering pings. Returns once `enabled`'s sender is dropped.
+    pub async fn run(mut self, mut enabled: watch::Receiver<bool>) {
+        *HOST_RELAY_CERT_SHA256.write().unwrap() = self.tls.cert_sha256.clone();
+        let (min_backoff, max_backoff) = self.backoff;
+        let mut backoff = min_backoff;
+        loop {
+            if !wait_enabled(&mut enabled).await {
+                break;
+            }
+            match self.open_connection().await {
+                Ok(connection) => {
+                    tokio::pin!(connection);
+                    tokio::select! {
+                        registered = &mut connection => {
+                            if registered {
+                                backoff = min_backoff;
+                            }
+                        }
+                        () = disabled(&mut enabled) => {
+                            info!("Relay disabled; disconnecting");
+                            self.disconnect().await;
+                            // Give the close a moment to go out
+                            tokio::time::timeout(Duration::from_secs(1), connection).await.ok();
+                            continue;
+                        }
+                    }
+                }
+                Err(e) => warn!("Relay connection failed: {:#}", e),
+            }
+            info!("Reconnecting to relay in {:?}", backoff);
+            tokio::select! {
+                () = tokio::time::sleep(backoff) => {}
+                () = disabled(&mut enabled) => {}
+            }
+            backoff = (backoff * 2).min(max_backoff);
+        }
+        self.disconnect().await;
     }
 
-    /// Connect to relay server via WebSocket
-    pub async fn connect(&mut self) -> Result<()> {
+    /// Open the WebSocket, returning the connection for the caller to run.
+    /// It resolves once the connection is over, to whether the relay had
+    /// accepted the registration.
+    async fn open_connection(&mut self) -> Result<impl Future<Output = bool> + Send + 'static> {
         // Update status
         {
             let mut status = self.status.lock().await;
@@ -117,86 +455,179 @@ impl RelayClient {
         }
 
         let url = Url::parse(&url_str).context("Invalid relay URL")?;
+        let keypair = match &self.keypair {
+            Some(keypair) => keypair.clone(),
+            None => Arc::new(load_or_create_host_keypair()?),
+        };
         info!("Connecting to relay: {}", self.relay_url);
 
-        let (ws_stream, _) = match connect_async(url).await {
-            Ok(result) => result,
+        let ws_stream = match self.tls.open(&url).await {
+            Ok(ws_stream) => ws_stream,
             Err(e) => {
                 let mut status = self.status.lock().await;
-                *status = RelayStatus::Error(e.to_string());
+                *status = RelayStatus::Error(format!("{:#}", e));
                 return Err(e).context("Failed to connect to relay");
             }
         };
 
+        // Connected once the relay accepts the registration
         info!("Connected to relay server: relay_id={}", self.relay_id);
 
-        // Update status
-        {
-            let mut status = self.status.lock().await;
-            *status = RelayStatus::Connected;
-        }
-
         let (mut ws_tx, mut ws_rx) = ws_stream.split();
 
-        // Create channel for outgoing messages
-        let (outgoing_tx, mut outgoing_rx) = mpsc::unbounded_channel::<Vec<u8>>();
+        // Control messages are few and go on an unbounded channel; session
+        // data is bounded, so slow sessions wait rather than buffer
+        let (outgoing_tx, mut outgoing_rx) = mpsc::unbounded_channel::<Message>();
+        let (data_tx, mut data_rx) = mpsc::channel::<Message>(RELAY_DATA_MESSAGES);
         self.outgoing_tx = Some(outgoing_tx.clone());
 
         // Clone status for the tasks
         let status_clone = self.status.clone();
         let relay_id = self.relay_id.clone();
-
-        // Task: Forward outgoing messages to WebSocket
-        let relay_id_out = relay_id.clone();
-        tokio::spawn(async move {
-            while let Some(data) = outgoing_rx.recv().await {
-                if let Err(e) = ws_tx.send(Message::Binary(data)).await {
-                    error!("Failed to send to relay {}: {}", relay_id_out, e);
-                    break;
-                }
-            }
-            debug!("Outgoing relay task ended for {}", relay_id_out);
-        });
-
-        // Task: Handle incoming messages from relay
         let bridge = self.bridge.clone();
-        let relay_id_in = relay_id.clone();
-        let outgoing_tx_handler = outgoing_tx.clone();
-        
-        tokio::spawn(async move {
-            let mut decoder = FrameDecoder::new();
-            
-            // Per-session state (simplified for Relay: assuming one active controller per relay session)
-            let mut authenticated = false;
-            let mut auth_nonce: Option<String> = None;
-            let attached = Arc::new(Mutex::new(None::<usize>));
-
-            while let Some(msg_result) = ws_rx.next().await {
-                match msg_result {
-                    Ok(Message::Binary(data)) => {
-                        decoder.push(&data);
-
-                        while let Ok(Some(frame)) = decoder.next_frame() {
-                            // If not authenticated and not already challenging, send challenge
-                            // But ONLY if it's not an AuthResponse or PairingRequest
-                            // For simplicity, we enforce auth for sensitive ops
-                            
-                            if let Err(e) = Self::handle_incoming_frame(
-                                &bridge, 
-                                &relay_id_in, 
-                                frame, 
-                                &outgoing_tx_handler,
-                                &mut authenticated,
-                                &mut auth_nonce,
-                                &attached
-                            ).await {
-                                error!("Error handling frame from {}: {}", relay_id_in, e);
-                            }
+        let keepalive = self.keepalive;
+
+        Ok(async move {
+            // Task: Forward outgoing messages to WebSocket
+            let relay_id_out = relay_id.clone();
+            let writer = tokio::spawn(async move {
+                loop {
+                    // Control first, so that pings and closes don't wait
+                    // behind session data
+                    let msg = tokio::select! {
+                        biased;
+                        Some(msg) = outgoing_rx.recv() => msg,
+                        Some(msg) = data_rx.recv() => msg,
+                        else => break,
+                    };
+                    if let Err(e) = ws_tx.send(msg).await {
+                        error!("Failed to send to relay {}: {}", relay_id_out, e);
+                        break;
+                    }
+                }
+                debug!("Outgoing relay task ended for {}", relay_id_out);
+            });
+
+            // Handle incoming messages from relay, pinging it whenever it
+            // has been quiet for a while
+            let relay_id_in = relay_id;
+            let mut registered = false;
+            let mut pings =
+                tokio::time::interval_at(tokio::time::Instant::now() + keepalive, keepalive);
+            pings.set_missed_tick_behavior(MissedTickBehavior::Delay);
+            let mut last_heard = Instant::now();
+            // Each session, by session_id
+            let mut sessions: HashMap<String, RelaySession> = HashMap::new();
+            loop {
+                let msg_result = tokio::select! {
+                    msg = ws_rx.next() => match msg {
+                        Some(msg) => msg,
+                        None => break,
+                    },
+                    _ = pings.tick() => {
+                        if last_heard.elapsed() >= keepalive * DEAD_AFTER_KEEPALIVES {
+                            warn!("Relay {} stopped answering; giving up on it", relay_id_in);
+                            break;
                         }
+                        outgoing_tx.send(Message::Ping(Vec::new())).ok();
+                        continue;
                     }
-                    Ok(Message::Text(text)) => {
-                        debug!("Received text from relay: {}", text);
+                };
+                last_heard = Instant::now();
+                match msg_result {
+                    Ok(Message::Binary(data)) => {
+                        let Some((session_id, payload)) = decode_session_data(&data) else {
+                            warn!("Malformed session data from relay {}", relay_id_in);
+                            continue;
+                        };
+                        deliver(&mut sessions, &outgoing_tx, session_id, payload);
                     }
+                    Ok(Message::Text(text)) => match serde_json::from_str::<RelayMessage>(&text) {
+                        Ok(RelayMessage::Challenge { nonce }) => {
+                            let message = registration_message(&relay_id_in, &nonce);
+                            send_control(
+                                &outgoing_tx,
+                                &RelayMessage::Register {
+                                    relay_id: relay_id_in.clone(),
+                                    public_key: Some(keypair.public_key().to_base64()),
+                                    signature: Some(keypair.sign(&message).to_base64()),
+                                },
+                            );
+                        }
+                        Ok(RelayMessage::SessionRequest {
+                            session_id,
+                            client_id,
+                        }) => {
+                            info!("Relay session {} from {}", session_id, client_id);
+                            let Some(bridge) = bridge.clone() else {
+                                warn!("No pane bridge; refusing relay session {}", session_id);
+                                send_control(
+                                    &outgoing_tx,
+                                    &RelayMessage::Close {
+                                        session_id,
+                                        reason: "host has no panes".to_string(),
+                                    },
+                                );
+                                continue;
+                            };
+                            // Sessions run on their own thread, since pane
+                            // operations block
+                            let (frames_tx, frames_rx) = mpsc::channel(INBOUND_CHUNKS);
+                            let session = RelaySession::new(frames_tx);
+                            let secured = session.secured.clone();
+                            sessions.insert(session_id.clone(), session);
+                            send_control(
+                                &outgoing_tx,
+                                &RelayMessage::SessionAccept {
+                                    session_id: session_id.clone(),
+                                },
+                            );
+                            Self::spawn_session(
+                                bridge,
+                                data_tx.clone(),
+                                frames_rx,
+                                secured,
+                                session_id,
+                            );
+                        }
+                        Ok(RelayMessage::PunchOffer {
+                            session_id,
+                            candidates,
+                        }) => {
+                            // Only devices that have secured their relayed
+                            // session may go direct, one offer at a time,
+                            // since answering sends UDP where they ask
+                            let punching = sessions
+                                .get(&session_id)
+                                .and_then(RelaySession::start_punch);
+                            let (Some(bridge), Some(punching)) = (bridge.clone(), punching) else {
+                                debug!("Ignoring punch offer for session {}", session_id);
+                                continue;
+                            };
+                            tokio::spawn(Self::answer_punch(
+                                bridge,
+                                outgoing_tx.clone(),
+                                session_id,
+                                candidates,
+                                punching,
+                            ));
+                        }
+                        Ok(RelayMessage::Close { session_id, reason }) => {
+                            debug!("Relay closed session {}: {}", session_id, reason);
+                            sessions.remove(&session_id);
+                        }
+                        Ok(RelayMessage::Control { code: 200, .. }) => {
+                            info!("Registered with relay: relay_id={}", relay_id_in);
+                            registered = true;
+                            *status_clone.lock().await = RelayStatus::Connected;
+                        }
+                        Ok(RelayMessage::Control { code, message }) => {
+                            warn!("Relay {} says {}: {}", relay_id_in, code, message);
+                            *status_clone.lock().await = RelayStatus::Error(message);
+                        }
+                        Ok(other) => debug!("Ignoring {:?} from relay", other),
+                        Err(e) => warn!("Bad control message from relay: {}", e),
+                    },
                     Ok(Message::Ping(_)) => {
                         debug!("Received ping from relay");
                     }
@@ -215,195 +646,97 @@ impl RelayClient {
                 }
             }
 
-            // Update status on disconnect
+            writer.abort();
+
+            // Update status on disconnect, keeping any refusal
             let mut status = status_clone.lock().await;
-            *status = RelayStatus::Disconnected;
+            if !matches!(*status, RelayStatus::Error(_)) {
+                *status = RelayStatus::Disconnected;
+            }
             info!("Relay connection ended: {}", relay_id_in);
-        });
-
-        Ok(())
+            registered
+        })
     }
 
-    /// Handle an incoming frame from the relay
-    async fn handle_incoming_frame(
-        bridge: &Option<Arc<dyn PaneBridge>>,
-        _relay_id: &str,
-        frame: Frame,
-        tx: &mpsc::UnboundedSender<Vec<u8>>,
-        authenticated: &mut bool,
-        auth_nonce: &mut Option<String>,
-        attached: &Arc<Mutex<Option<usize>>>,
-    ) -> Result<()> {
-        match frame.typ {
-            TYPE_JSON => {
-                let req: JsonRequest = match serde_json::from_slice(&frame.payload) {
-                    Ok(r) => r,
-                    Err(err) => {
-                        Self::send_json_response(tx, &JsonResponse::Error {
-                            message: format!("invalid json request: {err}"),
-                        })?;
-                        return Ok(());
-                    }
-                };
-                
-                // Handle authentication logic
-                match req {
-                    JsonRequest::AuthResponse { public_key, signature, client_nonce } => {
-                       if let Some(nonce) = auth_nonce {
-                           verify_device_auth(&public_key, &signature, nonce)?;
-                           *authenticated = true;
-                           
-                           // Register for push notifications
-                           let (push_tx, mut push_rx) = tokio::sync::mpsc::unbounded_channel();
-                           let tx_push = tx.clone();
-                           tokio::spawn(async move {
-                               while let Some(msg) = push_rx.recv().await {
-                                   if let Err(_) = Self::send_json_response(&tx_push, &msg) {
-                                       break;
-                                   }
-                               }
-                           });
-                           crate::registry::REGISTRY.register(public_key.clone(), push_tx);
-
-                           let host_sig = if let Some(cn) = client_nonce {
-                               let keypair = load_or_create_host_keypair()?;
-                               Some(keypair.sign(cn.as_bytes()).to_base64())
-                           } else {
-                               None
-                           };
-                           
-                           Self::send_json_response(tx, &JsonResponse::AuthSuccess {
-                               signature: host_sig,
-                           })?;
-                           return Ok(());
-                       } else {
-                           // Unexpected auth response, maybe stale?
-                           // Treat as unauthed if we didn't ask? Or just accept if valid? 
-                           // Protocol requires challenge-response.
-                           Self::send_json_response(tx, &JsonResponse::Error {
-                               message: "unexpected auth response (no nonce)".to_string(),
-                           })?;
-                           return Ok(());
-                       }
-                    }
-                    // These ops are allowed without auth
-                    JsonRequest::PairingPayload | JsonRequest::PairingSubmit { .. } => {}
-                    
-                    // All other ops require auth
-                    _ if !*authenticated => {
-                        // Generate challenge
-                        let nonce = Uuid::new_v4().to_string();
-                        *auth_nonce = Some(nonce.clone());
-                        
-                        // Send challenge
-                        Self::send_json_response(tx, &JsonResponse::AuthChallenge {
-                            nonce: nonce,
-                        })?;
-                        
-                        // Also send Error to indicate original request failed
-                        Self::send_json_response(tx, &JsonResponse::Error {
-                            message: "authentication required".to_string(),
-                        })?;
-                        return Ok(());
-                    }
-                    _ => {}
-                }
-
-                // Process request
-                match req {
-                    JsonRequest::ListPanes => {
-                        if let Some(b) = bridge {
-                            let panes = b.list_panes()?;
-                            Self::send_json_response(tx, &JsonResponse::ListPanes { panes })?;
-                        }
-                    }
-                    JsonRequest::Attach { pane_id } => {
-                        if let Some(b) = bridge {
-                            {
-                                let mut a = attached.lock().await;
-                                if a.is_some() {
-                                    Self::send_json_response(tx, &JsonResponse::Error {
-                                        message: "already attached".to_string(),
-                                    })?;
-                                    return Ok(());
-                                }
-                                *a = Some(pane_id);
-                            }
-                            
-                            let sub = b.subscribe_output(pane_id)?;
-                            let tx2 = tx.clone();
-                            
-                            // Spawn monitoring thread
-                             tokio::task::spawn_blocking(move || {
-                                while let Ok(Some(bytes)) = sub.recv_timeout(Duration::from_millis(250)) {
-                                    let frame = encode_frame(TYPE_PANE_OUTPUT, &bytes);
-                                    if tx2.send(frame).is_err() {
-                                        break; 
-                                    }
-                                }
-                            });
-                            
-                            Self::send_json_response(tx, &JsonResponse::AttachOk { pane_id })?;
-                        }
-                    }
-                    JsonRequest::PairingPayload => {
-                        // Relay mode: we don't know P2P addrs easily here, or we could pass them?
-                        // For now pass None/None as we are using Relay
-                        let payload = pairing_payload_with_p2p(None, None)?;
-                        Self::send_json_response(tx, &JsonResponse::PairingPayload { payload })?;
-                    }
-                    JsonRequest::PairingSubmit { request } => {
-                         let response = handle_pairing_submit(request)?;
-                         Self::send_json_response(tx, &JsonResponse::PairingResponse { response })?;
-                    }
-                    JsonRequest::PairingListTrustedDevices => {
-                        let devices = list_trusted_devices()?;
-                        Self::send_json_response(tx, &JsonResponse::PairingTrustedDevices { devices })?;
-                    }
-                    JsonRequest::Paste { pane_id, text } => {
-                        if let Some(b) = bridge {
-                            b.send_paste(pane_id, &text)?;
-                        }
-                    }
-                    JsonRequest::Resize { pane_id, rows, cols } => {
-                        if let Some(b) = bridge {
-                            b.resize(pane_id, rows, cols)?;
-                        }
-                    }
-                    JsonRequest::RevokeDevice { public_key } => {
-                        crate::pairing_api::revoke_device(&public_key)?;
-                        Self::send_json_response(tx, &JsonResponse::Error {
-                            message: "device revoked".to_string(),
-                        })?;
-                    }
-                    _ => {} // AuthResponse handled above
-                }
+    /// Serve one mobile on the far side of the relay. Its output goes
+    /// through a bounded queue, as a TCP client's does. If the session ends
+    /// on the host's side, the relay is asked to close the mobile too.
+    fn spawn_session(
+        bridge: Arc<dyn PaneBridge>,
+        data: mpsc::Sender<Message>,
+        rx: mpsc::Receiver<Vec<u8>>,
+        secured: Arc<AtomicBool>,
+        session_id: String,
+    ) {
+        let (queue, queue_rx, queued) = OutboundQueue::new();
+        let ended_here = Arc::new(AtomicBool::new(false));
+        tokio::spawn(forward_session(
+            session_id.clone(),
+            queue_rx,
+            queued,
+            data,
+            Arc::clone(&ended_here),
+        ));
+        std::thread::spawn(move || {
+            let policy = TransportPolicy {
+                trusted: false,
+                // The relay operator must only ever see ciphertext
+                require_encryption: true,
+            };
+            let mut source = ChannelSource::new(rx);
+            let mut core = SessionCore::new(bridge, queue, policy);
+            core.report_secured(secured);
+            let result = core.start().and_then(|()| core.run(&mut source));
+            if let Err(e) = &result {
+                error!("Relay session {} failed: {:#}", session_id, e);
             }
-            TYPE_PANE_INPUT => {
-                if let Some(b) = bridge {
-                    let mut a = attached.lock().await;
-                     if let Some(pane_id) = *a {
-                         b.send_input(pane_id, &frame.payload)?;
-                     }
-                }
+            // Read once the queue closes, as `core` goes
+            ended_here.store(!source.is_finished(), Ordering::Relaxed);
+        });
+    }
+
+    /// Answer a mobile's offer to go direct, then serve it if it gets
+    /// through. The relayed session carries on either way, until the
+    /// mobile leaves it. The session can offer again once this is over.
+    async fn answer_punch(
+        bridge: Arc<dyn PaneBridge>,
+        outgoing: mpsc::UnboundedSender<Message>,
+        session_id: String,
+        offered: Vec<String>,
+        _punching: Punching,
+    ) {
+        match punch::answer(&offered, bridge).await {
+            Ok((candidates, cert_sha256, serve)) => {
+                info!("Punching towards {:?} for session {}", offered, session_id);
+                send_control(
+                    &outgoing,
+                    &RelayMessage::PunchAnswer {
+                        session_id,
+                        candidates,
+                        cert_sha256,
+                    },
+                );
+                serve.await;
             }
-            _ => {
-                warn!("Unknown frame type from relay: {}", frame.typ);
+            Err(e) => {
+                warn!("Can't go direct for session {}: {:#}", session_id, e);
+                send_control(
+                    &outgoing,
+                    &RelayMessage::PunchAnswer {
+                        session_id,
+                        candidates: vec![],
+                        cert_sha256: String::new(),
+                    },
+                );
             }
         }
-        Ok(())
-    }
-    
-    fn send_json_response(tx: &mpsc::UnboundedSender<Vec<u8>>, resp: &JsonResponse) -> Result<()> {
-        let payload = serde_json::to_vec(resp)?;
-        let frame = encode_frame(TYPE_JSON, &payload);
-        tx.send(frame).map_err(|_| anyhow!("failed to send to relay channel"))?;
-        Ok(())
     }
 
     /// Disconnect from the relay
     pub async fn disconnect(&mut self) {
-        self.outgoing_tx = None;
+        if let Some(outgoing_tx) = self.outgoing_tx.take() {
+            outgoing_tx.send(Message::Close(None)).ok();
+        }
         let mut status = self.status.lock().await;
         *status = RelayStatus::Disconnected;
         info!("Disconnected from relay");
@@ -432,4 +765,44 @@ mod tests {
         );
         assert_eq!(client.status().await, RelayStatus::Disconnected);
     }
+
+    #[test]
+    fn sessions_that_fall_behind_are_closed_alone() {
+        let (outgoing, mut relay) = mpsc::unbounded_channel();
+        let (slow_tx, _slow_rx) = mpsc::channel(1);
+        let (fast_tx, mut fast_rx) = mpsc::channel(1);
+        let mut sessions = HashMap::from([
+            ("slow".to_string(), RelaySession::new(slow_tx)),
+            ("fast".to_string(), RelaySession::new(fast_tx)),
+        ]);
+
+        deliver(&mut sessions, &outgoing, "slow", b"one");
+        deliver(&mut sessions, &outgoing, "slow", b"two");
+        assert!(!sessions.contains_key("slow"));
+        let Ok(Message::Text(close)) = relay.try_recv() else {
+            panic!("slow session was not closed");
+        };
+        assert!(matches!(
+            serde_json::from_str(&close).unwrap(),
+            RelayMessage::Close { session_id, .. } if session_id == "slow"
+        ));
+
+        deliver(&mut sessions, &outgoing, "fast", b"three");
+        assert_eq!(fast_rx.try_recv().unwrap(), b"three");
+        assert!(sessions.contains_key("fast"));
+        assert!(relay.try_recv().is_err());
+    }
+
+    #[test]
+    fn sessions_go_direct_once_secured_and_one_offer_at_a_time() {
+        let (frames, _rx) = mpsc::channel(1);
+        let session = RelaySession::new(frames);
+        assert!(session.start_punch().is_none());
+
+        session.secured.store(true, Ordering::Release);
+        let punching = session.start_punch().unwrap();
+        assert!(session.start_punch().is_none());
+        drop(punching);
+        assert!(session.start_punch().is_some());
+    }
 }
@@ -0,0 +1,775 @@
+//! Resumable sessions.
+//!
+//! An authenticated client receives a session token in `SessionToken`, the
+//! first frame after `AuthSuccess` and so the first sealed one. Raw pane
+//! output frames are numbered from 1 across every pane the session has
+//! attached (continuing from the `next_seq` of a `ResumeOk`) and kept in a
+//! bounded ring buffer. When the connection drops, the session keeps
+//! buffering for `RESUME_GRACE`. A client that reconnects, authenticates as
+//! the same device, and sends `Resume { token, last_seq }` gets the frames it
+//! missed, or a fresh snapshot of each attached pane if the buffer no longer
+//! reaches back that far.
+//!
+//! The ring also absorbs a connection that is backed up. While more than
+//! `BEHIND_BYTES` are waiting to be sent, new output is only recorded; once
+//! the connection drains, the client gets everything it is missing in one go.
+//! A client that stays behind for `RESYNC_AFTER`, or falls off the end of the
+//! ring, gets `Resync` and fresh snapshots instead.
+
+use crate::bridge::{OutputSubscription, PaneBridge};
+use crate::protocol::{TYPE_PANE_OUTPUT, TYPE_PANE_OUTPUT_ADDRESSED, TYPE_PANE_SNAPSHOT};
+use crate::secure::FrameWriter;
+use anyhow::anyhow;
+use dashmap::DashMap;
+use lucidity_proto::frame::encode_pane_payload;
+use lucidity_proto::protocol::JsonResponse;
+use mux::pane::PaneId;
+use once_cell::sync::Lazy;
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// How long a dropped session keeps buffering output for a resume
+pub(crate) const RESUME_GRACE: Duration = Duration::from_secs(300);
+/// Output kept per session for replay
+pub(crate) const OUTPUT_RING_BYTES: usize = 1024 * 1024;
+/// Unsent bytes beyond which output is held back in the ring
+pub(crate) const BEHIND_BYTES: usize = 256 * 1024;
+/// How long a client may stay behind before it is resynced
+pub(crate) const RESYNC_AFTER: Duration = Duration::from_secs(10);
+/// Upper bound on an output frame made by merging chunks that were
+/// already waiting
+const COALESCE_BYTES: usize = 64 * 1024;
+
+pub(crate) type SharedWriter = Arc<Mutex<dyn FrameWriter>>;
+
+static SESSIONS: Lazy<DashMap<String, Arc<ResumableSession>>> = Lazy::new(DashMap::new);
+
+/// One raw output frame, as it was sent to the client.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct OutputFrame {
+    pub pane_id: PaneId,
+    /// Sent as `TYPE_PANE_OUTPUT_ADDRESSED` rather than `TYPE_PANE_OUTPUT`
+    pub addressed: bool,
+    pub bytes: Arc<[u8]>,
+}
+
+impl OutputFrame {
+    fn write_to(&self, writer: &mut dyn FrameWriter) -> anyhow::Result<()> {
+        if self.addressed {
+            let payload = encode_pane_payload(self.pane_id as u32, &self.bytes);
+            writer.write_frame(TYPE_PANE_OUTPUT_ADDRESSED, &payload)
+        } else {
+            writer.write_frame(TYPE_PANE_OUTPUT, &self.bytes)
+        }
+    }
+}
+
+/// Numbered output frames, bounded by total payload size.
+pub(crate) struct OutputRing {
+    frames: VecDeque<(u64, OutputFrame)>,
+    bytes: usize,
+    limit: usize,
+    next_seq: u64,
+}
+
+impl OutputRing {
+    pub fn new(limit: usize) -> Self {
+        Self {
+            frames: VecDeque::new(),
+            bytes: 0,
+            limit,
+            next_seq: 1,
+        }
+    }
+
+    pub fn next_seq(&self) -> u64 {
+        self.next_seq
+    }
+
+    pub fn push(&mut self, frame: OutputFrame) -> u64 {
+        let seq = self.next_seq;
+        self.next_seq += 1;
+        self.bytes += frame.bytes.len();
+        self.frames.push_back((seq, frame));
+        while self.bytes > self.limit {
+            match self.frames.pop_front() {
+                Some((_, old)) => self.bytes -= old.bytes.len(),
+                None => break,
+            }
+        }
+        seq
+    }
+
+    /// Frames after `last_seq`, or None if some of them have been dropped
+    pub fn since(&self, last_seq: u64) -> Option<Vec<OutputFrame>> {
+        if last_seq >= self.next_seq {
+            return None;
+        }
+        let oldest = self.frames.front().map_or(self.next_seq, |(seq, _)| *seq);
+        if oldest > last_seq + 1 {
+            return None;
+        }
+        Some(
+            self.frames
+                .iter()
+                .filter(|(seq, _)| *seq > last_seq)
+                .map(|(_, frame)| frame.clone())
+                .collect(),
+        )
+    }
+}
+
+/// A pane whose raw output the session forwards. `id` tells a pump thread
+/// apart from one started by a later attach to the same pane.
+struct PaneOutput {
+    id: u64,
+    addressed: bool,
+    /// Source of resync snapshots
+    bridge: Arc<dyn PaneBridge>,
+}
+
+struct SessionState {
+    writer: Option<SharedWriter>,
+    ring: OutputRing,
+    /// Last frame written to `writer`
+    sent_seq: u64,
+    /// When `writer` last had more than `BEHIND_BYTES` unsent
+    behind_since: Option<Instant>,
+    outputs: HashMap<PaneId, PaneOutput>,
+    next_output_id: u64,
+    detached_at: Option<Instant>,
+    closed: bool,
+}
+
+impl SessionState {
+    /// Write the frames the client has not had yet, unless its connection
+    /// is backed up.
+    fn deliver(&mut self) {
+        let Some(writer) = self.writer.clone() else {
+            return;
+        };
+        let newest = self.ring.next_seq() - 1;
+        if self.sent_seq >= newest {
+            self.behind_since = None;
+            return;
+        }
+
+        let mut w = writer.lock().unwrap();
+        if w.backlog() > BEHIND_BYTES {
+            self.behind_since.get_or_insert_with(Instant::now);
+            return;
+        }
+        let stale = self
+            .behind_since
+            .is_some_and(|since| since.elapsed() > RESYNC_AFTER);
+        let written = match self.ring.since(self.sent_seq) {
+            Some(frames) if !stale => frames.iter().try_for_each(|frame| frame.write_to(&mut *w)),
+            _ => self.resync(&mut *w),
+        };
+        drop(w);
+
+        self.sent_seq = newest;
+        self.behind_since = None;
+        if written.is_err() {
+            self.writer = None;
+            self.detached_at = Some(Instant::now());
+        }
+    }
+
+    /// Replace the output the client missed with a snapshot of each pane
+    fn resync(&self, w: &mut dyn FrameWriter) -> anyhow::Result<()> {
+        w.write_json(&JsonResponse::Resync {
+            next_seq: self.ring.next_seq(),
+        })?;
+        let mut outputs: Vec<_> = self.outputs.iter().collect();
+        outputs.sort_unstable_by_key(|(pane_id, _)| **pane_id);
+        for (&pane_id, output) in outputs {
+            let scrollback = crate::snapshot::scrollback_rows(None);
+            match output.bridge.snapshot(pane_id, scrollback) {
+                Ok(snapshot) => {
+                    w.write_frame(TYPE_PANE_SNAPSHOT, &serde_json::to_vec(&snapshot)?)?
+                }
+                // The pane is closing; its pump will notice
+                Err(err) => log::debug!("resync snapshot of pane {pane_id}: {err:#}"),
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The part of a client connection that outlives the connection.
+pub(crate) struct ResumableSession {
+    token: Mutex<Option<String>>,
+    device_key: Mutex<Option<String>>,
+    state: Mutex<SessionState>,
+}
+
+/// The raw attachments a resumed session carries over
+#[derive(Debug, Default, PartialEq, Eq)]
+pub(crate) struct ResumedPanes {
+    /// The pane attached without `addressed`
+    pub pane_id: Option<PaneId>,
+    pub addressed: Vec<PaneId>,
+}
+
+/// What a successful resume restored
+pub(crate) struct Resumed {
+    pub session: Arc<ResumableSession>,
+    pub panes: ResumedPanes,
+}
+
+impl ResumableSession {
+    pub fn new(writer: SharedWriter) -> Arc<Self> {
+        Arc::new(Self {
+            token: Mutex::new(None),
+            device_key: Mutex::new(None),
+            state: Mutex::new(SessionState {
+                writer: Some(writer),
+                ring: OutputRing::new(OUTPUT_RING_BYTES),
+                sent_seq: 0,
+                behind_since: None,
+                outputs: HashMap::new(),
+                next_output_id: 0,
+                detached_at: None,
+                closed: false,
+            }),
+        })
+    }
+
+    /// Make the session resumable by `device_key` and return its token.
+    pub fn issue_token(self: &Arc<Self>, device_key: &str) -> String {
+        let token = format!(
+            "{}{}",
+            uuid::Uuid::new_v4().simple(),
+            uuid::Uuid::new_v4().simple()
+        );
+        *self.device_key.lock().unwrap() = Some(device_key.to_string());
+        if let Some(old) = self.token.lock().unwrap().replace(token.clone()) {
+            SESSIONS.remove(&old);
+        }
+        SESSIONS.insert(token.clone(), Arc::clone(self));
+        token
+    }
+
+    /// Start recording and forwarding raw output for `pane_id` until
+    /// `stop_output`. The caller has already written `AttachOk` and the
+    /// snapshot.
+    pub fn start_output(
+        self: &Arc<Self>,
+        bridge: &Arc<dyn PaneBridge>,
+        pane_id: PaneId,
+        addressed: bool,
+        sub: Box<dyn OutputSubscription>,
+    ) {
+        let id = {
+            let mut state = self.state.lock().unwrap();
+            state.next_output_id += 1;
+            let id = state.next_output_id;
+            let bridge = Arc::clone(bridge);
+            state.outputs.insert(
+                pane_id,
+                PaneOutput {
+                    id,
+                    addressed,
+                    bridge,
+                },
+            );
+            id
+        };
+
+        let session = Arc::clone(self);
+        std::thread::spawn(move || loop {
+            let bytes = match sub.recv_timeout(Duration::from_millis(250)) {
+                Ok(bytes) => bytes.map(|first| coalesce(first, &*sub)),
+                Err(_) => {
+                    session.pane_gone(pane_id, id);
+                    break;
+                }
+            };
+
+            let mut state = session.state.lock().unwrap();
+            if state.closed {
+                break;
+            }
+            if let Some(at) = state.detached_at {
+                if at.elapsed() > RESUME_GRACE {
+                    drop(state);
+                    session.close();
+                    break;
+                }
+            }
+            match state.outputs.get(&pane_id) {
+                Some(output) if output.id == id => {}
+                _ => break,
+            }
+            if let Some(bytes) = bytes {
+                state.ring.push(OutputFrame {
+                    pane_id,
+                    addressed,
+                    bytes,
+                });
+            }
+            // Also on timeouts, so that a backed-up client catches up once
+            // its connection drains
+            state.deliver();
+        });
+    }
+
+    /// Stop forwarding output for `pane_id`. Returns false if it was not
+    /// attached.
+    pub fn stop_output(&self, pane_id: PaneId) -> bool {
+        self.state
+            .lock()
+            .unwrap()
+            .outputs
+            .remove(&pane_id)
+            .is_some()
+    }
+
+    /// The pane behind output `id` has closed. A detached session with
+    /// nothing left to buffer is closed too.
+    fn pane_gone(&self, pane_id: PaneId, id: u64) {
+        let mut state = self.state.lock().unwrap();
+        if state.outputs.get(&pane_id).is_some_and(|o| o.id == id) {
+            state.outputs.remove(&pane_id);
+        }
+        let idle = state.writer.is_none() && state.outputs.is_empty();
+        drop(state);
+        if idle {
+            self.close();
+        }
+    }
+
+    /// The connection that owns `writer` has gone away. A session with
+    /// attached output and a token lingers for `RESUME_GRACE`; anything else
+    /// is closed now.
+    pub fn detach(&self, writer: &SharedWriter) {
+        let mut state = self.state.lock().unwrap();
+        if !state
+            .writer
+            .as_ref()
+            .is_some_and(|w| Arc::ptr_eq(w, writer))
+        {
+            // Already taken over by a resumed connection
+            return;
+        }
+        state.writer = None;
+        state.detached_at = Some(Instant::now());
+        let resumable = !state.outputs.is_empty() && self.token.lock().unwrap().is_some();
+        drop(state);
+        if !resumable {
+            self.close();
+        }
+    }
+
+    fn close(&self) {
+        self.state.lock().unwrap().closed = true;
+        if let Some(token) = self.token.lock().unwrap().take() {
+            SESSIONS.remove(&token);
+        }
+    }
+
+    /// Move the session named by `token` onto a new connection. Writes
+    /// `ResumeOk` followed by either the missed output or a snapshot of each
+    /// attached pane.
+    pub fn resume(
+        token: &str,
+        device_key: &str,
+        last_seq: u64,
+        writer: SharedWriter,
+        bridge: &dyn PaneBridge,
+        scrollback: usize,
+    ) -> anyhow::Result<Resumed> {
+        let session = SESSIONS
+            .get(token)
+            .map(|s| Arc::clone(s.value()))
+            .ok_or_else(|| anyhow!("unknown or expired session"))?;
+        if session.device_key.lock().unwrap().as_deref() != Some(device_key) {
+            return Err(anyhow!("unknown or expired session"));
+        }
+
+        let mut state = session.state.lock().unwrap();
+        if state.closed {
+            return Err(anyhow!("unknown or expired session"));
+        }
+
+        let mut attached: Vec<_> = state
+            .outputs
+            .iter()
+            .map(|(id, o)| (*id, o.addressed))
+            .collect();
+        attached.sort_unstable();
+        let mut panes = ResumedPanes::default();
+        for &(pane_id, addressed) in &attached {
+            if addressed {
+                panes.addressed.push(pane_id);
+            } else {
+                panes.pane_id = Some(pane_id);
+            }
+        }
+
+        let mut w = writer.lock().unwrap();
+        match state.ring.since(last_seq) {
+            Some(frames) => {
+                w.write_json(&JsonResponse::ResumeOk {
+                    pane_id: panes.pane_id,
+                    panes: panes.addressed.clone(),
+                    next_seq: last_seq + 1,
+                    snapshot: false,
+                })?;
+                for frame in frames {
+                    frame.write_to(&mut *w)?;
+                }
+            }
+            None => {
+                let snapshots = attached
+                    .iter()
+                    .map(|&(pane_id, _)| {
+                        let snapshot = bridge.snapshot(pane_id, scrollback)?;
+                        Ok(serde_json::to_vec(&snapshot)?)
+                    })
+                    .collect::<anyhow::Result<Vec<_>>>()?;
+                w.write_json(&JsonResponse::ResumeOk {
+                    pane_id: panes.pane_id,
+                    panes: panes.addressed.clone(),
+                    next_seq: state.ring.next_seq(),
+                    snapshot: true,
+                })?;
+                for snapshot in snapshots {
+                    w.write_frame(TYPE_PANE_SNAPSHOT, &snapshot)?;
+                }
+            }
+        }
+        drop(w);
+
+        state.writer = Some(writer);
+        state.sent_seq = state.ring.next_seq() - 1;
+        state.behind_since = None;
+        state.detached_at = None;
+        drop(state);
+
+        Ok(Resumed { session, panes })
+    }
+}
+
+/// Merge chunks that are already waiting behind `first` into one frame
+fn coalesce(first: Arc<[u8]>, sub: &dyn OutputSubscription) -> Arc<[u8]> {
+    let mut merged: Option<Vec<u8>> = None;
+    while merged.as_ref().map_or(first.len(), Vec::len) < COALESCE_BYTES {
+        match sub.recv_timeout(Duration::ZERO) {
+            Ok(Some(more)) => merged
+                .get_or_insert_with(|| first.to_vec())
+                .extend_from_slice(&more),
+            _ => break,
+        }
+    }
+    merged.map_or(first, Arc::from)
+}
+
+/// Ties a session to one connection and detaches it when the connection
+/// ends, however that happens.
+pub(crate) struct SessionHandle {
+    session: Arc<ResumableSession>,
+    writer: SharedWriter,
+}
+
+impl SessionHandle {
+    pub fn new(writer: SharedWriter) -> Self {
+        Self {
+            session: ResumableSession::new(Arc::clone(&writer)),
+            writer,
+        }
+    }
+
+    pub fn session(&self) -> &Arc<ResumableSession> {
+        &self.session
+    }
+
+    /// The connection's writer
+    pub fn writer(&self) -> &SharedWriter {
+        &self.writer
+    }
+
+    /// Swap this connection's session for the one named by `token`.
+    /// Returns the panes that are attached again.
+    pub fn resume(
+        &mut self,
+        token: &str,
+        device_key: &str,
+        last_seq: u64,
+        bridge: &dyn PaneBridge,
+        scrollback: usize,
+    ) -> anyhow::Result<ResumedPanes> {
+        let resumed = ResumableSession::resume(
+            token,
+            device_key,
+            last_seq,
+            Arc::clone(&self.writer),
+            bridge,
+            scrollback,
+        )?;
+        let previous = std::mem::replace(&mut self.session, resumed.session);
+        if !Arc::ptr_eq(&previous, &self.session) {
+            previous.detach(&self.writer);
+        }
+        Ok(resumed.panes)
+    }
+}
+
+impl Drop for SessionHandle {
+    fn drop(&mut self) {
+        self.session.detach(&self.writer);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bridge::FakePaneBridge;
+    use crate::protocol::TYPE_JSON;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    type Frames = Vec<(u8, Vec<u8>)>;
+
+    /// Records frames, and reports whatever backlog the test sets
+    #[derive(Clone, Default)]
+    struct Collect(Arc<Mutex<Frames>>, Arc<AtomicUsize>);
+
+    impl FrameWriter for Collect {
+        fn write_frame(&mut self, typ: u8, payload: &[u8]) -> anyhow::Result<()> {
+            self.0.lock().unwrap().push((typ, payload.to_vec()));
+            Ok(())
+        }
+
+        fn backlog(&self) -> usize {
+            self.1.load(Ordering::SeqCst)
+        }
+    }
+
+    impl Collect {
+        fn wait_for(&self, count: usize) -> Frames {
+            for _ in 0..100 {
+                if self.0.lock().unwrap().len() >= count {
+                    break;
+                }
+                std::thread::sleep(Duration::from_millis(10));
+            }
+            self.0.lock().unwrap().clone()
+        }
+    }
+
+    fn dyn_bridge(bridge: &Arc<FakePaneBridge>) -> Arc<dyn PaneBridge> {
+        Arc::clone(bridge) as Arc<dyn PaneBridge>
+    }
+
+    fn frame(s: &str) -> OutputFrame {
+        OutputFrame {
+            pane_id: 1,
+            addressed: false,
+            bytes: Arc::from(s.as_bytes()),
+        }
+    }
+
+    #[test]
+    fn ring_replays_after_last_seq() {
+        let mut ring = OutputRing::new(100);
+        ring.push(frame("a"));
+        ring.push(frame("b"));
+        ring.push(frame("c"));
+
+        let missed = ring.since(1).unwrap();
+        assert_eq!(missed, vec![frame("b"), frame("c")]);
+        assert!(ring.since(3).unwrap().is_empty());
+        assert!(ring.since(4).is_none());
+    }
+
+    #[test]
+    fn ring_reports_overflow() {
+        let mut ring = OutputRing::new(4);
+        ring.push(frame("aa"));
+        ring.push(frame("bb"));
+        ring.push(frame("cc"));
+
+        assert_eq!(ring.next_seq(), 4);
+        assert!(ring.since(0).is_none());
+        assert_eq!(ring.since(1).unwrap(), vec![frame("bb"), frame("cc")]);
+    }
+
+    #[test]
+    fn resume_replays_output_missed_while_detached() {
+        let bridge = Arc::new(FakePaneBridge::new(vec![]));
+        let first = Collect::default();
+        let handle = SessionHandle::new(Arc::new(Mutex::new(first.clone())));
+        let token = handle.session().issue_token("device");
+        handle.session().start_output(
+            &dyn_bridge(&bridge),
+            7,
+            false,
+            bridge.subscribe_output(7).unwrap(),
+        );
+
+        bridge.emit_output(7, b"one");
+        assert_eq!(first.wait_for(1), vec![(TYPE_PANE_OUTPUT, b"one".to_vec())]);
+        drop(handle);
+
+        bridge.emit_output(7, b"two");
+        std::thread::sleep(Duration::from_millis(50));
+
+        let second = Collect::default();
+        let mut handle = SessionHandle::new(Arc::new(Mutex::new(second.clone())));
+        assert!(handle.resume(&token, "other", 1, &*bridge, 0).is_err());
+        assert_eq!(
+            handle.resume(&token, "device", 1, &*bridge, 0).unwrap(),
+            ResumedPanes {
+                pane_id: Some(7),
+                addressed: vec![],
+            }
+        );
+
+        let frames = second.wait_for(2);
+        assert_eq!(frames[0].0, TYPE_JSON);
+        let ok: serde_json::Value = serde_json::from_slice(&frames[0].1).unwrap();
+        assert_eq!(ok["op"], "resume_ok");
+        assert_eq!(ok["next_seq"], 2);
+        assert_eq!(ok["snapshot"], false);
+        assert_eq!(frames[1], (TYPE_PANE_OUTPUT, b"two".to_vec()));
+
+        bridge.emit_output(7, b"three");
+        assert_eq!(second.wait_for(3)[2], (TYPE_PANE_OUTPUT, b"three".to_vec()));
+    }
+
+    #[test]
+    fn addressed_outputs_share_one_sequence() {
+        let bridge = Arc::new(FakePaneBridge::new(vec![]));
+        let first = Collect::default();
+        let handle = SessionHandle::new(Arc::new(Mutex::new(first.clone())));
+        let token = handle.session().issue_token("device");
+        for pane_id in [1, 2] {
+            handle.session().start_output(
+                &dyn_bridge(&bridge),
+                pane_id,
+                true,
+                bridge.subscribe_output(pane_id).unwrap(),
+            );
+        }
+
+        bridge.emit_output(1, b"a");
+        first.wait_for(1);
+        bridge.emit_output(2, b"b");
+        assert_eq!(
+            first.wait_for(2),
+            vec![
+                (TYPE_PANE_OUTPUT_ADDRESSED, encode_pane_payload(1, b"a")),
+                (TYPE_PANE_OUTPUT_ADDRESSED, encode_pane_payload(2, b"b")),
+            ]
+        );
+
+        assert!(handle.session().stop_output(1));
+        assert!(!handle.session().stop_output(1));
+        drop(handle);
+        bridge.emit_output(1, b"dropped");
+        bridge.emit_output(2, b"c");
+        std::thread::sleep(Duration::from_millis(50));
+
+        let second = Collect::default();
+        let mut handle = SessionHandle::new(Arc::new(Mutex::new(second.clone())));
+        assert_eq!(
+            handle.resume(&token, "device", 1, &*bridge, 0).unwrap(),
+            ResumedPanes {
+                pane_id: None,
+                addressed: vec![2],
+            }
+        );
+
+        let frames = second.wait_for(3);
+        let ok: serde_json::Value = serde_json::from_slice(&frames[0].1).unwrap();
+        assert_eq!(ok["panes"], serde_json::json!([2]));
+        assert_eq!(ok["next_seq"], 2);
+        assert_eq!(
+            frames[1..],
+            [
+                (TYPE_PANE_OUTPUT_ADDRESSED, encode_pane_payload(2, b"b")),
+                (TYPE_PANE_OUTPUT_ADDRESSED, encode_pane_payload(2, b"c")),
+            ]
+        );
+    }
+
+    #[test]
+    fn backed_up_client_catches_up_in_one_go() {
+        let bridge = Arc::new(FakePaneBridge::new(vec![]));
+        let out = Collect::default();
+        let handle = SessionHandle::new(Arc::new(Mutex::new(out.clone())));
+        handle.session().start_output(
+            &dyn_bridge(&bridge),
+            7,
+            false,
+            bridge.subscribe_output(7).unwrap(),
+        );
+
+        out.1.store(BEHIND_BYTES + 1, Ordering::SeqCst);
+        bridge.emit_output(7, b"one");
+        bridge.emit_output(7, b"two");
+        std::thread::sleep(Duration::from_millis(100));
+        assert!(out.0.lock().unwrap().is_empty());
+
+        out.1.store(0, Ordering::SeqCst);
+        out.wait_for(1);
+        std::thread::sleep(Duration::from_millis(50));
+        let sent: Vec<u8> = out
+            .0
+            .lock()
+            .unwrap()
+            .iter()
+            .flat_map(|(typ, payload)| {
+                assert_eq!(*typ, TYPE_PANE_OUTPUT);
+                payload.clone()
+            })
+            .collect();
+        assert_eq!(sent, b"onetwo");
+    }
+
+    #[test]
+    fn client_behind_past_the_ring_is_resynced() {
+        let bridge = Arc::new(FakePaneBridge::new(vec![]));
+        bridge.set_screen(7, vec!["$ yes".to_string()]);
+        let out = Collect::default();
+        let handle = SessionHandle::new(Arc::new(Mutex::new(out.clone())));
+        handle.session().start_output(
+            &dyn_bridge(&bridge),
+            7,
+            false,
+            bridge.subscribe_output(7).unwrap(),
+        );
+
+        out.1.store(BEHIND_BYTES + 1, Ordering::SeqCst);
+        let chunk = vec![b'y'; COALESCE_BYTES];
+        for _ in 0..OUTPUT_RING_BYTES / COALESCE_BYTES + 2 {
+            bridge.emit_output(7, &chunk);
+            std::thread::sleep(Duration::from_millis(5));
+        }
+        std::thread::sleep(Duration::from_millis(100));
+        assert!(out.0.lock().unwrap().is_empty());
+
+        out.1.store(0, Ordering::SeqCst);
+        let frames = out.wait_for(2);
+        let resync: serde_json::Value = serde_json::from_slice(&frames[0].1).unwrap();
+        assert_eq!(resync["op"], "resync");
+        assert_eq!(frames[1].0, TYPE_PANE_SNAPSHOT);
+
+        bridge.emit_output(7, b"next");
+        let frames = out.wait_for(3);
+        assert_eq!(frames[2], (TYPE_PANE_OUTPUT, b"next".to_vec()));
+    }
+
+    #[test]
+    fn session_without_output_is_not_resumable() {
+        let bridge = FakePaneBridge::new(vec![]);
+        let handle = SessionHandle::new(Arc::new(Mutex::new(Collect::default())));
+        let token = handle.session().issue_token("device");
+        drop(handle);
+
+        let mut handle = SessionHandle::new(Arc::new(Mutex::new(Collect::default())));
+        assert!(handle.resume(&token, "device", 0, &bridge, 0).is_err());
+    }
+}
@@ -0,0 +1,145 @@
+//! Host side of the end-to-end encrypted session.
+//!
+//! See `lucidity_proto::secure` for the handshake. This module binds it to the
+//! host identity from `KeypairStore` and wraps the transports so that every
+//! frame written after `AuthSuccess` is sealed.
+
+use crate::pairing_api::load_or_create_host_keypair;
+use crate::protocol::{TYPE_JSON, TYPE_SEALED};
+use anyhow::{anyhow, Context};
+use lucidity_pairing::PublicKey;
+use lucidity_proto::frame::{encode_frame, Frame};
+use lucidity_proto::protocol::JsonResponse;
+use lucidity_proto::secure::{
+    decode_ephemeral_key, device_auth_message, encode_ephemeral_key, FrameOpener, FrameSealer,
+    KeyExchange, Role, Transcript,
+};
+use std::io::Write;
+use std::net::TcpStream;
+
+/// A transport that accepts already-encoded frames.
+pub(crate) trait RawFrameSink: Send {
+    fn send_raw(&mut self, bytes: Vec<u8>) -> anyhow::Result<()>;
+
+    /// Bytes accepted but not yet handed to the network
+    fn backlog(&self) -> usize {
+        0
+    }
+}
+
+impl RawFrameSink for TcpStream {
+    fn send_raw(&mut self, bytes: Vec<u8>) -> anyhow::Result<()> {
+        self.write_all(&bytes)?;
+        self.flush().ok();
+        Ok(())
+    }
+}
+
+/// Anything frames can be written to, independent of the transport.
+pub(crate) trait FrameWriter: Send {
+    fn write_frame(&mut self, typ: u8, payload: &[u8]) -> anyhow::Result<()>;
+
+    fn write_json(&mut self, msg: &JsonResponse) -> anyhow::Result<()> {
+        let payload = serde_json::to_vec(msg)?;
+        self.write_frame(TYPE_JSON, &payload)
+    }
+
+    /// Bytes written but not yet handed to the network
+    fn backlog(&self) -> usize {
+        0
+    }
+}
+
+/// Writes frames to a client, sealing them once a key exchange has completed.
+pub(crate) struct SealedWriter<S> {
+    sink: S,
+    sealer: Option<FrameSealer>,
+}
+
+impl<S: RawFrameSink> SealedWriter<S> {
+    pub fn new(sink: S) -> Self {
+        Self { sink, sealer: None }
+    }
+
+    /// Seal every frame written from now on.
+    pub fn enable_sealing(&mut self, sealer: FrameSealer) {
+        self.sealer = Some(sealer);
+    }
+}
+
+impl<S: RawFrameSink> FrameWriter for SealedWriter<S> {
+    fn write_frame(&mut self, typ: u8, payload: &[u8]) -> anyhow::Result<()> {
+        let bytes = match self.sealer.as_mut() {
+            Some(sealer) => encode_frame(TYPE_SEALED, &sealer.seal(typ, payload)?),
+            None => encode_frame(typ, payload),
+        };
+        self.sink.send_raw(bytes)
+    }
+
+    fn backlog(&self) -> usize {
+        self.sink.backlog()
+    }
+}
+
+/// The message the device must have signed for the given `AuthResponse`.
+pub(crate) fn auth_challenge(nonce: &str, ephemeral_key: Option<&str>) -> anyhow::Result<Vec<u8>> {
+    Ok(match ephemeral_key {
+        Some(key) => {
+            let key = decode_ephemeral_key(key).context("decoding device ephemeral key")?;
+            device_auth_message(nonce, &key)
+        }
+        None => nonce.as_bytes().to_vec(),
+    })
+}
+
+/// Result of the host half of the key exchange.
+pub(crate) struct HostKeyExchange {
+    /// Host ephemeral key for `AuthSuccess`
+    pub ephemeral_key: String,
+    /// Host identity signature over the transcript for `AuthSuccess`
+    pub key_signature: String,
+    pub sealer: FrameSealer,
+    pub opener: FrameOpener,
+}
+
+/// Answer a device's key exchange. The device key must already have been
+/// verified against the trust store.
+pub(crate) fn accept_key_exchange(
+    nonce: &str,
+    device_key: &PublicKey,
+    device_ephemeral: &str,
+) -> anyhow::Result<HostKeyExchange> {
+    let device_ephemeral =
+        decode_ephemeral_key(device_ephemeral).context("decoding device ephemeral key")?;
+    let host_keypair = load_or_create_host_keypair()?;
+
+    let kx = KeyExchange::new();
+    let host_ephemeral = kx.public_key();
+    let transcript = Transcript::new(
+        nonce,
+        device_key,
+        &host_keypair.public_key(),
+        &device_ephemeral,
+        &host_ephemeral,
+    );
+    let key_signature = host_keypair.sign(transcript.as_bytes()).to_base64();
+    let (sealer, opener) = kx.finish(Role::Host, &device_ephemeral, &transcript)?;
+
+    Ok(HostKeyExchange {
+        ephemeral_key: encode_ephemeral_key(&host_ephemeral),
+        key_signature,
+        sealer,
+        opener,
+    })
+}
+
+/// Remove the seal from an incoming frame. Once keys have been agreed,
+/// plaintext frames are refused.
+pub(crate) fn unseal_frame(opener: &mut Option<FrameOpener>, frame: Frame) -> anyhow::Result<Frame> {
+    match (opener.as_mut(), frame.typ) {
+        (Some(opener), TYPE_SEALED) => Ok(opener.open(&frame.payload)?),
+        (Some(_), typ) => Err(anyhow!("plaintext frame type {typ} after key exchange")),
+        (None, TYPE_SEALED) => Err(anyhow!("sealed frame before key exchange")),
+        (None, _) => Ok(frame),
+    }
+}
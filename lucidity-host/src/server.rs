@@ -1,17 +1,23 @@
-use crate::bridge::{PaneBridge, PaneInfo};
-use crate::pairing_api::{current_pairing_payload, handle_pairing_submit, list_trusted_devices, pairing_payload_with_p2p};
+use crate::bridge::PaneBridge;
 use crate::p2p::P2PConnectivity;
-use crate::protocol::{TYPE_JSON, TYPE_PANE_INPUT, TYPE_PANE_OUTPUT};
-use anyhow::{anyhow, Context};
-use lucidity_proto::frame::{encode_frame, FrameDecoder};
-use serde::{Deserialize, Serialize};
-use std::io::{Read, Write};
-use std::net::{SocketAddr, TcpListener, TcpStream};
-use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use crate::protocol::TYPE_JSON;
+use crate::secure::RawFrameSink;
+use crate::session::{ChannelSource, SessionCore, TransportPolicy, INBOUND_CHUNKS};
+use anyhow::anyhow;
+use lucidity_pairing::ConnectionCandidate;
+use lucidity_proto::frame::encode_frame;
+use socket2::{Domain, Protocol, Socket, Type};
+use std::net::{SocketAddr, TcpListener};
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::{Arc, Mutex, OnceLock};
 use std::thread;
 use std::time::Duration;
-use uuid::Uuid;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::sync::mpsc;
+
+/// How long to wait before accepting again after a failed accept
+const ACCEPT_RETRY: Duration = Duration::from_millis(100);
 
 fn max_clients() -> usize {
     std::env::var("LUCIDITY_MAX_CLIENTS")
@@ -61,261 +67,157 @@ impl Default for HostConfig {
     }
 }
 
-pub use lucidity_proto::protocol::{JsonRequest, JsonResponse};
+use lucidity_proto::protocol::JsonResponse;
 
-fn write_json_frame(writer: &mut dyn Write, msg: &JsonResponse) -> anyhow::Result<()> {
-    let payload = serde_json::to_vec(msg)?;
-    let frame = encode_frame(TYPE_JSON, &payload);
-    writer.write_all(&frame)?;
-    writer.flush().ok();
-    Ok(())
-}
+/// Frames a client's outbound queue holds before writers have to wait
+const OUTBOUND_FRAMES: usize = 1024;
+/// Queued frames are merged into writes of up to this size
+const WRITE_BATCH_BYTES: usize = 64 * 1024;
+/// How long a finished session may spend flushing its last frames
+const FLUSH_TIMEOUT: Duration = Duration::from_secs(5);
 
-fn handle_client(stream: TcpStream, bridge: Arc<dyn PaneBridge>) -> anyhow::Result<()> {
-    stream.set_nodelay(true).ok();
-    stream.set_read_timeout(Some(Duration::from_secs(30))).ok();
-
-    let peer_addr = stream.peer_addr()?;
-    let mut reader = stream.try_clone()?;
-    let writer = Arc::new(Mutex::new(stream));
-
-    let attached = Arc::new(Mutex::new(None::<usize>));
-    let output_thread_dead = Arc::new(AtomicBool::new(false));
-
-    let mut decoder = FrameDecoder::new();
-    let mut buf = [0u8; 64 * 1024];
-
-    // Authentication handshake
-    let is_localhost = peer_addr.ip().is_loopback();
-    let mut authenticated = is_localhost;
-    let auth_nonce = if !authenticated {
-        let nonce = Uuid::new_v4().to_string();
-        let mut w = writer.lock().unwrap();
-        write_json_frame(
-            &mut *w,
-            &JsonResponse::AuthChallenge {
-                nonce: nonce.clone(),
-            },
-        )?;
-        Some(nonce)
-    } else {
-        None
-    };
+/// The sending side of a client's outbound queue. Writers block once it is
+/// full; output pumps look at `backlog` and hold back well before that.
+pub(crate) struct OutboundQueue {
+    tx: mpsc::Sender<Vec<u8>>,
+    queued: Arc<AtomicUsize>,
+}
 
-    loop {
-        let n = match reader.read(&mut buf) {
-            Ok(0) => break,
-            Ok(n) => n,
-            Err(err) => return Err(err).context("reading from client"),
+impl OutboundQueue {
+    /// A new queue, with its receiving end and the count of bytes queued,
+    /// which whoever drains it lowers as the bytes go out
+    pub(crate) fn new() -> (Self, mpsc::Receiver<Vec<u8>>, Arc<AtomicUsize>) {
+        let (tx, rx) = mpsc::channel(OUTBOUND_FRAMES);
+        let queued = Arc::new(AtomicUsize::new(0));
+        let queue = Self {
+            tx,
+            queued: Arc::clone(&queued),
         };
+        (queue, rx, queued)
+    }
+}
 
-        decoder.push(&buf[..n]);
-        while let Some(frame) = decoder.next_frame()? {
-            match frame.typ {
-                TYPE_JSON => {
-                    let req: JsonRequest = match serde_json::from_slice(&frame.payload) {
-                        Ok(r) => r,
-                        Err(err) => {
-                            let mut w = writer.lock().unwrap();
-                            write_json_frame(
-                                &mut *w,
-                                &JsonResponse::Error {
-                                    message: format!("invalid json request: {err}"),
-                                },
-                            )?;
-                            continue;
-                        }
-                    };
-
-                    match req {
-                        JsonRequest::AuthResponse {
-                            public_key,
-                            signature,
-                            client_nonce,
-                        } => {
-                            if let Some(nonce) = &auth_nonce {
-                                crate::pairing_api::verify_device_auth(
-                                    &public_key,
-                                    &signature,
-                                    nonce,
-                                )?;
-                                authenticated = true;
-
-                                // Register for push notifications
-                                let (push_tx, push_rx) = tokio::sync::mpsc::unbounded_channel();
-                                let writer_push = Arc::clone(&writer);
-                                let dead_push = Arc::clone(&output_thread_dead);
-                                thread::spawn(move || {
-                                    let mut rx = push_rx;
-                                    while !dead_push.load(Ordering::Relaxed) {
-                                        if let Some(msg) = rx.blocking_recv() {
-                                            let mut w = writer_push.lock().unwrap();
-                                            write_json_frame(&mut *w, &msg).ok();
-                                        } else {
-                                            break;
-                                        }
-                                    }
-                                });
-                                crate::registry::REGISTRY.register(public_key.clone(), push_tx);
-
-                                let host_sig = if let Some(cn) = client_nonce {
-                                    let keypair = crate::pairing_api::load_or_create_host_keypair()?;
-                                    Some(keypair.sign(cn.as_bytes()).to_base64())
-                                } else {
-                                    None
-                                };
-
-                                let mut w = writer.lock().unwrap();
-                                write_json_frame(
-                                    &mut *w,
-                                    &JsonResponse::AuthSuccess {
-                                        signature: host_sig,
-                                    },
-                                )?;
-                            }
-                        }
-                        _ if !authenticated => {
-                            let mut w = writer.lock().unwrap();
-                            write_json_frame(
-                                &mut *w,
-                                &JsonResponse::Error {
-                                    message: "authentication required".to_string(),
-                                },
-                            )?;
-                            return Err(anyhow!("authentication required"));
-                        }
-                        JsonRequest::ListPanes => {
-                            let panes = bridge.list_panes()?;
-                            let mut w = writer.lock().unwrap();
-                            write_json_frame(&mut *w, &JsonResponse::ListPanes { panes })?;
-                        }
-                        JsonRequest::Attach { pane_id } => {
-                            {
-                                let mut a = attached.lock().unwrap();
-                                if a.is_some() {
-                                    let mut w = writer.lock().unwrap();
-                                    write_json_frame(
-                                        &mut *w,
-                                        &JsonResponse::Error {
-                                            message: "already attached".to_string(),
-                                        },
-                                    )?;
-                                    continue;
-                                }
-                                *a = Some(pane_id);
-                            }
+impl RawFrameSink for OutboundQueue {
+    fn send_raw(&mut self, bytes: Vec<u8>) -> anyhow::Result<()> {
+        let len = bytes.len();
+        self.queued.fetch_add(len, Ordering::Relaxed);
+        self.tx.blocking_send(bytes).map_err(|_| {
+            self.queued.fetch_sub(len, Ordering::Relaxed);
+            anyhow!("client connection closed")
+        })
+    }
 
-                            let sub = bridge.subscribe_output(pane_id)?;
-                            let writer2 = Arc::clone(&writer);
-                            let dead2 = Arc::clone(&output_thread_dead);
-                            thread::spawn(move || {
-                                while !dead2.load(Ordering::Relaxed) {
-                                    let bytes = match sub.recv_timeout(Duration::from_millis(250)) {
-                                        Ok(Some(b)) => b,
-                                        Ok(None) => continue,
-                                        Err(_) => break,
-                                    };
-                                    let frame = encode_frame(TYPE_PANE_OUTPUT, &bytes);
-                                    let mut w = writer2.lock().unwrap();
-                                    if w.write_all(&frame).is_err() {
-                                        break;
-                                    }
-                                    w.flush().ok();
-                                }
-                            });
-
-                            let mut w = writer.lock().unwrap();
-                            write_json_frame(&mut *w, &JsonResponse::AttachOk { pane_id })?;
-                        }
-                        JsonRequest::PairingPayload => {
-                            // Try to get P2P connection info
-                            let (lan_addr, external_addr) = if let Some(p2p) = get_p2p() {
-                                if let Some(info) = p2p.lock().unwrap().get_external_info() {
-                                    (
-                                        Some(info.lan_addr().to_string()),
-                                        Some(info.socket_addr().to_string()),
-                                    )
-                                } else {
-                                    (None, None)
-                                }
-                            } else {
-                                (None, None)
-                            };
+    fn backlog(&self) -> usize {
+        self.queued.load(Ordering::Relaxed)
+    }
+}
 
-                            let payload = pairing_payload_with_p2p(lan_addr, external_addr)?;
-                            let mut w = writer.lock().unwrap();
-                            write_json_frame(&mut *w, &JsonResponse::PairingPayload { payload })?;
-                        }
-                        JsonRequest::PairingSubmit { request } => {
-                            let response = handle_pairing_submit(request)?;
-                            let mut w = writer.lock().unwrap();
-                            write_json_frame(&mut *w, &JsonResponse::PairingResponse { response })?;
-                        }
-                        JsonRequest::PairingListTrustedDevices => {
-                            let devices = list_trusted_devices()?;
-                            let mut w = writer.lock().unwrap();
-                            write_json_frame(
-                                &mut *w,
-                                &JsonResponse::PairingTrustedDevices { devices },
-                            )?;
-                        }
-                        JsonRequest::Paste { pane_id, text } => {
-                            bridge.send_paste(pane_id, &text)?;
-                        }
-                        JsonRequest::Resize { pane_id, rows, cols } => {
-                            bridge.resize(pane_id, rows, cols)?;
-                        }
-                        JsonRequest::RevokeDevice { public_key } => {
-                            crate::pairing_api::revoke_device(&public_key)?;
-                            // If we revoked our own key, we should disconnect
-                            // For now just success
-                            let mut w = writer.lock().unwrap();
-                            write_json_frame(&mut *w, &JsonResponse::Error {
-                                message: "device revoked".to_string(),
-                            })?;
-                        }
-                    }
-                }
-                TYPE_PANE_INPUT => {
-                    let pane_id = attached
-                        .lock()
-                        .unwrap()
-                        .ok_or_else(|| anyhow!("received input before attach"))?;
-                    bridge.send_input(pane_id, &frame.payload)?;
-                }
-                other => {
-                    let mut w = writer.lock().unwrap();
-                    write_json_frame(
-                        &mut *w,
-                        &JsonResponse::Error {
-                            message: format!("unsupported frame type: {other}"),
-                        },
-                    )?;
+/// Drain the outbound queue into the socket until every sender is gone
+async fn write_frames(
+    mut socket: impl AsyncWrite + Unpin,
+    mut rx: mpsc::Receiver<Vec<u8>>,
+    queued: Arc<AtomicUsize>,
+) {
+    while let Some(mut batch) = rx.recv().await {
+        while batch.len() < WRITE_BATCH_BYTES {
+            match rx.try_recv() {
+                Ok(more) => batch.extend_from_slice(&more),
+                Err(_) => break,
+            }
+        }
+        if socket.write_all(&batch).await.is_err() {
+            break;
+        }
+        queued.fetch_sub(batch.len(), Ordering::Relaxed);
+    }
+}
+
+/// Hand incoming bytes to the session until the client goes away
+async fn read_chunks(mut socket: impl AsyncRead + Unpin, tx: mpsc::Sender<Vec<u8>>) {
+    let mut buf = vec![0u8; 64 * 1024];
+    loop {
+        match socket.read(&mut buf).await {
+            Ok(0) | Err(_) => break,
+            Ok(n) => {
+                if tx.send(buf[..n].to_vec()).await.is_err() {
+                    break;
                 }
             }
         }
     }
+}
+
+async fn handle_client(stream: TcpStream, bridge: Arc<dyn PaneBridge>) -> anyhow::Result<()> {
+    stream.set_nodelay(true).ok();
+    let policy = TransportPolicy::direct(stream.peer_addr()?.ip());
+
+    let (read_half, write_half) = stream.into_split();
+    serve_stream(read_half, write_half, bridge, policy).await
+}
 
-    output_thread_dead.store(true, Ordering::Relaxed);
-    Ok(())
+/// Run a session over a byte stream until either side ends it
+pub(crate) async fn serve_stream(
+    read_half: impl AsyncRead + Unpin + Send + 'static,
+    write_half: impl AsyncWrite + Unpin + Send + 'static,
+    bridge: Arc<dyn PaneBridge>,
+    policy: TransportPolicy,
+) -> anyhow::Result<()> {
+    let (queue, out_rx, queued) = OutboundQueue::new();
+    let writer = tokio::spawn(write_frames(write_half, out_rx, queued));
+    let (in_tx, in_rx) = mpsc::channel(INBOUND_CHUNKS);
+    let reader = tokio::spawn(read_chunks(read_half, in_tx));
+
+    // Pane operations block, so the session runs off the async threads
+    let result = tokio::task::spawn_blocking(move || {
+        let mut core = SessionCore::new(bridge, queue, policy);
+        core.start()?;
+        core.run(&mut ChannelSource::new(in_rx))
+    })
+    .await?;
+
+    reader.abort();
+    // The writer ends once output pumps notice the session is gone
+    if tokio::time::timeout(FLUSH_TIMEOUT, writer).await.is_err() {
+        log::debug!("lucidity-host gave up flushing to a closed session");
+    }
+    result
 }
 
 pub fn serve_blocking(listener: TcpListener, bridge: Arc<dyn PaneBridge>) -> anyhow::Result<()> {
     serve_blocking_with_limit(listener, bridge, max_clients())
 }
 
+/// Serve clients from `listener` on a runtime of its own until accepting
+/// fails for good.
 pub fn serve_blocking_with_limit(
     listener: TcpListener,
     bridge: Arc<dyn PaneBridge>,
     max_clients: usize,
 ) -> anyhow::Result<()> {
+    listener.set_nonblocking(true)?;
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .thread_name("lucidity-host-io")
+        .build()?;
+    runtime.block_on(serve(listener, bridge, max_clients))
+}
+
+async fn serve(
+    listener: TcpListener,
+    bridge: Arc<dyn PaneBridge>,
+    max_clients: usize,
+) -> anyhow::Result<()> {
+    let listener = tokio::net::TcpListener::from_std(listener)?;
     let active_clients = Arc::new(AtomicUsize::new(0));
 
-    for conn in listener.incoming() {
-        let mut stream = match conn {
-            Ok(s) => s,
+    loop {
+        let (mut stream, peer) = match listener.accept().await {
+            Ok(conn) => conn,
             Err(err) => {
+                // Errors such as running out of file descriptors last a
+                // while, so don't spin on them
                 log::warn!("lucidity-host accept failed: {err:#}");
+                tokio::time::sleep(ACCEPT_RETRY).await;
                 continue;
             }
         };
@@ -324,31 +226,24 @@ pub fn serve_blocking_with_limit(
         let guard = match ActiveClientGuard::try_new(Arc::clone(&active_clients), max) {
             Some(g) => g,
             None => {
-                let peer = stream
-                    .peer_addr()
-                    .map(|p| p.to_string())
-                    .unwrap_or_else(|_| "<unknown>".to_string());
                 log::warn!("lucidity-host rejecting client {peer}: max clients ({max}) reached");
-                let _ = write_json_frame(
-                    &mut stream,
-                    &JsonResponse::Error {
-                        message: format!("server busy: max clients ({max}) reached"),
-                    },
-                );
+                let msg = JsonResponse::Error {
+                    message: format!("server busy: max clients ({max}) reached"),
+                };
+                let frame = encode_frame(TYPE_JSON, &serde_json::to_vec(&msg)?);
+                tokio::spawn(async move {
+                    stream.write_all(&frame).await.ok();
+                });
                 continue;
             }
         };
 
-        let peer = stream
-            .peer_addr()
-            .map(|p| p.to_string())
-            .unwrap_or_else(|_| "<unknown>".to_string());
         log::info!("lucidity-host client connected: {peer} (max {max})");
 
         let bridge = Arc::clone(&bridge);
-        thread::spawn(move || {
+        tokio::spawn(async move {
             let _guard = guard;
-            match handle_client(stream, bridge) {
+            match handle_client(stream, bridge).await {
                 Ok(()) => {
                     log::info!("lucidity-host client disconnected: {peer}");
                 }
@@ -358,16 +253,112 @@ pub fn serve_blocking_with_limit(
             }
         });
     }
-    Ok(())
 }
 
 static AUTOSTARTED: OnceLock<()> = OnceLock::new();
 static P2P_CONNECTIVITY: OnceLock<Arc<Mutex<P2PConnectivity>>> = OnceLock::new();
+static RELAY_ENABLED: OnceLock<tokio::sync::watch::Sender<bool>> = OnceLock::new();
+static MDNS: Mutex<Option<crate::mdns::Advertisement>> = Mutex::new(None);
+
+fn relay_enabled() -> &'static tokio::sync::watch::Sender<bool> {
+    RELAY_ENABLED.get_or_init(|| tokio::sync::watch::channel(true).0)
+}
+
+/// Connect to the relay, or disconnect from it. Takes effect at once if
+/// the host is running, and otherwise when it starts.
+pub fn set_relay_enabled(enabled: bool) {
+    relay_enabled().send_replace(enabled);
+}
+
+/// Keep the host connected to `LUCIDITY_RELAY_URL`, if set, for as long as
+/// the relay is enabled
+fn start_relay(bridge: Arc<dyn PaneBridge>) {
+    let Ok(relay_url) = std::env::var("LUCIDITY_RELAY_URL") else {
+        log::info!("LUCIDITY_RELAY_URL not set, relay disabled");
+        return;
+    };
+    // We need a keypair to derive relay_id
+    let keypair = match crate::pairing_api::load_or_create_host_keypair() {
+        Ok(keypair) => keypair,
+        Err(e) => {
+            log::error!("Cannot start relay: failed to load host keypair: {:#}", e);
+            return;
+        }
+    };
+    let relay_id = lucidity_pairing::PairingPayload::derive_relay_id(&keypair.public_key());
+    let mut relay_client = crate::relay_client::RelayClient::new(relay_url, relay_id);
+    relay_client.set_keypair(keypair);
+    relay_client.set_bridge(bridge);
+    let enabled = relay_enabled().subscribe();
+
+    thread::Builder::new()
+        .name("lucidity-relay".to_string())
+        .spawn(move || {
+            let rt = tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()
+                .expect("Failed to create relay runtime");
+            rt.block_on(relay_client.run(enabled));
+        })
+        .ok();
+}
+
+/// Advertise the host on the LAN, unless `LUCIDITY_DISABLE_MDNS` is set
+fn start_mdns(port: u16) {
+    if std::env::var("LUCIDITY_DISABLE_MDNS")
+        .map(|v| v == "1" || v.eq_ignore_ascii_case("true"))
+        .unwrap_or(false)
+    {
+        return;
+    }
+    let advertisement = crate::pairing_api::load_or_create_host_keypair()
+        .and_then(|keypair| crate::mdns::Advertisement::new(&keypair.public_key(), port));
+    match advertisement {
+        Ok(advertisement) => {
+            log::info!("Advertising {} on port {}", crate::mdns::SERVICE_TYPE, port);
+            *MDNS.lock().unwrap() = Some(advertisement);
+        }
+        Err(e) => log::warn!("LAN discovery unavailable: {:#}", e),
+    }
+}
 
 fn get_p2p() -> Option<Arc<Mutex<P2PConnectivity>>> {
     P2P_CONNECTIVITY.get().map(Arc::clone)
 }
 
+/// Remove the port mapping made by `autostart_in_process`, if any, and
+/// stop advertising the host. Call on exit, since the host's statics are
+/// never dropped.
+pub fn shutdown() {
+    MDNS.lock().unwrap().take();
+    if let Some(p2p) = get_p2p() {
+        p2p.lock().unwrap().cleanup();
+    }
+}
+
+/// The addresses to advertise in a pairing payload
+pub(crate) fn p2p_candidates() -> Vec<ConnectionCandidate> {
+    get_p2p()
+        .and_then(|p2p| p2p.lock().unwrap().get_external_info())
+        .map(|info| info.candidates())
+        .unwrap_or_default()
+}
+
+/// Bind `listen`. The IPv6 unspecified address accepts IPv4 as well,
+/// whatever the platform's default for such sockets.
+fn bind_listener(listen: SocketAddr) -> std::io::Result<TcpListener> {
+    if !(listen.is_ipv6() && listen.ip().is_unspecified()) {
+        return TcpListener::bind(listen);
+    }
+    let socket = Socket::new(Domain::IPV6, Type::STREAM, Some(Protocol::TCP))?;
+    socket.set_only_v6(false)?;
+    #[cfg(unix)]
+    socket.set_reuse_address(true)?;
+    socket.bind(&listen.into())?;
+    socket.listen(128)?;
+    Ok(socket.into())
+}
+
 pub fn autostart_in_process() {
     AUTOSTARTED.get_or_init(|| {
         if std::env::var("LUCIDITY_DISABLE_HOST")
@@ -391,17 +382,15 @@ pub fn autostart_in_process() {
             );
         }
 
-        // Start clipboard monitor
-        crate::clipboard::start_clipboard_monitor(|text| {
-            crate::registry::REGISTRY.broadcast(JsonResponse::ClipboardPush { text });
-        });
+        crate::clipboard::start_clipboard_forwarding();
+        crate::events::start_mux_events();
 
         // Create bridge shared between TCP server and Relay client
         let bridge: Arc<dyn PaneBridge> = Arc::new(crate::bridge::MuxPaneBridge::default());
         let bridge_for_server = bridge.clone();
         let bridge_for_relay = bridge.clone();
 
-        let listener = match TcpListener::bind(listen) {
+        let listener = match bind_listener(listen) {
             Ok(l) => l,
             Err(err) => {
                 log::error!("lucidity-host failed to bind {listen}: {err:#}");
@@ -411,71 +400,48 @@ pub fn autostart_in_process() {
 
         // Initialize P2P connectivity in background
         let local_port = listen.port();
+        let listen_ipv6 = listen.is_ipv6() && listen.ip().is_unspecified();
         thread::Builder::new()
             .name("lucidity-p2p-init".to_string())
             .spawn(move || {
                 let mut p2p = P2PConnectivity::new(local_port);
-                match p2p.initialize() {
-                    Ok(info) => {
-                        log::info!("P2P ready: LAN={}, External={}", info.lan_addr(), info.socket_addr());
-                        let p2p_arc = Arc::new(Mutex::new(p2p));
-                        let _ = P2P_CONNECTIVITY.set(p2p_arc.clone());
-
-                        // Spawn refresh thread
-                        thread::Builder::new()
-                            .name("lucidity-p2p-refresh".to_string())
-                            .spawn(move || {
-                                loop {
-                                    thread::sleep(Duration::from_secs(1800)); // 30 minutes
-                                    if let Err(e) = p2p_arc.lock().unwrap().refresh_mapping() {
-                                        log::warn!("Failed to refresh UPnP mapping: {}", e);
-                                    }
-                                }
-                            })
-                            .ok();
-                    }
-                    Err(e) => {
-                        log::warn!("P2P connectivity unavailable: {}. Attempting Relay fallback.", e);
-                        
-                        // Fallback to relay if configured
-                        if let Ok(relay_url) = std::env::var("LUCIDITY_RELAY_URL") {
-                            log::info!("Connecting to relay: {}", relay_url);
-                            
-                            // We need a keypair to derive relay_id
-                            if let Ok(keypair) = crate::pairing_api::load_or_create_host_keypair() {
-                                let pubkey_b64 = keypair.public_key().to_base64();
-                                let relay_id = pubkey_b64.chars().take(16).collect::<String>();
-                                
-                                let mut relay_client = crate::relay_client::RelayClient::new(relay_url, relay_id);
-                                relay_client.set_bridge(bridge_for_relay);
-                                
-                                // Spawn sync thread that starts a runtime for the relay client
-                                thread::Builder::new()
-                                    .name("lucidity-relay".to_string())
-                                    .spawn(move || {
-                                        let rt = tokio::runtime::Builder::new_current_thread()
-                                            .enable_all()
-                                            .build()
-                                            .expect("Failed to create relay runtime");
-                                            
-                                        rt.block_on(async {
-                                            if let Err(e) = relay_client.connect().await {
-                                                log::error!("Relay connection failed: {}", e);
-                                            }
-                                        });
-                                    })
-                                    .ok();
-                            } else {
-                                log::error!("Cannot start relay: failed to load host keypair");
+                p2p.set_ipv6(listen_ipv6);
+                p2p.initialize();
+                let p2p_arc = Arc::new(Mutex::new(p2p));
+                let _ = P2P_CONNECTIVITY.set(p2p_arc.clone());
+
+                // Spawn refresh thread
+                thread::Builder::new()
+                    .name("lucidity-p2p-refresh".to_string())
+                    .spawn(move || {
+                        loop {
+                            let interval = p2p_arc.lock().unwrap().refresh_interval();
+                            thread::sleep(interval);
+                            // The round trips take seconds, and pairing
+                            // payloads read the addresses meanwhile
+                            let refresh = p2p_arc.lock().unwrap().start_refresh();
+                            let Some(mut refresh) = refresh else {
+                                continue;
+                            };
+                            let result = refresh.run();
+                            p2p_arc.lock().unwrap().finish_refresh(refresh);
+                            if let Err(e) = result {
+                                log::warn!("Failed to refresh port mapping: {:#}", e);
                             }
-                        } else {
-                            log::info!("LUCIDITY_RELAY_URL not set, relay disabled");
                         }
-                    }
-                }
+                    })
+                    .ok();
             })
             .ok();
 
+        // Phones on the LAN can find us by identity if our address changes
+        if !listen.ip().is_loopback() {
+            start_mdns(listen.port());
+        }
+
+        // The relay runs alongside P2P, for phones that can't reach us directly
+        start_relay(bridge_for_relay);
+
         thread::Builder::new()
             .name("lucidity-host".to_string())
             .spawn(move || {
@@ -0,0 +1,1007 @@
+//! The per-connection state machine shared by every transport.
+//!
+//! A transport turns its input into frames through a `FrameSource` and
+//! supplies a `RawFrameSink` for replies. Authentication, the key exchange,
+//! attachments and request dispatch all live here, so a client sees the same
+//! protocol whether it reached the host over TCP or through the relay.
+
+use crate::attach::Attachments;
+use crate::bridge::{PaneBridge, PaneInfo};
+use crate::clipboard;
+use crate::events::EventFilter;
+use crate::pairing_api::{
+    handle_pairing_submit, handle_short_code_confirm, handle_short_code_hello,
+    list_trusted_devices, load_or_create_host_keypair, pairing_payload_with_p2p, revoke_device,
+    start_short_code, verify_device_auth,
+};
+use crate::paste::PasteGuard;
+use crate::protocol::{TYPE_JSON, TYPE_PANE_INPUT, TYPE_PANE_INPUT_ADDRESSED};
+use crate::registry::{Registration, PUSH_QUEUE, REGISTRY};
+use crate::resume::SessionHandle;
+use crate::secure::{
+    accept_key_exchange, auth_challenge, unseal_frame, FrameWriter, RawFrameSink, SealedWriter,
+};
+use anyhow::anyhow;
+use lucidity_pairing::DeviceScopes;
+use lucidity_proto::frame::{decode_pane_payload, Frame, FrameDecoder};
+use lucidity_proto::protocol::{JsonRequest, JsonResponse, SplitPaneRequest};
+use lucidity_proto::secure::FrameOpener;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::sync::mpsc;
+use uuid::Uuid;
+
+/// Chunks of incoming bytes a transport may queue for its session
+pub(crate) const INBOUND_CHUNKS: usize = 16;
+
+/// Where a session's incoming frames come from.
+pub(crate) trait FrameSource {
+    /// The next frame, or None once the peer has gone away.
+    fn next_frame(&mut self) -> anyhow::Result<Option<Frame>>;
+}
+
+/// Frames decoded from chunks of bytes handed over by the transport's async
+/// reader. The channel is bounded, so a session that falls behind stops the
+/// reader rather than buffering.
+pub(crate) struct ChannelSource {
+    rx: mpsc::Receiver<Vec<u8>>,
+    decoder: FrameDecoder,
+    finished: bool,
+}
+
+impl ChannelSource {
+    pub fn new(rx: mpsc::Receiver<Vec<u8>>) -> Self {
+        Self {
+            rx,
+            decoder: FrameDecoder::new(),
+            finished: false,
+        }
+    }
+
+    /// Whether the sending side has gone away
+    pub fn is_finished(&self) -> bool {
+        self.finished
+    }
+}
+
+impl FrameSource for ChannelSource {
+    fn next_frame(&mut self) -> anyhow::Result<Option<Frame>> {
+        loop {
+            if let Some(frame) = self.decoder.next_frame()? {
+                return Ok(Some(frame));
+            }
+            match self.rx.blocking_recv() {
+                Some(bytes) => self.decoder.push(&bytes),
+                None => {
+                    self.finished = true;
+                    return Ok(None);
+                }
+            }
+        }
+    }
+}
+
+/// What a transport knows about its peer.
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct TransportPolicy {
+    /// The peer is on this machine and is trusted without authenticating
+    pub trusted: bool,
+    /// Refuse authentication without a key exchange, because the transport
+    /// is visible to a third party
+    pub require_encryption: bool,
+}
+
+impl TransportPolicy {
+    /// A direct connection from `peer`. Loopback peers are trusted, and
+    /// anyone else must encrypt, since others on the network can read it.
+    pub fn direct(peer: IpAddr) -> Self {
+        let trusted = peer.is_loopback();
+        Self {
+            trusted,
+            require_encryption: !trusted,
+        }
+    }
+}
+
+/// Whether the session continues after a frame.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Flow {
+    Continue,
+    Close,
+}
+
+/// One client's session, from the first frame until it closes.
+pub(crate) struct SessionCore<S: RawFrameSink + 'static> {
+    bridge: Arc<dyn PaneBridge>,
+    writer: Arc<Mutex<SealedWriter<S>>>,
+    policy: TransportPolicy,
+    auth_nonce: Option<String>,
+    authenticated_key: Option<String>,
+    authenticated: bool,
+    /// What the authenticated device may do
+    scopes: DeviceScopes,
+    opener: Option<FrameOpener>,
+    attachments: Attachments,
+    resume: SessionHandle,
+    registration: Option<Registration>,
+    pastes: PasteGuard,
+    /// Set once the device has authenticated with a key exchange
+    secured: Option<Arc<AtomicBool>>,
+}
+
+impl<S: RawFrameSink + 'static> SessionCore<S> {
+    pub fn new(bridge: Arc<dyn PaneBridge>, sink: S, policy: TransportPolicy) -> Self {
+        let writer = Arc::new(Mutex::new(SealedWriter::new(sink)));
+        let mut core = Self {
+            bridge,
+            resume: SessionHandle::new(writer.clone()),
+            writer,
+            policy,
+            auth_nonce: None,
+            authenticated_key: None,
+            authenticated: policy.trusted,
+            scopes: if policy.trusted {
+                DeviceScopes::full()
+            } else {
+                DeviceScopes::view_only()
+            },
+            opener: None,
+            attachments: Attachments::new(),
+            registration: None,
+            pastes: PasteGuard::default(),
+            secured: None,
+        };
+        if core.authenticated {
+            core.registration = Some(core.register_for_push());
+        }
+        core
+    }
+
+    /// Have `flag` set once the device has authenticated and the key
+    /// exchange is done, for a transport that lets only such devices do
+    /// more than talk to the session
+    pub fn report_secured(&mut self, flag: Arc<AtomicBool>) {
+        self.secured = Some(flag);
+    }
+
+    /// Greet a peer that has just connected. Transports that cannot tell
+    /// when that happens skip this, and the peer is challenged on its
+    /// first request instead.
+    pub fn start(&mut self) -> anyhow::Result<()> {
+        if !self.authenticated {
+            self.send_challenge()?;
+        }
+        Ok(())
+    }
+
+    /// Handle frames from `source` until it ends or the session closes.
+    pub fn run(&mut self, source: &mut dyn FrameSource) -> anyhow::Result<()> {
+        while let Some(frame) = source.next_frame()? {
+            if self.handle_frame(frame)? == Flow::Close {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    /// Handle one frame. Errors mean the connection is unusable; a request
+    /// that fails is answered with `error` instead.
+    pub fn handle_frame(&mut self, frame: Frame) -> anyhow::Result<Flow> {
+        let frame = unseal_frame(&mut self.opener, frame)?;
+        match frame.typ {
+            TYPE_JSON => {
+                let req: JsonRequest = match serde_json::from_slice(&frame.payload) {
+                    Ok(req) => req,
+                    Err(err) => {
+                        self.reply_error(format!("invalid json request: {err}"))?;
+                        return Ok(Flow::Continue);
+                    }
+                };
+                self.handle_request(req)
+            }
+            TYPE_PANE_INPUT | TYPE_PANE_INPUT_ADDRESSED if !self.authenticated => {
+                self.require_auth()?;
+                Ok(Flow::Continue)
+            }
+            TYPE_PANE_INPUT | TYPE_PANE_INPUT_ADDRESSED if !self.scopes.input => {
+                self.reply_error("this device may not send input")?;
+                Ok(Flow::Continue)
+            }
+            TYPE_PANE_INPUT => {
+                match self.attachments.input_target() {
+                    Some(pane_id) => {
+                        self.reply_on_error(self.bridge.send_input(pane_id, &frame.payload))?
+                    }
+                    None => self.reply_error("received input before attach")?,
+                }
+                Ok(Flow::Continue)
+            }
+            TYPE_PANE_INPUT_ADDRESSED => {
+                let (pane_id, input) = decode_pane_payload(&frame.payload)?;
+                let pane_id = pane_id as usize;
+                if self.attachments.accepts_addressed_input(pane_id) {
+                    self.reply_on_error(self.bridge.send_input(pane_id, input))?;
+                } else {
+                    self.reply_error(format!("not attached to pane {pane_id}"))?;
+                }
+                Ok(Flow::Continue)
+            }
+            other => {
+                self.reply_error(format!("unsupported frame type: {other}"))?;
+                Ok(Flow::Continue)
+            }
+        }
+    }
+
+    fn handle_request(&mut self, req: JsonRequest) -> anyhow::Result<Flow> {
+        if self.authenticated {
+            if let Err(err) = self.authorize(&req) {
+                self.reply_error(format!("{err:#}"))?;
+                return Ok(Flow::Continue);
+            }
+        }
+        match req {
+            JsonRequest::AuthResponse {
+                public_key,
+                signature,
+                client_nonce,
+                ephemeral_key,
+            } => {
+                if let Err(err) =
+                    self.authenticate(public_key, signature, client_nonce, ephemeral_key)
+                {
+                    self.reply_error(format!("authentication failed: {err:#}"))?;
+                    return Ok(Flow::Close);
+                }
+            }
+            // A device that is not yet trusted has nothing to authenticate
+            // with; the pairing approver decides instead.
+            JsonRequest::PairingSubmit { request } => {
+                let resp = handle_pairing_submit(request)
+                    .map(|response| JsonResponse::PairingResponse { response });
+                self.reply_result(resp)?;
+            }
+            JsonRequest::PairingShortCodeHello { hello } => {
+                let resp = handle_short_code_hello(&hello)
+                    .map(|challenge| JsonResponse::PairingShortCodeChallenge { challenge });
+                self.reply_result(resp)?;
+            }
+            JsonRequest::PairingShortCodeConfirm { confirm } => {
+                let resp = handle_short_code_confirm(&confirm)
+                    .map(|response| JsonResponse::PairingResponse { response });
+                self.reply_result(resp)?;
+            }
+            _ if !self.authenticated => self.require_auth()?,
+            JsonRequest::ListPanes => {
+                let resp = self
+                    .bridge
+                    .list_panes()
+                    .map(|panes| JsonResponse::ListPanes {
+                        panes: panes.into_iter().filter(|p| self.can_see(p)).collect(),
+                    });
+                self.reply_result(resp)?;
+            }
+            JsonRequest::Attach {
+                pane_id,
+                scrollback,
+                mode,
+                addressed,
+            } => {
+                let attached = self.attachments.attach(
+                    &self.bridge,
+                    &self.resume,
+                    pane_id,
+                    scrollback,
+                    mode,
+                    addressed,
+                );
+                self.reply_on_error(attached)?;
+            }
+            JsonRequest::Detach { pane_id } => {
+                if self.attachments.detach(&self.resume, pane_id) {
+                    self.reply(&JsonResponse::DetachOk { pane_id })?;
+                } else {
+                    self.reply_error(format!("not attached to pane {pane_id}"))?;
+                }
+            }
+            JsonRequest::Resume { token, last_seq } => {
+                let resumed = self.resume.resume(
+                    &token,
+                    self.authenticated_key.as_deref().unwrap_or_default(),
+                    last_seq,
+                    &*self.bridge,
+                    crate::snapshot::scrollback_rows(None),
+                );
+                match resumed {
+                    Ok(panes) => self.attachments.resumed(panes),
+                    Err(err) => self.reply_error(format!("{err:#}"))?,
+                }
+            }
+            JsonRequest::ScreenAck { pane_id, seqno } => {
+                self.attachments.ack(pane_id, seqno);
+            }
+            JsonRequest::PairingPayload => {
+                let candidates = crate::server::p2p_candidates();
+                let resp = pairing_payload_with_p2p(candidates)
+                    .map(|payload| JsonResponse::PairingPayload { payload });
+                self.reply_result(resp)?;
+            }
+            JsonRequest::PairingShortCode => {
+                let resp = start_short_code().map(|code| JsonResponse::PairingShortCode {
+                    code: code.to_string(),
+                });
+                self.reply_result(resp)?;
+            }
+            JsonRequest::PairingListTrustedDevices => {
+                let resp = list_trusted_devices()
+                    .map(|devices| JsonResponse::PairingTrustedDevices { devices });
+                self.reply_result(resp)?;
+            }
+            JsonRequest::Paste { pane_id, text } => {
+                match self.pastes.paste(&*self.bridge, pane_id, &text) {
+                    Ok(None) => {}
+                    Ok(Some(resp)) => self.reply(&resp)?,
+                    Err(err) => self.reply_error(format!("{err:#}"))?,
+                }
+            }
+            JsonRequest::ConfirmPaste { paste_id } => {
+                let result = self.pastes.confirm(&*self.bridge, &paste_id);
+                self.reply_on_error(result)?;
+            }
+            JsonRequest::CancelPaste { paste_id } => {
+                self.pastes.cancel(&paste_id);
+            }
+            JsonRequest::Resize {
+                pane_id,
+                rows,
+                cols,
+            } => {
+                self.reply_on_error(self.bridge.resize(pane_id, rows, cols))?;
+            }
+            JsonRequest::KeyEvent(req) => {
+                self.reply_on_error(self.bridge.key_event(req))?;
+            }
+            JsonRequest::MouseEvent(req) => {
+                self.reply_on_error(self.bridge.mouse_event(req))?;
+            }
+            JsonRequest::SubscribeEvents { events, panes } => {
+                let panes = match self.event_panes(panes) {
+                    Ok(panes) => panes,
+                    Err(err) => {
+                        self.reply_error(format!("{err:#}"))?;
+                        return Ok(Flow::Continue);
+                    }
+                };
+                if let Some(registration) = &self.registration {
+                    registration.set_event_filter(Some(EventFilter {
+                        events,
+                        panes,
+                        pane_events_only: self.scopes.restricts_panes(),
+                    }));
+                }
+                self.reply(&JsonResponse::Ok)?;
+            }
+            JsonRequest::UnsubscribeEvents => {
+                if let Some(registration) = &self.registration {
+                    registration.set_event_filter(None);
+                }
+                self.reply(&JsonResponse::Ok)?;
+            }
+            JsonRequest::SubscribeClipboard { max_bytes } => {
+                if let Some(registration) = &self.registration {
+                    registration
+                        .set_clipboard_limit(Some(clipboard::subscription_limit(max_bytes)));
+                }
+                self.reply(&JsonResponse::Ok)?;
+            }
+            JsonRequest::UnsubscribeClipboard => {
+                if let Some(registration) = &self.registration {
+                    registration.set_clipboard_limit(None);
+                }
+                self.reply(&JsonResponse::Ok)?;
+            }
+            JsonRequest::SetClipboard { text } => {
+                let resp = clipboard::set_host_clipboard(text).map(|()| JsonResponse::Ok);
+                self.reply_result(resp)?;
+            }
+            req @ (JsonRequest::Spawn(_)
+            | JsonRequest::SplitPane(_)
+            | JsonRequest::KillPane { .. }
+            | JsonRequest::ActivateTab(_)
+            | JsonRequest::ActivatePane { .. }
+            | JsonRequest::ZoomPane { .. }
+            | JsonRequest::RenameWorkspace { .. }) => {
+                let resp = crate::mux_ops::handle_request(&*self.bridge, req);
+                self.reply(&resp)?;
+            }
+            JsonRequest::RevokeDevice { public_key } => {
+                if let Err(err) = revoke_device(&public_key) {
+                    self.reply_error(format!("{err:#}"))?;
+                    return Ok(Flow::Continue);
+                }
+                self.reply(&JsonResponse::Ok)?;
+                // A device that revokes itself is no longer trusted to
+                // keep this session
+                if self.authenticated_key.as_deref() == Some(public_key.as_str()) {
+                    return Ok(Flow::Close);
+                }
+            }
+        }
+        Ok(Flow::Continue)
+    }
+
+    fn authenticate(
+        &mut self,
+        public_key: String,
+        signature: String,
+        client_nonce: Option<String>,
+        ephemeral_key: Option<String>,
+    ) -> anyhow::Result<()> {
+        if self.authenticated {
+            return Err(anyhow!("already authenticated"));
+        }
+        let nonce = self
+            .auth_nonce
+            .take()
+            .ok_or_else(|| anyhow!("no challenge outstanding"))?;
+        if ephemeral_key.is_none() && self.policy.require_encryption {
+            return Err(anyhow!("this transport requires end-to-end encryption"));
+        }
+
+        let challenge = auth_challenge(&nonce, ephemeral_key.as_deref())?;
+        let device = verify_device_auth(&public_key, &signature, &challenge)?;
+        let kx = ephemeral_key
+            .as_deref()
+            .map(|e| accept_key_exchange(&nonce, &device.public_key, e))
+            .transpose()?;
+        let host_sig = client_nonce
+            .map(|cn| {
+                anyhow::Ok(
+                    load_or_create_host_keypair()?
+                        .sign(cn.as_bytes())
+                        .to_base64(),
+                )
+            })
+            .transpose()?;
+        let session_token = self.resume.session().issue_token(&public_key);
+
+        // AuthSuccess carries the host half of the key exchange, so it is
+        // the last plaintext frame. The token follows it sealed.
+        {
+            let mut w = self.writer.lock().unwrap();
+            w.write_json(&JsonResponse::AuthSuccess {
+                signature: host_sig,
+                ephemeral_key: kx.as_ref().map(|kx| kx.ephemeral_key.clone()),
+                key_signature: kx.as_ref().map(|kx| kx.key_signature.clone()),
+            })?;
+            if let Some(kx) = kx {
+                w.enable_sealing(kx.sealer);
+                self.opener = Some(kx.opener);
+            }
+            w.write_json(&JsonResponse::SessionToken {
+                token: session_token,
+            })?;
+        }
+        self.authenticated = true;
+        self.authenticated_key = Some(public_key);
+        self.scopes = device.scopes;
+        self.registration = Some(self.register_for_push());
+        if let Some(secured) = self.secured.as_ref().filter(|_| self.opener.is_some()) {
+            secured.store(true, Ordering::Release);
+        }
+        Ok(())
+    }
+
+    /// Refuse a request that the device's scopes don't cover
+    fn authorize(&self, req: &JsonRequest) -> anyhow::Result<()> {
+        let require = |granted: bool, what: &str| {
+            if granted {
+                Ok(())
+            } else {
+                Err(anyhow!("this device may not {what}"))
+            }
+        };
+        let admin = |what: &str| require(self.scopes.admin, what);
+        match req {
+            JsonRequest::Attach { pane_id, .. } => self.check_pane(*pane_id),
+            JsonRequest::Paste { pane_id, .. } | JsonRequest::Resize { pane_id, .. } => {
+                self.check_input(*pane_id)
+            }
+            JsonRequest::KeyEvent(req) => self.check_input(req.pane_id),
+            JsonRequest::MouseEvent(req) => self.check_input(req.pane_id),
+            JsonRequest::ConfirmPaste { .. } => require(self.scopes.input, "send input"),
+            JsonRequest::SubscribeClipboard { .. } | JsonRequest::SetClipboard { .. } => {
+                require(self.scopes.clipboard, "use the clipboard")
+            }
+            JsonRequest::PairingPayload | JsonRequest::PairingShortCode => {
+                admin("pair other devices")
+            }
+            JsonRequest::PairingListTrustedDevices => admin("list devices"),
+            // A device may always forget itself
+            JsonRequest::RevokeDevice { public_key }
+                if self.authenticated_key.as_deref() == Some(public_key.as_str()) =>
+            {
+                Ok(())
+            }
+            JsonRequest::RevokeDevice { .. } => admin("revoke devices"),
+            JsonRequest::Spawn(_)
+            | JsonRequest::ActivateTab(_)
+            | JsonRequest::RenameWorkspace { .. } => admin("manage panes"),
+            JsonRequest::SplitPane(SplitPaneRequest { pane_id, .. })
+            | JsonRequest::KillPane { pane_id }
+            | JsonRequest::ActivatePane { pane_id }
+            | JsonRequest::ZoomPane { pane_id, .. } => {
+                admin("manage panes")?;
+                self.check_pane(*pane_id)
+            }
+            JsonRequest::ListPanes
+            | JsonRequest::Detach { .. }
+            | JsonRequest::Resume { .. }
+            | JsonRequest::ScreenAck { .. }
+            | JsonRequest::CancelPaste { .. }
+            | JsonRequest::SubscribeEvents { .. }
+            | JsonRequest::UnsubscribeEvents
+            | JsonRequest::UnsubscribeClipboard
+            | JsonRequest::PairingSubmit { .. }
+            | JsonRequest::PairingShortCodeHello { .. }
+            | JsonRequest::PairingShortCodeConfirm { .. }
+            | JsonRequest::AuthResponse { .. } => Ok(()),
+        }
+    }
+
+    fn can_see(&self, pane: &PaneInfo) -> bool {
+        self.scopes.allows_pane(
+            pane.pane_id,
+            pane.domain.as_deref(),
+            pane.workspace.as_deref(),
+        )
+    }
+
+    /// Refuse a pane the device may not see
+    fn check_pane(&self, pane_id: usize) -> anyhow::Result<()> {
+        if !self.scopes.restricts_panes() {
+            return Ok(());
+        }
+        let panes = self.bridge.list_panes()?;
+        if panes
+            .iter()
+            .any(|p| p.pane_id == pane_id && self.can_see(p))
+        {
+            Ok(())
+        } else {
+            Err(anyhow!("this device may not use pane {pane_id}"))
+        }
+    }
+
+    fn check_input(&self, pane_id: usize) -> anyhow::Result<()> {
+        if !self.scopes.input {
+            return Err(anyhow!("this device may not send input"));
+        }
+        self.check_pane(pane_id)
+    }
+
+    /// The panes to send events about, of `requested`, limited to those
+    /// the device may see now
+    fn event_panes(&self, requested: Vec<usize>) -> anyhow::Result<Vec<usize>> {
+        if !self.scopes.restricts_panes() {
+            return Ok(requested);
+        }
+        let visible: Vec<usize> = self
+            .bridge
+            .list_panes()?
+            .iter()
+            .filter(|p| self.can_see(p))
+            .map(|p| p.pane_id)
+            .filter(|pane_id| requested.is_empty() || requested.contains(pane_id))
+            .collect();
+        if visible.is_empty() {
+            return Err(anyhow!("this device may not see any of those panes"));
+        }
+        Ok(visible)
+    }
+
+    fn send_challenge(&mut self) -> anyhow::Result<()> {
+        let nonce = Uuid::new_v4().to_string();
+        self.auth_nonce = Some(nonce.clone());
+        self.reply(&JsonResponse::AuthChallenge { nonce })
+    }
+
+    /// Answer a request made before authenticating with a fresh challenge
+    fn require_auth(&mut self) -> anyhow::Result<()> {
+        self.send_challenge()?;
+        self.reply_error("authentication required")
+    }
+
+    /// Forward pushed messages (clipboard, mux events) to this session until
+    /// the registration is dropped
+    fn register_for_push(&self) -> Registration {
+        let (push_tx, mut push_rx) = tokio::sync::mpsc::channel(PUSH_QUEUE);
+        let writer = Arc::clone(&self.writer);
+        std::thread::spawn(move || {
+            while let Some(msg) = push_rx.blocking_recv() {
+                if writer.lock().unwrap().write_json(&msg).is_err() {
+                    break;
+                }
+            }
+        });
+        REGISTRY.register(push_tx)
+    }
+
+    fn reply(&self, msg: &JsonResponse) -> anyhow::Result<()> {
+        self.writer.lock().unwrap().write_json(msg)
+    }
+
+    fn reply_error(&self, message: impl Into<String>) -> anyhow::Result<()> {
+        self.reply(&JsonResponse::Error {
+            message: message.into(),
+        })
+    }
+
+    fn reply_result(&self, resp: anyhow::Result<JsonResponse>) -> anyhow::Result<()> {
+        match resp {
+            Ok(resp) => self.reply(&resp),
+            Err(err) => self.reply_error(format!("{err:#}")),
+        }
+    }
+
+    /// Only failures are answered; success is silent or already answered
+    fn reply_on_error(&self, result: anyhow::Result<()>) -> anyhow::Result<()> {
+        match result {
+            Ok(()) => Ok(()),
+            Err(err) => self.reply_error(format!("{err:#}")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bridge::{FakePaneBridge, PaneInfo};
+    use crate::pairing_api::{
+        invalidate_pairing_tokens, lock_pairing_state, set_pairing_approver, short_code_state,
+        PairingApproval, PairingApprover, ShortCodeState,
+    };
+    use lucidity_pairing::{Keypair, PairingRequest, ShortCode, ShortCodeClient};
+    use lucidity_proto::frame::encode_frame;
+    use lucidity_proto::protocol::{Key, KeyAction, MouseEventKind};
+
+    /// Collects everything the session writes
+    #[derive(Clone, Default)]
+    struct Capture(Arc<Mutex<Vec<u8>>>);
+
+    impl RawFrameSink for Capture {
+        fn send_raw(&mut self, bytes: Vec<u8>) -> anyhow::Result<()> {
+            self.0.lock().unwrap().extend_from_slice(&bytes);
+            Ok(())
+        }
+    }
+
+    impl Capture {
+        fn messages(&self) -> Vec<serde_json::Value> {
+            let mut decoder = FrameDecoder::new();
+            decoder.push(&std::mem::take(&mut *self.0.lock().unwrap()));
+            let mut messages = vec![];
+            while let Some(frame) = decoder.next_frame().unwrap() {
+                messages.push(serde_json::from_slice(&frame.payload).unwrap());
+            }
+            messages
+        }
+
+        fn ops(&self) -> Vec<String> {
+            self.messages()
+                .iter()
+                .map(|v| v["op"].as_str().unwrap().to_string())
+                .collect()
+        }
+    }
+
+    fn session(policy: TransportPolicy) -> (SessionCore<Capture>, Capture) {
+        let bridge = Arc::new(FakePaneBridge::new(vec![PaneInfo {
+            pane_id: 1,
+            title: "bash".to_string(),
+            domain: None,
+            workspace: None,
+        }]));
+        let capture = Capture::default();
+        (SessionCore::new(bridge, capture.clone(), policy), capture)
+    }
+
+    fn json(value: serde_json::Value) -> Frame {
+        let bytes = encode_frame(TYPE_JSON, &serde_json::to_vec(&value).unwrap());
+        let mut decoder = FrameDecoder::new();
+        decoder.push(&bytes);
+        decoder.next_frame().unwrap().unwrap()
+    }
+
+    #[test]
+    fn untrusted_peer_is_challenged_and_kept() {
+        let (mut core, out) = session(TransportPolicy::default());
+        let flow = core
+            .handle_frame(json(serde_json::json!({"op": "pairing_payload"})))
+            .unwrap();
+        assert_eq!(flow, Flow::Continue);
+        assert_eq!(out.ops(), vec!["auth_challenge", "error"]);
+
+        let input = Frame {
+            typ: TYPE_PANE_INPUT,
+            payload: b"ls\r".to_vec(),
+        };
+        assert_eq!(core.handle_frame(input).unwrap(), Flow::Continue);
+        assert_eq!(out.ops(), vec!["auth_challenge", "error"]);
+    }
+
+    #[test]
+    fn bad_auth_response_closes() {
+        let (mut core, out) = session(TransportPolicy {
+            trusted: false,
+            require_encryption: true,
+        });
+        core.start().unwrap();
+        let flow = core
+            .handle_frame(json(serde_json::json!({
+                "op": "auth_response",
+                "public_key": "x",
+                "signature": "y",
+            })))
+            .unwrap();
+        assert_eq!(flow, Flow::Close);
+        assert_eq!(out.ops(), vec!["auth_challenge", "error"]);
+    }
+
+    #[test]
+    fn lan_peer_must_encrypt() {
+        let policy = TransportPolicy::direct("192.168.1.20".parse().unwrap());
+        assert!(!policy.trusted);
+        let (mut core, out) = session(policy);
+        core.start().unwrap();
+        let flow = core
+            .handle_frame(json(serde_json::json!({
+                "op": "auth_response",
+                "public_key": "x",
+                "signature": "y",
+            })))
+            .unwrap();
+        assert_eq!(flow, Flow::Close);
+        let messages = out.messages();
+        assert_eq!(messages[1]["op"], "error");
+        assert!(messages[1]["message"]
+            .as_str()
+            .unwrap()
+            .contains("requires end-to-end encryption"));
+        assert!(!core.authenticated);
+
+        let local = TransportPolicy::direct("::1".parse().unwrap());
+        assert!(local.trusted && !local.require_encryption);
+    }
+
+    #[test]
+    fn trusted_peer_gets_errors_instead_of_disconnects() {
+        let (mut core, out) = session(TransportPolicy {
+            trusted: true,
+            require_encryption: false,
+        });
+        core.start().unwrap();
+        core.handle_frame(json(serde_json::json!({"op": "list_panes"})))
+            .unwrap();
+        let input = Frame {
+            typ: TYPE_PANE_INPUT,
+            payload: b"ls\r".to_vec(),
+        };
+        assert_eq!(core.handle_frame(input).unwrap(), Flow::Continue);
+        core.handle_frame(json(serde_json::json!({"op": "detach", "pane_id": 1})))
+            .unwrap();
+        assert_eq!(out.ops(), vec!["list_panes", "error", "error"]);
+    }
+
+    #[test]
+    fn key_and_mouse_events_reach_the_pane() {
+        let bridge = Arc::new(FakePaneBridge::new(vec![]));
+        let out = Capture::default();
+        let mut core = SessionCore::new(
+            bridge.clone(),
+            out.clone(),
+            TransportPolicy {
+                trusted: true,
+                require_encryption: false,
+            },
+        );
+        core.handle_frame(json(serde_json::json!({
+            "op": "key_event",
+            "pane_id": 1,
+            "key": {"char": "c"},
+            "mods": {"ctrl": true},
+        })))
+        .unwrap();
+        core.handle_frame(json(serde_json::json!({
+            "op": "mouse_event",
+            "pane_id": 1,
+            "kind": "click",
+            "button": "left",
+            "x": 3,
+            "y": 7,
+        })))
+        .unwrap();
+        assert_eq!(out.ops(), Vec::<String>::new());
+
+        let keys = bridge.take_key_events();
+        assert_eq!(keys.len(), 1);
+        assert_eq!(keys[0].key, Key::Char('c'));
+        assert!(keys[0].mods.ctrl);
+        assert_eq!(keys[0].action, KeyAction::Tap);
+        let clicks = bridge.take_mouse_events();
+        assert_eq!(clicks.len(), 1);
+        assert_eq!(
+            (clicks[0].kind, clicks[0].x, clicks[0].y),
+            (MouseEventKind::Click, 3, 7)
+        );
+    }
+
+    #[test]
+    fn scopes_limit_what_a_device_may_do() {
+        let pane = |pane_id, workspace: &str| PaneInfo {
+            pane_id,
+            title: "bash".to_string(),
+            domain: Some("local".to_string()),
+            workspace: Some(workspace.to_string()),
+        };
+        let bridge = Arc::new(FakePaneBridge::new(vec![
+            pane(1, "build"),
+            pane(2, "default"),
+        ]));
+        let out = Capture::default();
+        let mut core = SessionCore::new(
+            bridge.clone(),
+            out.clone(),
+            TransportPolicy {
+                trusted: true,
+                require_encryption: false,
+            },
+        );
+        core.scopes = DeviceScopes {
+            workspaces: vec!["build".to_string()],
+            ..DeviceScopes::view_only()
+        };
+
+        core.handle_frame(json(serde_json::json!({"op": "list_panes"})))
+            .unwrap();
+        let listed = out.messages();
+        assert_eq!(listed[0]["panes"].as_array().unwrap().len(), 1);
+        assert_eq!(listed[0]["panes"][0]["pane_id"], 1);
+
+        for request in [
+            serde_json::json!({"op": "key_event", "pane_id": 1, "key": {"char": "c"}}),
+            serde_json::json!({"op": "paste", "pane_id": 1, "text": "ls"}),
+            serde_json::json!({"op": "attach", "pane_id": 2}),
+            serde_json::json!({"op": "set_clipboard", "text": "secret"}),
+            serde_json::json!({"op": "kill_pane", "pane_id": 1}),
+            serde_json::json!({"op": "pairing_list_trusted_devices"}),
+            serde_json::json!({"op": "pairing_short_code"}),
+            serde_json::json!({"op": "revoke_device", "public_key": "someone else"}),
+        ] {
+            core.handle_frame(json(request)).unwrap();
+            assert_eq!(out.ops(), vec!["error"]);
+        }
+        let input = Frame {
+            typ: TYPE_PANE_INPUT_ADDRESSED,
+            payload: b"\0\0\0\x01ls\r".to_vec(),
+        };
+        assert_eq!(core.handle_frame(input).unwrap(), Flow::Continue);
+        assert_eq!(out.ops(), vec!["error"]);
+        assert!(bridge.take_key_events().is_empty());
+        assert!(bridge.take_pastes().is_empty());
+        assert!(bridge.take_inputs().is_empty());
+        assert_eq!(bridge.list_panes().unwrap().len(), 2);
+    }
+
+    /// Lets every device pair, to watch only
+    struct ViewOnlyApprover;
+
+    impl PairingApprover for ViewOnlyApprover {
+        fn approve_pairing(&self, _request: &PairingRequest) -> anyhow::Result<PairingApproval> {
+            Ok(PairingApproval::approved_with(DeviceScopes::view_only()))
+        }
+    }
+
+    /// Send a phone's hello for `code`, returning the phone and the reply
+    fn short_code_hello(
+        core: &mut SessionCore<Capture>,
+        out: &Capture,
+        code: &ShortCode,
+    ) -> (ShortCodeClient, serde_json::Value) {
+        let (client, hello) = ShortCodeClient::start(code).unwrap();
+        core.handle_frame(json(
+            serde_json::json!({"op": "pairing_short_code_hello", "hello": hello}),
+        ))
+        .unwrap();
+        (client, out.messages().remove(0))
+    }
+
+    #[test]
+    fn short_code_pairing_goes_through_approval() {
+        let _state = lock_pairing_state();
+        let dir = tempfile::tempdir().unwrap();
+        std::env::set_var(
+            "LUCIDITY_HOST_KEYPAIR",
+            dir.path().join("host_keypair.json"),
+        );
+        std::env::set_var("LUCIDITY_DEVICE_TRUST_DB", dir.path().join("devices.db"));
+        set_pairing_approver(Some(Arc::new(ViewOnlyApprover)));
+        let (mut core, out) = session(TransportPolicy {
+            trusted: false,
+            require_encryption: false,
+        });
+        let mobile = Keypair::generate();
+
+        // Nothing to pair with until the host shows a code
+        invalidate_pairing_tokens();
+        let (_, reply) = short_code_hello(&mut core, &out, &ShortCode::generate());
+        assert_eq!(reply["op"], "error");
+
+        // The host gives each code a few guesses
+        let code = start_short_code().unwrap();
+        let wrong = ShortCode::parse(if code.to_string() == "000 000" {
+            "000001"
+        } else {
+            "000000"
+        })
+        .unwrap();
+        for left in (1..=lucidity_pairing::MAX_ATTEMPTS).rev() {
+            assert_eq!(
+                short_code_state(),
+                ShortCodeState::Live {
+                    attempts_left: left
+                }
+            );
+            let (client, reply) = short_code_hello(&mut core, &out, &wrong);
+            assert_eq!(reply["op"], "pairing_short_code_challenge");
+            let challenge = serde_json::from_value(reply["challenge"].clone()).unwrap();
+            assert!(client
+                .confirm(&challenge, &mobile, String::new(), String::new())
+                .is_err());
+        }
+        let (_, reply) = short_code_hello(&mut core, &out, &code);
+        assert_eq!(reply["op"], "error");
+        // So the pairing screen can warn that someone may be guessing
+        assert_eq!(short_code_state(), ShortCodeState::UsedUp);
+
+        // The right code reaches the approver, whose scopes the device gets
+        let code = start_short_code().unwrap();
+        let (client, reply) = short_code_hello(&mut core, &out, &code);
+        let challenge = serde_json::from_value(reply["challenge"].clone()).unwrap();
+        let (desktop_key, confirm) = client
+            .confirm(
+                &challenge,
+                &mobile,
+                "user@example.com".to_string(),
+                "Test Phone".to_string(),
+            )
+            .unwrap();
+        assert_eq!(
+            desktop_key,
+            load_or_create_host_keypair().unwrap().public_key()
+        );
+        let confirm_request =
+            serde_json::json!({"op": "pairing_short_code_confirm", "confirm": confirm});
+        core.handle_frame(json(confirm_request.clone())).unwrap();
+        let reply = out.messages().remove(0);
+        assert_eq!(reply["op"], "pairing_response");
+        assert_eq!(reply["response"]["approved"], true);
+        let devices = list_trusted_devices().unwrap();
+        assert_eq!(devices.len(), 1);
+        assert_eq!(devices[0].public_key, mobile.public_key());
+        assert_eq!(devices[0].scopes, DeviceScopes::view_only());
+
+        // The code is spent, and its request carries no pairing token
+        assert_eq!(short_code_state(), ShortCodeState::None);
+        core.handle_frame(json(confirm_request)).unwrap();
+        assert_eq!(out.ops(), vec!["error"]);
+        core.handle_frame(json(
+            serde_json::json!({"op": "pairing_submit", "request": confirm.request}),
+        ))
+        .unwrap();
+        assert_eq!(out.ops(), vec!["error"]);
+
+        set_pairing_approver(None);
+    }
+}
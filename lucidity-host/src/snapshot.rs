@@ -0,0 +1,159 @@
+//! Capture a pane's screen for clients that attach after the application
+//! has already drawn it.
+
+use lucidity_proto::protocol::{PaneSnapshot, SnapshotCursor};
+use mux::pane::Pane;
+use std::fmt::Write;
+use termwiz::cell::{Blink, CellAttributes, Intensity, Underline};
+use termwiz::color::{ColorAttribute, ColorSpec};
+use termwiz::escape::csi::{Sgr, CSI};
+use termwiz::escape::osc::OperatingSystemCommand;
+use termwiz::surface::CursorVisibility;
+use wezterm_term::Line;
+
+/// Scrollback rows sent when the client does not ask for a specific amount
+pub(crate) const DEFAULT_SCROLLBACK_ROWS: usize = 200;
+/// Upper bound on the scrollback a client may request
+pub(crate) const MAX_SCROLLBACK_ROWS: usize = 5000;
+
+pub(crate) fn scrollback_rows(requested: Option<usize>) -> usize {
+    requested
+        .unwrap_or(DEFAULT_SCROLLBACK_ROWS)
+        .min(MAX_SCROLLBACK_ROWS)
+}
+
+pub(crate) fn capture(pane: &dyn Pane, scrollback: usize) -> PaneSnapshot {
+    let dims = pane.get_dimensions();
+    let cursor = pane.get_cursor_position();
+
+    let top = dims
+        .physical_top
+        .saturating_sub(scrollback as isize)
+        .max(dims.scrollback_top);
+    let bottom = dims.physical_top + dims.viewport_rows as isize;
+    let (first, lines) = pane.get_lines(top..bottom);
+
+    PaneSnapshot {
+        pane_id: pane.pane_id(),
+        rows: dims.viewport_rows,
+        cols: dims.cols,
+        lines: lines.iter().map(render_line).collect(),
+        scrollback_rows: (dims.physical_top - first).max(0) as usize,
+        cursor: SnapshotCursor {
+            x: cursor.x,
+            y: (cursor.y - dims.physical_top).max(0) as usize,
+            visible: cursor.visibility == CursorVisibility::Visible,
+        },
+        alt_screen: pane.is_alt_screen_active(),
+        bracketed_paste: pane.is_bracketed_paste_enabled(),
+        mouse_grabbed: pane.is_mouse_grabbed(),
+    }
+}
+
+/// Render a line as text with SGR and OSC 8 escapes. Trailing blank cells
+/// are dropped since the client clears the screen before painting.
+pub(crate) fn render_line(line: &Line) -> String {
+    let default_attrs = CellAttributes::default();
+    let cells: Vec<_> = line.visible_cells().collect();
+    let used = cells
+        .iter()
+        .rposition(|c| c.str() != " " || *c.attrs() != default_attrs)
+        .map_or(0, |idx| idx + 1);
+
+    let mut out = String::new();
+    let mut current = &default_attrs;
+    for cell in &cells[..used] {
+        let attrs = cell.attrs();
+        if attrs != current {
+            if attrs.hyperlink() != current.hyperlink() {
+                let link = attrs.hyperlink().map(|link| (**link).clone());
+                write!(out, "{}", OperatingSystemCommand::SetHyperlink(link)).ok();
+            }
+            write_sgr(&mut out, attrs);
+            current = attrs;
+        }
+        out.push_str(cell.str());
+    }
+
+    if current.hyperlink().is_some() {
+        write!(out, "{}", OperatingSystemCommand::SetHyperlink(None)).ok();
+    }
+    if *current != default_attrs {
+        write!(out, "{}", CSI::Sgr(Sgr::Reset)).ok();
+    }
+    out
+}
+
+/// Reset and then set every attribute that differs from the default.
+fn write_sgr(out: &mut String, attrs: &CellAttributes) {
+    let mut sgr = vec![Sgr::Reset];
+    if attrs.intensity() != Intensity::Normal {
+        sgr.push(Sgr::Intensity(attrs.intensity()));
+    }
+    if attrs.underline() != Underline::None {
+        sgr.push(Sgr::Underline(attrs.underline()));
+    }
+    if attrs.underline_color() != ColorAttribute::Default {
+        sgr.push(Sgr::UnderlineColor(color_spec(attrs.underline_color())));
+    }
+    if attrs.blink() != Blink::None {
+        sgr.push(Sgr::Blink(attrs.blink()));
+    }
+    if attrs.italic() {
+        sgr.push(Sgr::Italic(true));
+    }
+    if attrs.reverse() {
+        sgr.push(Sgr::Inverse(true));
+    }
+    if attrs.invisible() {
+        sgr.push(Sgr::Invisible(true));
+    }
+    if attrs.strikethrough() {
+        sgr.push(Sgr::StrikeThrough(true));
+    }
+    if attrs.overline() {
+        sgr.push(Sgr::Overline(true));
+    }
+    if attrs.foreground() != ColorAttribute::Default {
+        sgr.push(Sgr::Foreground(color_spec(attrs.foreground())));
+    }
+    if attrs.background() != ColorAttribute::Default {
+        sgr.push(Sgr::Background(color_spec(attrs.background())));
+    }
+    for s in sgr {
+        write!(out, "{}", CSI::Sgr(s)).ok();
+    }
+}
+
+fn color_spec(color: ColorAttribute) -> ColorSpec {
+    match color {
+        ColorAttribute::TrueColorWithPaletteFallback(tc, _)
+        | ColorAttribute::TrueColorWithDefaultFallback(tc) => ColorSpec::TrueColor(tc),
+        ColorAttribute::PaletteIndex(idx) => ColorSpec::PaletteIndex(idx),
+        ColorAttribute::Default => ColorSpec::Default,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use termwiz::color::AnsiColor;
+    use termwiz::surface::SEQ_ZERO;
+
+    #[test]
+    fn plain_line_has_no_escapes_or_trailing_blanks() {
+        let line = Line::from_text("hello", &CellAttributes::default(), SEQ_ZERO, None);
+        assert_eq!(render_line(&line), "hello");
+    }
+
+    #[test]
+    fn attributes_are_rendered_and_reset() {
+        let mut bold = CellAttributes::default();
+        bold.set_intensity(Intensity::Bold)
+            .set_foreground(AnsiColor::Maroon);
+        let mut line = Line::from_text("ok", &bold, SEQ_ZERO, None);
+        line.resize(10, SEQ_ZERO);
+
+        assert_eq!(render_line(&line), "\u{1b}[0m\u{1b}[1m\u{1b}[31mok\u{1b}[0m");
+    }
+}
@@ -1,21 +1,37 @@
 use lucidity_host::{
-    FakePaneBridge, PaneInfo, TYPE_JSON, 
+    FakePaneBridge, PaneInfo, RelayStatus, TYPE_JSON, TYPE_SEALED,
 };
+use lucidity_pairing::{Keypair, PairingPayload};
 use lucidity_proto::frame::{encode_frame, FrameDecoder};
-use std::sync::Arc;
+use lucidity_proto::relay::{encode_session_data, registration_message, RelayMessage};
+use lucidity_proto::secure::{
+    decode_ephemeral_key, device_auth_message, encode_ephemeral_key, KeyExchange, Role, Transcript,
+};
+use std::path::Path;
+use std::sync::{Arc, OnceLock};
 use std::time::Duration;
 use futures_util::{SinkExt, StreamExt};
 use tokio_tungstenite::{connect_async, tungstenite::protocol::Message};
 use url::Url;
 
+/// Where the host keeps its keypair and trusted devices. Tests running at
+/// once share the environment, so they share these too.
+fn host_data() -> &'static Path {
+    static DIR: OnceLock<tempfile::TempDir> = OnceLock::new();
+    DIR.get_or_init(|| {
+        let dir = tempfile::tempdir().unwrap();
+        std::env::set_var("LUCIDITY_HOST_KEYPAIR", dir.path().join("host_keypair.json"));
+        std::env::set_var("LUCIDITY_DEVICE_TRUST_DB", dir.path().join("devices.db"));
+        dir
+    })
+    .path()
+}
+
 #[tokio::test]
 async fn relay_end_to_end_test() {
-    let dir = tempfile::tempdir().unwrap();
-    let host_kp_path = dir.path().join("host_keypair.json");
-    let device_db_path = dir.path().join("devices.db");
+    let host_kp_path = host_data().join("host_keypair.json");
+    let device_db_path = host_data().join("devices.db");
     
-    std::env::set_var("LUCIDITY_HOST_KEYPAIR", &host_kp_path);
-    std::env::set_var("LUCIDITY_DEVICE_TRUST_DB", &device_db_path);
     std::env::set_var("LUCIDITY_RELAY_URL", "ws://127.0.0.1:9090");
     std::env::set_var("LUCIDITY_RELAY_SECRET", "test-secret-123");
     
@@ -39,7 +55,7 @@ async fn relay_end_to_end_test() {
                  let s = relay_server_desktop.clone();
                  ws.on_upgrade(move |websocket| async move {
                      if q.get("secret").map(|s| s.as_str()) != Some("test-secret-123") { return; }
-                     let _ = s.handle_desktop(id, websocket).await;
+                     let _ = s.handle_desktop(id, None, websocket).await;
                  })
             });
 
@@ -50,7 +66,7 @@ async fn relay_end_to_end_test() {
                  let s = relay_server_mobile.clone();
                  ws.on_upgrade(move |websocket| async move {
                      if q.get("secret").map(|s| s.as_str()) != Some("test-secret-123") { return; }
-                     let _ = s.handle_mobile(id, websocket).await;
+                     let _ = s.handle_mobile(id, None, None, websocket).await;
                  })
             });
             
@@ -68,6 +84,8 @@ async fn relay_end_to_end_test() {
     let fake_bridge = Arc::new(FakePaneBridge::new(vec![PaneInfo {
         pane_id: 1,
         title: "relay-test-pane".to_string(),
+        domain: None,
+        workspace: None,
     }]));
     
     let mut relay_client = lucidity_host::RelayClient::new("ws://127.0.0.1:9090".to_string(), relay_id.clone());
@@ -84,11 +102,19 @@ async fn relay_end_to_end_test() {
     let (ws_stream, _) = connect_async(Url::parse(&mobile_url).unwrap()).await.unwrap();
     let (mut ws_tx, mut ws_rx) = ws_stream.split();
 
-    // 4. Send list_panes via Relay
+    // 4. The host challenges the new session straight away
+    let msg = ws_rx.next().await.unwrap().unwrap();
+    let mut decoder = FrameDecoder::new();
+    decoder.push(&msg.into_data());
+    let frame = decoder.next_frame().unwrap().unwrap();
+    let v: serde_json::Value = serde_json::from_slice(&frame.payload).unwrap();
+    assert_eq!(v["op"], "auth_challenge");
+
+    // Send list_panes via Relay
     let list_req = serde_json::to_vec(&serde_json::json!({ "op": "list_panes" })).unwrap();
     ws_tx.send(Message::Binary(encode_frame(TYPE_JSON, &list_req))).await.unwrap();
 
-    // 5. Expect Auth Challenge (because we are not localhost)
+    // 5. Expect a fresh Auth Challenge (because we are not authenticated)
     let msg = ws_rx.next().await.unwrap().unwrap();
     let data = msg.into_data();
     let mut decoder = FrameDecoder::new();
@@ -119,17 +145,23 @@ async fn relay_end_to_end_test() {
         device_name: "Test Mobile".to_string(),
         paired_at: 0,
         last_seen: None,
+        scopes: lucidity_pairing::DeviceScopes::full(),
     }).unwrap();
 
-    let sig = mobile_kp.sign(nonce.as_bytes()).to_base64();
+    let kx = KeyExchange::new();
+    let device_ephemeral = kx.public_key();
+    let sig = mobile_kp
+        .sign(&device_auth_message(&nonce, &device_ephemeral))
+        .to_base64();
     let auth_resp = serde_json::to_vec(&serde_json::json!({
         "op": "auth_response",
         "public_key": mobile_kp.public_key().to_base64(),
         "signature": sig,
+        "ephemeral_key": encode_ephemeral_key(&device_ephemeral),
     })).unwrap();
     ws_tx.send(Message::Binary(encode_frame(TYPE_JSON, &auth_resp))).await.unwrap();
 
-    // 7. Expect Auth Success
+    // 7. Expect Auth Success carrying the host half of the key exchange
     let msg = ws_rx.next().await.unwrap().unwrap();
     let mut decoder = FrameDecoder::new();
     decoder.push(&msg.into_data());
@@ -137,12 +169,49 @@ async fn relay_end_to_end_test() {
     let v: serde_json::Value = serde_json::from_slice(&frame.payload).unwrap();
     assert_eq!(v["op"], "auth_success");
 
-    // 8. Now retry list_panes
-    ws_tx.send(Message::Binary(encode_frame(TYPE_JSON, &list_req))).await.unwrap();
+    let host_ephemeral = decode_ephemeral_key(v["ephemeral_key"].as_str().unwrap()).unwrap();
+    let transcript = Transcript::new(
+        &nonce,
+        &mobile_kp.public_key(),
+        &keypair.public_key(),
+        &device_ephemeral,
+        &host_ephemeral,
+    );
+    let key_signature =
+        lucidity_pairing::Signature::from_base64(v["key_signature"].as_str().unwrap()).unwrap();
+    keypair
+        .public_key()
+        .verify(transcript.as_bytes(), &key_signature)
+        .unwrap();
+    let (mut sealer, mut opener) = kx
+        .finish(Role::Device, &host_ephemeral, &transcript)
+        .unwrap();
+
+    // The session token is the first sealed frame
+    let msg = ws_rx.next().await.unwrap().unwrap();
+    let mut decoder = FrameDecoder::new();
+    decoder.push(&msg.into_data());
+    let frame = decoder.next_frame().unwrap().unwrap();
+    assert_eq!(frame.typ, TYPE_SEALED);
+    let frame = opener.open(&frame.payload).unwrap();
+    let v: serde_json::Value = serde_json::from_slice(&frame.payload).unwrap();
+    assert_eq!(v["op"], "session_token");
+    assert!(v["token"].as_str().is_some());
+
+    // 8. Now retry list_panes; everything from here on is sealed
+    let sealed = sealer.seal(TYPE_JSON, &list_req).unwrap();
+    ws_tx.send(Message::Binary(encode_frame(TYPE_SEALED, &sealed))).await.unwrap();
     let msg = ws_rx.next().await.unwrap().unwrap();
     let mut decoder = FrameDecoder::new();
     decoder.push(&msg.into_data());
     let frame = decoder.next_frame().unwrap().unwrap();
+    assert_eq!(frame.typ, TYPE_SEALED);
+    assert!(!frame
+        .payload
+        .windows(b"relay-test-pane".len())
+        .any(|w| w == b"relay-test-pane"));
+    let frame = opener.open(&frame.payload).unwrap();
+    assert_eq!(frame.typ, TYPE_JSON);
     let v: serde_json::Value = serde_json::from_slice(&frame.payload).unwrap();
     assert_eq!(v["op"], "list_panes");
     assert_eq!(v["panes"][0]["title"], "relay-test-pane");
@@ -152,7 +221,8 @@ async fn relay_end_to_end_test() {
         "op": "revoke_device",
         "public_key": mobile_kp.public_key().to_base64(),
     })).unwrap();
-    ws_tx.send(Message::Binary(encode_frame(TYPE_JSON, &revoke_req))).await.unwrap();
+    let sealed = sealer.seal(TYPE_JSON, &revoke_req).unwrap();
+    ws_tx.send(Message::Binary(encode_frame(TYPE_SEALED, &sealed))).await.unwrap();
     
     // Catch the success/error message
     let _ = ws_rx.next().await.unwrap().unwrap();
@@ -163,3 +233,854 @@ async fn relay_end_to_end_test() {
     println!("✅ Revocation Test Passed!");
     println!("✅ Relay End-to-End Integrated Test Passed!");
 }
+
+/// The first JSON message on a mobile's relay connection
+async fn next_op<S>(ws_rx: &mut S) -> serde_json::Value
+where
+    S: StreamExt<Item = Result<Message, tokio_tungstenite::tungstenite::Error>> + Unpin,
+{
+    let msg = tokio::time::timeout(Duration::from_secs(5), ws_rx.next())
+        .await
+        .expect("timed out waiting for the relay")
+        .unwrap()
+        .unwrap();
+    let mut decoder = FrameDecoder::new();
+    decoder.push(&msg.into_data());
+    let frame = decoder.next_frame().unwrap().unwrap();
+    serde_json::from_slice(&frame.payload).unwrap()
+}
+
+/// Serve `relay_server`'s desktop and mobile endpoints on `port`
+/// The relay's desktop and mobile routes, with desktops held to
+/// `desktop_certs`
+fn relay_routes(
+    relay_server: Arc<lucidity_relay::RelayServer>,
+    desktop_certs: Vec<String>,
+) -> warp::filters::BoxedFilter<(impl warp::Reply,)> {
+    use warp::Filter;
+    let relay_server_desktop = relay_server.clone();
+    let relay_server_mobile = relay_server.clone();
+    let d_route = warp::path!("desktop" / String)
+        .and(lucidity_relay::tls::require_client_cert(
+            desktop_certs,
+            relay_server.metrics(),
+        ))
+        .and(warp::ws())
+        .and(lucidity_relay::tls::remote())
+        .map(
+            move |id, ws: warp::ws::Ws, remote: Option<std::net::IpAddr>| {
+                let s = relay_server_desktop.clone();
+                ws.on_upgrade(move |websocket| async move {
+                    let _ = s.handle_desktop(id, remote, websocket).await;
+                })
+            },
+        );
+    let m_route = warp::path!("mobile" / String)
+        .and(warp::ws())
+        .and(lucidity_relay::tls::remote())
+        .map(
+            move |id, ws: warp::ws::Ws, remote: Option<std::net::IpAddr>| {
+                let s = relay_server_mobile.clone();
+                ws.on_upgrade(move |websocket| async move {
+                    let _ = s.handle_mobile(id, None, remote, websocket).await;
+                })
+            },
+        );
+    d_route
+        .or(m_route)
+        .recover(lucidity_relay::tls::recover)
+        .boxed()
+}
+
+async fn spawn_relay(relay_server: Arc<lucidity_relay::RelayServer>, port: u16) {
+    let relay_addr: std::net::SocketAddr = ([127, 0, 0, 1], port).into();
+    tokio::spawn(warp::serve(relay_routes(relay_server, vec![])).run(relay_addr));
+    tokio::time::sleep(Duration::from_millis(200)).await;
+}
+
+/// A relay client registered on `port` with a key of its own
+fn relay_client(port: u16) -> (lucidity_host::RelayClient, String) {
+    relay_client_at(format!("ws://127.0.0.1:{}", port))
+}
+
+fn relay_client_at(relay_url: String) -> (lucidity_host::RelayClient, String) {
+    let keypair = Keypair::generate();
+    let relay_id = PairingPayload::derive_relay_id(&keypair.public_key());
+    let mut relay_client = lucidity_host::RelayClient::new(relay_url, relay_id.clone());
+    relay_client.set_keypair(keypair);
+    relay_client.set_bridge(Arc::new(FakePaneBridge::new(vec![PaneInfo {
+        pane_id: 1,
+        title: "shared-pane".to_string(),
+        domain: None,
+        workspace: None,
+    }])));
+    (relay_client, relay_id)
+}
+
+#[tokio::test]
+async fn relay_keeps_two_phones_apart() {
+    let relay_server = Arc::new(lucidity_relay::RelayServer::new());
+    let manager = relay_server.manager();
+    spawn_relay(relay_server, 9091).await;
+
+    let (mut relay_client, relay_id) = relay_client(9091);
+    relay_client.connect().await.unwrap();
+    tokio::time::sleep(Duration::from_millis(200)).await;
+
+    let url = Url::parse(&format!("ws://127.0.0.1:9091/mobile/{}", relay_id)).unwrap();
+    let (first, _) = connect_async(url.clone()).await.unwrap();
+    let (mut first_tx, mut first_rx) = first.split();
+    let (second, _) = connect_async(url).await.unwrap();
+    let (mut second_tx, mut second_rx) = second.split();
+
+    // Each phone has a session, and a challenge, of its own
+    let first_challenge = next_op(&mut first_rx).await;
+    let second_challenge = next_op(&mut second_rx).await;
+    assert_eq!(first_challenge["op"], "auth_challenge");
+    assert_eq!(second_challenge["op"], "auth_challenge");
+    assert_ne!(first_challenge["nonce"], second_challenge["nonce"]);
+    assert_eq!(manager.session_count(), 2);
+
+    // The first phone leaving doesn't disturb the second
+    first_tx.send(Message::Close(None)).await.unwrap();
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    assert_eq!(manager.session_count(), 1);
+
+    let list_req = serde_json::to_vec(&serde_json::json!({ "op": "list_panes" })).unwrap();
+    second_tx
+        .send(Message::Binary(encode_frame(TYPE_JSON, &list_req)))
+        .await
+        .unwrap();
+    assert_eq!(next_op(&mut second_rx).await["op"], "auth_challenge");
+    assert_eq!(next_op(&mut second_rx).await["op"], "error");
+
+    // A phone for a desktop that isn't there is turned away
+    let url = Url::parse("ws://127.0.0.1:9091/mobile/nobody").unwrap();
+    let (stray, _) = connect_async(url).await.unwrap();
+    let (_, mut stray_rx) = stray.split();
+    let msg = stray_rx.next().await.unwrap().unwrap();
+    let v: serde_json::Value = serde_json::from_str(msg.to_text().unwrap()).unwrap();
+    assert_eq!(v["type"], "control");
+    assert_eq!(v["code"], 404);
+}
+
+/// The next control message on a desktop's relay connection
+async fn next_control<S>(ws_rx: &mut S) -> RelayMessage
+where
+    S: StreamExt<Item = Result<Message, tokio_tungstenite::tungstenite::Error>> + Unpin,
+{
+    let msg = tokio::time::timeout(Duration::from_secs(5), ws_rx.next())
+        .await
+        .expect("timed out waiting for the relay")
+        .unwrap()
+        .unwrap();
+    serde_json::from_str(msg.to_text().unwrap()).unwrap()
+}
+
+#[tokio::test]
+async fn relay_only_registers_desktops_that_own_the_relay_id() {
+    let relay_server = Arc::new(lucidity_relay::RelayServer::with_config(
+        lucidity_relay::RelayConfig {
+            allow_unproven: true,
+            ..Default::default()
+        },
+    ));
+    let manager = relay_server.manager();
+    spawn_relay(relay_server, 9092).await;
+
+    let (mut relay_client, relay_id) = relay_client(9092);
+    relay_client.connect().await.unwrap();
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    assert_eq!(relay_client.status().await, RelayStatus::Connected);
+
+    let impostor_url = Url::parse(&format!("ws://127.0.0.1:9092/desktop/{}", relay_id)).unwrap();
+
+    // Signing with some other key doesn't prove anything
+    let (impostor, _) = connect_async(impostor_url.clone()).await.unwrap();
+    let (mut impostor_tx, mut impostor_rx) = impostor.split();
+    let RelayMessage::Challenge { nonce } = next_control(&mut impostor_rx).await else {
+        panic!("expected a challenge");
+    };
+    let other = Keypair::generate();
+    let register = RelayMessage::Register {
+        relay_id: relay_id.clone(),
+        public_key: Some(other.public_key().to_base64()),
+        signature: Some(other.sign(&registration_message(&relay_id, &nonce)).to_base64()),
+    };
+    impostor_tx
+        .send(Message::Text(serde_json::to_string(&register).unwrap()))
+        .await
+        .unwrap();
+    assert!(matches!(
+        next_control(&mut impostor_rx).await,
+        RelayMessage::Control { code: 401, .. }
+    ));
+
+    // Nor can an unproven desktop displace a registered one
+    let (impostor, _) = connect_async(impostor_url).await.unwrap();
+    let (mut impostor_tx, mut impostor_rx) = impostor.split();
+    next_control(&mut impostor_rx).await;
+    let register = RelayMessage::Register {
+        relay_id: relay_id.clone(),
+        public_key: None,
+        signature: None,
+    };
+    impostor_tx
+        .send(Message::Text(serde_json::to_string(&register).unwrap()))
+        .await
+        .unwrap();
+    assert!(matches!(
+        next_control(&mut impostor_rx).await,
+        RelayMessage::Control { code: 409, .. }
+    ));
+
+    // Phones still reach the real desktop
+    assert_eq!(manager.desktop_count(), 1);
+    let url = Url::parse(&format!("ws://127.0.0.1:9092/mobile/{}", relay_id)).unwrap();
+    let (mobile, _) = connect_async(url).await.unwrap();
+    let (_, mut mobile_rx) = mobile.split();
+    assert_eq!(next_op(&mut mobile_rx).await["op"], "auth_challenge");
+}
+
+#[tokio::test]
+async fn relay_holds_connections_to_their_quotas() {
+    let relay_server = Arc::new(lucidity_relay::RelayServer::with_config(
+        lucidity_relay::RelayConfig {
+            max_sessions_per_relay_id: 1,
+            max_connections_per_ip: 3,
+            idle_timeout: Duration::from_millis(400),
+            max_message_bytes: 1024,
+            ..Default::default()
+        },
+    ));
+    let manager = relay_server.manager();
+    tokio::spawn(relay_server.clone().reap_sessions());
+    spawn_relay(relay_server, 9093).await;
+
+    let (mut first_desktop, relay_id) = relay_client(9093);
+    first_desktop.connect().await.unwrap();
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    let url = Url::parse(&format!("ws://127.0.0.1:9093/mobile/{}", relay_id)).unwrap();
+
+    let (phone, _) = connect_async(url.clone()).await.unwrap();
+    let (mut phone_tx, mut phone_rx) = phone.split();
+    assert_eq!(next_op(&mut phone_rx).await["op"], "auth_challenge");
+
+    // One phone per desktop
+    let (extra, _) = connect_async(url.clone()).await.unwrap();
+    let (_, mut extra_rx) = extra.split();
+    assert_eq!(
+        next_control(&mut extra_rx).await,
+        RelayMessage::Control {
+            code: 429,
+            message: "too many sessions for this desktop".to_string(),
+        }
+    );
+
+    // Three connections from one address: two desktops and a phone
+    let (mut second_desktop, second_id) = relay_client(9093);
+    second_desktop.connect().await.unwrap();
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    assert_eq!(second_desktop.status().await, RelayStatus::Connected);
+    let second_url = Url::parse(&format!("ws://127.0.0.1:9093/mobile/{}", second_id)).unwrap();
+    let (extra, _) = connect_async(second_url).await.unwrap();
+    let (_, mut extra_rx) = extra.split();
+    assert_eq!(
+        next_control(&mut extra_rx).await,
+        RelayMessage::Control {
+            code: 429,
+            message: "too many connections from this address".to_string(),
+        }
+    );
+
+    // Oversized messages end the session
+    phone_tx
+        .send(Message::Binary(vec![0; 2048]))
+        .await
+        .unwrap();
+    assert!(matches!(
+        next_control(&mut phone_rx).await,
+        RelayMessage::Control { code: 413, .. }
+    ));
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    assert_eq!(manager.session_count(), 0);
+
+    // even from a desktop that hasn't registered yet
+    let desktop_url = Url::parse(&format!("ws://127.0.0.1:9093/desktop/{}", relay_id)).unwrap();
+    let (desktop, _) = connect_async(desktop_url).await.unwrap();
+    let (mut desktop_tx, mut desktop_rx) = desktop.split();
+    assert!(matches!(
+        next_control(&mut desktop_rx).await,
+        RelayMessage::Challenge { .. }
+    ));
+    desktop_tx
+        .send(Message::Text("x".repeat(2048)))
+        .await
+        .unwrap();
+    assert!(matches!(
+        next_control(&mut desktop_rx).await,
+        RelayMessage::Control { code: 413, .. }
+    ));
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    // and quiet ones are reaped
+    let (phone, _) = connect_async(url).await.unwrap();
+    let (_, mut phone_rx) = phone.split();
+    assert_eq!(next_op(&mut phone_rx).await["op"], "auth_challenge");
+    assert_eq!(manager.session_count(), 1);
+    tokio::time::sleep(Duration::from_millis(700)).await;
+    assert_eq!(manager.session_count(), 0);
+    let closed = tokio::time::timeout(Duration::from_secs(5), phone_rx.next())
+        .await
+        .unwrap();
+    assert!(matches!(closed, None | Some(Ok(Message::Close(_)))));
+}
+
+/// A test's own WebSocket to the relay
+type Socket =
+    tokio_tungstenite::WebSocketStream<tokio_tungstenite::MaybeTlsStream<tokio::net::TcpStream>>;
+
+/// Register a desktop on the relay at `port` by hand, returning its socket
+/// and relay_id
+async fn register_desktop(port: u16) -> (Socket, String) {
+    let keypair = Keypair::generate();
+    let relay_id = PairingPayload::derive_relay_id(&keypair.public_key());
+    let url = Url::parse(&format!("ws://127.0.0.1:{}/desktop/{}", port, relay_id)).unwrap();
+    let (mut desktop, _) = connect_async(url).await.unwrap();
+    let RelayMessage::Challenge { nonce } = next_control(&mut desktop).await else {
+        panic!("expected a challenge");
+    };
+    let register = RelayMessage::Register {
+        relay_id: relay_id.clone(),
+        public_key: Some(keypair.public_key().to_base64()),
+        signature: Some(
+            keypair
+                .sign(&registration_message(&relay_id, &nonce))
+                .to_base64(),
+        ),
+    };
+    desktop
+        .send(Message::Text(serde_json::to_string(&register).unwrap()))
+        .await
+        .unwrap();
+    assert!(matches!(
+        next_control(&mut desktop).await,
+        RelayMessage::Control { code: 200, .. }
+    ));
+    (desktop, relay_id)
+}
+
+#[tokio::test]
+async fn relay_closes_only_the_session_flooding_a_slow_desktop() {
+    let relay_server = Arc::new(lucidity_relay::RelayServer::new());
+    let manager = relay_server.manager();
+    spawn_relay(relay_server, 9099).await;
+
+    // A desktop that registers and then stops reading
+    let (_desktop, relay_id) = register_desktop(9099).await;
+
+    let url = Url::parse(&format!("ws://127.0.0.1:9099/mobile/{}", relay_id)).unwrap();
+    let (quiet, _) = connect_async(url.clone()).await.unwrap();
+    let (mut quiet_tx, _quiet_rx) = quiet.split();
+    let (flooder, _) = connect_async(url).await.unwrap();
+    let (mut flooder_tx, mut flooder_rx) = flooder.split();
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    assert_eq!(manager.session_count(), 2);
+    let quiet_session = manager.sessions_of(&relay_id);
+
+    let flood = tokio::spawn(async move {
+        for _ in 0..400 {
+            if flooder_tx
+                .send(Message::Binary(vec![0; 256 * 1024]))
+                .await
+                .is_err()
+            {
+                break;
+            }
+        }
+    });
+    assert_eq!(
+        next_control(&mut flooder_rx).await,
+        RelayMessage::Control {
+            code: 429,
+            message: "desktop is not keeping up".to_string(),
+        }
+    );
+    flood.abort();
+    tokio::time::sleep(Duration::from_millis(200)).await;
+
+    // The desktop and the other phone's session carry on
+    assert_eq!(manager.desktop_count(), 1);
+    assert_eq!(manager.session_count(), 1);
+    let remaining = manager.sessions_of(&relay_id);
+    assert!(quiet_session.contains(&remaining[0]));
+    quiet_tx.send(Message::Binary(vec![1; 16])).await.unwrap();
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    assert_eq!(manager.sessions_of(&relay_id), remaining);
+    assert_eq!(manager.desktop_count(), 1);
+}
+
+#[tokio::test]
+async fn relay_throttles_each_session_on_its_own() {
+    let relay_server = Arc::new(lucidity_relay::RelayServer::with_config(
+        lucidity_relay::RelayConfig {
+            relay_id_bytes_per_second: Some(4096),
+            forward_queue: 4,
+            ..Default::default()
+        },
+    ));
+    let manager = relay_server.manager();
+    spawn_relay(relay_server, 9100).await;
+    let (mut desktop, relay_id) = register_desktop(9100).await;
+
+    let url = Url::parse(&format!("ws://127.0.0.1:9100/mobile/{}", relay_id)).unwrap();
+    let mut session_ids = Vec::new();
+    let mut mobiles = Vec::new();
+    for _ in 0..2 {
+        let (mobile, _) = connect_async(url.clone()).await.unwrap();
+        mobiles.push(mobile);
+        let RelayMessage::SessionRequest { session_id, .. } = next_control(&mut desktop).await
+        else {
+            panic!("expected a session request");
+        };
+        session_ids.push(session_id);
+    }
+    let mut other = mobiles.pop().unwrap();
+    let mut throttled = mobiles.pop().unwrap();
+
+    // Seconds' worth of data for one phone doesn't hold up the desktop's
+    // message for the other
+    for _ in 0..3 {
+        desktop
+            .send(Message::Binary(encode_session_data(
+                &session_ids[0],
+                &[0; 4096],
+            )))
+            .await
+            .unwrap();
+    }
+    let close = RelayMessage::Close {
+        session_id: session_ids[1].clone(),
+        reason: "done".to_string(),
+    };
+    desktop
+        .send(Message::Text(serde_json::to_string(&close).unwrap()))
+        .await
+        .unwrap();
+    let closed = tokio::time::timeout(Duration::from_secs(1), other.next())
+        .await
+        .expect("the other phone waited on the throttled one");
+    assert!(matches!(closed, None | Some(Ok(Message::Close(_)))));
+
+    // A phone whose queue fills up loses its own session
+    for _ in 0..8 {
+        desktop
+            .send(Message::Binary(encode_session_data(
+                &session_ids[0],
+                &[0; 4096],
+            )))
+            .await
+            .unwrap();
+    }
+    assert_eq!(
+        next_control(&mut desktop).await,
+        RelayMessage::Close {
+            session_id: session_ids[0].clone(),
+            reason: "session is not keeping up".to_string(),
+        }
+    );
+    let closed = tokio::time::timeout(Duration::from_secs(5), async {
+        loop {
+            match throttled.next().await {
+                Some(Ok(Message::Binary(_))) => continue,
+                other => return other,
+            }
+        }
+    })
+    .await
+    .unwrap();
+    assert!(matches!(
+        closed,
+        None | Some(Err(_)) | Some(Ok(Message::Close(_)))
+    ));
+    assert_eq!(manager.desktop_count(), 1);
+    assert_eq!(manager.session_count(), 0);
+}
+
+#[tokio::test]
+async fn relay_reports_metrics_and_lets_admins_close_sessions() {
+    use warp::Filter;
+    let relay_server = Arc::new(lucidity_relay::RelayServer::new());
+    let metrics = lucidity_relay::admin::metrics(relay_server.clone());
+    let admin = lucidity_relay::admin::routes(relay_server.clone(), Some("hunter2".to_string()))
+        .recover(lucidity_relay::admin::recover);
+    spawn_relay(relay_server.clone(), 9094).await;
+
+    let (mut desktop, relay_id) = relay_client(9094);
+    desktop.connect().await.unwrap();
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    let url = Url::parse(&format!("ws://127.0.0.1:9094/mobile/{}", relay_id)).unwrap();
+    let (phone, _) = connect_async(url).await.unwrap();
+    let (_, mut phone_rx) = phone.split();
+    assert_eq!(next_op(&mut phone_rx).await["op"], "auth_challenge");
+
+    let page = warp::test::request().path("/metrics").reply(&metrics).await;
+    assert_eq!(page.status(), 200);
+    let page = String::from_utf8(page.body().to_vec()).unwrap();
+    assert!(page.contains("lucidity_relay_desktops_connected 1\n"));
+    assert!(page.contains("lucidity_relay_mobiles_connected 1\n"));
+    assert!(page.contains("lucidity_relay_sessions_active 1\n"));
+    assert!(
+        !page.contains("lucidity_relay_forwarded_bytes_total{direction=\"desktop_to_mobile\"} 0\n")
+    );
+
+    // Admins need the token
+    let refused = warp::test::request()
+        .path("/admin/sessions")
+        .reply(&admin)
+        .await;
+    assert_eq!(refused.status(), 401);
+    let refused = warp::test::request()
+        .path("/admin/sessions")
+        .header("authorization", "Bearer hunter3")
+        .reply(&admin)
+        .await;
+    assert_eq!(refused.status(), 401);
+    assert_eq!(
+        warp::test::request()
+            .path("/admin/sessions")
+            .reply(&lucidity_relay::admin::routes(relay_server.clone(), None))
+            .await
+            .status(),
+        404
+    );
+
+    let listed = warp::test::request()
+        .path(&format!("/admin/sessions?relay_id={}", relay_id))
+        .header("authorization", "Bearer hunter2")
+        .reply(&admin)
+        .await;
+    assert_eq!(listed.status(), 200);
+    let listed: serde_json::Value = serde_json::from_slice(listed.body()).unwrap();
+    let sessions = listed.as_array().unwrap();
+    assert_eq!(sessions.len(), 1);
+    assert_eq!(sessions[0]["relay_id"], relay_id.as_str());
+    assert_eq!(sessions[0]["accepted"], true);
+    let session_id = sessions[0]["session_id"].as_str().unwrap().to_string();
+
+    let desktops = warp::test::request()
+        .path("/admin/desktops")
+        .header("authorization", "Bearer hunter2")
+        .reply(&admin)
+        .await;
+    let desktops: serde_json::Value = serde_json::from_slice(desktops.body()).unwrap();
+    assert_eq!(desktops[0]["relay_id"], relay_id.as_str());
+    assert_eq!(desktops[0]["proven"], true);
+    assert_eq!(desktops[0]["sessions"], 1);
+
+    // Closing a session disconnects the phone
+    let close = |session_id: String| {
+        warp::test::request()
+            .method("DELETE")
+            .path(&format!("/admin/sessions/{}", session_id))
+            .header("authorization", "Bearer hunter2")
+            .reply(&admin)
+    };
+    assert_eq!(close(session_id.clone()).await.status(), 204);
+    assert_eq!(close(session_id).await.status(), 404);
+    let closed = tokio::time::timeout(Duration::from_secs(5), phone_rx.next())
+        .await
+        .unwrap();
+    assert!(matches!(closed, None | Some(Ok(Message::Close(_)))));
+
+    let page = warp::test::request().path("/metrics").reply(&metrics).await;
+    let page = String::from_utf8(page.body().to_vec()).unwrap();
+    assert!(page.contains("lucidity_relay_mobiles_connected 0\n"));
+    assert!(page.contains("lucidity_relay_auth_rejected_total 2\n"));
+}
+
+/// A self-signed certificate and key written to `dir`, and the
+/// certificate's fingerprint
+fn write_cert(
+    dir: &std::path::Path,
+    name: &str,
+) -> (std::path::PathBuf, std::path::PathBuf, String) {
+    let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+    let cert_pem = cert.serialize_pem().unwrap();
+    let cert_path = dir.join(format!("{}.pem", name));
+    let key_path = dir.join(format!("{}.key", name));
+    std::fs::write(&cert_path, &cert_pem).unwrap();
+    std::fs::write(&key_path, cert.serialize_private_key_pem()).unwrap();
+    let der = native_tls::Certificate::from_pem(cert_pem.as_bytes())
+        .unwrap()
+        .to_der()
+        .unwrap();
+    (
+        cert_path,
+        key_path,
+        lucidity_pairing::cert_fingerprint(&der),
+    )
+}
+
+#[tokio::test]
+async fn relay_serves_tls_and_pins_certificates_both_ways() {
+    use lucidity_relay::tls::{TlsAcceptor, TlsConfig};
+    let dir = tempfile::tempdir().unwrap();
+    let (relay_cert, relay_key, relay_sha256) = write_cert(dir.path(), "relay");
+    let (desktop_cert, desktop_key, desktop_sha256) = write_cert(dir.path(), "desktop");
+
+    let mut tls_config = TlsConfig::new(relay_cert, relay_key);
+    tls_config.request_client_cert = true;
+    let tls = TlsAcceptor::new(tls_config).unwrap();
+    assert_eq!(tls.fingerprint(), relay_sha256);
+    let relay_server = Arc::new(lucidity_relay::RelayServer::new());
+    let routes = relay_routes(relay_server.clone(), vec![desktop_sha256.to_uppercase()]);
+    tokio::spawn(lucidity_relay::tls::serve(
+        routes,
+        ([127, 0, 0, 1], 9095).into(),
+        tls,
+    ));
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    let relay_url = "wss://127.0.0.1:9095".to_string();
+
+    // A relay with some other certificate is refused
+    let (mut desktop, _) = relay_client_at(relay_url.clone());
+    desktop.set_cert_sha256(desktop_sha256.clone());
+    desktop.set_client_cert(desktop_cert.clone(), desktop_key.clone());
+    assert!(desktop.connect().await.is_err());
+    assert!(matches!(
+        desktop.status().await,
+        RelayStatus::Error(e) if e.contains("is not the pinned")
+    ));
+
+    // and the relay refuses desktops without a pinned certificate
+    let (mut desktop, _) = relay_client_at(relay_url.clone());
+    desktop.set_cert_sha256(relay_sha256.clone());
+    assert!(desktop.connect().await.is_err());
+    assert_eq!(relay_server.manager().desktop_count(), 0);
+
+    let (mut desktop, _) = relay_client_at(relay_url);
+    desktop.set_cert_sha256(relay_sha256);
+    desktop.set_client_cert(desktop_cert, desktop_key);
+    desktop.connect().await.unwrap();
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    assert_eq!(desktop.status().await, RelayStatus::Connected);
+    assert_eq!(relay_server.manager().desktop_count(), 1);
+}
+
+/// A relay that registers each desktop, then stops reading, so pings go
+/// unanswered. Reports each connection on the returned channel.
+async fn spawn_silent_relay(port: u16) -> tokio::sync::mpsc::UnboundedReceiver<()> {
+    let listener = tokio::net::TcpListener::bind(("127.0.0.1", port))
+        .await
+        .unwrap();
+    let (connected_tx, connected_rx) = tokio::sync::mpsc::unbounded_channel();
+    tokio::spawn(async move {
+        loop {
+            let (stream, _) = listener.accept().await.unwrap();
+            let connected_tx = connected_tx.clone();
+            tokio::spawn(async move {
+                let mut ws = tokio_tungstenite::accept_async(stream).await.unwrap();
+                let challenge = RelayMessage::Challenge {
+                    nonce: "nonce".to_string(),
+                };
+                ws.send(Message::Text(serde_json::to_string(&challenge).unwrap()))
+                    .await
+                    .unwrap();
+                ws.next().await.unwrap().unwrap();
+                let registered = RelayMessage::Control {
+                    code: 200,
+                    message: "registered".to_string(),
+                };
+                ws.send(Message::Text(serde_json::to_string(&registered).unwrap()))
+                    .await
+                    .unwrap();
+                connected_tx.send(()).ok();
+                // Hold the connection open without reading from it
+                let _ws = ws;
+                std::future::pending::<()>().await;
+            });
+        }
+    });
+    connected_rx
+}
+
+#[tokio::test]
+async fn relay_client_gives_up_on_a_silent_relay() {
+    let mut connected = spawn_silent_relay(9096).await;
+    let (mut desktop, _) = relay_client(9096);
+    desktop.set_keepalive(Duration::from_millis(100));
+    desktop.set_backoff(Duration::from_millis(50), Duration::from_millis(200));
+    let (_enabled_tx, enabled_rx) = tokio::sync::watch::channel(true);
+    tokio::spawn(desktop.run(enabled_rx));
+
+    for _ in 0..2 {
+        tokio::time::timeout(Duration::from_secs(5), connected.recv())
+            .await
+            .unwrap()
+            .unwrap();
+    }
+}
+
+/// Wait for `relay_server` to have `count` desktops registered
+async fn wait_for_desktops(relay_server: &lucidity_relay::RelayServer, count: usize) {
+    let manager = relay_server.manager();
+    tokio::time::timeout(Duration::from_secs(5), async {
+        while manager.desktop_count() != count {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+    })
+    .await
+    .unwrap();
+}
+
+#[tokio::test]
+async fn relay_client_follows_the_relay_toggle() {
+    let relay_server = Arc::new(lucidity_relay::RelayServer::new());
+    spawn_relay(relay_server.clone(), 9097).await;
+    let (mut desktop, _) = relay_client(9097);
+    desktop.set_backoff(Duration::from_millis(50), Duration::from_millis(200));
+    let (enabled_tx, enabled_rx) = tokio::sync::watch::channel(true);
+    let supervisor = tokio::spawn(desktop.run(enabled_rx));
+    wait_for_desktops(&relay_server, 1).await;
+
+    // Turned off, it disconnects at once; turned on, it reconnects
+    enabled_tx.send_replace(false);
+    wait_for_desktops(&relay_server, 0).await;
+    tokio::time::sleep(Duration::from_millis(300)).await;
+    assert_eq!(relay_server.manager().desktop_count(), 0);
+    enabled_tx.send_replace(true);
+    wait_for_desktops(&relay_server, 1).await;
+
+    drop(enabled_tx);
+    tokio::time::timeout(Duration::from_secs(5), supervisor)
+        .await
+        .unwrap()
+        .unwrap();
+    wait_for_desktops(&relay_server, 0).await;
+}
+
+/// Trust a new device and authenticate it on a mobile's relay connection,
+/// challenged with `nonce`, up to the first sealed frame
+async fn authenticate_mobile<T, R>(ws_tx: &mut T, ws_rx: &mut R, nonce: &str)
+where
+    T: SinkExt<Message> + Unpin,
+    T::Error: std::fmt::Debug,
+    R: StreamExt<Item = Result<Message, tokio_tungstenite::tungstenite::Error>> + Unpin,
+{
+    let mobile_kp = Keypair::generate();
+    let store = lucidity_pairing::DeviceTrustStore::open(host_data().join("devices.db")).unwrap();
+    store
+        .add_device(&lucidity_pairing::TrustedDevice {
+            public_key: mobile_kp.public_key(),
+            user_email: "test@example.com".to_string(),
+            device_name: "Test Mobile".to_string(),
+            paired_at: 0,
+            last_seen: None,
+            scopes: lucidity_pairing::DeviceScopes::full(),
+        })
+        .unwrap();
+
+    let kx = KeyExchange::new();
+    let device_ephemeral = kx.public_key();
+    let sig = mobile_kp
+        .sign(&device_auth_message(nonce, &device_ephemeral))
+        .to_base64();
+    let auth_resp = serde_json::to_vec(&serde_json::json!({
+        "op": "auth_response",
+        "public_key": mobile_kp.public_key().to_base64(),
+        "signature": sig,
+        "ephemeral_key": encode_ephemeral_key(&device_ephemeral),
+    }))
+    .unwrap();
+    ws_tx
+        .send(Message::Binary(encode_frame(TYPE_JSON, &auth_resp)))
+        .await
+        .unwrap();
+    assert_eq!(next_op(ws_rx).await["op"], "auth_success");
+    let msg = ws_rx.next().await.unwrap().unwrap();
+    let mut decoder = FrameDecoder::new();
+    decoder.push(&msg.into_data());
+    assert_eq!(decoder.next_frame().unwrap().unwrap().typ, TYPE_SEALED);
+}
+
+#[tokio::test]
+async fn mobiles_can_go_direct_after_punching() {
+    let relay_server = Arc::new(lucidity_relay::RelayServer::new());
+    spawn_relay(relay_server, 9098).await;
+    let (mut relay_client, relay_id) = relay_client(9098);
+    relay_client.connect().await.unwrap();
+    tokio::time::sleep(Duration::from_millis(200)).await;
+
+    let url = Url::parse(&format!("ws://127.0.0.1:9098/mobile/{}", relay_id)).unwrap();
+    let (mobile, _) = connect_async(url).await.unwrap();
+    let (mut mobile_tx, mut mobile_rx) = mobile.split();
+    let challenge = next_op(&mut mobile_rx).await;
+    assert_eq!(challenge["op"], "auth_challenge");
+
+    // Offer the desktop our addresses through the relay. It won't send
+    // UDP anywhere for a device that hasn't authenticated.
+    let socket = lucidity_host::PunchSocket::bind().await.unwrap();
+    let offer = Message::Text(
+        serde_json::to_string(&RelayMessage::PunchOffer {
+            session_id: String::new(),
+            candidates: socket.candidates(),
+        })
+        .unwrap(),
+    );
+    mobile_tx.send(offer.clone()).await.unwrap();
+    let unanswered = tokio::time::timeout(Duration::from_millis(500), mobile_rx.next()).await;
+    assert!(unanswered.is_err());
+
+    // Once it has, the desktop answers with its own addresses
+    authenticate_mobile(
+        &mut mobile_tx,
+        &mut mobile_rx,
+        challenge["nonce"].as_str().unwrap(),
+    )
+    .await;
+    mobile_tx.send(offer).await.unwrap();
+    let RelayMessage::PunchAnswer {
+        candidates,
+        cert_sha256,
+        ..
+    } = next_control(&mut mobile_rx).await
+    else {
+        panic!("expected a punch answer");
+    };
+    assert!(!candidates.is_empty());
+
+    // The direct connection is a session of its own, challenged afresh
+    let mut direct = lucidity_host::connect_direct(socket, &candidates, &cert_sha256)
+        .await
+        .unwrap();
+    let mut decoder = FrameDecoder::new();
+    let mut buf = [0u8; 4096];
+    let challenge = loop {
+        if let Some(frame) = decoder.next_frame().unwrap() {
+            break frame;
+        }
+        let n = direct.recv.read(&mut buf).await.unwrap().unwrap();
+        decoder.push(&buf[..n]);
+    };
+    let v: serde_json::Value = serde_json::from_slice(&challenge.payload).unwrap();
+    assert_eq!(v["op"], "auth_challenge");
+
+    let list_req = serde_json::to_vec(&serde_json::json!({ "op": "list_panes" })).unwrap();
+    direct
+        .send
+        .write_all(&encode_frame(TYPE_JSON, &list_req))
+        .await
+        .unwrap();
+    let mut ops = vec![];
+    while ops.len() < 2 {
+        if let Some(frame) = decoder.next_frame().unwrap() {
+            let v: serde_json::Value = serde_json::from_slice(&frame.payload).unwrap();
+            ops.push(v["op"].as_str().unwrap().to_string());
+            continue;
+        }
+        let n = direct.recv.read(&mut buf).await.unwrap().unwrap();
+        decoder.push(&buf[..n]);
+    }
+    assert_eq!(ops, ["auth_challenge", "error"]);
+}
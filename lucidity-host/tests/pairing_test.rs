@@ -153,6 +153,7 @@ fn test_device_trust_store_operations() {
         device_name: "Test Device".to_string(),
         paired_at: now,
         last_seen: Some(now),
+        scopes: lucidity_pairing::DeviceScopes::full(),
     };
     store.add_device(&device).unwrap();
 
@@ -214,6 +215,7 @@ fn test_full_pairing_flow_local() {
             device_name: request.device_name.clone(),
             paired_at: now,
             last_seen: Some(now),
+            scopes: lucidity_pairing::DeviceScopes::full(),
         })
         .unwrap();
 
@@ -1,10 +1,11 @@
 use k9::assert_equal;
 use lucidity_host::{
     serve_blocking, set_pairing_approver, FakePaneBridge, PairingApproval, PairingApprover,
-    PaneInfo, TYPE_JSON, TYPE_PANE_OUTPUT,
+    PaneInfo, TYPE_JSON, TYPE_PANE_INPUT_ADDRESSED, TYPE_PANE_OUTPUT, TYPE_PANE_OUTPUT_ADDRESSED,
+    TYPE_PANE_SNAPSHOT,
 };
-use lucidity_pairing::{Keypair, PairingRequest};
-use lucidity_proto::frame::{encode_frame, FrameDecoder};
+use lucidity_pairing::{Keypair, PairingPayload, PairingRequest};
+use lucidity_proto::frame::{decode_pane_payload, encode_frame, encode_pane_payload, FrameDecoder};
 use std::io::{Read, Write};
 use std::net::{SocketAddr, TcpListener, TcpStream};
 use std::sync::Arc;
@@ -60,6 +61,8 @@ fn tcp_server_lists_and_attaches_and_streams_output() {
     let fake = Arc::new(FakePaneBridge::new(vec![PaneInfo {
         pane_id: 123,
         title: "test".to_string(),
+        domain: None,
+        workspace: None,
     }]));
 
     std::thread::spawn({
@@ -96,15 +99,14 @@ fn tcp_server_lists_and_attaches_and_streams_output() {
     assert_equal!(pair_resp.typ, TYPE_JSON);
     let pair_v: serde_json::Value = serde_json::from_slice(&pair_resp.payload).unwrap();
     assert_equal!(pair_v["op"], "pairing_payload");
-    let desktop_public_key = pair_v["payload"]["desktop_public_key"].clone();
+    let payload: PairingPayload = serde_json::from_value(pair_v["payload"].clone()).unwrap();
+    assert!(payload.pairing_token.is_some());
 
     // Pairing submit should be rejected unless auto-approve is enabled
     let mobile_keypair = Keypair::generate();
-    let desktop_pk: lucidity_pairing::PublicKey =
-        serde_json::from_value(desktop_public_key.clone()).unwrap();
-    let request = PairingRequest::new(
+    let request = PairingRequest::for_payload(
         &mobile_keypair,
-        &desktop_pk,
+        &payload,
         "user@example.com".to_string(),
         "Test Phone".to_string(),
     );
@@ -138,6 +140,18 @@ fn tcp_server_lists_and_attaches_and_streams_output() {
     assert_equal!(submit_v2["op"], "pairing_response");
     assert_equal!(submit_v2["response"]["approved"], true);
 
+    // The pairing token is used up, so the request can't be replayed
+    stream
+        .write_all(&encode_frame(TYPE_JSON, &submit_req2))
+        .unwrap();
+    let replay_resp = read_next_frame(&mut stream, &mut dec);
+    let replay_v: serde_json::Value = serde_json::from_slice(&replay_resp.payload).unwrap();
+    assert_equal!(replay_v["op"], "error");
+    assert!(replay_v["message"]
+        .as_str()
+        .unwrap()
+        .contains("already been used"));
+
     let list_req = serde_json::to_vec(&serde_json::json!({
         "op": "pairing_list_trusted_devices"
     }))
@@ -150,6 +164,8 @@ fn tcp_server_lists_and_attaches_and_streams_output() {
     assert_equal!(list_v["op"], "pairing_trusted_devices");
     assert_equal!(list_v["devices"][0]["user_email"], "user@example.com");
 
+    fake.set_screen(123, vec!["$ vim".to_string(), "~".to_string()]);
+
     let attach_req =
         serde_json::to_vec(&serde_json::json!({ "op": "attach", "pane_id": 123 })).unwrap();
     stream
@@ -167,6 +183,14 @@ fn tcp_server_lists_and_attaches_and_streams_output() {
         }
     }
 
+    // The snapshot comes before any live output
+    let snap = read_next_frame(&mut stream, &mut dec);
+    assert_equal!(snap.typ, TYPE_PANE_SNAPSHOT);
+    let snap_v: serde_json::Value = serde_json::from_slice(&snap.payload).unwrap();
+    assert_equal!(snap_v["pane_id"], 123);
+    assert_equal!(snap_v["lines"], serde_json::json!(["$ vim", "~"]));
+    assert_equal!(snap_v["cursor"]["y"], 1);
+
     // Verify that input is accepted and routed to the selected pane
     stream
         .write_all(&encode_frame(lucidity_host::TYPE_PANE_INPUT, b"ls\r\n"))
@@ -188,3 +212,139 @@ fn tcp_server_lists_and_attaches_and_streams_output() {
         }
     }
 }
+
+fn send_json(stream: &mut TcpStream, value: serde_json::Value) {
+    let payload = serde_json::to_vec(&value).unwrap();
+    stream.write_all(&encode_frame(TYPE_JSON, &payload)).unwrap();
+}
+
+fn read_json(stream: &mut TcpStream, dec: &mut FrameDecoder) -> serde_json::Value {
+    let f = read_next_frame(stream, dec);
+    assert_equal!(f.typ, TYPE_JSON);
+    serde_json::from_slice(&f.payload).unwrap()
+}
+
+#[test]
+fn tcp_server_attaches_several_panes_with_addressed_frames() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr: SocketAddr = listener.local_addr().unwrap();
+    let fake = Arc::new(FakePaneBridge::new(vec![]));
+
+    std::thread::spawn({
+        let fake = Arc::clone(&fake);
+        move || {
+            serve_blocking(listener, fake).unwrap();
+        }
+    });
+
+    let mut stream = TcpStream::connect(addr).unwrap();
+    stream
+        .set_read_timeout(Some(Duration::from_secs(2)))
+        .unwrap();
+    let mut dec = FrameDecoder::new();
+
+    for pane_id in [1, 2] {
+        send_json(
+            &mut stream,
+            serde_json::json!({ "op": "attach", "pane_id": pane_id, "addressed": true }),
+        );
+        assert_equal!(read_json(&mut stream, &mut dec)["op"], "attach_ok");
+        let snap = read_next_frame(&mut stream, &mut dec);
+        assert_equal!(snap.typ, TYPE_PANE_SNAPSHOT);
+    }
+
+    stream
+        .write_all(&encode_frame(
+            TYPE_PANE_INPUT_ADDRESSED,
+            &encode_pane_payload(2, b"ls\r"),
+        ))
+        .unwrap();
+    std::thread::sleep(Duration::from_millis(50));
+    assert_equal!(fake.take_inputs(), vec![(2, b"ls\r".to_vec())]);
+
+    fake.emit_output(1, b"one");
+    let f = read_next_frame(&mut stream, &mut dec);
+    assert_equal!(f.typ, TYPE_PANE_OUTPUT_ADDRESSED);
+    assert_equal!(decode_pane_payload(&f.payload).unwrap(), (1, &b"one"[..]));
+
+    send_json(&mut stream, serde_json::json!({ "op": "detach", "pane_id": 1 }));
+    let v = read_json(&mut stream, &mut dec);
+    assert_equal!(v["op"], "detach_ok");
+    assert_equal!(v["pane_id"], 1);
+
+    // Pane 1 no longer forwards output or accepts input
+    fake.emit_output(1, b"ignored");
+    fake.emit_output(2, b"two");
+    let f = read_next_frame(&mut stream, &mut dec);
+    assert_equal!(decode_pane_payload(&f.payload).unwrap(), (2, &b"two"[..]));
+
+    stream
+        .write_all(&encode_frame(
+            TYPE_PANE_INPUT_ADDRESSED,
+            &encode_pane_payload(1, b"x"),
+        ))
+        .unwrap();
+    assert_equal!(read_json(&mut stream, &mut dec)["message"], "not attached to pane 1");
+}
+
+#[test]
+fn tcp_server_runs_mux_operations() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr: SocketAddr = listener.local_addr().unwrap();
+    let fake = Arc::new(FakePaneBridge::new(vec![PaneInfo {
+        pane_id: 0,
+        title: "shell".to_string(),
+        domain: None,
+        workspace: None,
+    }]));
+
+    std::thread::spawn({
+        let fake = Arc::clone(&fake);
+        move || {
+            serve_blocking(listener, fake).unwrap();
+        }
+    });
+
+    let mut stream = TcpStream::connect(addr).unwrap();
+    stream
+        .set_read_timeout(Some(Duration::from_secs(2)))
+        .unwrap();
+    let mut dec = FrameDecoder::new();
+
+    send_json(&mut stream, serde_json::json!({ "op": "spawn", "pane_id": 0 }));
+    let spawned = read_json(&mut stream, &mut dec);
+    assert_equal!(spawned["op"], "spawned");
+    let new_pane = spawned["pane_id"].as_u64().unwrap() as usize;
+
+    send_json(
+        &mut stream,
+        serde_json::json!({ "op": "split_pane", "pane_id": new_pane, "side": "right" }),
+    );
+    let split = read_json(&mut stream, &mut dec);
+    assert_equal!(split["op"], "spawned");
+    assert_equal!(split["tab_id"], spawned["tab_id"]);
+    let split_pane = split["pane_id"].as_u64().unwrap() as usize;
+
+    send_json(&mut stream, serde_json::json!({ "op": "zoom_pane", "pane_id": split_pane }));
+    assert_equal!(read_json(&mut stream, &mut dec)["op"], "ok");
+    send_json(
+        &mut stream,
+        serde_json::json!({ "op": "activate_tab", "tab_index": 0, "pane_id": split_pane }),
+    );
+    assert_equal!(read_json(&mut stream, &mut dec)["op"], "ok");
+    assert_equal!(fake.focused_pane(), Some(0));
+
+    send_json(&mut stream, serde_json::json!({ "op": "kill_pane", "pane_id": new_pane }));
+    assert_equal!(read_json(&mut stream, &mut dec)["op"], "ok");
+
+    let tabs = fake.tabs();
+    assert_equal!(tabs.len(), 2);
+    assert_equal!(tabs[1].panes.clone(), vec![split_pane]);
+    assert_equal!(tabs[1].zoomed, true);
+
+    // A failed operation is an error response, not a dropped connection
+    send_json(&mut stream, serde_json::json!({ "op": "kill_pane", "pane_id": new_pane }));
+    let err = read_json(&mut stream, &mut dec);
+    assert_equal!(err["op"], "error");
+    assert_equal!(err["message"], format!("no such pane: {new_pane}"));
+}
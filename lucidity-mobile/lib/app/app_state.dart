@@ -139,8 +139,10 @@ class AppState extends ChangeNotifier {
       relayId: payload.relayId,
       relayUrl: payload.relayUrl,
       relaySecret: payload.relaySecret,
+      relayCertSha256: payload.relayCertSha256,
       lanAddr: payload.lanAddr,
       externalAddr: payload.externalAddr,
+      candidateAddrs: payload.candidateAddrs,
       lastConnectedAtSeconds: now,
     );
 
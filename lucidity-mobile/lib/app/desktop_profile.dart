@@ -15,10 +15,17 @@ class DesktopProfile {
   final String? relayUrl;
   final String? relaySecret;
 
+  /// SHA-256 fingerprint the relay's TLS certificate must have, in place
+  /// of the usual CA checks
+  final String? relayCertSha256;
+
   /// P2P connection addresses
   final String? lanAddr;
   final String? externalAddr;
 
+  /// Every address from the pairing payload, IPv4 and IPv6, best first
+  final List<String> candidateAddrs;
+
   final int createdAtSeconds;
   final int? lastConnectedAtSeconds;
 
@@ -33,8 +40,10 @@ class DesktopProfile {
     required this.relayId,
     this.relayUrl,
     this.relaySecret,
+    this.relayCertSha256,
     this.lanAddr,
     this.externalAddr,
+    this.candidateAddrs = const [],
   });
 
   bool get isPaired => desktopPublicKey != null && relayId != null;
@@ -64,8 +73,10 @@ class DesktopProfile {
     String? relayId,
     String? relayUrl,
     String? relaySecret,
+    String? relayCertSha256,
     String? lanAddr,
     String? externalAddr,
+    List<String>? candidateAddrs,
     int? createdAtSeconds,
     int? lastConnectedAtSeconds,
   }) {
@@ -78,8 +89,10 @@ class DesktopProfile {
       relayId: relayId ?? this.relayId,
       relayUrl: relayUrl ?? this.relayUrl,
       relaySecret: relaySecret ?? this.relaySecret,
+      relayCertSha256: relayCertSha256 ?? this.relayCertSha256,
       lanAddr: lanAddr ?? this.lanAddr,
       externalAddr: externalAddr ?? this.externalAddr,
+      candidateAddrs: candidateAddrs ?? this.candidateAddrs,
       createdAtSeconds: createdAtSeconds ?? this.createdAtSeconds,
       lastConnectedAtSeconds: lastConnectedAtSeconds ?? this.lastConnectedAtSeconds,
     );
@@ -94,8 +107,10 @@ class DesktopProfile {
         'relay_id': relayId,
         'relay_url': relayUrl,
         'relay_secret': relaySecret,
+        'relay_cert_sha256': relayCertSha256,
         'lan_addr': lanAddr,
         'external_addr': externalAddr,
+        'candidates': candidateAddrs,
         'created_at': createdAtSeconds,
         'last_connected_at': lastConnectedAtSeconds,
       };
@@ -109,8 +124,10 @@ class DesktopProfile {
     final relayId = json['relay_id'];
     final relayUrl = json['relay_url'];
     final relaySecret = json['relay_secret'];
+    final relayCertSha256 = json['relay_cert_sha256'];
     final lanAddr = json['lan_addr'];
     final externalAddr = json['external_addr'];
+    final candidates = json['candidates'];
     final createdAt = json['created_at'];
     final lastConnectedAt = json['last_connected_at'];
 
@@ -124,6 +141,9 @@ class DesktopProfile {
     if (relayId != null && relayId is! String) throw FormatException('invalid relay_id');
     if (relayUrl != null && relayUrl is! String) throw FormatException('invalid relay_url');
     if (relaySecret != null && relaySecret is! String) throw FormatException('invalid relay_secret');
+    if (relayCertSha256 != null && relayCertSha256 is! String) {
+      throw FormatException('invalid relay_cert_sha256');
+    }
     final isPaired = desktopPublicKey is String && relayId is String;
 
     if (host is! String) throw FormatException('invalid host');
@@ -146,8 +166,10 @@ class DesktopProfile {
       relayId: relayId as String?,
       relayUrl: relayUrl as String?,
       relaySecret: relaySecret as String?,
+      relayCertSha256: relayCertSha256 as String?,
       lanAddr: lanAddr as String?,
       externalAddr: externalAddr as String?,
+      candidateAddrs: candidates is List ? candidates.whereType<String>().toList() : const [],
       createdAtSeconds: createdAt,
       lastConnectedAtSeconds: lastConnectedAt as int?,
     );
@@ -4,12 +4,13 @@ import 'dart:io';
 import 'package:flutter/foundation.dart';
 
 import '../app/desktop_profile.dart';
+import 'messages.dart';
 
 /// Connection type indicating how we're connected to the desktop
 enum ConnectionType {
   /// Direct LAN connection (same network)
   lan,
-  /// Direct internet connection via UPnP/STUN
+  /// Direct internet connection via IPv6 or UPnP/STUN
   external,
   /// Connection via relay server
   relay,
@@ -110,39 +111,26 @@ class ConnectionManager extends ChangeNotifier {
     notifyListeners();
     
     try {
-      // Strategy 1: Try LAN direct (fastest, ~1ms latency)
-      if (profile.lanAddr != null && profile.lanAddr!.isNotEmpty) {
-        _updateStatus('Trying LAN connection...');
-        
-        final result = await _tryDirectConnection(
-          profile.lanAddr!,
-          ConnectionType.lan,
-        );
-        
-        if (result is ConnectionSuccess) {
-          _setConnected(result.result);
-          return result;
-        } else if (result is ConnectionFailure) {
-          _failedAttempts.add(result);
-          debugPrint('[ConnectionManager] LAN failed: ${result.error}');
-        }
+      // Strategies 1 and 2: direct connections, best first. The LAN comes
+      // first (fastest, ~1ms latency), then IPv6 and external (UPnP/STUN).
+      final addrs = <String>[...profile.candidateAddrs];
+      for (final addr in [profile.lanAddr, profile.externalAddr]) {
+        if (addr != null && addr.isNotEmpty && !addrs.contains(addr)) addrs.add(addr);
       }
-      
-      // Strategy 2: Try external (UPnP/STUN) for remote P2P
-      if (profile.externalAddr != null && profile.externalAddr!.isNotEmpty) {
-        _updateStatus('Trying direct connection...');
-        
-        final result = await _tryDirectConnection(
-          profile.externalAddr!,
-          ConnectionType.external,
-        );
-        
+      for (final addr in addrs) {
+        final type = addr == profile.lanAddr ? ConnectionType.lan : ConnectionType.external;
+        _updateStatus(type == ConnectionType.lan
+            ? 'Trying LAN connection...'
+            : 'Trying direct connection...');
+
+        final result = await _tryDirectConnection(addr, type);
+
         if (result is ConnectionSuccess) {
           _setConnected(result.result);
           return result;
         } else if (result is ConnectionFailure) {
           _failedAttempts.add(result);
-          debugPrint('[ConnectionManager] External failed: ${result.error}');
+          debugPrint('[ConnectionManager] $addr failed: ${result.error}');
         }
       }
       
@@ -180,8 +168,8 @@ class ConnectionManager extends ChangeNotifier {
     ConnectionType type,
   ) async {
     try {
-      final parts = address.split(':');
-      if (parts.length != 2) {
+      final hostPort = splitHostPort(address);
+      if (hostPort == null) {
         return ConnectionFailure(
           attemptedType: type,
           address: address,
@@ -189,12 +177,9 @@ class ConnectionManager extends ChangeNotifier {
         );
       }
       
-      final host = parts[0];
-      final port = int.tryParse(parts[1]) ?? 9797;
-      
       final socket = await Socket.connect(
-        host,
-        port,
+        hostPort.host,
+        hostPort.port,
         timeout: attemptTimeout,
       );
       socket.setOption(SocketOption.tcpNoDelay, true);
@@ -3,4 +3,5 @@ const int maxFrameLen = 16 * 1024 * 1024;
 const int typeJson = 1;
 const int typePaneOutput = 2;
 const int typePaneInput = 3;
+const int typeSealed = 4;
 
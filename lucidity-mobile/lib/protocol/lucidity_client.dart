@@ -6,12 +6,13 @@ import 'dart:typed_data';
 import 'package:cryptography/cryptography.dart';
 import 'package:flutter/foundation.dart';
 import 'package:flutter/services.dart';
-import 'package:uuid/uuid.dart';
 
+import 'base64url_nopad.dart';
 import 'constants.dart';
 import 'frame.dart';
 import 'messages.dart';
 import 'mobile_identity.dart';
+import 'secure.dart';
 
 import 'connection_state.dart';
 import 'relay_client.dart';
@@ -32,7 +33,24 @@ class LucidityClient extends ChangeNotifier {
   Completer<void>? _attachOkCompleter;
 
   String? _expectedDesktopPublicKey;
-  String? _clientNonce;
+
+  /// The challenge we answered and the key exchange we offered with it
+  String? _authNonce;
+  KeyExchange? _keyExchange;
+
+  /// Set once the key exchange completes; every frame after is sealed
+  FrameSealer? _sealer;
+  FrameOpener? _opener;
+  bool _authenticated = false;
+
+  /// Frames are sealed and opened asynchronously, so sends and incoming
+  /// frames are each handled one at a time to keep records in order.
+  Future<void> _sendQueue = Future.value();
+  Future<void> _receiveQueue = Future.value();
+
+  /// Presented in `resume` to pick the session up after a drop
+  String? _sessionToken;
+  String? get sessionToken => _sessionToken;
 
   LucidityConnectionState _connectionState = LucidityConnectionState.disconnected;
   LucidityConnectionState get connectionState => _connectionState;
@@ -69,61 +87,52 @@ class LucidityClient extends ChangeNotifier {
   }
 
   /// Connect using the best available strategy from pairing info
-  /// Tries: 1) LAN direct, 2) External (UPnP), 3) Relay (fallback)
+  /// Tries: 1) LAN direct, 2) IPv6 and External (UPnP), 3) Relay (fallback)
+  ///
+  /// [candidates] are the desktop's addresses, best first; [lanAddr] and
+  /// [externalAddr] are tried after them if not among them.
   Future<void> connectWithStrategy({
     required SimpleKeyPairData identity,
     String? desktopPublicKey,
+    List<String> candidates = const [],
     String? lanAddr,
     String? externalAddr,
     String? relayUrl,
     String? relayId,
     String? relaySecret,
+    String? relayCertSha256,
   }) async {
-    // Strategy 1: Try LAN connection first (fastest, ~1ms latency)
-    if (lanAddr != null && lanAddr.isNotEmpty) {
-      try {
-        final parts = lanAddr.split(':');
-        if (parts.length == 2) {
-          final host = parts[0];
-          final port = int.tryParse(parts[1]) ?? 9797;
-          _updateState(LucidityConnectionState.connecting, 'Trying LAN connection...');
-          await connectTcp(
-            host,
-            port,
-            identity: identity,
-            expectedDesktopPublicKey: desktopPublicKey,
-          );
-          if (connected) {
-            _connectionType = 'lan';
-            return;
-          }
-        }
-      } catch (e) {
-        debugPrint('LAN connection failed: $e');
-      }
+    final addrs = <String>[...candidates];
+    for (final addr in [lanAddr, externalAddr]) {
+      if (addr != null && addr.isNotEmpty && !addrs.contains(addr)) addrs.add(addr);
     }
 
-    // Strategy 2: Try external (UPnP/STUN) connection for remote P2P
-    if (externalAddr != null && externalAddr.isNotEmpty) {
+    // Strategies 1 and 2: direct connections, LAN first (fastest, ~1ms latency)
+    for (final addr in addrs) {
+      final hostPort = splitHostPort(addr);
+      if (hostPort == null) continue;
+      final type = addr == lanAddr
+          ? 'lan'
+          : addr.startsWith('[')
+              ? 'ipv6'
+              : 'external';
       try {
-        final parts = externalAddr.split(':');
-        if (parts.length == 2) {
-          final host = parts[0];
-          final port = int.tryParse(parts[1]) ?? 9797;
-          _updateState(LucidityConnectionState.connecting, 'Connecting via internet...');
-          await connectTcp(
-            host,
-            port,
-            identity: identity,
-            expectedDesktopPublicKey: desktopPublicKey,
-          );
-          if (connected) {
-            _connectionType = 'external';
-            return;
-          }
+        _updateState(
+          LucidityConnectionState.connecting,
+          type == 'lan' ? 'Trying LAN connection...' : 'Connecting via internet...',
+        );
+        await connectTcp(
+          hostPort.host,
+          hostPort.port,
+          identity: identity,
+          expectedDesktopPublicKey: desktopPublicKey,
+        );
+        if (connected) {
+          _connectionType = type;
+          return;
         }
       } catch (e) {
-        debugPrint('Direct remote connection failed: $e');
+        debugPrint('Direct connection to $addr failed: $e');
       }
     }
 
@@ -135,6 +144,7 @@ class LucidityClient extends ChangeNotifier {
           relayUrl: relayUrl,
           relayId: relayId,
           relaySecret: relaySecret,
+          relayCertSha256: relayCertSha256,
           identity: identity,
           expectedDesktopPublicKey: desktopPublicKey,
         );
@@ -182,7 +192,7 @@ class LucidityClient extends ChangeNotifier {
       socket.listen(
         (data) {
           _decoder.push(Uint8List.fromList(data));
-          _processFrames(identity);
+          _receive(identity);
         },
         onError: (Object err, StackTrace st) {
           _updateState(LucidityConnectionState.error, 'Socket error: $err');
@@ -195,10 +205,8 @@ class LucidityClient extends ChangeNotifier {
         cancelOnError: true,
       );
 
-      // If pairing, we don't auth or list panes automatically.
-      if (!pairing) {
-        await sendListPanes();
-      }
+      // The host challenges us as soon as we connect; panes are listed once
+      // we are authenticated.
     } catch (e) {
       _updateState(LucidityConnectionState.error, 'Connect failed: $e');
       rethrow;
@@ -211,6 +219,7 @@ class LucidityClient extends ChangeNotifier {
     required String relayUrl,
     required String relayId,
     String? relaySecret,
+    String? relayCertSha256,
     SimpleKeyPairData? identity,
     String? expectedDesktopPublicKey,
   }) async {
@@ -228,6 +237,7 @@ class LucidityClient extends ChangeNotifier {
         relayUrl: relayUrl, 
         relayId: relayId,
         relaySecret: relaySecret,
+        relayCertSha256: relayCertSha256,
       );
       _relayClient = client;
 
@@ -239,7 +249,7 @@ class LucidityClient extends ChangeNotifier {
       client.dataStream.listen(
         (data) {
           _decoder.push(data);
-          _processFrames(identity);
+          _receive(identity);
         },
         onError: (Object err, StackTrace st) {
           _updateState(LucidityConnectionState.error, 'Relay error: $err');
@@ -251,9 +261,7 @@ class LucidityClient extends ChangeNotifier {
         },
       );
 
-      // Start the handshake
-      await sendListPanes();
-
+      // The host challenges us once it accepts the session
     } catch (e) {
       _updateState(LucidityConnectionState.error, 'Relay connect failed: $e');
       rethrow;
@@ -263,6 +271,13 @@ class LucidityClient extends ChangeNotifier {
   Future<void> disconnect() async {
     _attachedPaneId = null;
     _panes = const [];
+    _authNonce = null;
+    _keyExchange = null;
+    _sealer = null;
+    _opener = null;
+    _authenticated = false;
+    _sendQueue = Future.value();
+    _sessionToken = null;
     _updateState(LucidityConnectionState.disconnected, 'Disconnected');
     _failPending(StateError('disconnected'));
 
@@ -309,7 +324,11 @@ class LucidityClient extends ChangeNotifier {
 
     final c = Completer<List<PaneInfo>>();
     _listPanesCompleter = c;
-    await sendListPanes();
+    // Asking before authenticating would make the host challenge us again;
+    // panes are listed once authentication completes.
+    if (_authenticated) {
+      await sendListPanes();
+    }
     return c.future.timeout(timeout);
   }
 
@@ -347,7 +366,7 @@ class LucidityClient extends ChangeNotifier {
     }
 
     final payload = utf8.encode(data);
-    _sendBytes(encodeFrame(type: typePaneInput, payload: payload));
+    await _sendFrame(typePaneInput, payload);
   }
 
   Future<void> sendPaste(String text) async {
@@ -401,7 +420,22 @@ class LucidityClient extends ChangeNotifier {
   Future<void> _sendJson(Map<String, Object?> msg) async {
     if (!_hasTransport) return;
     final payload = utf8.encode(jsonEncode(msg));
-    _sendBytes(encodeFrame(type: typeJson, payload: payload));
+    await _sendFrame(typeJson, payload);
+  }
+
+  /// Send a frame, sealed once the key exchange has completed
+  Future<void> _sendFrame(int type, List<int> payload) {
+    final sealer = _sealer;
+    if (sealer == null) {
+      _sendBytes(encodeFrame(type: type, payload: payload));
+      return Future.value();
+    }
+    // Sealing takes the next record counter now, so queue the send to keep
+    // records on the wire in counter order.
+    final sealed = sealer.seal(type, payload);
+    return _sendQueue = _sendQueue.then((_) async {
+      _sendBytes(encodeFrame(type: typeSealed, payload: await sealed));
+    });
   }
 
   bool get _hasTransport => _socket != null || _relayClient != null;
@@ -418,10 +452,22 @@ class LucidityClient extends ChangeNotifier {
     }
   }
 
+  void _receive(SimpleKeyPairData? identity) {
+    _receiveQueue = _receiveQueue.then((_) => _processFrames(identity));
+  }
+
   Future<void> _processFrames(SimpleKeyPairData? identity) async {
     while (true) {
-      final frame = _decoder.nextFrame();
-      if (frame == null) return;
+      final Frame frame;
+      try {
+        final next = _decoder.nextFrame();
+        if (next == null) return;
+        frame = await _unseal(next);
+      } catch (e) {
+        _updateState(LucidityConnectionState.error, 'Secure session failed: $e');
+        await disconnect();
+        return;
+      }
 
       switch (frame.type) {
         case typeJson:
@@ -438,6 +484,22 @@ class LucidityClient extends ChangeNotifier {
     }
   }
 
+  /// Remove the seal from an incoming frame. Once keys have been agreed,
+  /// plaintext frames are refused.
+  Future<Frame> _unseal(Frame frame) async {
+    final opener = _opener;
+    if (opener != null) {
+      if (frame.type != typeSealed) {
+        throw StateError('host sent plaintext frame type ${frame.type}');
+      }
+      return opener.open(frame.payload);
+    }
+    if (frame.type == typeSealed) {
+      throw StateError('sealed frame before key exchange');
+    }
+    return frame;
+  }
+
   Future<void> _handleJson(Uint8List payload, SimpleKeyPairData? identity) async {
     try {
       final text = utf8.decode(payload, allowMalformed: true);
@@ -448,47 +510,76 @@ class LucidityClient extends ChangeNotifier {
       if (op == 'auth_challenge') {
         if (identity == null) return; // Ignore auth if no identity (pairing mode)
         final challenge = AuthChallenge.fromJson(obj);
+        final kx = await KeyExchange.create();
+        _authNonce = challenge.nonce;
+        _keyExchange = kx;
         final signature = await MobileIdentity().sign(
           identity,
-          utf8.encode(challenge.nonce),
+          deviceAuthMessage(challenge.nonce, kx.publicKey),
         );
-        final sigBase64 = Base64UrlNoPad.encode(signature);
-        final pubKey = MobileIdentity().publicKeyBase64UrlNoPad(identity);
-        _clientNonce = Uuid().v4();
 
         await _sendJson({
           'op': 'auth_response',
-          'public_key': pubKey,
-          'signature': sigBase64,
-          'client_nonce': _clientNonce,
+          'public_key': MobileIdentity().publicKeyBase64UrlNoPad(identity),
+          'signature': Base64UrlNoPad.encode(signature),
+          'ephemeral_key': Base64UrlNoPad.encode(kx.publicKey),
         });
       } else if (op == 'auth_success') {
+        if (identity == null) return;
         final success = AuthSuccess.fromJson(obj);
-        final hostSig = success.signature;
         final expectedPub = _expectedDesktopPublicKey;
-        final nonce = _clientNonce;
-
-        if (hostSig != null && expectedPub != null && nonce != null) {
-          final verified = await MobileIdentity().verify(
-            Base64UrlNoPad.decode(expectedPub),
-            utf8.encode(nonce),
-            Base64UrlNoPad.decode(hostSig),
+        final nonce = _authNonce;
+        final kx = _keyExchange;
+        _authNonce = null;
+        _keyExchange = null;
+        final hostEphemeral = success.ephemeralKey;
+        final keySignature = success.keySignature;
+
+        if (expectedPub == null ||
+            nonce == null ||
+            kx == null ||
+            hostEphemeral == null ||
+            keySignature == null) {
+          _updateState(
+            LucidityConnectionState.error,
+            'Host did not complete the key exchange',
           );
-          if (!verified) {
-            _updateState(LucidityConnectionState.error, 'Host verification failed');
-            await disconnect();
-            return;
-          }
-          _statusMessage = 'Authenticated & Verified Host';
-        } else {
-          _statusMessage = 'Authenticated';
+          await disconnect();
+          return;
         }
-        notifyListeners();
 
-        // Retry list panes if we were waiting for it
-        if (_listPanesCompleter != null && !_listPanesCompleter!.isCompleted) {
-          await sendListPanes();
+        // The host signs the whole handshake, which proves it holds the
+        // key we paired with and that nobody swapped the ephemeral keys.
+        final hostKey = Base64UrlNoPad.decode(expectedPub);
+        final hostEphemeralBytes = Base64UrlNoPad.decode(hostEphemeral);
+        final transcript = transcriptBytes(
+          nonce: nonce,
+          deviceKey: identity.publicKey.bytes,
+          hostKey: hostKey,
+          deviceEphemeral: kx.publicKey,
+          hostEphemeral: hostEphemeralBytes,
+        );
+        final verified = await MobileIdentity().verify(
+          hostKey,
+          transcript,
+          Base64UrlNoPad.decode(keySignature),
+        );
+        if (!verified) {
+          _updateState(LucidityConnectionState.error, 'Host verification failed');
+          await disconnect();
+          return;
         }
+
+        final (sealer, opener) = await kx.finish(hostEphemeralBytes, transcript);
+        _sealer = sealer;
+        _opener = opener;
+        _authenticated = true;
+        _statusMessage = 'Authenticated (end-to-end encrypted)';
+        notifyListeners();
+
+        await sendListPanes();
+      } else if (op == 'session_token') {
+        _sessionToken = obj['token'] as String?;
       } else if (op == 'list_panes') {
         final panesJson = obj['panes'];
         if (panesJson is List) {
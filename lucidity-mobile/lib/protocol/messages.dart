@@ -17,6 +17,39 @@ class PaneInfo {
   }
 }
 
+/// Split `host:port`, where IPv6 hosts are in brackets (`[2001:db8::1]:9797`).
+/// Returns null if [addr] isn't in that form.
+({String host, int port})? splitHostPort(String addr) {
+  final colon = addr.lastIndexOf(':');
+  if (colon <= 0) return null;
+  final port = int.tryParse(addr.substring(colon + 1));
+  if (port == null) return null;
+  var host = addr.substring(0, colon);
+  if (host.startsWith('[') && host.endsWith(']')) {
+    host = host.substring(1, host.length - 1);
+  } else if (host.contains(':')) {
+    return null;
+  }
+  return (host: host, port: port);
+}
+
+/// An address the desktop may be reachable at
+class ConnectionCandidate {
+  final String addr;
+  final String kind; // lan, ipv6, mapped, public
+  final int priority; // higher is tried first
+
+  const ConnectionCandidate({required this.addr, required this.kind, required this.priority});
+
+  factory ConnectionCandidate.fromJson(Map<String, dynamic> json) {
+    return ConnectionCandidate(
+      addr: json['addr'] as String,
+      kind: json['kind'] as String? ?? '',
+      priority: json['priority'] as int? ?? 0,
+    );
+  }
+}
+
 class PairingPayload {
   final String desktopPublicKey; // base64url(no pad), 32 bytes
   final String relayId;
@@ -26,7 +59,10 @@ class PairingPayload {
   final String? externalAddr;
   final String? relayUrl;
   final String? relaySecret;
+  final String? relayCertSha256; // pins the relay's TLS certificate
   final List<String> capabilities;
+  final List<ConnectionCandidate> candidates;
+  final String? pairingToken; // one-time, signed into the PairingRequest
 
   const PairingPayload({
     required this.desktopPublicKey,
@@ -37,9 +73,23 @@ class PairingPayload {
     this.externalAddr,
     this.relayUrl,
     this.relaySecret,
+    this.relayCertSha256,
     this.capabilities = const [],
+    this.candidates = const [],
+    this.pairingToken,
   });
 
+  /// Addresses to try, best first, including [lanAddr] and [externalAddr]
+  /// from desktops that predate [candidates]
+  List<String> get candidateAddrs {
+    final sorted = [...candidates]..sort((a, b) => b.priority.compareTo(a.priority));
+    final addrs = sorted.map((c) => c.addr).toList();
+    for (final addr in [lanAddr, externalAddr]) {
+      if (addr != null && addr.isNotEmpty && !addrs.contains(addr)) addrs.add(addr);
+    }
+    return addrs;
+  }
+
   /// Whether this payload supports relay fallback
   bool get supportsRelay => relayUrl != null && relayUrl!.isNotEmpty;
   
@@ -59,7 +109,14 @@ class PairingPayload {
       externalAddr: json['external_addr'] as String?,
       relayUrl: json['relay_url'] as String?,
       relaySecret: json['relay_secret'] as String?,
+      relayCertSha256: json['relay_cert_sha256'] as String?,
       capabilities: (json['capabilities'] as List?)?.whereType<String>().toList() ?? const [],
+      candidates: (json['candidates'] as List?)
+              ?.whereType<Map<String, dynamic>>()
+              .map(ConnectionCandidate.fromJson)
+              .toList() ??
+          const [],
+      pairingToken: json['pairing_token'] as String?,
     );
   }
 }
@@ -70,6 +127,7 @@ class PairingRequest {
   final String userEmail;
   final String deviceName;
   final int timestamp; // unix seconds
+  final String? pairingToken; // from the scanned PairingPayload
 
   const PairingRequest({
     required this.mobilePublicKey,
@@ -77,6 +135,7 @@ class PairingRequest {
     required this.userEmail,
     required this.deviceName,
     required this.timestamp,
+    this.pairingToken,
   });
 
   Map<String, Object?> toJson() => {
@@ -85,6 +144,7 @@ class PairingRequest {
         'user_email': userEmail,
         'device_name': deviceName,
         'timestamp': timestamp,
+        if (pairingToken != null) 'pairing_token': pairingToken,
       };
 }
 
@@ -145,9 +205,19 @@ class AuthResponse {
 class AuthSuccess {
   final String? signature;
 
-  const AuthSuccess({this.signature});
+  /// Host's ephemeral X25519 key, answering ours
+  final String? ephemeralKey;
+
+  /// Host identity signature over the key exchange transcript
+  final String? keySignature;
+
+  const AuthSuccess({this.signature, this.ephemeralKey, this.keySignature});
 
   factory AuthSuccess.fromJson(Map<String, dynamic> json) {
-    return AuthSuccess(signature: json['signature'] as String?);
+    return AuthSuccess(
+      signature: json['signature'] as String?,
+      ephemeralKey: json['ephemeral_key'] as String?,
+      keySignature: json['key_signature'] as String?,
+    );
   }
 }
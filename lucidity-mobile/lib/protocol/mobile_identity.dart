@@ -1,3 +1,4 @@
+import 'dart:convert';
 import 'dart:typed_data';
 
 import 'package:cryptography/cryptography.dart';
@@ -45,17 +46,21 @@ class MobileIdentity {
     return Base64UrlNoPad.encode(hash.bytes);
   }
 
+  /// Sign (desktopPublicKey || timestamp || pairingToken) for a
+  /// PairingRequest
   Future<Uint8List> signDesktopKeyAndTimestamp({
     required SimpleKeyPairData identity,
     required Uint8List desktopPublicKey,
     required int timestampSeconds,
+    String? pairingToken,
   }) async {
     final ts = ByteData(8)..setInt64(0, timestampSeconds, Endian.little);
-    final msg = Uint8List(desktopPublicKey.length + 8);
-    msg.setRange(0, desktopPublicKey.length, desktopPublicKey);
-    msg.setRange(desktopPublicKey.length, msg.length, ts.buffer.asUint8List());
+    final msg = BytesBuilder(copy: false)
+      ..add(desktopPublicKey)
+      ..add(ts.buffer.asUint8List());
+    if (pairingToken != null) msg.add(utf8.encode(pairingToken));
 
-    return sign(identity, msg);
+    return sign(identity, msg.takeBytes());
   }
 
   Future<Uint8List> sign(SimpleKeyPairData identity, List<int> message) async {
@@ -65,5 +70,20 @@ class MobileIdentity {
     );
     return Uint8List.fromList(sig.bytes);
   }
+
+  /// Check an Ed25519 [signature] by [publicKey] over [message]
+  Future<bool> verify(
+    List<int> publicKey,
+    List<int> message,
+    List<int> signature,
+  ) {
+    return _algo.verify(
+      message,
+      signature: Signature(
+        signature,
+        publicKey: SimplePublicKey(publicKey, type: KeyPairType.ed25519),
+      ),
+    );
+  }
 }
 
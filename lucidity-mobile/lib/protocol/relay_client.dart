@@ -1,7 +1,10 @@
 import 'dart:async';
+import 'dart:io';
 import 'dart:typed_data';
 
+import 'package:cryptography/dart.dart';
 import 'package:flutter/foundation.dart';
+import 'package:web_socket_channel/io.dart';
 import 'package:web_socket_channel/web_socket_channel.dart';
 
 /// WebSocket-based relay client for connecting through the Lucidity relay server.
@@ -11,6 +14,10 @@ class RelayClient extends ChangeNotifier {
   final String relayUrl;
   final String relayId;
   final String? relaySecret;
+
+  /// SHA-256 fingerprint the relay's certificate must have. When set, it
+  /// stands in for the CA and hostname checks of a wss:// relay.
+  final String? relayCertSha256;
   
   WebSocketChannel? _channel;
   RelayStatus _status = RelayStatus.disconnected;
@@ -22,6 +29,7 @@ class RelayClient extends ChangeNotifier {
     required this.relayUrl,
     required this.relayId,
     this.relaySecret,
+    this.relayCertSha256,
   });
   
   /// Current connection status
@@ -48,7 +56,7 @@ class RelayClient extends ChangeNotifier {
       final wsUrl = _buildWebSocketUrl();
       debugPrint('[RelayClient] Connecting to relay: $wsUrl');
       
-      final channel = WebSocketChannel.connect(Uri.parse(wsUrl));
+      final channel = _open(Uri.parse(wsUrl));
       
       // Wait for connection to be ready (optional, but good for verification)
       await channel.ready;
@@ -68,6 +76,26 @@ class RelayClient extends ChangeNotifier {
     }
   }
   
+  /// Open the WebSocket. A pinned relay's certificate is checked during the
+  /// TLS handshake, before the request carrying the secret is sent.
+  WebSocketChannel _open(Uri uri) {
+    final pinned = relayCertSha256;
+    if (pinned == null || pinned.isEmpty || uri.scheme != 'wss') {
+      return WebSocketChannel.connect(uri);
+    }
+    // Trusting no roots sends every certificate to the callback, so the
+    // pin is the only check, as on the desktop
+    final client = HttpClient(context: SecurityContext(withTrustedRoots: false))
+      ..badCertificateCallback = (cert, host, port) {
+        final matches = certMatches(cert.der, pinned);
+        if (!matches) {
+          debugPrint('[RelayClient] Relay certificate ${certFingerprint(cert.der)} is not the pinned $pinned');
+        }
+        return matches;
+      };
+    return IOWebSocketChannel.connect(uri, customClient: client);
+  }
+
   /// Build the WebSocket URL for mobile client connection
   String _buildWebSocketUrl() {
     // Ensure we're using ws:// or wss:// scheme
@@ -181,6 +209,18 @@ class RelayClient extends ChangeNotifier {
   }
 }
 
+/// Hex SHA-256 of a DER certificate, as the desktop writes fingerprints
+String certFingerprint(List<int> der) {
+  final hash = const DartSha256().hashSync(der);
+  return hash.bytes.map((b) => b.toRadixString(16).padLeft(2, '0')).join();
+}
+
+/// Whether [der] is the certificate [pinned] identifies. Colons and case
+/// in [pinned] are ignored, so OpenSSL-style fingerprints match too.
+bool certMatches(List<int> der, String pinned) {
+  return certFingerprint(der) == pinned.replaceAll(':', '').toLowerCase();
+}
+
 /// Relay connection status
 enum RelayStatus {
   disconnected,
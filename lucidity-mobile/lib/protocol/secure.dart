@@ -0,0 +1,164 @@
+import 'dart:convert';
+import 'dart:typed_data';
+
+import 'package:cryptography/cryptography.dart';
+
+import 'frame.dart';
+
+// Device side of the end-to-end encrypted session.
+//
+// Mirrors `lucidity-proto/src/secure.rs`: the device signs its ephemeral
+// X25519 key together with the host's challenge, the host signs the whole
+// transcript, and both derive one ChaCha20-Poly1305 key per direction with
+// HKDF-SHA256 salted with the transcript hash. Every later frame is sealed
+// as an 8-byte little-endian record counter followed by the ciphertext of
+// `type || payload`.
+
+const _deviceAuthLabel = 'lucidity-auth-v2\u0000';
+const _transcriptLabel = 'lucidity-kx-v1\u0000';
+const _deviceToHostInfo = 'lucidity device->host';
+const _hostToDeviceInfo = 'lucidity host->device';
+const _counterLen = 8;
+
+/// The message the device signs in its `auth_response`, binding its
+/// ephemeral key to the host's challenge.
+Uint8List deviceAuthMessage(String nonce, List<int> deviceEphemeral) {
+  return (BytesBuilder(copy: false)
+        ..add(utf8.encode(_deviceAuthLabel))
+        ..add(utf8.encode(nonce))
+        ..add(deviceEphemeral))
+      .takeBytes();
+}
+
+/// Everything both sides agreed on; the host signs these bytes with its
+/// identity key in `auth_success`.
+Uint8List transcriptBytes({
+  required String nonce,
+  required List<int> deviceKey,
+  required List<int> hostKey,
+  required List<int> deviceEphemeral,
+  required List<int> hostEphemeral,
+}) {
+  final nonceBytes = utf8.encode(nonce);
+  final nonceLen = ByteData(4)..setUint32(0, nonceBytes.length, Endian.little);
+  return (BytesBuilder(copy: false)
+        ..add(utf8.encode(_transcriptLabel))
+        ..add(nonceLen.buffer.asUint8List())
+        ..add(nonceBytes)
+        ..add(deviceKey)
+        ..add(hostKey)
+        ..add(deviceEphemeral)
+        ..add(hostEphemeral))
+      .takeBytes();
+}
+
+/// The device's half of an X25519 key exchange.
+class KeyExchange {
+  static final _x25519 = X25519();
+
+  final SimpleKeyPair _keyPair;
+  final Uint8List publicKey;
+
+  KeyExchange._(this._keyPair, this.publicKey);
+
+  static Future<KeyExchange> create() async {
+    final keyPair = await _x25519.newKeyPair();
+    final publicKey = await keyPair.extractPublicKey();
+    return KeyExchange._(keyPair, Uint8List.fromList(publicKey.bytes));
+  }
+
+  /// Derive the ciphers for talking to the host.
+  Future<(FrameSealer, FrameOpener)> finish(
+    List<int> hostEphemeral,
+    Uint8List transcript,
+  ) async {
+    final shared = await _x25519.sharedSecretKey(
+      keyPair: _keyPair,
+      remotePublicKey: SimplePublicKey(hostEphemeral, type: KeyPairType.x25519),
+    );
+    final sharedBytes = await shared.extractBytes();
+    if (sharedBytes.every((b) => b == 0)) {
+      throw StateError('invalid ephemeral key');
+    }
+
+    final salt = await Sha256().hash(transcript);
+    final hkdf = Hkdf(hmac: Hmac.sha256(), outputLength: 32);
+    Future<SecretKey> expand(String info) => hkdf.deriveKey(
+          secretKey: SecretKey(sharedBytes),
+          nonce: salt.bytes,
+          info: utf8.encode(info),
+        );
+    final send = await expand(_deviceToHostInfo);
+    final recv = await expand(_hostToDeviceInfo);
+    return (FrameSealer._(send), FrameOpener._(recv));
+  }
+}
+
+final _aead = Chacha20.poly1305Aead();
+
+List<int> _recordNonce(int counter) {
+  final nonce = ByteData(12)..setUint64(4, counter, Endian.little);
+  return nonce.buffer.asUint8List();
+}
+
+/// Seals outgoing frames. Counters are taken when `seal` is called, so
+/// records must be sent in the order they were sealed.
+class FrameSealer {
+  final SecretKey _key;
+  int _counter = 0;
+
+  FrameSealer._(this._key);
+
+  /// The payload of a sealed record carrying [type] and [payload].
+  Future<Uint8List> seal(int type, List<int> payload) async {
+    final counter = _counter++;
+    final aad = ByteData(_counterLen)..setUint64(0, counter, Endian.little);
+    final box = await _aead.encrypt(
+      [type & 0xff, ...payload],
+      secretKey: _key,
+      nonce: _recordNonce(counter),
+      aad: aad.buffer.asUint8List(),
+    );
+    return (BytesBuilder(copy: false)
+          ..add(aad.buffer.asUint8List())
+          ..add(box.cipherText)
+          ..add(box.mac.bytes))
+        .takeBytes();
+  }
+}
+
+/// Opens incoming sealed records, which must arrive in order.
+class FrameOpener {
+  final SecretKey _key;
+  int _counter = 0;
+
+  FrameOpener._(this._key);
+
+  Future<Frame> open(Uint8List sealed) async {
+    final macLen = _aead.macAlgorithm.macLength;
+    if (sealed.length < _counterLen + macLen + 1) {
+      throw StateError('sealed record too short');
+    }
+    final aad = Uint8List.sublistView(sealed, 0, _counterLen);
+    final got = ByteData.sublistView(aad).getUint64(0, Endian.little);
+    if (got != _counter) {
+      throw StateError('sealed record counter $got does not match expected $_counter');
+    }
+
+    final macStart = sealed.length - macLen;
+    final plaintext = await _aead.decrypt(
+      SecretBox(
+        Uint8List.sublistView(sealed, _counterLen, macStart),
+        nonce: _recordNonce(got),
+        mac: Mac(Uint8List.sublistView(sealed, macStart)),
+      ),
+      secretKey: _key,
+      aad: aad,
+    );
+    _counter = got + 1;
+    return Frame(
+      type: plaintext[0],
+      payload: Uint8List.fromList(plaintext.sublist(1)),
+    );
+  }
+}
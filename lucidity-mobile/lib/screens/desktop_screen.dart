@@ -344,11 +344,13 @@ class _TerminalTabViewState extends State<_TerminalTabView> {
       await _client.connectWithStrategy(
         identity: identity,
         desktopPublicKey: d.desktopPublicKey,
+        candidates: d.candidateAddrs,
         lanAddr: d.lanAddr ?? (d.host.isNotEmpty ? '${d.host}:${d.port}' : null),
         externalAddr: d.externalAddr,
         relayUrl: d.relayUrl,
         relayId: d.relayId,
         relaySecret: d.relaySecret,
+        relayCertSha256: d.relayCertSha256,
       );
       // Panes are loaded automatically by client now
       final panes = await _client.listPanesOnce();
@@ -99,10 +99,12 @@ class _PairingScreenState extends State<PairingScreen> {
       await client.connectWithStrategy(
         identity: keypair,
         desktopPublicKey: widget.payload.desktopPublicKey,
+        candidates: widget.payload.candidateAddrs,
         lanAddr: widget.payload.lanAddr,
         externalAddr: widget.payload.externalAddr,
         relayUrl: widget.payload.relayUrl,
         relayId: widget.payload.relayId,
+        relayCertSha256: widget.payload.relayCertSha256,
       );
 
       final ts = DateTime.now().millisecondsSinceEpoch ~/ 1000;
@@ -111,6 +113,7 @@ class _PairingScreenState extends State<PairingScreen> {
         identity: keypair,
         desktopPublicKey: desktopPub,
         timestampSeconds: ts,
+        pairingToken: widget.payload.pairingToken,
       );
 
       final req = PairingRequest(
@@ -119,6 +122,7 @@ class _PairingScreenState extends State<PairingScreen> {
         userEmail: email,
         deviceName: deviceName,
         timestamp: ts,
+        pairingToken: widget.payload.pairingToken,
       );
 
       setState(() => _status = 'Waiting for approval on desktop...');
@@ -25,5 +25,21 @@ void main() {
     expect(decoded.createdAtSeconds, d.createdAtSeconds);
     expect(decoded.lastConnectedAtSeconds, d.lastConnectedAtSeconds);
   });
-}
 
+  test('DesktopProfile keeps candidate addresses', () {
+    const d = DesktopProfile(
+      id: 'id-1',
+      displayName: 'My Desktop',
+      host: '',
+      port: 0,
+      desktopPublicKey: 'abcd',
+      relayId: 'relay',
+      candidateAddrs: ['192.168.1.5:9797', '[2001:db8::5]:9797'],
+      createdAtSeconds: 1700000000,
+      lastConnectedAtSeconds: null,
+    );
+
+    final decoded = DesktopProfile.fromJson(d.toJson());
+    expect(decoded.candidateAddrs, d.candidateAddrs);
+  });
+}
@@ -4,6 +4,9 @@ import 'package:flutter_test/flutter_test.dart';
 
 import 'package:lucidity_mobile/protocol/constants.dart';
 import 'package:lucidity_mobile/protocol/frame.dart';
+import 'package:lucidity_mobile/protocol/messages.dart';
+import 'package:lucidity_mobile/protocol/relay_client.dart';
+import 'package:lucidity_mobile/protocol/secure.dart';
 
 void main() {
   test('encodeFrame + FrameDecoder roundtrip (single chunk)', () {
@@ -51,5 +54,63 @@ void main() {
     dec.push(bad);
     expect(() => dec.nextFrame(), throwsStateError);
   });
-}
 
+  test('splitHostPort handles IPv4 and bracketed IPv6', () {
+    expect(splitHostPort('192.168.1.5:9797'), (host: '192.168.1.5', port: 9797));
+    expect(splitHostPort('[2001:db8::5]:9797'), (host: '2001:db8::5', port: 9797));
+    expect(splitHostPort('2001:db8::5'), isNull);
+    expect(splitHostPort('no-port'), isNull);
+  });
+
+  test('PairingPayload orders candidate addresses by priority', () {
+    final payload = PairingPayload.fromJson({
+      'desktop_public_key': 'key',
+      'relay_id': 'relay',
+      'timestamp': 0,
+      'version': 2,
+      'lan_addr': '192.168.1.5:9797',
+      'external_addr': '203.0.113.5:40000',
+      'candidates': [
+        {'addr': '203.0.113.5:40000', 'kind': 'mapped', 'priority': 200},
+        {'addr': '[2001:db8::5]:9797', 'kind': 'ipv6', 'priority': 300},
+      ],
+    });
+    expect(payload.candidateAddrs, [
+      '[2001:db8::5]:9797',
+      '203.0.113.5:40000',
+      '192.168.1.5:9797',
+    ]);
+  });
+
+  test('key exchange transcript matches the host layout', () {
+    final transcript = transcriptBytes(
+      nonce: 'abc',
+      deviceKey: List.filled(32, 1),
+      hostKey: List.filled(32, 2),
+      deviceEphemeral: List.filled(32, 3),
+      hostEphemeral: List.filled(32, 4),
+    );
+    final label = 'lucidity-kx-v1\u0000'.codeUnits;
+    expect(transcript.length, label.length + 4 + 3 + 4 * 32);
+    expect(transcript.sublist(0, label.length), label);
+    expect(transcript.sublist(label.length, label.length + 7), [3, 0, 0, 0, 97, 98, 99]);
+    expect(transcript.sublist(transcript.length - 32), List.filled(32, 4));
+
+    final auth = deviceAuthMessage('abc', List.filled(32, 3));
+    expect(auth.sublist(0, 17), 'lucidity-auth-v2\u0000'.codeUnits);
+    expect(auth.length, 17 + 3 + 32);
+  });
+
+  test('relay certificate pins ignore separators and case', () {
+    final der = 'abc'.codeUnits;
+    const hex = 'ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad';
+    expect(certFingerprint(der), hex);
+    expect(certMatches(der, hex), isTrue);
+
+    final openssl = [
+      for (var i = 0; i < hex.length; i += 2) hex.substring(i, i + 2).toUpperCase(),
+    ].join(':');
+    expect(certMatches(der, openssl), isTrue);
+    expect(certMatches('abd'.codeUnits, hex), isFalse);
+  });
+}
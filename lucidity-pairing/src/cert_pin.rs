@@ -0,0 +1,47 @@
+//! Certificate pinning for the relay's TLS connections.
+//!
+//! A certificate is identified by the SHA-256 of its DER encoding, written
+//! as lowercase hex. Fingerprints may be given with `:` separators and in
+//! either case, as `openssl x509 -fingerprint -sha256` prints them.
+
+use sha2::{Digest, Sha256};
+
+/// The fingerprint of a DER encoded certificate
+pub fn cert_fingerprint(der: &[u8]) -> String {
+    hex::encode(Sha256::digest(der))
+}
+
+/// `fingerprint` as `cert_fingerprint` would write it
+pub fn normalize_fingerprint(fingerprint: &str) -> String {
+    fingerprint
+        .chars()
+        .filter(|c| *c != ':')
+        .collect::<String>()
+        .to_ascii_lowercase()
+}
+
+/// Whether `der` is the certificate `pinned` identifies
+pub fn cert_matches(der: &[u8], pinned: &str) -> bool {
+    cert_fingerprint(der) == normalize_fingerprint(pinned)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fingerprints_ignore_separators_and_case() {
+        let der = b"not really a certificate";
+        let fingerprint = cert_fingerprint(der);
+        assert_eq!(fingerprint.len(), 64);
+        let openssl_style = fingerprint
+            .to_ascii_uppercase()
+            .as_bytes()
+            .chunks(2)
+            .map(|pair| std::str::from_utf8(pair).unwrap())
+            .collect::<Vec<_>>()
+            .join(":");
+        assert!(cert_matches(der, &openssl_style));
+        assert!(!cert_matches(b"another certificate", &openssl_style));
+    }
+}
@@ -2,6 +2,7 @@ use crate::PublicKey;
 use anyhow::Result;
 use rusqlite::{params, Connection};
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 
 /// A trusted mobile device
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -16,6 +17,104 @@ pub struct TrustedDevice {
     pub paired_at: i64,
     /// Last time device connected (unix timestamp)
     pub last_seen: Option<i64>,
+    /// What the device may do on the host
+    #[serde(default)]
+    pub scopes: DeviceScopes,
+}
+
+/// What a trusted device may do on the host. A device can always watch the
+/// panes it may see.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DeviceScopes {
+    /// Type, paste, click and resize in the panes it may see
+    pub input: bool,
+    /// Read and set the host's clipboard
+    pub clipboard: bool,
+    /// Spawn, kill and rearrange panes, make pairing payloads, and list and
+    /// revoke other devices
+    pub admin: bool,
+    /// The only panes it may see, by id; empty for any
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub panes: Vec<usize>,
+    /// The only domains whose panes it may see; empty for any
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub domains: Vec<String>,
+    /// The only workspaces whose panes it may see; empty for any
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub workspaces: Vec<String>,
+}
+
+impl DeviceScopes {
+    /// Everything, as devices paired before scopes existed may do
+    pub fn full() -> Self {
+        Self {
+            input: true,
+            clipboard: true,
+            admin: true,
+            panes: vec![],
+            domains: vec![],
+            workspaces: vec![],
+        }
+    }
+
+    /// Watching, and nothing else
+    pub fn view_only() -> Self {
+        Self {
+            input: false,
+            clipboard: false,
+            admin: false,
+            ..Self::full()
+        }
+    }
+
+    /// Whether some panes are out of sight
+    pub fn restricts_panes(&self) -> bool {
+        !(self.panes.is_empty() && self.domains.is_empty() && self.workspaces.is_empty())
+    }
+
+    /// Whether the pane `pane_id`, in `domain` and `workspace`, may be seen
+    pub fn allows_pane(
+        &self,
+        pane_id: usize,
+        domain: Option<&str>,
+        workspace: Option<&str>,
+    ) -> bool {
+        fn listed(list: &[String], value: Option<&str>) -> bool {
+            list.is_empty() || value.is_some_and(|value| list.iter().any(|v| v == value))
+        }
+        (self.panes.is_empty() || self.panes.contains(&pane_id))
+            && listed(&self.domains, domain)
+            && listed(&self.workspaces, workspace)
+    }
+}
+
+impl Default for DeviceScopes {
+    fn default() -> Self {
+        Self::full()
+    }
+}
+
+fn device_from_row(row: &rusqlite::Row) -> rusqlite::Result<TrustedDevice> {
+    let public_key_bytes: Vec<u8> = row.get(0)?;
+    let mut public_key_arr = [0u8; 32];
+    public_key_arr.copy_from_slice(&public_key_bytes);
+
+    // Devices paired before scopes existed have none stored
+    let scopes = match row.get::<_, Option<String>>(5)? {
+        Some(json) => serde_json::from_str(&json).map_err(|e| {
+            rusqlite::Error::FromSqlConversionFailure(5, rusqlite::types::Type::Text, e.into())
+        })?,
+        None => DeviceScopes::full(),
+    };
+
+    Ok(TrustedDevice {
+        public_key: PublicKey::from_bytes(public_key_arr),
+        user_email: row.get(1)?,
+        device_name: row.get(2)?,
+        paired_at: row.get(3)?,
+        last_seen: row.get(4)?,
+        scopes,
+    })
 }
 
 /// Device trust store backed by SQLite
@@ -26,8 +125,15 @@ pub struct DeviceTrustStore {
 impl DeviceTrustStore {
     /// Open or create a device trust store at the given path
     pub fn open(path: impl AsRef<std::path::Path>) -> Result<Self> {
-        let conn = Connection::open(path)?;
+        Self::init(Connection::open(path)?)
+    }
 
+    /// Create an in-memory device trust store (for testing)
+    pub fn in_memory() -> Result<Self> {
+        Self::init(Connection::open_in_memory()?)
+    }
+
+    fn init(conn: Connection) -> Result<Self> {
         // Create tables if they don't exist
         conn.execute(
             "CREATE TABLE IF NOT EXISTS trusted_devices (
@@ -35,25 +141,24 @@ impl DeviceTrustStore {
                 user_email TEXT NOT NULL,
                 device_name TEXT NOT NULL,
                 paired_at INTEGER NOT NULL,
-                last_seen INTEGER
+                last_seen INTEGER,
+                scopes TEXT
             )",
             [],
         )?;
-
-        Ok(Self { conn })
-    }
-
-    /// Create an in-memory device trust store (for testing)
-    pub fn in_memory() -> Result<Self> {
-        let conn = Connection::open_in_memory()?;
-
+        let has_scopes: i64 = conn.query_row(
+            "SELECT COUNT(*) FROM pragma_table_info('trusted_devices') WHERE name = 'scopes'",
+            [],
+            |row| row.get(0),
+        )?;
+        if has_scopes == 0 {
+            conn.execute("ALTER TABLE trusted_devices ADD COLUMN scopes TEXT", [])?;
+        }
+        // Pairing tokens that have been used, by their SHA-256
         conn.execute(
-            "CREATE TABLE trusted_devices (
-                public_key BLOB PRIMARY KEY,
-                user_email TEXT NOT NULL,
-                device_name TEXT NOT NULL,
-                paired_at INTEGER NOT NULL,
-                last_seen INTEGER
+            "CREATE TABLE IF NOT EXISTS used_pairing_tokens (
+                token_sha256 TEXT PRIMARY KEY,
+                used_at INTEGER NOT NULL
             )",
             [],
         )?;
@@ -65,14 +170,15 @@ impl DeviceTrustStore {
     pub fn add_device(&self, device: &TrustedDevice) -> Result<()> {
         self.conn.execute(
             "INSERT OR REPLACE INTO trusted_devices 
-             (public_key, user_email, device_name, paired_at, last_seen)
-             VALUES (?1, ?2, ?3, ?4, ?5)",
+             (public_key, user_email, device_name, paired_at, last_seen, scopes)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
             params![
                 device.public_key.as_bytes().as_slice(),
                 &device.user_email,
                 &device.device_name,
                 device.paired_at,
                 device.last_seen,
+                serde_json::to_string(&device.scopes)?,
             ],
         )?;
         Ok(())
@@ -81,7 +187,7 @@ impl DeviceTrustStore {
     /// Get a trusted device by public key
     pub fn get_device(&self, public_key: &PublicKey) -> Result<Option<TrustedDevice>> {
         let mut stmt = self.conn.prepare(
-            "SELECT public_key, user_email, device_name, paired_at, last_seen
+            "SELECT public_key, user_email, device_name, paired_at, last_seen, scopes
              FROM trusted_devices
              WHERE public_key = ?1",
         )?;
@@ -89,17 +195,7 @@ impl DeviceTrustStore {
         let mut rows = stmt.query(params![public_key.as_bytes().as_slice()])?;
 
         if let Some(row) = rows.next()? {
-            let public_key_bytes: Vec<u8> = row.get(0)?;
-            let mut public_key_arr = [0u8; 32];
-            public_key_arr.copy_from_slice(&public_key_bytes);
-
-            Ok(Some(TrustedDevice {
-                public_key: PublicKey::from_bytes(public_key_arr),
-                user_email: row.get(1)?,
-                device_name: row.get(2)?,
-                paired_at: row.get(3)?,
-                last_seen: row.get(4)?,
-            }))
+            Ok(Some(device_from_row(row)?))
         } else {
             Ok(None)
         }
@@ -113,24 +209,12 @@ impl DeviceTrustStore {
     /// List all trusted devices
     pub fn list_devices(&self) -> Result<Vec<TrustedDevice>> {
         let mut stmt = self.conn.prepare(
-            "SELECT public_key, user_email, device_name, paired_at, last_seen
+            "SELECT public_key, user_email, device_name, paired_at, last_seen, scopes
              FROM trusted_devices
              ORDER BY paired_at DESC",
         )?;
 
-        let rows = stmt.query_map([], |row| {
-            let public_key_bytes: Vec<u8> = row.get(0)?;
-            let mut public_key_arr = [0u8; 32];
-            public_key_arr.copy_from_slice(&public_key_bytes);
-
-            Ok(TrustedDevice {
-                public_key: PublicKey::from_bytes(public_key_arr),
-                user_email: row.get(1)?,
-                device_name: row.get(2)?,
-                paired_at: row.get(3)?,
-                last_seen: row.get(4)?,
-            })
-        })?;
+        let rows = stmt.query_map([], device_from_row)?;
 
         let mut devices = Vec::new();
         for device in rows {
@@ -157,6 +241,37 @@ impl DeviceTrustStore {
         Ok(rows_affected > 0)
     }
 
+    /// Record that a pairing token has been used, returning false if it
+    /// already had been
+    pub fn use_pairing_token(&self, token: &str, used_at: i64) -> Result<bool> {
+        let rows_affected = self.conn.execute(
+            "INSERT OR IGNORE INTO used_pairing_tokens (token_sha256, used_at)
+             VALUES (?1, ?2)",
+            params![hex::encode(Sha256::digest(token.as_bytes())), used_at],
+        )?;
+        Ok(rows_affected > 0)
+    }
+
+    /// Whether a pairing token has been used
+    pub fn is_pairing_token_used(&self, token: &str) -> Result<bool> {
+        let used: i64 = self.conn.query_row(
+            "SELECT COUNT(*) FROM used_pairing_tokens WHERE token_sha256 = ?1",
+            params![hex::encode(Sha256::digest(token.as_bytes()))],
+            |row| row.get(0),
+        )?;
+        Ok(used > 0)
+    }
+
+    /// Count used pairing tokens
+    pub fn count_used_pairing_tokens(&self) -> Result<usize> {
+        let count: i64 =
+            self.conn
+                .query_row("SELECT COUNT(*) FROM used_pairing_tokens", [], |row| {
+                    row.get(0)
+                })?;
+        Ok(count as usize)
+    }
+
     /// Count trusted devices
     pub fn count_devices(&self) -> Result<usize> {
         let count: i64 =
@@ -182,6 +297,7 @@ mod tests {
             device_name: "Test Device".to_string(),
             paired_at: chrono::Utc::now().timestamp(),
             last_seen: None,
+            scopes: DeviceScopes::full(),
         };
 
         // Add device
@@ -208,6 +324,75 @@ mod tests {
         assert!(!store.is_trusted(&device.public_key).unwrap());
     }
 
+    #[test]
+    fn scopes_are_stored() {
+        let store = DeviceTrustStore::in_memory().unwrap();
+        let keypair = Keypair::generate();
+        let scopes = DeviceScopes {
+            workspaces: vec!["builds".to_string()],
+            ..DeviceScopes::view_only()
+        };
+        store
+            .add_device(&TrustedDevice {
+                public_key: keypair.public_key(),
+                user_email: "teammate@example.com".to_string(),
+                device_name: "Tablet".to_string(),
+                paired_at: 1000,
+                last_seen: None,
+                scopes: scopes.clone(),
+            })
+            .unwrap();
+        let device = store.get_device(&keypair.public_key()).unwrap().unwrap();
+        assert_eq!(device.scopes, scopes);
+
+        assert!(device.scopes.restricts_panes());
+        assert!(device.scopes.allows_pane(3, Some("local"), Some("builds")));
+        assert!(!device.scopes.allows_pane(3, Some("local"), Some("default")));
+        assert!(!device.scopes.allows_pane(3, Some("local"), None));
+        assert!(!DeviceScopes::full().restricts_panes());
+    }
+
+    #[test]
+    fn devices_paired_before_scopes_keep_full_access() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("devices.db");
+        let keypair = Keypair::generate();
+        {
+            let conn = Connection::open(&path).unwrap();
+            conn.execute(
+                "CREATE TABLE trusted_devices (
+                    public_key BLOB PRIMARY KEY,
+                    user_email TEXT NOT NULL,
+                    device_name TEXT NOT NULL,
+                    paired_at INTEGER NOT NULL,
+                    last_seen INTEGER
+                )",
+                [],
+            )
+            .unwrap();
+            conn.execute(
+                "INSERT INTO trusted_devices VALUES (?1, 'old@example.com', 'Old Phone', 1000, NULL)",
+                params![keypair.public_key().as_bytes().as_slice()],
+            )
+            .unwrap();
+        }
+
+        let store = DeviceTrustStore::open(&path).unwrap();
+        let device = store.get_device(&keypair.public_key()).unwrap().unwrap();
+        assert_eq!(device.scopes, DeviceScopes::full());
+    }
+
+    #[test]
+    fn pairing_tokens_are_used_once() {
+        let store = DeviceTrustStore::in_memory().unwrap();
+        assert!(store.use_pairing_token("token", 1000).unwrap());
+        assert!(!store.use_pairing_token("token", 1001).unwrap());
+        assert!(store.use_pairing_token("another", 1002).unwrap());
+        assert!(store.is_pairing_token_used("token").unwrap());
+        assert!(!store.is_pairing_token_used("unused").unwrap());
+        assert_eq!(store.count_used_pairing_tokens().unwrap(), 2);
+    }
+
     #[test]
     fn list_devices_ordered() {
         let store = DeviceTrustStore::in_memory().unwrap();
@@ -221,6 +406,7 @@ mod tests {
                 device_name: format!("Device {}", i),
                 paired_at: 1000 + i,
                 last_seen: None,
+                scopes: DeviceScopes::full(),
             };
             store.add_device(&device).unwrap();
         }
@@ -2,6 +2,7 @@ use anyhow::Result;
 use base64::Engine;
 use ed25519_dalek::{Signer, Verifier};
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 
 /// Ed25519 public key for device identity
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
@@ -82,6 +83,12 @@ impl PublicKey {
         format!("{prefix}…{suffix}")
     }
 
+    /// SHA-256 of the key as lowercase hex, to identify it without
+    /// publishing it.
+    pub fn fingerprint(&self) -> String {
+        hex::encode(Sha256::digest(self.0))
+    }
+
     pub fn from_bytes(bytes: [u8; 32]) -> Self {
         Self(bytes)
     }
@@ -122,7 +129,7 @@ impl Signature {
 }
 
 // Helper module for base64 serialization
-mod base64_serde {
+pub(crate) mod base64_serde {
     use base64::Engine;
     use serde::{Deserialize, Deserializer, Serializer};
 
@@ -1,11 +1,20 @@
+mod cert_pin;
 mod device_trust;
 mod keypair;
 mod keypair_store;
 mod pairing;
 mod qr;
+mod short_code;
 
-pub use device_trust::{DeviceTrustStore, TrustedDevice};
+pub use cert_pin::{cert_fingerprint, cert_matches, normalize_fingerprint};
+pub use device_trust::{DeviceScopes, DeviceTrustStore, TrustedDevice};
 pub use keypair::{Keypair, PublicKey, Signature};
 pub use keypair_store::KeypairStore;
-pub use pairing::{PairingPayload, PairingRequest, PairingResponse};
+pub use pairing::{
+    CandidateKind, ConnectionCandidate, PairingPayload, PairingRequest, PairingResponse,
+};
 pub use qr::{generate_pairing_qr, generate_pairing_qr_ascii, pairing_url, parse_pairing_url};
+pub use short_code::{
+    ShortCode, ShortCodeChallenge, ShortCodeClient, ShortCodeConfirm, ShortCodeHello,
+    ShortCodeHost, CODE_TTL_SECS, MAX_ATTEMPTS,
+};
@@ -1,6 +1,66 @@
 use crate::{PublicKey, Signature};
 use anyhow::Result;
 use serde::{Deserialize, Serialize};
+use std::cmp::Reverse;
+use std::net::SocketAddr;
+
+/// How a candidate address reaches the desktop
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CandidateKind {
+    /// The desktop's address on its LAN
+    Lan,
+    /// A global IPv6 address, reachable without NAT where the firewall
+    /// allows
+    Ipv6,
+    /// A port mapped on the router, by UPnP, PCP or NAT-PMP
+    Mapped,
+    /// The public address, reachable only if the port is forwarded by hand
+    Public,
+    /// A kind added by a newer desktop
+    #[serde(other)]
+    Unknown,
+}
+
+impl CandidateKind {
+    /// The default priority of candidates of this kind; higher is tried
+    /// first
+    pub fn priority(self) -> u32 {
+        match self {
+            Self::Lan => 400,
+            Self::Ipv6 => 300,
+            Self::Mapped => 200,
+            Self::Public => 100,
+            Self::Unknown => 0,
+        }
+    }
+}
+
+/// An address the desktop may be reachable at
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ConnectionCandidate {
+    /// `host:port`, with IPv6 hosts in brackets
+    pub addr: String,
+    pub kind: CandidateKind,
+    /// Higher is tried first
+    pub priority: u32,
+}
+
+impl ConnectionCandidate {
+    pub fn new(kind: CandidateKind, addr: SocketAddr) -> Self {
+        Self {
+            addr: addr.to_string(),
+            kind,
+            priority: kind.priority(),
+        }
+    }
+
+    fn is_ipv4(&self) -> bool {
+        self.addr
+            .parse::<SocketAddr>()
+            .is_ok_and(|addr| addr.is_ipv4())
+    }
+}
 
 /// Payload embedded in QR code for pairing
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -28,6 +88,19 @@ pub struct PairingPayload {
     /// Secret for relay authentication (optional)
     #[serde(skip_serializing_if = "Option::is_none")]
     pub relay_secret: Option<String>,
+    /// SHA-256 fingerprint of the relay's TLS certificate, for relays
+    /// whose certificate isn't signed by a CA the phone trusts
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub relay_cert_sha256: Option<String>,
+    /// Every address the desktop may be reachable at, IPv4 and IPv6, best
+    /// first. `lan_addr` and `external_addr` repeat the best IPv4 ones for
+    /// phones that predate this list.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub candidates: Vec<ConnectionCandidate>,
+    /// One-time secret the host issued for this QR code, which the phone
+    /// signs into its `PairingRequest`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pairing_token: Option<String>,
 }
 
 impl PairingPayload {
@@ -43,7 +116,10 @@ impl PairingPayload {
             external_addr: None,
             relay_url: None,
             relay_secret: None,
+            relay_cert_sha256: None,
+            candidates: vec![],
             capabilities: vec![],
+            pairing_token: None,
         }
     }
 
@@ -77,12 +153,57 @@ impl PairingPayload {
             external_addr,
             relay_url,
             relay_secret,
+            relay_cert_sha256: None,
+            candidates: vec![],
             capabilities,
+            pairing_token: None,
         }
     }
 
+    /// Create a payload listing `candidates` for P2P, and Relay
+    pub fn with_candidates(
+        desktop_public_key: PublicKey,
+        mut candidates: Vec<ConnectionCandidate>,
+        relay_url: Option<String>,
+        relay_secret: Option<String>,
+    ) -> Self {
+        candidates.sort_by_key(|c| Reverse(c.priority));
+        let best_ipv4 = |kinds: &[CandidateKind]| {
+            candidates
+                .iter()
+                .find(|c| kinds.contains(&c.kind) && c.is_ipv4())
+                .map(|c| c.addr.clone())
+        };
+        let mut payload = Self::with_connection_info(
+            desktop_public_key,
+            best_ipv4(&[CandidateKind::Lan]),
+            best_ipv4(&[CandidateKind::Mapped, CandidateKind::Public]),
+            relay_url,
+            relay_secret,
+        );
+        if candidates.iter().any(|c| c.kind == CandidateKind::Ipv6) {
+            payload.capabilities.push("ipv6".to_string());
+        }
+        payload.candidates = candidates;
+        payload
+    }
+
+    /// Addresses to try, best first, including `lan_addr` and
+    /// `external_addr` from desktops that predate `candidates`
+    pub fn candidate_addrs(&self) -> Vec<String> {
+        let mut candidates: Vec<&ConnectionCandidate> = self.candidates.iter().collect();
+        candidates.sort_by_key(|c| Reverse(c.priority));
+        let mut addrs: Vec<String> = candidates.into_iter().map(|c| c.addr.clone()).collect();
+        for addr in [&self.lan_addr, &self.external_addr].into_iter().flatten() {
+            if !addrs.contains(addr) {
+                addrs.push(addr.clone());
+            }
+        }
+        addrs
+    }
+
     /// Derive relay ID from public key (first 16 chars of base64)
-    fn derive_relay_id(public_key: &PublicKey) -> String {
+    pub fn derive_relay_id(public_key: &PublicKey) -> String {
         let b64 = public_key.to_base64();
         b64.chars().take(16).collect()
     }
@@ -97,7 +218,7 @@ impl PairingPayload {
 
     /// Check if this payload supports direct P2P connections
     pub fn supports_p2p(&self) -> bool {
-        self.external_addr.is_some()
+        self.external_addr.is_some() || self.candidates.iter().any(|c| c.kind != CandidateKind::Lan)
     }
 
     /// Serialize to JSON for QR code
@@ -116,7 +237,8 @@ impl PairingPayload {
 pub struct PairingRequest {
     /// Mobile device's public key
     pub mobile_public_key: PublicKey,
-    /// Mobile device's signature over (desktop_pubkey || timestamp)
+    /// Mobile device's signature over
+    /// (desktop_pubkey || timestamp || pairing_token)
     pub signature: Signature,
     /// User's Google OAuth email (for display)
     pub user_email: String,
@@ -124,6 +246,9 @@ pub struct PairingRequest {
     pub device_name: String,
     /// Timestamp of request
     pub timestamp: i64,
+    /// The `pairing_token` from the scanned QR code
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pairing_token: Option<String>,
 }
 
 impl PairingRequest {
@@ -134,13 +259,44 @@ impl PairingRequest {
         user_email: String,
         device_name: String,
     ) -> Self {
-        let timestamp = chrono::Utc::now().timestamp();
+        Self::signed(
+            mobile_keypair,
+            desktop_public_key,
+            None,
+            user_email,
+            device_name,
+        )
+    }
 
-        // Sign (desktop_pubkey || timestamp) to prove we scanned the QR
-        let mut message = Vec::new();
-        message.extend_from_slice(desktop_public_key.as_bytes());
-        message.extend_from_slice(&timestamp.to_le_bytes());
+    /// Create a pairing request answering a scanned `payload`, carrying its
+    /// pairing token
+    pub fn for_payload(
+        mobile_keypair: &crate::Keypair,
+        payload: &PairingPayload,
+        user_email: String,
+        device_name: String,
+    ) -> Self {
+        Self::signed(
+            mobile_keypair,
+            &payload.desktop_public_key,
+            payload.pairing_token.clone(),
+            user_email,
+            device_name,
+        )
+    }
 
+    fn signed(
+        mobile_keypair: &crate::Keypair,
+        desktop_public_key: &PublicKey,
+        pairing_token: Option<String>,
+        user_email: String,
+        device_name: String,
+    ) -> Self {
+        let timestamp = chrono::Utc::now().timestamp();
+
+        // Sign (desktop_pubkey || timestamp || pairing_token) to prove we
+        // scanned the QR
+        let message = Self::signed_message(desktop_public_key, timestamp, pairing_token.as_deref());
         let signature = mobile_keypair.sign(&message);
 
         Self {
@@ -149,15 +305,33 @@ impl PairingRequest {
             user_email,
             device_name,
             timestamp,
+            pairing_token,
         }
     }
 
-    /// Verify the pairing request signature
-    pub fn verify(&self, desktop_public_key: &PublicKey) -> Result<()> {
-        // Reconstruct the signed message
+    fn signed_message(
+        desktop_public_key: &PublicKey,
+        timestamp: i64,
+        pairing_token: Option<&str>,
+    ) -> Vec<u8> {
         let mut message = Vec::new();
         message.extend_from_slice(desktop_public_key.as_bytes());
-        message.extend_from_slice(&self.timestamp.to_le_bytes());
+        message.extend_from_slice(&timestamp.to_le_bytes());
+        if let Some(token) = pairing_token {
+            message.extend_from_slice(token.as_bytes());
+        }
+        message
+    }
+
+    /// Verify the pairing request signature. The host must also check that
+    /// `pairing_token` is one it issued and hasn't seen used.
+    pub fn verify(&self, desktop_public_key: &PublicKey) -> Result<()> {
+        // Reconstruct the signed message
+        let message = Self::signed_message(
+            desktop_public_key,
+            self.timestamp,
+            self.pairing_token.as_deref(),
+        );
 
         self.mobile_public_key.verify(&message, &self.signature)?;
 
@@ -215,6 +389,58 @@ mod tests {
         assert_eq!(payload.version, decoded.version);
     }
 
+    #[test]
+    fn candidates_are_ordered_and_summarized_for_older_phones() {
+        let keypair = Keypair::generate();
+        let payload = PairingPayload::with_candidates(
+            keypair.public_key(),
+            vec![
+                ConnectionCandidate::new(
+                    CandidateKind::Mapped,
+                    "203.0.113.5:40000".parse().unwrap(),
+                ),
+                ConnectionCandidate::new(
+                    CandidateKind::Ipv6,
+                    "[2001:db8::5]:9797".parse().unwrap(),
+                ),
+                ConnectionCandidate::new(CandidateKind::Lan, "192.168.1.5:9797".parse().unwrap()),
+            ],
+            None,
+            None,
+        );
+        assert_eq!(payload.lan_addr.as_deref(), Some("192.168.1.5:9797"));
+        assert_eq!(payload.external_addr.as_deref(), Some("203.0.113.5:40000"));
+        assert!(payload.capabilities.contains(&"ipv6".to_string()));
+
+        let decoded = PairingPayload::from_json(&payload.to_json().unwrap()).unwrap();
+        assert_eq!(
+            decoded.candidate_addrs(),
+            vec![
+                "192.168.1.5:9797",
+                "[2001:db8::5]:9797",
+                "203.0.113.5:40000"
+            ]
+        );
+
+        // Payloads from older desktops have only the two addresses, and
+        // kinds from newer ones are tolerated
+        let mut json: serde_json::Value =
+            serde_json::from_str(&payload.to_json().unwrap()).unwrap();
+        json["candidates"] = serde_json::json!([
+            {"addr": "relay.example.com:443", "kind": "turn", "priority": 50}
+        ]);
+        let decoded = PairingPayload::from_json(&json.to_string()).unwrap();
+        assert_eq!(decoded.candidates[0].kind, CandidateKind::Unknown);
+        assert_eq!(
+            decoded.candidate_addrs(),
+            vec![
+                "relay.example.com:443",
+                "192.168.1.5:9797",
+                "203.0.113.5:40000"
+            ]
+        );
+    }
+
     #[test]
     fn pairing_request_verify() {
         let desktop_keypair = Keypair::generate();
@@ -235,6 +461,30 @@ mod tests {
         assert!(request.verify(&wrong_keypair.public_key()).is_err());
     }
 
+    #[test]
+    fn pairing_tokens_are_signed() {
+        let desktop_keypair = Keypair::generate();
+        let mobile_keypair = Keypair::generate();
+        let mut payload = PairingPayload::new(desktop_keypair.public_key());
+        payload.pairing_token = Some("one-time".to_string());
+
+        let decoded = PairingPayload::from_json(&payload.to_json().unwrap()).unwrap();
+        let mut request = PairingRequest::for_payload(
+            &mobile_keypair,
+            &decoded,
+            "user@example.com".to_string(),
+            "Test Device".to_string(),
+        );
+        assert_eq!(request.pairing_token.as_deref(), Some("one-time"));
+        request.verify(&desktop_keypair.public_key()).unwrap();
+
+        // The token can't be swapped for another, or dropped
+        request.pairing_token = Some("another".to_string());
+        assert!(request.verify(&desktop_keypair.public_key()).is_err());
+        request.pairing_token = None;
+        assert!(request.verify(&desktop_keypair.public_key()).is_err());
+    }
+
     #[test]
     fn pairing_payload_expiry() {
         let keypair = Keypair::generate();
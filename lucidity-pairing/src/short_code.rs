@@ -0,0 +1,417 @@
+//! Pairing by typing a short code, for when the QR code can't be scanned:
+//! over screen sharing, on a headless host, or without a camera.
+//!
+//! The host shows a code and both sides run SPAKE2 with it. Their keys
+//! agree only if the phone typed the code the host showed, so an exchange
+//! tells someone without the code whether a single guess was right and
+//! nothing more. Each side then proves it holds the key, which binds the
+//! host's public key and the phone's `PairingRequest` to the code:
+//!
+//! 1. The phone sends `ShortCodeHello` with its SPAKE2 message.
+//! 2. The host answers `ShortCodeChallenge`: its SPAKE2 message, its
+//!    public key, and a MAC over both messages and the key.
+//! 3. The phone checks the MAC and sends `ShortCodeConfirm`: a
+//!    `PairingRequest` for the host's key and a MAC over it.
+//! 4. The host checks that MAC and `PairingRequest::verify`, and asks its
+//!    user to approve the phone as it would after scanning the QR code.
+//!    The code stands in for the QR code's pairing token, so the request
+//!    carries none.
+//!
+//! Every hello uses up one of the code's `MAX_ATTEMPTS`, whether or not it
+//! is confirmed, and a code that has paired a phone or expired is dead.
+
+use crate::keypair::base64_serde;
+use crate::{Keypair, PairingRequest, PublicKey};
+use anyhow::{Context, Result};
+use hmac::{Hmac, Mac};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use spake2::{Ed25519Group, Identity, Password, Spake2};
+use std::fmt;
+
+/// Digits in a code
+pub const CODE_DIGITS: usize = 6;
+
+/// Exchanges a code allows before it has to be replaced
+pub const MAX_ATTEMPTS: u32 = 3;
+
+/// How long a code lasts, as long as a QR code does
+pub const CODE_TTL_SECS: i64 = 300;
+
+/// Length of a SPAKE2 message over Ed25519
+const PAKE_MESSAGE_LEN: usize = 33;
+
+const PHONE_IDENTITY: &[u8] = b"lucidity mobile";
+const HOST_IDENTITY: &[u8] = b"lucidity desktop";
+const HOST_CONFIRM_LABEL: &[u8] = b"lucidity short code: desktop";
+const PHONE_CONFIRM_LABEL: &[u8] = b"lucidity short code: mobile";
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// The code the host shows and the phone's user types
+#[derive(Clone, PartialEq, Eq)]
+pub struct ShortCode(String);
+
+impl ShortCode {
+    /// A new random code
+    pub fn generate() -> Self {
+        let max = 10u32.pow(CODE_DIGITS as u32);
+        let n = rand::thread_rng().gen_range(0..max);
+        Self(format!("{n:0width$}", width = CODE_DIGITS))
+    }
+
+    /// Parse a code as typed, ignoring spaces and dashes
+    pub fn parse(typed: &str) -> Result<Self> {
+        let digits: String = typed
+            .chars()
+            .filter(|c| !c.is_whitespace() && *c != '-')
+            .collect();
+        if digits.len() != CODE_DIGITS || !digits.chars().all(|c| c.is_ascii_digit()) {
+            anyhow::bail!("a pairing code is {} digits", CODE_DIGITS);
+        }
+        Ok(Self(digits))
+    }
+
+    fn password(&self) -> Password {
+        Password::new(self.0.as_bytes())
+    }
+}
+
+impl fmt::Display for ShortCode {
+    /// In two groups, for reading aloud and typing
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let (first, second) = self.0.split_at(CODE_DIGITS / 2);
+        write!(f, "{first} {second}")
+    }
+}
+
+impl fmt::Debug for ShortCode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("ShortCode(..)")
+    }
+}
+
+/// Sent by the phone to start pairing
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ShortCodeHello {
+    #[serde(with = "base64_serde")]
+    pub pake: [u8; PAKE_MESSAGE_LEN],
+}
+
+/// The host's answer to `ShortCodeHello`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ShortCodeChallenge {
+    #[serde(with = "base64_serde")]
+    pub pake: [u8; PAKE_MESSAGE_LEN],
+    pub desktop_public_key: PublicKey,
+    /// Proves the host knows the code
+    #[serde(with = "base64_serde")]
+    pub confirm: [u8; 32],
+}
+
+/// The phone's answer to `ShortCodeChallenge`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ShortCodeConfirm {
+    pub request: PairingRequest,
+    /// Proves the phone knows the code, and that `request` came from it
+    #[serde(with = "base64_serde")]
+    pub confirm: [u8; 32],
+}
+
+/// Both SPAKE2 messages and the host's key, which each MAC covers
+fn transcript(hello: &[u8], challenge: &[u8], desktop_public_key: &PublicKey) -> Vec<u8> {
+    let mut transcript = Vec::with_capacity(2 * PAKE_MESSAGE_LEN + 32);
+    transcript.extend_from_slice(hello);
+    transcript.extend_from_slice(challenge);
+    transcript.extend_from_slice(desktop_public_key.as_bytes());
+    transcript
+}
+
+/// The parts of `request` the phone's MAC covers. Its signature is checked
+/// by `PairingRequest::verify`.
+fn request_bytes(request: &PairingRequest) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    bytes.extend_from_slice(request.mobile_public_key.as_bytes());
+    bytes.extend_from_slice(&request.timestamp.to_le_bytes());
+    for field in [&request.user_email, &request.device_name] {
+        bytes.extend_from_slice(&(field.len() as u64).to_le_bytes());
+        bytes.extend_from_slice(field.as_bytes());
+    }
+    bytes
+}
+
+fn mac(key: &[u8], label: &[u8], parts: &[&[u8]]) -> HmacSha256 {
+    let mut mac = HmacSha256::new_from_slice(key).expect("HMAC takes keys of any length");
+    mac.update(label);
+    for part in parts {
+        mac.update(part);
+    }
+    mac
+}
+
+/// A key exchange waiting for the phone's `ShortCodeConfirm`
+struct Pending {
+    key: Vec<u8>,
+    transcript: Vec<u8>,
+}
+
+/// The host's side: one code, good for one phone
+pub struct ShortCodeHost {
+    code: ShortCode,
+    desktop_public_key: PublicKey,
+    created_at: i64,
+    attempts: u32,
+    pending: Option<Pending>,
+    paired: bool,
+}
+
+impl ShortCodeHost {
+    /// Start pairing with a new code for the host with `desktop_public_key`
+    pub fn new(desktop_public_key: PublicKey) -> Self {
+        Self {
+            code: ShortCode::generate(),
+            desktop_public_key,
+            created_at: chrono::Utc::now().timestamp(),
+            attempts: 0,
+            pending: None,
+            paired: false,
+        }
+    }
+
+    /// The code to show
+    pub fn code(&self) -> &ShortCode {
+        &self.code
+    }
+
+    /// Exchanges left before the code is dead
+    pub fn attempts_left(&self) -> u32 {
+        MAX_ATTEMPTS.saturating_sub(self.attempts)
+    }
+
+    /// Whether the code can still pair a phone
+    pub fn is_valid(&self) -> bool {
+        let age = chrono::Utc::now().timestamp() - self.created_at;
+        !self.paired && self.attempts_left() > 0 && (0..CODE_TTL_SECS).contains(&age)
+    }
+
+    /// Answer a phone's hello. Any exchange still waiting to be confirmed
+    /// is abandoned.
+    pub fn respond(&mut self, hello: &ShortCodeHello) -> Result<ShortCodeChallenge> {
+        if !self.is_valid() {
+            anyhow::bail!("pairing code is no longer valid; show a new one");
+        }
+        self.attempts += 1;
+        self.pending = None;
+
+        let (spake, pake) = Spake2::<Ed25519Group>::start_b(
+            &self.code.password(),
+            &Identity::new(PHONE_IDENTITY),
+            &Identity::new(HOST_IDENTITY),
+        );
+        let key = spake.finish(&hello.pake)?;
+        let transcript = transcript(&hello.pake, &pake, &self.desktop_public_key);
+        let confirm = mac(&key, HOST_CONFIRM_LABEL, &[&transcript])
+            .finalize()
+            .into_bytes()
+            .into();
+        self.pending = Some(Pending { key, transcript });
+
+        Ok(ShortCodeChallenge {
+            pake: pake
+                .try_into()
+                .map_err(|_| anyhow::anyhow!("unexpected SPAKE2 message length"))?,
+            desktop_public_key: self.desktop_public_key.clone(),
+            confirm,
+        })
+    }
+
+    /// Check the phone's confirmation, returning its verified request for
+    /// the host's user to approve
+    pub fn finish(&mut self, confirm: &ShortCodeConfirm) -> Result<PairingRequest> {
+        let pending = self
+            .pending
+            .take()
+            .context("no pairing exchange in progress")?;
+        mac(
+            &pending.key,
+            PHONE_CONFIRM_LABEL,
+            &[&pending.transcript, &request_bytes(&confirm.request)],
+        )
+        .verify_slice(&confirm.confirm)
+        .map_err(|_| anyhow::anyhow!("wrong pairing code"))?;
+        confirm.request.verify(&self.desktop_public_key)?;
+        self.paired = true;
+        Ok(confirm.request.clone())
+    }
+}
+
+/// The phone's side
+pub struct ShortCodeClient {
+    spake: Spake2<Ed25519Group>,
+    hello: ShortCodeHello,
+}
+
+impl ShortCodeClient {
+    /// Start pairing with the code the user typed
+    pub fn start(code: &ShortCode) -> Result<(Self, ShortCodeHello)> {
+        let (spake, pake) = Spake2::<Ed25519Group>::start_a(
+            &code.password(),
+            &Identity::new(PHONE_IDENTITY),
+            &Identity::new(HOST_IDENTITY),
+        );
+        let hello = ShortCodeHello {
+            pake: pake
+                .try_into()
+                .map_err(|_| anyhow::anyhow!("unexpected SPAKE2 message length"))?,
+        };
+        Ok((
+            Self {
+                spake,
+                hello: hello.clone(),
+            },
+            hello,
+        ))
+    }
+
+    /// Check that the host knows the code, and ask it to trust the device
+    /// with `mobile_keypair`. Returns the host's key, with the confirmation
+    /// to send it.
+    pub fn confirm(
+        self,
+        challenge: &ShortCodeChallenge,
+        mobile_keypair: &Keypair,
+        user_email: String,
+        device_name: String,
+    ) -> Result<(PublicKey, ShortCodeConfirm)> {
+        let key = self.spake.finish(&challenge.pake)?;
+        let transcript = transcript(
+            &self.hello.pake,
+            &challenge.pake,
+            &challenge.desktop_public_key,
+        );
+        mac(&key, HOST_CONFIRM_LABEL, &[&transcript])
+            .verify_slice(&challenge.confirm)
+            .map_err(|_| anyhow::anyhow!("wrong pairing code"))?;
+
+        let request = PairingRequest::new(
+            mobile_keypair,
+            &challenge.desktop_public_key,
+            user_email,
+            device_name,
+        );
+        let confirm = mac(
+            &key,
+            PHONE_CONFIRM_LABEL,
+            &[&transcript, &request_bytes(&request)],
+        )
+        .finalize()
+        .into_bytes()
+        .into();
+        Ok((
+            challenge.desktop_public_key.clone(),
+            ShortCodeConfirm { request, confirm },
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hello_for(code: &ShortCode) -> (ShortCodeClient, ShortCodeHello) {
+        ShortCodeClient::start(code).unwrap()
+    }
+
+    #[test]
+    fn codes_are_typed_loosely() {
+        let code = ShortCode::generate();
+        let shown = code.to_string();
+        assert_eq!(shown.len(), CODE_DIGITS + 1);
+        assert_eq!(ShortCode::parse(&shown).unwrap(), code);
+        assert_eq!(ShortCode::parse(&shown.replace(' ', "-")).unwrap(), code);
+        assert!(ShortCode::parse("12345").is_err());
+        assert!(ShortCode::parse("12345a").is_err());
+    }
+
+    #[test]
+    fn the_right_code_pairs() {
+        let desktop = Keypair::generate();
+        let mobile = Keypair::generate();
+        let mut host = ShortCodeHost::new(desktop.public_key());
+
+        let (client, hello) = hello_for(host.code());
+        let challenge = host.respond(&hello).unwrap();
+        let (desktop_key, confirm) = client
+            .confirm(
+                &challenge,
+                &mobile,
+                "user@example.com".to_string(),
+                "Test Phone".to_string(),
+            )
+            .unwrap();
+        assert_eq!(desktop_key, desktop.public_key());
+
+        let request = host.finish(&confirm).unwrap();
+        assert_eq!(request.mobile_public_key, mobile.public_key());
+        assert_eq!(request.user_email, "user@example.com");
+        assert_eq!(request.device_name, "Test Phone");
+        assert_eq!(request.pairing_token, None);
+
+        // A code pairs one phone
+        assert!(!host.is_valid());
+        assert!(host.respond(&hello_for(host.code()).1).is_err());
+    }
+
+    #[test]
+    fn wrong_codes_are_limited() {
+        let desktop = Keypair::generate();
+        let mobile = Keypair::generate();
+        let mut host = ShortCodeHost::new(desktop.public_key());
+        let wrong = ShortCode(if host.code().0 == "000000" {
+            "000001".to_string()
+        } else {
+            "000000".to_string()
+        });
+
+        for left in (0..MAX_ATTEMPTS).rev() {
+            let (client, hello) = hello_for(&wrong);
+            let challenge = host.respond(&hello).unwrap();
+            assert_eq!(host.attempts_left(), left);
+            // The phone sees the host doesn't know its code
+            assert!(client
+                .confirm(&challenge, &mobile, String::new(), String::new())
+                .is_err());
+        }
+
+        // Out of attempts, even the right code is refused
+        assert!(!host.is_valid());
+        assert!(host.respond(&hello_for(host.code()).1).is_err());
+    }
+
+    #[test]
+    fn confirmations_are_bound_to_the_code_and_request() {
+        let desktop = Keypair::generate();
+        let mobile = Keypair::generate();
+        let mut host = ShortCodeHost::new(desktop.public_key());
+
+        let (client, hello) = hello_for(host.code());
+        let challenge = host.respond(&hello).unwrap();
+        let (_, mut confirm) = client
+            .confirm(
+                &challenge,
+                &mobile,
+                "user@example.com".to_string(),
+                "Test Phone".to_string(),
+            )
+            .unwrap();
+
+        // Someone relaying the exchange can't rename the device
+        confirm.request.device_name = "Evil Phone".to_string();
+        assert!(host.finish(&confirm).is_err());
+        // and the exchange is spent
+        confirm.request.device_name = "Test Phone".to_string();
+        assert!(host.finish(&confirm).is_err());
+        assert_eq!(host.attempts_left(), MAX_ATTEMPTS - 1);
+    }
+}
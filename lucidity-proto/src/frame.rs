@@ -36,6 +36,23 @@ pub fn encode_frame(typ: u8, payload: &[u8]) -> Vec<u8> {
     out
 }
 
+/// Payload of a pane-addressed frame: the pane id as u32 little-endian,
+/// followed by the data.
+pub fn encode_pane_payload(pane_id: u32, data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(4 + data.len());
+    out.extend_from_slice(&pane_id.to_le_bytes());
+    out.extend_from_slice(data);
+    out
+}
+
+pub fn decode_pane_payload(payload: &[u8]) -> Result<(u32, &[u8]), DecodeError> {
+    if payload.len() < 4 {
+        return Err(DecodeError::BufferTooShort);
+    }
+    let (id, data) = payload.split_at(4);
+    Ok((u32::from_le_bytes(id.try_into().unwrap()), data))
+}
+
 #[derive(Debug, Default)]
 pub struct FrameDecoder {
     buf: Vec<u8>,
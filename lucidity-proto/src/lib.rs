@@ -1,3 +1,4 @@
 pub mod frame;
 pub mod relay;
 pub mod protocol;
+pub mod secure;
@@ -1,10 +1,474 @@
 use serde::{Deserialize, Serialize};
-use lucidity_pairing::{PairingRequest, PairingPayload, PairingResponse, TrustedDevice};
+use lucidity_pairing::{
+    PairingPayload, PairingRequest, PairingResponse, ShortCodeChallenge, ShortCodeConfirm,
+    ShortCodeHello, TrustedDevice,
+};
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct PaneInfo {
     pub pane_id: usize,
     pub title: String,
+    /// Name of the domain the pane belongs to
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub domain: Option<String>,
+    /// Workspace of the window holding the pane
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub workspace: Option<String>,
+}
+
+/// Cursor state captured in a `PaneSnapshot`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SnapshotCursor {
+    /// Column, zero based
+    pub x: usize,
+    /// Row within the visible screen, zero based
+    pub y: usize,
+    pub visible: bool,
+}
+
+/// The screen of a pane at the moment a client attached. Sent in a
+/// `TYPE_PANE_SNAPSHOT` frame after `AttachOk` and before any live output.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PaneSnapshot {
+    pub pane_id: usize,
+    pub rows: usize,
+    pub cols: usize,
+    /// Scrollback rows followed by the visible screen, oldest first. Each
+    /// line carries its colors and attributes as SGR escape sequences and
+    /// its hyperlinks as OSC 8, and always ends with attributes reset.
+    pub lines: Vec<String>,
+    /// How many of `lines` are scrollback above the visible screen
+    pub scrollback_rows: usize,
+    pub cursor: SnapshotCursor,
+    pub alt_screen: bool,
+    pub bracketed_paste: bool,
+    /// True if the application is tracking the mouse
+    pub mouse_grabbed: bool,
+}
+
+impl PaneSnapshot {
+    /// Escape sequences that reproduce the snapshot on a terminal emulator
+    /// of the same size, leaving it ready for live output.
+    pub fn to_vt(&self) -> String {
+        let history_rows = self.scrollback_rows.min(self.lines.len());
+        let (history, screen) = self.lines.split_at(history_rows);
+
+        let mut out = String::new();
+        // Scrollback is written on the primary screen so that it scrolls
+        // into the emulator's history.
+        for line in history {
+            out.push_str(line);
+            out.push_str("\r\n");
+        }
+        if self.alt_screen {
+            out.push_str("\x1b[?1049h");
+        }
+        out.push_str("\x1b[H\x1b[2J");
+        for (row, line) in screen.iter().enumerate() {
+            out.push_str(&format!("\x1b[{};1H", row + 1));
+            out.push_str(line);
+        }
+        out.push_str(if self.bracketed_paste {
+            "\x1b[?2004h"
+        } else {
+            "\x1b[?2004l"
+        });
+        out.push_str(&format!("\x1b[{};{}H", self.cursor.y + 1, self.cursor.x + 1));
+        out.push_str(if self.cursor.visible {
+            "\x1b[?25h"
+        } else {
+            "\x1b[?25l"
+        });
+        out
+    }
+}
+
+/// How an attached pane's output is delivered
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StreamMode {
+    /// `TYPE_PANE_SNAPSHOT` followed by raw PTY bytes in `TYPE_PANE_OUTPUT`
+    #[default]
+    Raw,
+    /// `ScreenUpdate`s in `TYPE_SCREEN_UPDATE`, each acknowledged with
+    /// `screen_ack`
+    Cells,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CellColor {
+    Palette(u8),
+    Rgb(u8, u8, u8),
+}
+
+fn is_false(b: &bool) -> bool {
+    !*b
+}
+
+/// Attributes shared by every cell in a `CellRun`. Unset fields take the
+/// terminal defaults.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CellStyle {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub fg: Option<CellColor>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bg: Option<CellColor>,
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub bold: bool,
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub dim: bool,
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub italic: bool,
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub underline: bool,
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub blink: bool,
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub reverse: bool,
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub invisible: bool,
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub strikethrough: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hyperlink: Option<String>,
+}
+
+impl CellStyle {
+    pub fn is_default(&self) -> bool {
+        *self == Self::default()
+    }
+}
+
+/// Consecutive cells with the same style. A run is either any number of
+/// single-width cells or exactly one double-width cell.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CellRun {
+    /// Column of the first cell
+    pub col: usize,
+    pub text: String,
+    #[serde(default, skip_serializing_if = "CellStyle::is_default")]
+    pub style: CellStyle,
+}
+
+/// The new contents of one line. Cells after the last run are blank.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LineUpdate {
+    /// Stable row index; unaffected by scrolling
+    pub row: i64,
+    pub runs: Vec<CellRun>,
+}
+
+/// Lines that changed since the sequence number the client last
+/// acknowledged. Sent in a `TYPE_SCREEN_UPDATE` frame.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ScreenUpdate {
+    pub pane_id: usize,
+    /// Acknowledge this with `screen_ack` once applied
+    pub seqno: u64,
+    /// When true, `lines` covers the whole screen and the client should
+    /// discard the lines it holds
+    pub full: bool,
+    /// Stable row index of the top of the screen
+    pub top: i64,
+    pub rows: usize,
+    pub cols: usize,
+    pub lines: Vec<LineUpdate>,
+    pub cursor: SnapshotCursor,
+    pub alt_screen: bool,
+    pub bracketed_paste: bool,
+    pub mouse_grabbed: bool,
+}
+
+/// Open a new tab, or a new window, like `wezterm cli spawn`
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SpawnRequest {
+    /// The pane whose window and domain the new tab joins. Defaults to the
+    /// pane focused most recently on the host.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pane_id: Option<usize>,
+    /// Spawn into this window rather than the one holding `pane_id`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub window_id: Option<usize>,
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub new_window: bool,
+    /// Workspace of a new window
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub workspace: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub domain_name: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cwd: Option<String>,
+    /// Program and arguments; the default shell when empty
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub command: Vec<String>,
+}
+
+/// Where a split places the new pane relative to the one being split
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SplitSide {
+    Left,
+    Right,
+    Top,
+    #[default]
+    Bottom,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SplitSize {
+    Cells(usize),
+    Percent(u8),
+}
+
+/// Split a pane, like `wezterm cli split-pane`
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SplitPaneRequest {
+    pub pane_id: usize,
+    #[serde(default)]
+    pub side: SplitSide,
+    /// Split the whole tab rather than just `pane_id`
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub top_level: bool,
+    /// Size of the new pane; half of the split pane when omitted
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub size: Option<SplitSize>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cwd: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub command: Vec<String>,
+    /// Move this existing pane into the split instead of spawning one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub move_pane_id: Option<usize>,
+}
+
+/// Activate a tab, like `wezterm cli activate-tab`. Give `tab_id`, or one of
+/// `tab_index` and `tab_relative` to pick a tab in the window holding
+/// `pane_id`.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ActivateTabRequest {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tab_id: Option<usize>,
+    /// Negative values count from the last tab
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tab_index: Option<isize>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tab_relative: Option<isize>,
+    /// Stop at the first or last tab instead of wrapping around
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub no_wrap: bool,
+    /// Defaults to the pane focused most recently on the host
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pane_id: Option<usize>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ZoomMode {
+    #[default]
+    Zoom,
+    Unzoom,
+    Toggle,
+}
+
+/// The pane created by `spawn` or `split_pane`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SpawnedPane {
+    pub pane_id: usize,
+    pub tab_id: usize,
+    pub window_id: usize,
+}
+
+/// A key, named after the termwiz `KeyCode` it maps to on the host
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Key {
+    Char(char),
+    /// F1 to F24
+    Function(u8),
+    Enter,
+    Tab,
+    Backspace,
+    Escape,
+    Insert,
+    Delete,
+    Home,
+    End,
+    PageUp,
+    PageDown,
+    UpArrow,
+    DownArrow,
+    LeftArrow,
+    RightArrow,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct KeyMods {
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub shift: bool,
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub alt: bool,
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub ctrl: bool,
+    #[serde(rename = "super", default, skip_serializing_if = "is_false")]
+    pub super_: bool,
+}
+
+impl KeyMods {
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+}
+
+/// Whether a key event presses the key, releases it, or both
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum KeyAction {
+    Press,
+    Release,
+    #[default]
+    Tap,
+}
+
+/// A key event, encoded by the pane the way a local keyboard would be
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct KeyEventRequest {
+    pub pane_id: usize,
+    pub key: Key,
+    #[serde(default, skip_serializing_if = "KeyMods::is_empty")]
+    pub mods: KeyMods,
+    #[serde(default)]
+    pub action: KeyAction,
+}
+
+/// What a mouse event does. `click` is a press followed by a release, which
+/// is what a tap on a touchscreen turns into.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MouseEventKind {
+    Press,
+    Release,
+    Move,
+    Click,
+}
+
+/// Wheel buttons scroll by one step per `press`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MouseButton {
+    Left,
+    Middle,
+    Right,
+    WheelUp,
+    WheelDown,
+    WheelLeft,
+    WheelRight,
+    #[default]
+    None,
+}
+
+/// A mouse event at a cell of the pane's viewport, counted from the top
+/// left starting at zero
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MouseEventRequest {
+    pub pane_id: usize,
+    pub kind: MouseEventKind,
+    #[serde(default)]
+    pub button: MouseButton,
+    pub x: usize,
+    pub y: i64,
+    #[serde(default, skip_serializing_if = "KeyMods::is_empty")]
+    pub mods: KeyMods,
+}
+
+/// A change in the host's mux, pushed to clients that asked for it with
+/// `subscribe_events`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum MuxEvent {
+    PaneAdded {
+        pane_id: usize,
+        title: String,
+    },
+    PaneRemoved {
+        pane_id: usize,
+    },
+    PaneTitleChanged {
+        pane_id: usize,
+        title: String,
+    },
+    PaneFocused {
+        pane_id: usize,
+    },
+    TabAdded {
+        tab_id: usize,
+        window_id: usize,
+    },
+    TabTitleChanged {
+        tab_id: usize,
+        title: String,
+    },
+    WindowCreated {
+        window_id: usize,
+    },
+    WindowRemoved {
+        window_id: usize,
+    },
+    WindowTitleChanged {
+        window_id: usize,
+        title: String,
+    },
+    Bell {
+        pane_id: usize,
+    },
+    Toast {
+        pane_id: usize,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        title: Option<String>,
+        body: String,
+    },
+    ActiveWorkspaceChanged {
+        workspace: String,
+    },
+    WorkspaceRenamed {
+        old_workspace: String,
+        new_workspace: String,
+    },
+}
+
+impl MuxEvent {
+    /// The `event` tag, as used in `subscribe_events`
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::PaneAdded { .. } => "pane_added",
+            Self::PaneRemoved { .. } => "pane_removed",
+            Self::PaneTitleChanged { .. } => "pane_title_changed",
+            Self::PaneFocused { .. } => "pane_focused",
+            Self::TabAdded { .. } => "tab_added",
+            Self::TabTitleChanged { .. } => "tab_title_changed",
+            Self::WindowCreated { .. } => "window_created",
+            Self::WindowRemoved { .. } => "window_removed",
+            Self::WindowTitleChanged { .. } => "window_title_changed",
+            Self::Bell { .. } => "bell",
+            Self::Toast { .. } => "toast",
+            Self::ActiveWorkspaceChanged { .. } => "active_workspace_changed",
+            Self::WorkspaceRenamed { .. } => "workspace_renamed",
+        }
+    }
+
+    /// The pane the event is about, if it is about one
+    pub fn pane_id(&self) -> Option<usize> {
+        match self {
+            Self::PaneAdded { pane_id, .. }
+            | Self::PaneRemoved { pane_id }
+            | Self::PaneTitleChanged { pane_id, .. }
+            | Self::PaneFocused { pane_id }
+            | Self::Bell { pane_id }
+            | Self::Toast { pane_id, .. } => Some(*pane_id),
+            _ => None,
+        }
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -13,30 +477,124 @@ pub enum JsonRequest {
     ListPanes,
     Attach {
         pane_id: usize,
+        /// Rows of scrollback to include in the snapshot sent ahead of live
+        /// output. The host picks a default when omitted.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        scrollback: Option<usize>,
+        #[serde(default)]
+        mode: StreamMode,
+        /// Raw output arrives in `TYPE_PANE_OUTPUT_ADDRESSED` frames and
+        /// input goes in `TYPE_PANE_INPUT_ADDRESSED`, so that any number of
+        /// panes can be attached at once
+        #[serde(default, skip_serializing_if = "is_false")]
+        addressed: bool,
+    },
+    /// Stop receiving output from a pane
+    Detach {
+        pane_id: usize,
+    },
+    /// Pick up a dropped session after authenticating as the same device.
+    /// `last_seq` is the number of the last raw output frame received.
+    Resume {
+        token: String,
+        last_seq: u64,
+    },
+    /// Confirms that a cell-mode client has applied a `ScreenUpdate`
+    ScreenAck {
+        pane_id: usize,
+        seqno: u64,
     },
     PairingPayload,
     PairingSubmit {
         request: PairingRequest,
     },
+    /// Show a new short pairing code, for another device to type
+    PairingShortCode,
+    /// Start pairing with a short code
+    PairingShortCodeHello {
+        hello: ShortCodeHello,
+    },
+    /// Finish pairing with a short code. Answered with `pairing_response`.
+    PairingShortCodeConfirm {
+        confirm: ShortCodeConfirm,
+    },
     PairingListTrustedDevices,
     AuthResponse {
         public_key: String,
         signature: String,
         client_nonce: Option<String>,
+        /// Device's ephemeral X25519 key. When present, `signature` covers
+        /// `secure::device_auth_message(nonce, ephemeral_key)` instead of the
+        /// bare nonce and every later frame is sealed.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        ephemeral_key: Option<String>,
     },
+    /// Paste into a pane. Answered with `paste_pending` when the host wants
+    /// the paste confirmed first.
     Paste {
         pane_id: usize,
         text: String,
     },
+    ConfirmPaste {
+        paste_id: String,
+    },
+    CancelPaste {
+        paste_id: String,
+    },
     Resize {
         pane_id: usize,
         rows: usize,
         cols: usize,
     },
+    KeyEvent(KeyEventRequest),
+    MouseEvent(MouseEventRequest),
     /// Revoke this device's trust on the host
     RevokeDevice {
         public_key: String,
     },
+    Spawn(SpawnRequest),
+    SplitPane(SplitPaneRequest),
+    KillPane {
+        pane_id: usize,
+    },
+    ActivateTab(ActivateTabRequest),
+    ActivatePane {
+        pane_id: usize,
+    },
+    ZoomPane {
+        pane_id: usize,
+        #[serde(default)]
+        mode: ZoomMode,
+    },
+    /// Start pushing `event` messages. `events` limits them to those names
+    /// and `panes` limits pane events to those panes; empty means all.
+    /// Replaces any earlier subscription.
+    SubscribeEvents {
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        events: Vec<String>,
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        panes: Vec<usize>,
+    },
+    UnsubscribeEvents,
+    /// Start pushing `clipboard_push` for text copied on the host, up to
+    /// `max_bytes` long. The host picks a default when omitted and caps it.
+    SubscribeClipboard {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        max_bytes: Option<usize>,
+    },
+    UnsubscribeClipboard,
+    /// Put `text` on the host clipboard
+    SetClipboard {
+        text: String,
+    },
+    /// Rename `workspace`, or the workspace holding `pane_id`
+    RenameWorkspace {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        workspace: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pane_id: Option<usize>,
+        new_workspace: String,
+    },
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -54,6 +612,13 @@ pub enum JsonResponse {
     PairingResponse {
         response: PairingResponse,
     },
+    /// A short pairing code, as it should be shown
+    PairingShortCode {
+        code: String,
+    },
+    PairingShortCodeChallenge {
+        challenge: ShortCodeChallenge,
+    },
     PairingTrustedDevices {
         devices: Vec<TrustedDevice>,
     },
@@ -62,11 +627,58 @@ pub enum JsonResponse {
     },
     AuthSuccess {
         signature: Option<String>,
+        /// Host's ephemeral X25519 key, answering the device's key exchange
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        ephemeral_key: Option<String>,
+        /// Host identity signature over the key exchange transcript
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        key_signature: Option<String>,
+    },
+    /// Sent right after `AuthSuccess`, so it is the first sealed frame.
+    /// Presented in `Resume` to pick this session up after a drop.
+    SessionToken {
+        token: String,
+    },
+    /// The session was resumed. Raw output frames that follow are numbered
+    /// from `next_seq`; when `snapshot` is true a `TYPE_PANE_SNAPSHOT` frame
+    /// comes first because the missed output was no longer buffered.
+    ResumeOk {
+        pane_id: Option<usize>,
+        /// Panes attached with `addressed`
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        panes: Vec<usize>,
+        next_seq: u64,
+        snapshot: bool,
+    },
+    DetachOk {
+        pane_id: usize,
+    },
+    /// The client fell too far behind, so the raw output it had not yet
+    /// received was dropped. A `TYPE_PANE_SNAPSHOT` frame follows for each
+    /// raw attachment, and output frames continue from `next_seq`.
+    Resync {
+        next_seq: u64,
+    },
+    /// Answers `spawn` and `split_pane`
+    Spawned(SpawnedPane),
+    /// Answers a mux operation that has nothing else to report
+    Ok,
+    Event {
+        #[serde(flatten)]
+        event: MuxEvent,
     },
     Error {
         message: String,
     },
-    /// Host pushes clipboard changes to mobile
+    /// A paste with line breaks into a pane that would run each line as it
+    /// arrives. Nothing is sent until `confirm_paste`.
+    PastePending {
+        pane_id: usize,
+        paste_id: String,
+        lines: usize,
+        bytes: usize,
+    },
+    /// Text copied on the host, sent after `subscribe_clipboard`
     ClipboardPush {
         text: String,
     },
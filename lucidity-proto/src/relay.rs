@@ -1,12 +1,31 @@
+//! Messages between the relay and the desktops registered with it.
+//!
+//! A desktop keeps one control WebSocket to the relay, and registers on it
+//! by signing the relay's challenge with the host key. Control messages
+//! travel on it as JSON `RelayMessage`s in text messages. Each mobile that
+//! connects becomes a session, and the frames of that session travel in
+//! binary messages of the form `[u8 session_id length][session_id][frames]`,
+//! built by `encode_session_data`. Mobiles send and receive plain frames,
+//! apart from the `PunchOffer` they may send and the `PunchAnswer` they
+//! get back, in text messages, to set up a direct UDP connection.
+
 use serde::{Deserialize, Serialize};
 
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 #[serde(tag = "type", rename_all = "snake_case")]
 pub enum RelayMessage {
+    /// Relay -> Desktop: "Prove you own this relay_id", sent as soon as
+    /// the desktop connects
+    Challenge { nonce: String },
+
     /// Desktop -> Relay: "I am ready to accept connections"
     Register {
         relay_id: String,
-        /// Signed timestamp to prove ownership of the relay_id (optional for now, good for security later)
+        /// The host's Ed25519 public key, from which `relay_id` is derived
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        public_key: Option<String>,
+        /// That key's signature over `registration_message`, proving
+        /// ownership of the relay_id
         signature: Option<String>,
     },
 
@@ -26,7 +45,9 @@ pub enum RelayMessage {
     /// Desktop -> Relay: "I accept this session"
     SessionAccept { session_id: String },
 
-    /// Relay -> Desktop/Mobile: "Here is data for your session"
+    /// Relay -> Desktop/Mobile: "Here is data for your session". Relays
+    /// carry session data in binary messages instead; see
+    /// `encode_session_data`.
     Data {
         session_id: String,
         payload: Vec<u8>,
@@ -37,4 +58,59 @@ pub enum RelayMessage {
 
     /// Relay -> Client: "Error / Ack"
     Control { code: u16, message: String },
+
+    /// Mobile -> Relay -> Desktop: "Let's go direct; I'm listening for UDP
+    /// at these addresses". The relay fills in `session_id`.
+    PunchOffer {
+        #[serde(default)]
+        session_id: String,
+        candidates: Vec<String>,
+    },
+
+    /// Desktop -> Relay -> Mobile: "I'm sending to your addresses from
+    /// these, and will accept QUIC with a certificate of this SHA-256".
+    /// No candidates means the desktop can't go direct.
+    PunchAnswer {
+        session_id: String,
+        candidates: Vec<String>,
+        #[serde(default)]
+        cert_sha256: String,
+    },
+}
+
+/// Candidates a `PunchOffer` may list; the relay and desktop drop any past
+/// these
+pub const MAX_PUNCH_CANDIDATES: usize = 8;
+
+const REGISTRATION_LABEL: &[u8] = b"lucidity-relay-register-v1\0";
+
+/// What a desktop signs to answer the relay's `Challenge`
+pub fn registration_message(relay_id: &str, nonce: &str) -> Vec<u8> {
+    let mut msg = REGISTRATION_LABEL.to_vec();
+    msg.extend_from_slice(relay_id.as_bytes());
+    msg.push(0);
+    msg.extend_from_slice(nonce.as_bytes());
+    msg
+}
+
+/// Wrap a session's frames for the desktop's control WebSocket
+pub fn encode_session_data(session_id: &str, payload: &[u8]) -> Vec<u8> {
+    let id = session_id.as_bytes();
+    assert!(id.len() <= u8::MAX as usize, "session_id too long");
+    let mut out = Vec::with_capacity(1 + id.len() + payload.len());
+    out.push(id.len() as u8);
+    out.extend_from_slice(id);
+    out.extend_from_slice(payload);
+    out
+}
+
+/// Split a binary control message into its session id and frames
+pub fn decode_session_data(data: &[u8]) -> Option<(&str, &[u8])> {
+    let (&len, rest) = data.split_first()?;
+    let len = len as usize;
+    if rest.len() < len {
+        return None;
+    }
+    let (id, payload) = rest.split_at(len);
+    Some((std::str::from_utf8(id).ok()?, payload))
 }
@@ -0,0 +1,258 @@
+//! End-to-end encryption for Lucidity frames.
+//!
+//! The key exchange piggybacks on the existing Ed25519 challenge/response:
+//!
+//! 1. Host sends `AuthChallenge { nonce }`.
+//! 2. Device generates an ephemeral X25519 key and signs
+//!    `device_auth_message(nonce, device_ephemeral)` with its paired identity key.
+//! 3. Host verifies that signature against the trust store, generates its own
+//!    ephemeral key and signs the full `Transcript` with the host identity key.
+//! 4. Both sides derive one ChaCha20-Poly1305 key per direction from the X25519
+//!    shared secret with HKDF-SHA256, salted with the transcript hash.
+//!
+//! Every later frame travels as a sealed record: an 8-byte little-endian record
+//! counter followed by the AEAD ciphertext of `[typ] ++ payload`.
+
+use crate::frame::Frame;
+use base64::Engine;
+use chacha20poly1305::aead::{Aead, KeyInit, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use hkdf::Hkdf;
+use lucidity_pairing::PublicKey;
+use sha2::{Digest, Sha256};
+use thiserror::Error;
+use x25519_dalek::{EphemeralSecret, PublicKey as X25519PublicKey};
+
+const DEVICE_AUTH_LABEL: &[u8] = b"lucidity-auth-v2\0";
+const TRANSCRIPT_LABEL: &[u8] = b"lucidity-kx-v1\0";
+const DEVICE_TO_HOST_INFO: &[u8] = b"lucidity device->host";
+const HOST_TO_DEVICE_INFO: &[u8] = b"lucidity host->device";
+const COUNTER_LEN: usize = 8;
+
+#[derive(Debug, Error, Clone, PartialEq, Eq)]
+pub enum SecureError {
+    #[error("invalid ephemeral key")]
+    InvalidKey,
+    #[error("sealed record too short")]
+    Truncated,
+    #[error("sealed record counter {got} does not match expected {expected}")]
+    UnexpectedCounter { expected: u64, got: u64 },
+    #[error("sealed record failed authentication")]
+    Decrypt,
+    #[error("record counter exhausted")]
+    CounterExhausted,
+}
+
+/// Which end of the connection we are; selects the send/receive keys.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Role {
+    Host,
+    Device,
+}
+
+/// The message a device signs in its `AuthResponse` when it offers a key
+/// exchange. Binds the ephemeral key to the host's challenge.
+pub fn device_auth_message(nonce: &str, device_ephemeral: &[u8; 32]) -> Vec<u8> {
+    let mut msg = Vec::with_capacity(DEVICE_AUTH_LABEL.len() + nonce.len() + 32);
+    msg.extend_from_slice(DEVICE_AUTH_LABEL);
+    msg.extend_from_slice(nonce.as_bytes());
+    msg.extend_from_slice(device_ephemeral);
+    msg
+}
+
+/// Everything both sides agreed on during the handshake.
+pub struct Transcript {
+    bytes: Vec<u8>,
+}
+
+impl Transcript {
+    pub fn new(
+        nonce: &str,
+        device_key: &PublicKey,
+        host_key: &PublicKey,
+        device_ephemeral: &[u8; 32],
+        host_ephemeral: &[u8; 32],
+    ) -> Self {
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(TRANSCRIPT_LABEL);
+        bytes.extend_from_slice(&(nonce.len() as u32).to_le_bytes());
+        bytes.extend_from_slice(nonce.as_bytes());
+        bytes.extend_from_slice(device_key.as_bytes());
+        bytes.extend_from_slice(host_key.as_bytes());
+        bytes.extend_from_slice(device_ephemeral);
+        bytes.extend_from_slice(host_ephemeral);
+        Self { bytes }
+    }
+
+    /// The bytes the host signs with its identity key in `AuthSuccess`.
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    fn hash(&self) -> [u8; 32] {
+        Sha256::digest(&self.bytes).into()
+    }
+}
+
+/// One side of an X25519 key exchange.
+pub struct KeyExchange {
+    secret: EphemeralSecret,
+    public: [u8; 32],
+}
+
+impl KeyExchange {
+    pub fn new() -> Self {
+        let secret = EphemeralSecret::random_from_rng(rand_core::OsRng);
+        let public = X25519PublicKey::from(&secret).to_bytes();
+        Self { secret, public }
+    }
+
+    pub fn public_key(&self) -> [u8; 32] {
+        self.public
+    }
+
+    /// Consume the ephemeral secret and derive the per-direction ciphers.
+    pub fn finish(
+        self,
+        role: Role,
+        peer_public: &[u8; 32],
+        transcript: &Transcript,
+    ) -> Result<(FrameSealer, FrameOpener), SecureError> {
+        let shared = self
+            .secret
+            .diffie_hellman(&X25519PublicKey::from(*peer_public));
+        if !shared.was_contributory() {
+            return Err(SecureError::InvalidKey);
+        }
+
+        let hk = Hkdf::<Sha256>::new(Some(&transcript.hash()), shared.as_bytes());
+        let mut d2h = [0u8; 32];
+        let mut h2d = [0u8; 32];
+        hk.expand(DEVICE_TO_HOST_INFO, &mut d2h)
+            .expect("32 bytes is a valid hkdf output length");
+        hk.expand(HOST_TO_DEVICE_INFO, &mut h2d)
+            .expect("32 bytes is a valid hkdf output length");
+
+        let (send, recv) = match role {
+            Role::Host => (h2d, d2h),
+            Role::Device => (d2h, h2d),
+        };
+        Ok((FrameSealer::new(&send), FrameOpener::new(&recv)))
+    }
+}
+
+impl Default for KeyExchange {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Encode an X25519 public key the same way the pairing keys are encoded.
+pub fn encode_ephemeral_key(key: &[u8; 32]) -> String {
+    base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(key)
+}
+
+pub fn decode_ephemeral_key(s: &str) -> Result<[u8; 32], SecureError> {
+    let bytes = base64::engine::general_purpose::URL_SAFE_NO_PAD
+        .decode(s)
+        .map_err(|_| SecureError::InvalidKey)?;
+    bytes.try_into().map_err(|_| SecureError::InvalidKey)
+}
+
+fn record_nonce(counter: u64) -> Nonce {
+    let mut nonce = [0u8; 12];
+    nonce[4..].copy_from_slice(&counter.to_le_bytes());
+    Nonce::from(nonce)
+}
+
+/// Seals outgoing frames.
+pub struct FrameSealer {
+    cipher: ChaCha20Poly1305,
+    counter: u64,
+}
+
+impl FrameSealer {
+    fn new(key: &[u8; 32]) -> Self {
+        Self {
+            cipher: ChaCha20Poly1305::new(&Key::from(*key)),
+            counter: 0,
+        }
+    }
+
+    /// Returns the payload of a sealed record carrying `typ` and `payload`.
+    pub fn seal(&mut self, typ: u8, payload: &[u8]) -> Result<Vec<u8>, SecureError> {
+        let counter = self.counter;
+        self.counter = counter
+            .checked_add(1)
+            .ok_or(SecureError::CounterExhausted)?;
+
+        let mut plaintext = Vec::with_capacity(1 + payload.len());
+        plaintext.push(typ);
+        plaintext.extend_from_slice(payload);
+
+        let aad = counter.to_le_bytes();
+        let ciphertext = self
+            .cipher
+            .encrypt(
+                &record_nonce(counter),
+                Payload {
+                    msg: &plaintext,
+                    aad: &aad,
+                },
+            )
+            .map_err(|_| SecureError::Decrypt)?;
+
+        let mut out = Vec::with_capacity(COUNTER_LEN + ciphertext.len());
+        out.extend_from_slice(&aad);
+        out.extend_from_slice(&ciphertext);
+        Ok(out)
+    }
+}
+
+/// Opens incoming sealed records. Records must arrive in order; a replayed,
+/// dropped or reordered record is rejected.
+pub struct FrameOpener {
+    cipher: ChaCha20Poly1305,
+    counter: u64,
+}
+
+impl FrameOpener {
+    fn new(key: &[u8; 32]) -> Self {
+        Self {
+            cipher: ChaCha20Poly1305::new(&Key::from(*key)),
+            counter: 0,
+        }
+    }
+
+    pub fn open(&mut self, sealed: &[u8]) -> Result<Frame, SecureError> {
+        if sealed.len() < COUNTER_LEN + 1 {
+            return Err(SecureError::Truncated);
+        }
+        let (aad, ciphertext) = sealed.split_at(COUNTER_LEN);
+        let got = u64::from_le_bytes(aad.try_into().unwrap());
+        if got != self.counter {
+            return Err(SecureError::UnexpectedCounter {
+                expected: self.counter,
+                got,
+            });
+        }
+
+        let plaintext = self
+            .cipher
+            .decrypt(
+                &record_nonce(got),
+                Payload {
+                    msg: ciphertext,
+                    aad,
+                },
+            )
+            .map_err(|_| SecureError::Decrypt)?;
+        self.counter = got.checked_add(1).ok_or(SecureError::CounterExhausted)?;
+
+        let (typ, payload) = plaintext.split_first().ok_or(SecureError::Truncated)?;
+        Ok(Frame {
+            typ: *typ,
+            payload: payload.to_vec(),
+        })
+    }
+}
@@ -0,0 +1,34 @@
+use k9::assert_equal;
+use lucidity_proto::protocol::{JsonRequest, JsonResponse, MuxEvent};
+
+#[test]
+fn event_fields_sit_beside_op() {
+    let msg = JsonResponse::Event {
+        event: MuxEvent::PaneTitleChanged {
+            pane_id: 2,
+            title: "vim".to_string(),
+        },
+    };
+    let json = serde_json::to_value(&msg).unwrap();
+    assert_equal!(
+        json,
+        serde_json::json!({"op": "event", "event": "pane_title_changed", "pane_id": 2, "title": "vim"})
+    );
+
+    let back: JsonResponse = serde_json::from_value(json).unwrap();
+    let JsonResponse::Event { event } = back else {
+        panic!("not an event: {back:?}");
+    };
+    assert_equal!(event.name(), "pane_title_changed");
+    assert_equal!(event.pane_id(), Some(2));
+}
+
+#[test]
+fn subscribe_events_defaults_to_everything() {
+    let req: JsonRequest = serde_json::from_str(r#"{"op":"subscribe_events"}"#).unwrap();
+    let JsonRequest::SubscribeEvents { events, panes } = req else {
+        panic!("not subscribe_events: {req:?}");
+    };
+    assert!(events.is_empty());
+    assert!(panes.is_empty());
+}
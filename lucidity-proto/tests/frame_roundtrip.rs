@@ -1,5 +1,8 @@
 use k9::assert_equal;
-use lucidity_proto::frame::{encode_frame, DecodeError, Frame, FrameDecoder, MAX_FRAME_LEN};
+use lucidity_proto::frame::{
+    decode_pane_payload, encode_frame, encode_pane_payload, DecodeError, Frame, FrameDecoder,
+    MAX_FRAME_LEN,
+};
 
 #[test]
 fn frame_roundtrips_single_chunk() {
@@ -52,3 +55,14 @@ fn frame_rejects_zero_length() {
     dec.push(&0u32.to_le_bytes());
     assert_equal!(dec.next_frame().unwrap_err(), DecodeError::InvalidLength(0));
 }
+
+#[test]
+fn pane_payload_roundtrips() {
+    let payload = encode_pane_payload(258, b"ls");
+    assert_equal!(payload, vec![2, 1, 0, 0, b'l', b's']);
+    assert_equal!(decode_pane_payload(&payload).unwrap(), (258, &b"ls"[..]));
+    assert_equal!(
+        decode_pane_payload(&[1, 0]).unwrap_err(),
+        DecodeError::BufferTooShort
+    );
+}
@@ -1,10 +1,11 @@
 use k9::assert_equal;
-use lucidity_proto::relay::RelayMessage;
+use lucidity_proto::relay::{decode_session_data, encode_session_data, RelayMessage};
 
 #[test]
 fn test_register_serialization() {
     let original = RelayMessage::Register {
         relay_id: "desktop-123".to_string(),
+        public_key: Some("key-xyz".to_string()),
         signature: Some("sig-abc".to_string()),
     };
 
@@ -12,6 +13,32 @@ fn test_register_serialization() {
     let decoded: RelayMessage = serde_json::from_str(&json).unwrap();
 
     assert_equal!(decoded, original);
+
+    // Registrations from before proof of ownership carry no key
+    let decoded: RelayMessage =
+        serde_json::from_str(r#"{"type":"register","relay_id":"desktop-123","signature":null}"#)
+            .unwrap();
+    assert_equal!(
+        decoded,
+        RelayMessage::Register {
+            relay_id: "desktop-123".to_string(),
+            public_key: None,
+            signature: None,
+        }
+    );
+}
+
+#[test]
+fn test_challenge_serialization() {
+    let original = RelayMessage::Challenge {
+        nonce: "n-1".to_string(),
+    };
+
+    let json = serde_json::to_string(&original).unwrap();
+    assert_equal!(json, r#"{"type":"challenge","nonce":"n-1"}"#);
+    let decoded: RelayMessage = serde_json::from_str(&json).unwrap();
+
+    assert_equal!(decoded, original);
 }
 
 #[test]
@@ -90,3 +117,18 @@ fn test_control_serialization() {
 
     assert_equal!(decoded, original);
 }
+
+#[test]
+fn test_session_data_roundtrip() {
+    let data = encode_session_data("session-789", &[1, 2, 3]);
+    assert_equal!(
+        decode_session_data(&data),
+        Some(("session-789", &[1u8, 2, 3][..]))
+    );
+
+    let empty = encode_session_data("s", &[]);
+    assert_equal!(decode_session_data(&empty), Some(("s", &[][..])));
+
+    assert_equal!(decode_session_data(&[]), None);
+    assert_equal!(decode_session_data(&[5, b'a']), None);
+}
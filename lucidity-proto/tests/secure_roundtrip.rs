@@ -0,0 +1,110 @@
+use k9::assert_equal;
+use lucidity_pairing::Keypair;
+use lucidity_proto::secure::{
+    decode_ephemeral_key, encode_ephemeral_key, FrameOpener, FrameSealer, KeyExchange, Role,
+    SecureError, Transcript,
+};
+
+fn handshake() -> ((FrameSealer, FrameOpener), (FrameSealer, FrameOpener)) {
+    let device = Keypair::generate();
+    let host = Keypair::generate();
+    let device_kx = KeyExchange::new();
+    let host_kx = KeyExchange::new();
+    let device_eph = device_kx.public_key();
+    let host_eph = host_kx.public_key();
+
+    let transcript = Transcript::new(
+        "nonce-1",
+        &device.public_key(),
+        &host.public_key(),
+        &device_eph,
+        &host_eph,
+    );
+
+    let host_side = host_kx.finish(Role::Host, &device_eph, &transcript).unwrap();
+    let device_side = device_kx
+        .finish(Role::Device, &host_eph, &transcript)
+        .unwrap();
+    (host_side, device_side)
+}
+
+#[test]
+fn sealed_frames_roundtrip_both_directions() {
+    let ((mut host_seal, mut host_open), (mut dev_seal, mut dev_open)) = handshake();
+
+    let sealed = dev_seal.seal(1, b"{\"op\":\"list_panes\"}").unwrap();
+    let frame = host_open.open(&sealed).unwrap();
+    assert_equal!(frame.typ, 1);
+    assert_equal!(frame.payload, b"{\"op\":\"list_panes\"}".to_vec());
+
+    let sealed = host_seal.seal(2, b"hello").unwrap();
+    assert!(!sealed.windows(5).any(|w| w == b"hello"));
+    let frame = dev_open.open(&sealed).unwrap();
+    assert_equal!(frame.typ, 2);
+    assert_equal!(frame.payload, b"hello".to_vec());
+}
+
+#[test]
+fn replayed_record_is_rejected() {
+    let ((_, mut host_open), (mut dev_seal, _)) = handshake();
+
+    let sealed = dev_seal.seal(3, b"ls\r").unwrap();
+    host_open.open(&sealed).unwrap();
+    assert_equal!(
+        host_open.open(&sealed).unwrap_err(),
+        SecureError::UnexpectedCounter {
+            expected: 1,
+            got: 0
+        }
+    );
+}
+
+#[test]
+fn tampered_record_is_rejected() {
+    let ((_, mut host_open), (mut dev_seal, _)) = handshake();
+
+    let mut sealed = dev_seal.seal(3, b"ls\r").unwrap();
+    let last = sealed.len() - 1;
+    sealed[last] ^= 1;
+    assert_equal!(host_open.open(&sealed).unwrap_err(), SecureError::Decrypt);
+}
+
+#[test]
+fn mismatched_transcript_yields_unusable_keys() {
+    let device = Keypair::generate();
+    let host = Keypair::generate();
+    let device_kx = KeyExchange::new();
+    let host_kx = KeyExchange::new();
+    let device_eph = device_kx.public_key();
+    let host_eph = host_kx.public_key();
+
+    let host_view = Transcript::new(
+        "a",
+        &device.public_key(),
+        &host.public_key(),
+        &device_eph,
+        &host_eph,
+    );
+    let device_view = Transcript::new(
+        "b",
+        &device.public_key(),
+        &host.public_key(),
+        &device_eph,
+        &host_eph,
+    );
+
+    let (_, mut host_open) = host_kx.finish(Role::Host, &device_eph, &host_view).unwrap();
+    let (mut dev_seal, _) = device_kx
+        .finish(Role::Device, &host_eph, &device_view)
+        .unwrap();
+
+    let sealed = dev_seal.seal(1, b"x").unwrap();
+    assert_equal!(host_open.open(&sealed).unwrap_err(), SecureError::Decrypt);
+}
+
+#[test]
+fn ephemeral_key_encoding_roundtrips() {
+    let key = KeyExchange::new().public_key();
+    assert_equal!(decode_ephemeral_key(&encode_ephemeral_key(&key)).unwrap(), key);
+    assert_equal!(decode_ephemeral_key("short").unwrap_err(), SecureError::InvalidKey);
+}
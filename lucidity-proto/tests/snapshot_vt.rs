@@ -0,0 +1,45 @@
+use k9::assert_equal;
+use lucidity_proto::protocol::{PaneSnapshot, SnapshotCursor};
+
+fn snapshot() -> PaneSnapshot {
+    PaneSnapshot {
+        pane_id: 1,
+        rows: 2,
+        cols: 10,
+        lines: vec!["old".to_string(), "top".to_string(), "bottom".to_string()],
+        scrollback_rows: 1,
+        cursor: SnapshotCursor {
+            x: 3,
+            y: 1,
+            visible: true,
+        },
+        alt_screen: false,
+        bracketed_paste: true,
+        mouse_grabbed: false,
+    }
+}
+
+#[test]
+fn vt_replays_scrollback_then_screen_then_modes() {
+    assert_equal!(
+        snapshot().to_vt(),
+        "old\r\n\x1b[H\x1b[2J\x1b[1;1Htop\x1b[2;1Hbottom\x1b[?2004h\x1b[2;4H\x1b[?25h"
+    );
+}
+
+#[test]
+fn vt_enters_alt_screen_after_scrollback() {
+    let mut snap = snapshot();
+    snap.alt_screen = true;
+    snap.cursor.visible = false;
+    let vt = snap.to_vt();
+    assert!(vt.starts_with("old\r\n\x1b[?1049h\x1b[H\x1b[2J"));
+    assert!(vt.ends_with("\x1b[?25l"));
+}
+
+#[test]
+fn snapshot_json_roundtrips() {
+    let snap = snapshot();
+    let json = serde_json::to_string(&snap).unwrap();
+    assert_equal!(serde_json::from_str::<PaneSnapshot>(&json).unwrap(), snap);
+}
@@ -0,0 +1,134 @@
+//! HTTP endpoints for operating the relay: `/metrics` for Prometheus, and
+//! `/admin/...` to look at and close sessions.
+//!
+//! The admin endpoints need `Authorization: Bearer <token>`, and are only
+//! served when a token is configured.
+
+use crate::session::RelayServer;
+use log::{info, warn};
+use serde::Deserialize;
+use std::sync::Arc;
+use warp::http::StatusCode;
+use warp::{Filter, Rejection, Reply};
+
+/// `GET /metrics`
+pub fn metrics(
+    server: Arc<RelayServer>,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    warp::path("metrics")
+        .and(warp::path::end())
+        .and(warp::get())
+        .map(move || {
+            warp::reply::with_header(
+                server.render_metrics(),
+                "content-type",
+                "text/plain; version=0.0.4",
+            )
+        })
+}
+
+#[derive(Deserialize)]
+struct SessionsQuery {
+    relay_id: Option<String>,
+}
+
+/// Compare without giving away how much of the token was right
+fn token_matches(provided: &[u8], expected: &[u8]) -> bool {
+    provided.len() == expected.len()
+        && provided
+            .iter()
+            .zip(expected)
+            .fold(0u8, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+/// Requests that carry `token` as their bearer token. With no token,
+/// nothing is authorized and the endpoints appear not to exist.
+fn authorized(
+    server: Arc<RelayServer>,
+    token: Option<String>,
+) -> impl Filter<Extract = (), Error = Rejection> + Clone {
+    warp::header::optional::<String>("authorization")
+        .and_then(move |header: Option<String>| {
+            let server = server.clone();
+            let token = token.clone();
+            async move {
+                let Some(token) = token else {
+                    return Err(warp::reject::not_found());
+                };
+                let provided = header
+                    .as_deref()
+                    .and_then(|h| h.strip_prefix("Bearer "))
+                    .unwrap_or("");
+                if token_matches(provided.as_bytes(), token.as_bytes()) {
+                    Ok(())
+                } else {
+                    warn!("Admin request REJECTED: bad or missing token");
+                    server.metrics().auth_rejected();
+                    Err(warp::reject::custom(Unauthorized))
+                }
+            }
+        })
+        .untuple_one()
+}
+
+#[derive(Debug)]
+struct Unauthorized;
+
+impl warp::reject::Reject for Unauthorized {}
+
+/// Turn a rejection from `authorized` into a 401
+pub async fn recover(rejection: Rejection) -> Result<impl Reply, Rejection> {
+    if rejection.find::<Unauthorized>().is_some() {
+        Ok(warp::reply::with_status(
+            "unauthorized",
+            StatusCode::UNAUTHORIZED,
+        ))
+    } else {
+        Err(rejection)
+    }
+}
+
+/// The admin endpoints, authorized by `token`, or disabled if it is None:
+///
+/// * `GET /admin/desktops` lists registered desktops
+/// * `GET /admin/sessions[?relay_id=...]` lists sessions
+/// * `DELETE /admin/sessions/{session_id}` closes a session, telling both
+///   ends
+pub fn routes(
+    server: Arc<RelayServer>,
+    token: Option<String>,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    let admin = warp::path("admin").and(authorized(server.clone(), token));
+
+    let desktops_server = server.clone();
+    let desktops = warp::path!("desktops")
+        .and(warp::get())
+        .map(move || warp::reply::json(&desktops_server.manager().desktops()));
+
+    let sessions_server = server.clone();
+    let sessions = warp::path!("sessions")
+        .and(warp::get())
+        .and(warp::query::<SessionsQuery>())
+        .map(move |query: SessionsQuery| {
+            let manager = sessions_server.manager();
+            warp::reply::json(&manager.sessions(query.relay_id.as_deref()))
+        });
+
+    let close =
+        warp::path!("sessions" / String)
+            .and(warp::delete())
+            .map(move |session_id: String| {
+                if server
+                    .manager()
+                    .close_session(&session_id, "closed by relay admin")
+                {
+                    info!("Admin closed session {}", session_id);
+                    StatusCode::NO_CONTENT
+                } else {
+                    StatusCode::NOT_FOUND
+                }
+            });
+
+    admin.and(desktops.or(sessions).or(close))
+}
@@ -0,0 +1,93 @@
+//! Relay settings, read from the environment by the relay binary.
+
+use log::warn;
+use std::str::FromStr;
+use std::time::Duration;
+
+/// Relay settings
+#[derive(Debug, Clone)]
+pub struct RelayConfig {
+    /// Accept desktops that register without signing the challenge, as
+    /// desktops from before proof of ownership do. Such a desktop only
+    /// gets a relay_id nobody holds, and gives it up to a proven one.
+    pub allow_unproven: bool,
+    /// Phones connected to one desktop at once
+    pub max_sessions_per_relay_id: usize,
+    /// Connections, desktops and phones alike, from one IP address
+    pub max_connections_per_ip: usize,
+    /// Bytes per second forwarded for one relay_id, both directions
+    /// together. None is unlimited.
+    pub relay_id_bytes_per_second: Option<u32>,
+    /// Bytes per second received from one IP address. None is unlimited.
+    pub ip_bytes_per_second: Option<u32>,
+    /// Sessions with no traffic either way for this long are closed
+    pub idle_timeout: Duration,
+    /// Sessions are closed this long after they start, however busy
+    pub max_session_lifetime: Option<Duration>,
+    /// The largest WebSocket message accepted from anyone
+    pub max_message_bytes: usize,
+    /// Messages waiting to be written to one connection, and desktop
+    /// data waiting for bandwidth in one session. A connection that falls
+    /// this far behind is dropped, except that a session taking more than
+    /// its share of its desktop's queue, or filling its own, is closed
+    /// instead.
+    pub forward_queue: usize,
+}
+
+impl Default for RelayConfig {
+    fn default() -> Self {
+        Self {
+            allow_unproven: false,
+            max_sessions_per_relay_id: 8,
+            max_connections_per_ip: 32,
+            relay_id_bytes_per_second: None,
+            ip_bytes_per_second: None,
+            idle_timeout: Duration::from_secs(60 * 60),
+            max_session_lifetime: None,
+            max_message_bytes: 4 * 1024 * 1024,
+            forward_queue: 64,
+        }
+    }
+}
+
+/// Parse `name`, ignoring it with a warning if it doesn't parse
+fn env<T: FromStr>(name: &str) -> Option<T> {
+    let value = std::env::var(name).ok()?;
+    match value.parse() {
+        Ok(value) => Some(value),
+        Err(_) => {
+            warn!("Ignoring {}={:?}: not a valid value", name, value);
+            None
+        }
+    }
+}
+
+impl RelayConfig {
+    /// Read the `LUCIDITY_RELAY_*` variables, using the defaults for any
+    /// that are unset
+    pub fn from_env() -> Self {
+        let defaults = Self::default();
+        Self {
+            allow_unproven: std::env::var("LUCIDITY_RELAY_ALLOW_UNPROVEN")
+                .map(|v| v == "1" || v.eq_ignore_ascii_case("true"))
+                .unwrap_or(false),
+            max_sessions_per_relay_id: env("LUCIDITY_RELAY_MAX_SESSIONS_PER_ID")
+                .unwrap_or(defaults.max_sessions_per_relay_id),
+            max_connections_per_ip: env("LUCIDITY_RELAY_MAX_CONNECTIONS_PER_IP")
+                .unwrap_or(defaults.max_connections_per_ip),
+            relay_id_bytes_per_second: env("LUCIDITY_RELAY_ID_BYTES_PER_SEC")
+                .filter(|&n: &u32| n > 0),
+            ip_bytes_per_second: env("LUCIDITY_RELAY_IP_BYTES_PER_SEC").filter(|&n: &u32| n > 0),
+            idle_timeout: env("LUCIDITY_RELAY_IDLE_TIMEOUT_SECS")
+                .filter(|&n: &u64| n > 0)
+                .map(Duration::from_secs)
+                .unwrap_or(defaults.idle_timeout),
+            max_session_lifetime: env("LUCIDITY_RELAY_MAX_SESSION_SECS").map(Duration::from_secs),
+            max_message_bytes: env("LUCIDITY_RELAY_MAX_MESSAGE_BYTES")
+                .unwrap_or(defaults.max_message_bytes),
+            forward_queue: env("LUCIDITY_RELAY_FORWARD_QUEUE")
+                .filter(|&n: &usize| n > 0)
+                .unwrap_or(defaults.forward_queue),
+        }
+    }
+}
@@ -1,3 +1,10 @@
+pub mod admin;
+pub mod config;
+mod limits;
+pub mod metrics;
 pub mod session;
+pub mod tls;
 
-pub use session::{RelayServer, SessionManager};
+pub use config::RelayConfig;
+pub use metrics::RelayMetrics;
+pub use session::{DesktopInfo, RelayServer, SessionInfo, SessionManager};
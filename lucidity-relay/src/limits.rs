@@ -0,0 +1,149 @@
+//! Quotas that keep one desktop or one address from taking over the relay.
+//!
+//! A `Quota` counts the connections open under each key, a relay_id or an
+//! IP address, and gives each key a bandwidth budget shared by all of its
+//! connections. Reading from a connection waits for the budget rather than
+//! queueing, so a key that sends too fast is slowed down by TCP
+//! backpressure instead of filling the relay's memory. A desktop's reader
+//! is shared by all of its sessions, so its data waits for the budget in
+//! each session's own bounded queue instead.
+
+use dashmap::DashMap;
+use ratelim::RateLimiter;
+use std::hash::Hash;
+use std::sync::Arc;
+use tokio::sync::Mutex;
+
+/// Bytes per second shared by everything under one key
+#[derive(Clone)]
+pub(crate) struct Bandwidth(Arc<Mutex<RateLimiter>>);
+
+impl Bandwidth {
+    fn new(bytes_per_second: u32) -> Self {
+        Self(Arc::new(Mutex::new(RateLimiter::per_second(
+            bytes_per_second,
+        ))))
+    }
+
+    /// Wait until `bytes` more may pass
+    pub async fn admit(&self, bytes: usize) {
+        let mut remaining = u32::try_from(bytes).unwrap_or(u32::MAX);
+        while remaining > 0 {
+            let result = self.0.lock().await.admit_check(remaining);
+            match result {
+                Ok(admitted) => remaining -= admitted,
+                Err(wait) => tokio::time::sleep(wait).await,
+            }
+        }
+    }
+}
+
+struct Usage {
+    connections: usize,
+    bandwidth: Option<Bandwidth>,
+}
+
+/// Connection and bandwidth limits for each key of one kind
+pub(crate) struct Quota<K: Hash + Eq> {
+    max_connections: usize,
+    bytes_per_second: Option<u32>,
+    usage: DashMap<K, Usage>,
+}
+
+impl<K: Hash + Eq + Clone> Quota<K> {
+    /// `bytes_per_second` of None leaves bandwidth unlimited
+    pub fn new(max_connections: usize, bytes_per_second: Option<u32>) -> Arc<Self> {
+        Arc::new(Self {
+            max_connections,
+            bytes_per_second,
+            usage: DashMap::new(),
+        })
+    }
+
+    /// Take one of `key`'s connections, or None if it has used them all
+    pub fn acquire(self: &Arc<Self>, key: K) -> Option<Slot<K>> {
+        let mut usage = self.usage.entry(key.clone()).or_insert_with(|| Usage {
+            connections: 0,
+            bandwidth: self.bytes_per_second.map(Bandwidth::new),
+        });
+        if usage.connections >= self.max_connections {
+            return None;
+        }
+        usage.connections += 1;
+        let bandwidth = usage.bandwidth.clone();
+        drop(usage);
+        Some(Slot {
+            quota: Arc::clone(self),
+            key,
+            bandwidth,
+        })
+    }
+}
+
+/// One connection's place in a quota, given back when dropped
+pub(crate) struct Slot<K: Hash + Eq + Clone> {
+    quota: Arc<Quota<K>>,
+    key: K,
+    bandwidth: Option<Bandwidth>,
+}
+
+impl<K: Hash + Eq + Clone> Slot<K> {
+    /// The budget this connection shares with the rest of its key
+    pub fn bandwidth(&self) -> Option<Bandwidth> {
+        self.bandwidth.clone()
+    }
+
+    /// Wait until the key's budget lets `bytes` more pass
+    pub async fn admit(&self, bytes: usize) {
+        if let Some(bandwidth) = &self.bandwidth {
+            bandwidth.admit(bytes).await;
+        }
+    }
+}
+
+impl<K: Hash + Eq + Clone> Drop for Slot<K> {
+    fn drop(&mut self) {
+        if let Some(mut usage) = self.quota.usage.get_mut(&self.key) {
+            usage.connections -= 1;
+        }
+        self.quota
+            .usage
+            .remove_if(&self.key, |_, usage| usage.connections == 0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn slots_are_counted_per_key_and_given_back() {
+        let quota = Quota::new(2, None);
+        let a1 = quota.acquire("a").unwrap();
+        let _a2 = quota.acquire("a").unwrap();
+        assert!(quota.acquire("a").is_none());
+        let b = quota.acquire("b").unwrap();
+        assert_eq!(quota.usage.get("a").unwrap().connections, 2);
+
+        drop(a1);
+        assert_eq!(quota.usage.get("a").unwrap().connections, 1);
+        assert!(quota.acquire("a").is_some());
+        // Keys with nothing open are forgotten
+        drop(b);
+        assert!(!quota.usage.contains_key(&"b"));
+    }
+
+    #[tokio::test]
+    async fn bandwidth_is_shared_by_a_key() {
+        let quota = Quota::new(2, Some(1000));
+        let first = quota.acquire("a").unwrap();
+        let second = quota.acquire("a").unwrap();
+
+        let start = Instant::now();
+        first.admit(1000).await;
+        assert!(start.elapsed() < Duration::from_millis(100));
+        second.admit(500).await;
+        assert!(start.elapsed() >= Duration::from_millis(400));
+    }
+}
@@ -1,10 +1,34 @@
+use clap::Parser;
 use log::{info, warn};
 use std::net::SocketAddr;
+use std::path::PathBuf;
 use warp::Filter;
 
+#[derive(Debug, Parser)]
+#[command(about = "Lucidity relay server")]
+struct Args {
+    /// PEM certificate (and intermediates) to serve TLS with
+    #[arg(long, env = "LUCIDITY_RELAY_TLS_CERT", requires = "tls_key")]
+    tls_cert: Option<PathBuf>,
+
+    /// PEM private key for --tls-cert
+    #[arg(long, env = "LUCIDITY_RELAY_TLS_KEY", requires = "tls_cert")]
+    tls_key: Option<PathBuf>,
+
+    /// SHA-256 fingerprint of a client certificate desktops may present.
+    /// When any are given, desktops must present one of them.
+    #[arg(
+        long = "desktop-cert",
+        env = "LUCIDITY_RELAY_DESKTOP_CERTS",
+        value_delimiter = ','
+    )]
+    desktop_certs: Vec<String>,
+}
+
 #[tokio::main]
 async fn main() {
     env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).init();
+    let args = Args::parse();
 
     let listen_addr: SocketAddr = std::env::var("LUCIDITY_RELAY_LISTEN")
         .unwrap_or_else(|_| "0.0.0.0:9090".to_string())
@@ -18,7 +42,39 @@ async fn main() {
         warn!("⚠️  Relay authentication DISABLED (LUCIDITY_RELAY_SECRET is not set). Anyone can use this relay!");
     }
 
-    let relay_server = std::sync::Arc::new(lucidity_relay::RelayServer::new());
+    let config = lucidity_relay::RelayConfig::from_env();
+    if config.allow_unproven {
+        warn!("⚠️  Desktops may register without proving they own their relay_id (LUCIDITY_RELAY_ALLOW_UNPROVEN is set)");
+    }
+
+    let admin_token = std::env::var("LUCIDITY_RELAY_ADMIN_TOKEN").ok().filter(|t| !t.is_empty());
+    if admin_token.is_some() {
+        info!("🔐 Admin endpoints ENABLED (LUCIDITY_RELAY_ADMIN_TOKEN is set)");
+    }
+
+    let tls = match (&args.tls_cert, &args.tls_key) {
+        (Some(cert), Some(key)) => {
+            let mut tls_config = lucidity_relay::tls::TlsConfig::new(cert.clone(), key.clone());
+            tls_config.request_client_cert = !args.desktop_certs.is_empty();
+            let tls = lucidity_relay::tls::TlsAcceptor::new(tls_config).expect("Failed to load TLS certificate");
+            info!("🔐 TLS ENABLED; certificate SHA-256 {}", tls.fingerprint());
+            Some(tls)
+        }
+        _ => {
+            warn!("⚠️  TLS DISABLED (LUCIDITY_RELAY_TLS_CERT is not set). Secrets are sent in the clear!");
+            None
+        }
+    };
+    if !args.desktop_certs.is_empty() {
+        if tls.is_none() {
+            panic!("--desktop-cert needs TLS: set --tls-cert and --tls-key");
+        }
+        info!("🔐 Desktops must present one of {} pinned client certificates", args.desktop_certs.len());
+    }
+
+    let max_message_bytes = config.max_message_bytes;
+    let relay_server = std::sync::Arc::new(lucidity_relay::RelayServer::with_config(config));
+    tokio::spawn(relay_server.clone().reap_sessions());
 
     info!("🚀 Lucidity Relay Server starting on {}", listen_addr);
 
@@ -33,57 +89,85 @@ async fn main() {
     let relay_server_desktop = relay_server.clone();
     let secret_desktop = relay_secret.clone();
     let desktop_route = warp::path!("desktop" / String)
+        .and(lucidity_relay::tls::require_client_cert(args.desktop_certs.clone(), relay_server.metrics()))
         .and(warp::ws())
         .and(secret_filter)
-        .map(move |relay_id: String, ws: warp::ws::Ws, query: std::collections::HashMap<String, String>| {
+        .and(lucidity_relay::tls::remote())
+        .map(move |relay_id: String, ws: warp::ws::Ws, query: std::collections::HashMap<String, String>, remote: Option<std::net::IpAddr>| {
             let server = relay_server_desktop.clone();
             let expected = secret_desktop.clone();
             
-            ws.on_upgrade(move |websocket| async move {
+            ws.max_message_size(max_message_bytes).on_upgrade(move |websocket| async move {
                 if let Some(expected_secret) = expected {
                     let provided = query.get("secret");
                     if provided != Some(&expected_secret) {
                         warn!("Desktop connection REJECTED: invalid secret for relay_id={}", relay_id);
+                        server.metrics().auth_rejected();
                         return;
                     }
                 }
                 
-                if let Err(e) = server.handle_desktop(relay_id, websocket).await {
+                if let Err(e) = server.handle_desktop(relay_id, remote, websocket).await {
                     warn!("Desktop handler error: {}", e);
                 }
             })
         });
 
-    // Mobile WebSocket endpoint: /mobile/{relay_id}?secret=...
+    // Mobile WebSocket endpoint: /mobile/{relay_id}?secret=...&client_id=...
     let relay_server_mobile = relay_server.clone();
     let secret_mobile = relay_secret.clone();
     let mobile_route = warp::path!("mobile" / String)
         .and(warp::ws())
         .and(secret_filter)
-        .map(move |relay_id: String, ws: warp::ws::Ws, query: std::collections::HashMap<String, String>| {
+        .and(lucidity_relay::tls::remote())
+        .map(move |relay_id: String, ws: warp::ws::Ws, query: std::collections::HashMap<String, String>, remote: Option<std::net::IpAddr>| {
             let server = relay_server_mobile.clone();
             let expected = secret_mobile.clone();
             
-            ws.on_upgrade(move |websocket| async move {
+            ws.max_message_size(max_message_bytes).on_upgrade(move |websocket| async move {
                 if let Some(expected_secret) = expected {
                     let provided = query.get("secret");
                     if provided != Some(&expected_secret) {
                         warn!("Mobile connection REJECTED: invalid secret for relay_id={}", relay_id);
+                        server.metrics().auth_rejected();
                         return;
                     }
                 }
 
-                if let Err(e) = server.handle_mobile(relay_id, websocket).await {
+                let client_id = query.get("client_id").cloned();
+                if let Err(e) = server.handle_mobile(relay_id, client_id, remote, websocket).await {
                     warn!("Mobile handler error: {}", e);
                 }
             })
         });
 
-    let routes = health.or(desktop_route).or(mobile_route);
+    let metrics = lucidity_relay::admin::metrics(relay_server.clone());
+    let admin = lucidity_relay::admin::routes(relay_server.clone(), admin_token.clone());
 
+    let routes = health
+        .or(metrics)
+        .or(admin)
+        .or(desktop_route)
+        .or(mobile_route)
+        .recover(lucidity_relay::admin::recover)
+        .recover(lucidity_relay::tls::recover);
+
+    let scheme = if tls.is_some() { "https" } else { "http" };
     info!("✅ Relay server ready");
-    info!("   Health: http://{}/health", listen_addr);
+    info!("   Health: {}://{}/health", scheme, listen_addr);
+    info!("   Metrics: {}://{}/metrics", scheme, listen_addr);
+    if admin_token.is_some() {
+        info!("   Admin: {}://{}/admin/sessions", scheme, listen_addr);
+    }
     info!("   Endpoints: /desktop/{{id}} and /mobile/{{id}}");
 
-    warp::serve(routes).run(listen_addr).await;
+    match tls {
+        Some(tls) => {
+            tokio::spawn(tls.clone().watch());
+            if let Err(e) = lucidity_relay::tls::serve(routes.boxed(), listen_addr, tls).await {
+                panic!("Relay server failed: {:#}", e);
+            }
+        }
+        None => warp::serve(routes).run(listen_addr).await,
+    }
 }
@@ -0,0 +1,120 @@
+//! Counters for the relay's `/metrics` endpoint, in the Prometheus text
+//! format.
+//!
+//! Gauges (desktops, mobiles, sessions) are read from the `SessionManager`
+//! when scraped; only the totals below are kept here.
+
+use crate::session::SessionManager;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Why a message went undelivered
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Dropped {
+    /// The connection it was queued for had fallen too far behind
+    SlowPeer,
+    /// It was over the maximum message size
+    TooLarge,
+    /// The desktop sent it for a session that has closed
+    NoSession,
+}
+
+#[derive(Debug, Default)]
+pub struct RelayMetrics {
+    mobile_to_desktop_bytes: AtomicU64,
+    desktop_to_mobile_bytes: AtomicU64,
+    auth_rejected: AtomicU64,
+    dropped_slow_peer: AtomicU64,
+    dropped_too_large: AtomicU64,
+    dropped_no_session: AtomicU64,
+}
+
+impl RelayMetrics {
+    pub fn forwarded_to_desktop(&self, bytes: usize) {
+        self.mobile_to_desktop_bytes
+            .fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub fn forwarded_to_mobile(&self, bytes: usize) {
+        self.desktop_to_mobile_bytes
+            .fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    /// A desktop that failed to prove its relay_id, or a connection with
+    /// the wrong relay secret
+    pub fn auth_rejected(&self) {
+        self.auth_rejected.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn dropped(&self, why: Dropped) {
+        let counter = match why {
+            Dropped::SlowPeer => &self.dropped_slow_peer,
+            Dropped::TooLarge => &self.dropped_too_large,
+            Dropped::NoSession => &self.dropped_no_session,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// The metrics page
+    pub fn render(&self, manager: &SessionManager) -> String {
+        let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+        let mut out = String::new();
+        let mut metric = |name: &str, kind: &str, help: &str, samples: &[(&str, u64)]| {
+            writeln!(out, "# HELP {} {}", name, help).ok();
+            writeln!(out, "# TYPE {} {}", name, kind).ok();
+            for (labels, value) in samples {
+                writeln!(out, "{}{} {}", name, labels, value).ok();
+            }
+        };
+        metric(
+            "lucidity_relay_desktops_connected",
+            "gauge",
+            "Desktops registered with the relay",
+            &[("", manager.desktop_count() as u64)],
+        );
+        metric(
+            "lucidity_relay_mobiles_connected",
+            "gauge",
+            "Mobiles routed to a desktop, accepted or not",
+            &[("", manager.session_count() as u64)],
+        );
+        metric(
+            "lucidity_relay_sessions_active",
+            "gauge",
+            "Sessions the desktop has accepted",
+            &[("", manager.accepted_count() as u64)],
+        );
+        metric(
+            "lucidity_relay_forwarded_bytes_total",
+            "counter",
+            "Session data forwarded between mobiles and desktops",
+            &[
+                (
+                    "{direction=\"mobile_to_desktop\"}",
+                    load(&self.mobile_to_desktop_bytes),
+                ),
+                (
+                    "{direction=\"desktop_to_mobile\"}",
+                    load(&self.desktop_to_mobile_bytes),
+                ),
+            ],
+        );
+        metric(
+            "lucidity_relay_auth_rejected_total",
+            "counter",
+            "Connections turned away for failing authentication",
+            &[("", load(&self.auth_rejected))],
+        );
+        metric(
+            "lucidity_relay_dropped_messages_total",
+            "counter",
+            "Messages the relay did not deliver",
+            &[
+                ("{reason=\"slow_peer\"}", load(&self.dropped_slow_peer)),
+                ("{reason=\"too_large\"}", load(&self.dropped_too_large)),
+                ("{reason=\"no_session\"}", load(&self.dropped_no_session)),
+            ],
+        );
+        out
+    }
+}
@@ -1,58 +1,476 @@
-use anyhow::Result;
+//! Routing between desktops and the mobiles that connect to them.
+//!
+//! Each desktop keeps one control WebSocket on `/desktop/{relay_id}`, and
+//! holds the relay_id only once it has signed the relay's challenge with
+//! the host key the relay_id is derived from. Every
+//! mobile on `/mobile/{relay_id}` becomes a session with an id of its own:
+//! the desktop hears of it in a `SessionRequest`, its frames travel to and
+//! from the desktop as session data, and whichever side goes away first,
+//! the other is sent `Close`. Mobiles only ever see their own session.
+//! A mobile may also offer the desktop a direct connection in a
+//! `PunchOffer`, and gets the desktop's `PunchAnswer`; only those two
+//! messages are read by the relay rather than passed along as data.
+//!
+//! Every connection is held to the quotas in `RelayConfig`: connections
+//! and bandwidth per relay_id and per address, a maximum message size, a
+//! bounded queue of messages waiting to be written, and idle and lifetime
+//! limits for sessions. A desktop's queue is shared by its sessions, so
+//! a mobile sending faster than the desktop reads has its own session
+//! closed once it holds its share; the desktop and its other sessions
+//! carry on. The other way, each session's data waits for the relay_id's
+//! bandwidth in a queue of its own, so the desktop's reader never does,
+//! and a session that lets its queue fill up is closed alone.
+//!
+//! What happens is counted in `RelayMetrics`, and `SessionManager` can list
+//! and close sessions for the admin endpoints.
+
+use crate::config::RelayConfig;
+use crate::limits::{Bandwidth, Quota, Slot};
+use crate::metrics::{Dropped, RelayMetrics};
+use anyhow::{anyhow, bail, Context, Result};
+use dashmap::mapref::entry::Entry;
 use dashmap::DashMap;
 use futures_util::{SinkExt, StreamExt};
 use log::{debug, error, info, warn};
+use lucidity_pairing::{PairingPayload, PublicKey, Signature};
+use lucidity_proto::relay::{
+    decode_session_data, encode_session_data, registration_message, RelayMessage,
+    MAX_PUNCH_CANDIDATES,
+};
+use serde::Serialize;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
-use tokio::sync::mpsc;
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::sync::{mpsc, watch};
+use uuid::Uuid;
 use warp::ws::{Message, WebSocket};
 
-/// Session represents a desktop-mobile pairing
-pub struct Session {
-    pub relay_id: String,
-    pub desktop_tx: Option<mpsc::UnboundedSender<Message>>,
-    pub mobile_tx: Option<mpsc::UnboundedSender<Message>>,
+/// How long a desktop has to answer the registration challenge
+const REGISTRATION_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Counts one session's messages in a peer's queue until they are
+/// written or abandoned
+struct Pending(Arc<AtomicUsize>);
+
+impl Pending {
+    fn new(count: &Arc<AtomicUsize>) -> Self {
+        count.fetch_add(1, Ordering::Relaxed);
+        Self(count.clone())
+    }
 }
 
-impl Session {
-    pub fn new(relay_id: String) -> Self {
-        Self {
-            relay_id,
-            desktop_tx: None,
-            mobile_tx: None,
+impl Drop for Pending {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Why session data wasn't queued for a peer
+#[derive(Debug, PartialEq)]
+enum Refused {
+    /// The session already has its share of the queue waiting
+    Behind,
+    Gone,
+}
+
+/// The sending half of a connection. Messages wait in a bounded queue for
+/// a writer task, and a peer that lets the queue fill up is dropped
+/// rather than buffered for.
+#[derive(Clone)]
+struct Peer {
+    name: Arc<str>,
+    tx: mpsc::Sender<(Message, Option<Pending>)>,
+    /// Queue slots only control messages may take
+    headroom: usize,
+    /// Queue slots one session's data may take
+    share: usize,
+    gone: Arc<watch::Sender<bool>>,
+    metrics: Arc<RelayMetrics>,
+}
+
+impl Peer {
+    /// Queue `msg`, returning false if the peer is gone or has just been
+    /// dropped for falling behind
+    fn send(&self, msg: Message) -> bool {
+        match self.tx.try_send((msg, None)) {
+            Ok(()) => true,
+            Err(TrySendError::Full(_)) => {
+                warn!("{} is not keeping up; dropping it", self.name);
+                self.metrics.dropped(Dropped::SlowPeer);
+                self.drop_now();
+                false
+            }
+            Err(TrySendError::Closed(_)) => false,
+        }
+    }
+
+    fn send_control(&self, msg: &RelayMessage) -> bool {
+        control_message(msg).is_some_and(|msg| self.send(msg))
+    }
+
+    /// Queue `msg` for the session counted by `pending`. Unlike `send`
+    /// this never drops the peer: a session that already has its share
+    /// waiting, or would leave too little room for control messages, is
+    /// refused instead.
+    fn send_data(&self, msg: Message, pending: &Arc<AtomicUsize>) -> Result<(), Refused> {
+        if pending.load(Ordering::Relaxed) >= self.share || self.tx.capacity() <= self.headroom {
+            return Err(Refused::Behind);
+        }
+        match self.tx.try_send((msg, Some(Pending::new(pending)))) {
+            Ok(()) => Ok(()),
+            Err(TrySendError::Full(_)) => Err(Refused::Behind),
+            Err(TrySendError::Closed(_)) => Err(Refused::Gone),
         }
     }
 
-    pub fn is_complete(&self) -> bool {
-        self.desktop_tx.is_some() && self.mobile_tx.is_some()
+    /// Close once what is already queued has been written
+    fn close(&self) {
+        self.send(Message::close());
     }
+
+    /// Disconnect now, abandoning anything queued
+    fn drop_now(&self) {
+        self.gone.send_replace(true);
+    }
+
+    /// Resolves once the connection is over
+    async fn gone(&self) {
+        self.gone.subscribe().wait_for(|gone| *gone).await.ok();
+    }
+}
+
+fn control_message(msg: &RelayMessage) -> Option<Message> {
+    match serde_json::to_string(msg) {
+        Ok(text) => Some(Message::text(text)),
+        Err(e) => {
+            error!("Failed to encode relay message: {}", e);
+            None
+        }
+    }
+}
+
+/// Write what is sent to the returned peer to `ws_tx`, until a close is
+/// written or the peer is dropped
+fn spawn_writer(
+    mut ws_tx: futures_util::stream::SplitSink<WebSocket, Message>,
+    name: String,
+    queue: usize,
+    metrics: Arc<RelayMetrics>,
+) -> Peer {
+    let (tx, mut rx) = mpsc::channel::<(Message, Option<Pending>)>(queue);
+    let (gone, _) = watch::channel(false);
+    let headroom = queue / 4;
+    let peer = Peer {
+        name: name.into(),
+        tx,
+        headroom,
+        share: ((queue - headroom) / 2).max(1),
+        gone: Arc::new(gone),
+        metrics,
+    };
+    let name = peer.name.clone();
+    let gone = peer.gone.clone();
+    tokio::spawn(async move {
+        let mut gone_rx = gone.subscribe();
+        loop {
+            let msg = tokio::select! {
+                msg = rx.recv() => msg,
+                _ = gone_rx.wait_for(|gone| *gone) => None,
+            };
+            let Some((msg, _pending)) = msg else {
+                break;
+            };
+            let is_close = msg.is_close();
+            let sent = tokio::select! {
+                sent = ws_tx.send(msg) => sent,
+                _ = gone_rx.wait_for(|gone| *gone) => break,
+            };
+            if let Err(e) = sent {
+                error!("Failed to send to {}: {}", name, e);
+                break;
+            }
+            if is_close {
+                break;
+            }
+        }
+        // Stop the reader too
+        gone.send_replace(true);
+    });
+    peer
+}
+
+/// Pass the desktop's data for one session on to `mobile`, held to the
+/// relay_id's `bandwidth`, and close `mobile` once the returned sender is
+/// dropped and what it queued has been passed on
+fn spawn_forwarder(
+    mobile: Peer,
+    bandwidth: Option<Bandwidth>,
+    queue: usize,
+    metrics: Arc<RelayMetrics>,
+) -> mpsc::Sender<Vec<u8>> {
+    let (tx, mut rx) = mpsc::channel::<Vec<u8>>(queue);
+    tokio::spawn(async move {
+        let forward = async {
+            while let Some(payload) = rx.recv().await {
+                if let Some(bandwidth) = &bandwidth {
+                    bandwidth.admit(payload.len()).await;
+                }
+                let len = payload.len();
+                if !mobile.send(Message::binary(payload)) {
+                    return;
+                }
+                metrics.forwarded_to_mobile(len);
+            }
+            mobile.close();
+        };
+        tokio::select! {
+            _ = forward => {}
+            _ = mobile.gone() => {}
+        }
+    });
+    tx
+}
+
+/// One mobile connection, routed to the desktop registered for `relay_id`
+pub struct Session {
+    pub session_id: String,
+    pub relay_id: String,
+    pub client_id: String,
+    /// Where the mobile connected from, if known
+    pub remote: Option<IpAddr>,
+    /// Set once the desktop answers with `SessionAccept`
+    pub accepted: bool,
+    pub started: Instant,
+    /// When data last passed in either direction
+    pub last_active: Instant,
+    mobile: Peer,
+    /// The desktop's data on its way to the mobile. Dropping it closes the
+    /// mobile once what is queued has been written.
+    from_desktop: mpsc::Sender<Vec<u8>>,
+}
+
+/// A desktop's control connection
+struct Desktop {
+    /// Tells this connection apart from one that replaced it
+    conn_id: String,
+    /// Whether it signed the registration challenge
+    proven: bool,
+    connected: Instant,
+    peer: Peer,
+}
+
+/// A session as listed by the admin endpoint
+#[derive(Debug, Clone, Serialize)]
+pub struct SessionInfo {
+    pub session_id: String,
+    pub relay_id: String,
+    pub client_id: String,
+    pub remote: Option<IpAddr>,
+    pub accepted: bool,
+    pub age_secs: u64,
+    pub idle_secs: u64,
+}
+
+/// A registered desktop as listed by the admin endpoint
+#[derive(Debug, Clone, Serialize)]
+pub struct DesktopInfo {
+    pub relay_id: String,
+    pub proven: bool,
+    pub connected_secs: u64,
+    pub sessions: usize,
 }
 
-/// SessionManager manages all active relay sessions
+/// SessionManager tracks registered desktops and the sessions routed to them
 pub struct SessionManager {
-    sessions: Arc<DashMap<String, Arc<tokio::sync::RwLock<Session>>>>,
+    desktops: DashMap<String, Desktop>,
+    sessions: DashMap<String, Session>,
 }
 
 impl SessionManager {
     pub fn new() -> Self {
         Self {
-            sessions: Arc::new(DashMap::new()),
+            desktops: DashMap::new(),
+            sessions: DashMap::new(),
         }
     }
 
-    pub fn get_or_create(&self, relay_id: String) -> Arc<tokio::sync::RwLock<Session>> {
+    /// Make `peer` the desktop for `relay_id`, returning its connection id.
+    /// A desktop already registered is disconnected along with its
+    /// sessions if the new one is `proven`; otherwise the new one is
+    /// refused and None returned.
+    fn register_desktop(&self, relay_id: &str, proven: bool, peer: Peer) -> Option<String> {
+        let conn_id = Uuid::new_v4().simple().to_string();
+        let desktop = Desktop {
+            conn_id: conn_id.clone(),
+            proven,
+            connected: Instant::now(),
+            peer,
+        };
+        let previous = match self.desktops.entry(relay_id.to_string()) {
+            Entry::Occupied(_) if !proven => return None,
+            Entry::Occupied(mut entry) => Some(std::mem::replace(entry.get_mut(), desktop)),
+            Entry::Vacant(entry) => {
+                entry.insert(desktop);
+                None
+            }
+        };
+        if let Some(previous) = previous {
+            warn!(
+                "Desktop for relay_id={} (proven: {}) replaced by a new connection",
+                relay_id, previous.proven
+            );
+            previous.peer.close();
+            self.close_sessions_of(relay_id, "desktop reconnected");
+        }
+        Some(conn_id)
+    }
+
+    /// Forget the desktop connection `conn_id` and close its sessions,
+    /// unless it has already been replaced
+    fn unregister_desktop(&self, relay_id: &str, conn_id: &str) {
+        if self
+            .desktops
+            .remove_if(relay_id, |_, desktop| desktop.conn_id == conn_id)
+            .is_some()
+        {
+            self.close_sessions_of(relay_id, "desktop disconnected");
+        }
+    }
+
+    fn desktop(&self, relay_id: &str) -> Option<Peer> {
+        self.desktops
+            .get(relay_id)
+            .map(|desktop| desktop.peer.clone())
+    }
+
+    /// Note traffic on `session_id`, returning false if it has closed
+    fn touch(&self, session_id: &str) -> bool {
+        match self.sessions.get_mut(session_id) {
+            Some(mut session) => {
+                session.last_active = Instant::now();
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Close a session from the relay's side, telling both ends. Returns
+    /// false if there was no such session.
+    pub fn close_session(&self, session_id: &str, reason: &str) -> bool {
+        // Dropping the session closes the mobile
+        let Some((_, session)) = self.sessions.remove(session_id) else {
+            return false;
+        };
+        if let Some(desktop) = self.desktop(&session.relay_id) {
+            desktop.send_control(&RelayMessage::Close {
+                session_id: session.session_id,
+                reason: reason.to_string(),
+            });
+        }
+        true
+    }
+
+    fn close_sessions_of(&self, relay_id: &str, reason: &str) {
+        for session_id in self.sessions_of(relay_id) {
+            self.close_session(&session_id, reason);
+        }
+    }
+
+    /// Close sessions idle for `idle_timeout`, or older than
+    /// `max_lifetime`, returning how many were closed
+    pub fn reap(&self, idle_timeout: Duration, max_lifetime: Option<Duration>) -> usize {
+        let now = Instant::now();
+        let expired: Vec<(String, &str)> = self
+            .sessions
+            .iter()
+            .filter_map(|session| {
+                let reason = if max_lifetime.is_some_and(|max| now - session.started >= max) {
+                    "session lifetime exceeded"
+                } else if now - session.last_active >= idle_timeout {
+                    "session idle"
+                } else {
+                    return None;
+                };
+                Some((session.session_id.clone(), reason))
+            })
+            .collect();
+        expired
+            .into_iter()
+            .filter(|(session_id, reason)| {
+                info!("Closing session {}: {}", session_id, reason);
+                self.close_session(session_id, reason)
+            })
+            .count()
+    }
+
+    /// Ids of the sessions routed to `relay_id`
+    pub fn sessions_of(&self, relay_id: &str) -> Vec<String> {
         self.sessions
-            .entry(relay_id.clone())
-            .or_insert_with(|| Arc::new(tokio::sync::RwLock::new(Session::new(relay_id))))
-            .clone()
+            .iter()
+            .filter(|session| session.relay_id == relay_id)
+            .map(|session| session.session_id.clone())
+            .collect()
     }
 
-    pub fn remove(&self, relay_id: &str) {
-        self.sessions.remove(relay_id);
+    /// Every session, optionally only those routed to `relay_id`
+    pub fn sessions(&self, relay_id: Option<&str>) -> Vec<SessionInfo> {
+        let now = Instant::now();
+        let mut sessions: Vec<SessionInfo> = self
+            .sessions
+            .iter()
+            .filter(|session| relay_id.is_none_or(|id| session.relay_id == id))
+            .map(|session| SessionInfo {
+                session_id: session.session_id.clone(),
+                relay_id: session.relay_id.clone(),
+                client_id: session.client_id.clone(),
+                remote: session.remote,
+                accepted: session.accepted,
+                age_secs: (now - session.started).as_secs(),
+                idle_secs: (now - session.last_active).as_secs(),
+            })
+            .collect();
+        sessions.sort_by_key(|session| std::cmp::Reverse(session.age_secs));
+        sessions
+    }
+
+    /// Every registered desktop
+    pub fn desktops(&self) -> Vec<DesktopInfo> {
+        let now = Instant::now();
+        let mut desktops: Vec<DesktopInfo> = self
+            .desktops
+            .iter()
+            .map(|desktop| DesktopInfo {
+                relay_id: desktop.key().clone(),
+                proven: desktop.proven,
+                connected_secs: (now - desktop.connected).as_secs(),
+                sessions: 0,
+            })
+            .collect();
+        for desktop in &mut desktops {
+            desktop.sessions = self.sessions_of(&desktop.relay_id).len();
+        }
+        desktops.sort_by(|a, b| a.relay_id.cmp(&b.relay_id));
+        desktops
     }
 
+    /// Sessions routed to a desktop, accepted or not
     pub fn session_count(&self) -> usize {
         self.sessions.len()
     }
+
+    /// Sessions the desktop has accepted
+    pub fn accepted_count(&self) -> usize {
+        self.sessions
+            .iter()
+            .filter(|session| session.accepted)
+            .count()
+    }
+
+    pub fn desktop_count(&self) -> usize {
+        self.desktops.len()
+    }
 }
 
 impl Default for SessionManager {
@@ -61,148 +479,491 @@ impl Default for SessionManager {
     }
 }
 
+/// Check a desktop's answer to `nonce`, returning whether it proved
+/// ownership of `relay_id`
+fn check_registration(
+    config: &RelayConfig,
+    relay_id: &str,
+    nonce: &str,
+    msg: RelayMessage,
+) -> Result<bool> {
+    let RelayMessage::Register {
+        relay_id: registered,
+        public_key,
+        signature,
+    } = msg
+    else {
+        bail!("expected register, got {:?}", msg);
+    };
+    if registered != relay_id {
+        bail!(
+            "registered for {} on the endpoint for {}",
+            registered,
+            relay_id
+        );
+    }
+    let (Some(public_key), Some(signature)) = (public_key, signature) else {
+        if config.allow_unproven {
+            return Ok(false);
+        }
+        bail!("registration must be signed");
+    };
+    let public_key = PublicKey::from_base64(&public_key).context("bad public_key")?;
+    if PairingPayload::derive_relay_id(&public_key) != relay_id {
+        bail!("public_key does not own relay_id {}", relay_id);
+    }
+    let signature = Signature::from_base64(&signature).context("bad signature")?;
+    public_key.verify(&registration_message(relay_id, nonce), &signature)?;
+    Ok(true)
+}
+
+/// The candidates in a mobile's `PunchOffer`, if `msg` is one, up to
+/// `MAX_PUNCH_CANDIDATES`. Anything else a mobile sends is session data.
+fn punch_offer(msg: &Message) -> Option<Vec<String>> {
+    match serde_json::from_str(msg.to_str().ok()?) {
+        Ok(RelayMessage::PunchOffer { mut candidates, .. }) => {
+            candidates.truncate(MAX_PUNCH_CANDIDATES);
+            Some(candidates)
+        }
+        _ => None,
+    }
+}
+
+/// Turn a connection away with a control message
+fn refuse(peer: &Peer, code: u16, message: impl Into<String>) {
+    peer.send_control(&RelayMessage::Control {
+        code,
+        message: message.into(),
+    });
+    peer.close();
+}
+
+/// The next message from `ws_rx`, or None once the connection is over
+async fn next_message(
+    ws_rx: &mut futures_util::stream::SplitStream<WebSocket>,
+    peer: &Peer,
+) -> Option<Message> {
+    let msg = tokio::select! {
+        msg = ws_rx.next() => msg?,
+        () = peer.gone() => return None,
+    };
+    match msg {
+        Ok(msg) if msg.is_close() => {
+            info!("{} disconnected", peer.name);
+            None
+        }
+        Ok(msg) => Some(msg),
+        Err(e) => {
+            error!("WebSocket error from {}: {}", peer.name, e);
+            None
+        }
+    }
+}
+
 /// RelayServer handles WebSocket connections and message routing
 pub struct RelayServer {
+    config: RelayConfig,
     manager: Arc<SessionManager>,
+    metrics: Arc<RelayMetrics>,
+    relay_ids: Arc<Quota<String>>,
+    ips: Arc<Quota<IpAddr>>,
 }
 
 impl RelayServer {
     pub fn new() -> Self {
+        Self::with_config(RelayConfig::default())
+    }
+
+    pub fn with_config(config: RelayConfig) -> Self {
         Self {
+            relay_ids: Quota::new(
+                config.max_sessions_per_relay_id,
+                config.relay_id_bytes_per_second,
+            ),
+            ips: Quota::new(config.max_connections_per_ip, config.ip_bytes_per_second),
+            config,
             manager: Arc::new(SessionManager::new()),
+            metrics: Arc::new(RelayMetrics::default()),
         }
     }
 
+    pub fn config(&self) -> &RelayConfig {
+        &self.config
+    }
+
     pub fn manager(&self) -> Arc<SessionManager> {
         self.manager.clone()
     }
 
-    /// Handle desktop connection
+    pub fn metrics(&self) -> Arc<RelayMetrics> {
+        self.metrics.clone()
+    }
+
+    /// The `/metrics` page
+    pub fn render_metrics(&self) -> String {
+        self.metrics.render(&self.manager)
+    }
+
+    /// Close idle and expired sessions, forever
+    pub async fn reap_sessions(self: Arc<Self>) {
+        let period = (self.config.idle_timeout / 4)
+            .clamp(Duration::from_millis(10), Duration::from_secs(60));
+        let mut interval = tokio::time::interval(period);
+        loop {
+            interval.tick().await;
+            self.manager
+                .reap(self.config.idle_timeout, self.config.max_session_lifetime);
+        }
+    }
+
+    /// Take a connection slot for `remote`, or turn it away. Connections
+    /// without a known address aren't counted.
+    fn admit_ip(&self, remote: Option<IpAddr>, peer: &Peer) -> Result<Option<Slot<IpAddr>>, ()> {
+        let Some(ip) = remote else {
+            return Ok(None);
+        };
+        match self.ips.acquire(ip) {
+            Some(slot) => Ok(Some(slot)),
+            None => {
+                warn!("{} REJECTED: too many connections from {}", peer.name, ip);
+                refuse(peer, 429, "too many connections from this address");
+                Err(())
+            }
+        }
+    }
+
+    /// Take a message off a connection, holding it to the size limit and
+    /// the address's bandwidth. None means the connection should end.
+    async fn receive(
+        &self,
+        ws_rx: &mut futures_util::stream::SplitStream<WebSocket>,
+        peer: &Peer,
+        ip_slot: Option<&Slot<IpAddr>>,
+    ) -> Option<Message> {
+        let msg = next_message(ws_rx, peer).await?;
+        let len = msg.as_bytes().len();
+        if len > self.config.max_message_bytes {
+            warn!(
+                "{} sent a {} byte message; the limit is {}",
+                peer.name, len, self.config.max_message_bytes
+            );
+            self.metrics.dropped(Dropped::TooLarge);
+            refuse(peer, 413, "message too large");
+            return None;
+        }
+        if let Some(slot) = ip_slot {
+            slot.admit(len).await;
+        }
+        Some(msg)
+    }
+
+    /// Wait for the desktop's `Register` and check it against `nonce`. The
+    /// message is held to the same limits as any other. None means the
+    /// connection ended, or was refused, first.
+    async fn await_registration(
+        &self,
+        relay_id: &str,
+        nonce: &str,
+        ws_rx: &mut futures_util::stream::SplitStream<WebSocket>,
+        peer: &Peer,
+        ip_slot: Option<&Slot<IpAddr>>,
+    ) -> Result<Option<bool>> {
+        let msg = tokio::time::timeout(REGISTRATION_TIMEOUT, self.receive(ws_rx, peer, ip_slot))
+            .await
+            .map_err(|_| anyhow!("no registration within {:?}", REGISTRATION_TIMEOUT))?;
+        let Some(msg) = msg else {
+            return Ok(None);
+        };
+        let text = msg
+            .to_str()
+            .map_err(|()| anyhow!("expected a register message"))?;
+        let msg = serde_json::from_str::<RelayMessage>(text)?;
+        check_registration(&self.config, relay_id, nonce, msg).map(Some)
+    }
+
+    /// Handle a desktop's control connection from `remote`
     pub async fn handle_desktop(
         &self,
         relay_id: String,
+        remote: Option<IpAddr>,
         ws: WebSocket,
     ) -> Result<()> {
         info!("Desktop connected: relay_id={}", relay_id);
 
-        let (mut ws_tx, mut ws_rx) = ws.split();
-        let (tx, mut rx) = mpsc::unbounded_channel::<Message>();
+        let (ws_tx, mut ws_rx) = ws.split();
+        let peer = spawn_writer(
+            ws_tx,
+            format!("desktop {}", relay_id),
+            self.config.forward_queue,
+            self.metrics.clone(),
+        );
+        let Ok(ip_slot) = self.admit_ip(remote, &peer) else {
+            return Ok(());
+        };
 
-        // Register desktop in session
-        let session = self.manager.get_or_create(relay_id.clone());
-        {
-            let mut sess = session.write().await;
-            sess.desktop_tx = Some(tx.clone());
-        }
-
-        // Spawn task to forward messages from channel to WebSocket
-        let relay_id_clone = relay_id.clone();
-        tokio::spawn(async move {
-            while let Some(msg) = rx.recv().await {
-                if let Err(e) = ws_tx.send(msg).await {
-                    error!("Failed to send to desktop {}: {}", relay_id_clone, e);
-                    break;
-                }
+        let nonce = Uuid::new_v4().to_string();
+        peer.send_control(&RelayMessage::Challenge {
+            nonce: nonce.clone(),
+        });
+        let registration = self
+            .await_registration(&relay_id, &nonce, &mut ws_rx, &peer, ip_slot.as_ref())
+            .await;
+        let proven = match registration {
+            Ok(Some(proven)) => proven,
+            Ok(None) => return Ok(()),
+            Err(e) => {
+                warn!(
+                    "Desktop registration REJECTED for relay_id={}: {:#}",
+                    relay_id, e
+                );
+                self.metrics.auth_rejected();
+                refuse(&peer, 401, format!("registration failed: {:#}", e));
+                return Ok(());
             }
+        };
+        let Some(conn_id) = self
+            .manager
+            .register_desktop(&relay_id, proven, peer.clone())
+        else {
+            warn!(
+                "Unproven desktop REJECTED: relay_id={} is already registered",
+                relay_id
+            );
+            self.metrics.auth_rejected();
+            refuse(&peer, 409, "relay_id is already registered");
+            return Ok(());
+        };
+        info!(
+            "Desktop registered: relay_id={} proven={}",
+            relay_id, proven
+        );
+        peer.send_control(&RelayMessage::Control {
+            code: 200,
+            message: "registered".to_string(),
         });
 
-        // Forward messages from WebSocket to mobile
-        while let Some(msg_result) = ws_rx.next().await {
-            match msg_result {
-                Ok(msg) => {
-                    if msg.is_close() {
-                        info!("Desktop {} disconnected", relay_id);
-                        break;
-                    }
-
-                    // Forward to mobile if connected
-                    let sess = session.read().await;
-                    if let Some(mobile_tx) = &sess.mobile_tx {
-                        if let Err(e) = mobile_tx.send(msg) {
-                            warn!("Failed to forward to mobile: {}", e);
-                        }
-                    } else {
-                        debug!("No mobile connected for relay_id={}", relay_id);
-                    }
-                }
-                Err(e) => {
-                    error!("WebSocket error from desktop {}: {}", relay_id, e);
-                    break;
+        while let Some(msg) = self.receive(&mut ws_rx, &peer, ip_slot.as_ref()).await {
+            if msg.is_binary() {
+                self.forward_to_mobile(&relay_id, msg.as_bytes());
+            } else if let Ok(text) = msg.to_str() {
+                match serde_json::from_str::<RelayMessage>(text) {
+                    Ok(msg) => self.handle_desktop_control(&relay_id, msg),
+                    Err(e) => warn!("Bad control message from desktop {}: {}", relay_id, e),
                 }
             }
         }
 
-        // Cleanup
-        {
-            let mut sess = session.write().await;
-            sess.desktop_tx = None;
-        }
+        self.manager.unregister_desktop(&relay_id, &conn_id);
         info!("Desktop {} session ended", relay_id);
 
         Ok(())
     }
 
-    /// Handle mobile connection
+    /// Queue session data from the desktop for its mobile. This never
+    /// waits: the desktop's reader is shared by all of its sessions.
+    fn forward_to_mobile(&self, relay_id: &str, data: &[u8]) {
+        let Some((session_id, payload)) = decode_session_data(data) else {
+            warn!("Malformed session data from desktop {}", relay_id);
+            return;
+        };
+        let (mobile, from_desktop) = match self.manager.sessions.get(session_id) {
+            // A desktop may only reach its own sessions
+            Some(session) if session.relay_id == relay_id => {
+                (session.mobile.clone(), session.from_desktop.clone())
+            }
+            _ => {
+                debug!("No session {} for relay_id={}", session_id, relay_id);
+                self.metrics.dropped(Dropped::NoSession);
+                return;
+            }
+        };
+        if !self.manager.touch(session_id) {
+            self.metrics.dropped(Dropped::NoSession);
+            return;
+        }
+        match from_desktop.try_send(payload.to_vec()) {
+            Ok(()) => {}
+            Err(TrySendError::Full(_)) => {
+                warn!(
+                    "Session {} is not keeping up with desktop {}; closing it",
+                    session_id, relay_id
+                );
+                self.metrics.dropped(Dropped::SlowPeer);
+                mobile.drop_now();
+                self.manager
+                    .close_session(session_id, "session is not keeping up");
+            }
+            Err(TrySendError::Closed(_)) => self.metrics.dropped(Dropped::NoSession),
+        }
+    }
+
+    fn handle_desktop_control(&self, relay_id: &str, msg: RelayMessage) {
+        match msg {
+            RelayMessage::SessionAccept { session_id } => {
+                if let Some(mut session) = self.manager.sessions.get_mut(&session_id) {
+                    if session.relay_id == relay_id {
+                        session.accepted = true;
+                    }
+                }
+            }
+            RelayMessage::Close { session_id, reason } => {
+                let owned = self
+                    .manager
+                    .sessions
+                    .remove_if(&session_id, |_, session| session.relay_id == relay_id);
+                // Dropping the session closes the mobile
+                if owned.is_some() {
+                    info!(
+                        "Desktop {} closed session {}: {}",
+                        relay_id, session_id, reason
+                    );
+                }
+            }
+            RelayMessage::PunchAnswer {
+                session_id,
+                candidates,
+                cert_sha256,
+            } => {
+                let mobile = match self.manager.sessions.get(&session_id) {
+                    Some(session) if session.relay_id == relay_id => session.mobile.clone(),
+                    _ => {
+                        debug!("No session {} for relay_id={}", session_id, relay_id);
+                        return;
+                    }
+                };
+                mobile.send_control(&RelayMessage::PunchAnswer {
+                    session_id,
+                    candidates,
+                    cert_sha256,
+                });
+            }
+            other => debug!("Ignoring {:?} from desktop {}", other, relay_id),
+        }
+    }
+
+    /// Handle a mobile connection from `remote` as a new session on
+    /// `relay_id`'s desktop
     pub async fn handle_mobile(
         &self,
         relay_id: String,
+        client_id: Option<String>,
+        remote: Option<IpAddr>,
         ws: WebSocket,
     ) -> Result<()> {
-        info!("Mobile connected: relay_id={}", relay_id);
+        let (ws_tx, mut ws_rx) = ws.split();
+        let session_id = Uuid::new_v4().simple().to_string();
+        let mobile = spawn_writer(
+            ws_tx,
+            format!("mobile {}", session_id),
+            self.config.forward_queue,
+            self.metrics.clone(),
+        );
+        let Ok(ip_slot) = self.admit_ip(remote, &mobile) else {
+            return Ok(());
+        };
 
-        let (mut ws_tx, mut ws_rx) = ws.split();
-        let (tx, mut rx) = mpsc::unbounded_channel::<Message>();
+        if self.manager.desktop(&relay_id).is_none() {
+            info!("Mobile rejected: no desktop for relay_id={}", relay_id);
+            refuse(&mobile, 404, "desktop not connected");
+            return Ok(());
+        }
+        let Some(relay_slot) = self.relay_ids.acquire(relay_id.clone()) else {
+            warn!(
+                "Mobile REJECTED: relay_id={} has too many sessions",
+                relay_id
+            );
+            refuse(&mobile, 429, "too many sessions for this desktop");
+            return Ok(());
+        };
 
-        // Register mobile in session
-        let session = self.manager.get_or_create(relay_id.clone());
-        {
-            let mut sess = session.write().await;
-            sess.mobile_tx = Some(tx.clone());
+        info!(
+            "Mobile connected: relay_id={} session_id={}",
+            relay_id, session_id
+        );
+        let client_id = client_id.unwrap_or_else(|| session_id.clone());
+        let now = Instant::now();
+        self.manager.sessions.insert(
+            session_id.clone(),
+            Session {
+                session_id: session_id.clone(),
+                relay_id: relay_id.clone(),
+                client_id: client_id.clone(),
+                remote,
+                accepted: false,
+                started: now,
+                last_active: now,
+                mobile: mobile.clone(),
+                from_desktop: spawn_forwarder(
+                    mobile.clone(),
+                    relay_slot.bandwidth(),
+                    self.config.forward_queue,
+                    self.metrics.clone(),
+                ),
+            },
+        );
+        if let Some(desktop) = self.manager.desktop(&relay_id) {
+            desktop.send_control(&RelayMessage::SessionRequest {
+                session_id: session_id.clone(),
+                client_id,
+            });
         }
 
-        // Spawn task to forward messages from channel to WebSocket
-        let relay_id_clone = relay_id.clone();
-        tokio::spawn(async move {
-            while let Some(msg) = rx.recv().await {
-                if let Err(e) = ws_tx.send(msg).await {
-                    error!("Failed to send to mobile {}: {}", relay_id_clone, e);
-                    break;
-                }
+        // Messages from the mobile waiting to be written to the desktop
+        let pending = Arc::new(AtomicUsize::new(0));
+        let mut reason = "mobile disconnected";
+        while let Some(msg) = self.receive(&mut ws_rx, &mobile, ip_slot.as_ref()).await {
+            if !(msg.is_binary() || msg.is_text()) {
+                continue;
             }
-        });
-
-        // Forward messages from WebSocket to desktop
-        while let Some(msg_result) = ws_rx.next().await {
-            match msg_result {
-                Ok(msg) => {
-                    if msg.is_close() {
-                        info!("Mobile {} disconnected", relay_id);
-                        break;
-                    }
-
-                    // Forward to desktop if connected
-                    let sess = session.read().await;
-                    if let Some(desktop_tx) = &sess.desktop_tx {
-                        if let Err(e) = desktop_tx.send(msg) {
-                            warn!("Failed to forward to desktop: {}", e);
-                        }
-                    } else {
-                        debug!("No desktop connected for relay_id={}", relay_id);
+            relay_slot.admit(msg.as_bytes().len()).await;
+            // The session is gone if either side closed it
+            if !self.manager.touch(&session_id) {
+                break;
+            }
+            let Some(desktop) = self.manager.desktop(&relay_id) else {
+                break;
+            };
+            let (forward, len) = match punch_offer(&msg) {
+                Some(candidates) => {
+                    let offer = control_message(&RelayMessage::PunchOffer {
+                        session_id: session_id.clone(),
+                        candidates,
+                    });
+                    let Some(offer) = offer else {
+                        continue;
+                    };
+                    (offer, None)
+                }
+                None => (
+                    Message::binary(encode_session_data(&session_id, msg.as_bytes())),
+                    Some(msg.as_bytes().len()),
+                ),
+            };
+            match desktop.send_data(forward, &pending) {
+                Ok(()) => {
+                    if let Some(len) = len {
+                        self.metrics.forwarded_to_desktop(len);
                     }
                 }
-                Err(e) => {
-                    error!("WebSocket error from mobile {}: {}", relay_id, e);
+                Err(Refused::Behind) => {
+                    warn!(
+                        "Desktop {} is not keeping up with session {}; closing it",
+                        relay_id, session_id
+                    );
+                    self.metrics.dropped(Dropped::SlowPeer);
+                    mobile.send_control(&RelayMessage::Control {
+                        code: 429,
+                        message: "desktop is not keeping up".to_string(),
+                    });
+                    reason = "desktop is not keeping up";
                     break;
                 }
+                Err(Refused::Gone) => break,
             }
         }
 
-        // Cleanup
-        {
-            let mut sess = session.write().await;
-            sess.mobile_tx = None;
-        }
-        info!("Mobile {} session ended", relay_id);
+        self.manager.close_session(&session_id, reason);
+        info!("Mobile session {} ended", session_id);
 
         Ok(())
     }
@@ -0,0 +1,333 @@
+//! TLS for the relay, so that secrets in connection URLs aren't sent in the
+//! clear.
+//!
+//! The certificate and key are read from PEM files, and read again when
+//! they change, so a renewed certificate is picked up without a restart.
+//! Clients may be asked for a certificate of their own; any certificate is
+//! accepted in the handshake, and `require_client_cert` decides which ones
+//! are trusted by their fingerprints.
+
+use crate::metrics::RelayMetrics;
+use anyhow::{Context, Result};
+use log::{debug, error, info, warn};
+use lucidity_pairing::{cert_fingerprint, normalize_fingerprint};
+use openssl::pkey::PKey;
+use openssl::ssl::{Ssl, SslAcceptor, SslMethod, SslVerifyMode};
+use openssl::x509::X509;
+use std::convert::Infallible;
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use tokio::net::{TcpListener, TcpStream};
+use tokio_openssl::SslStream;
+use warp::filters::BoxedFilter;
+use warp::http::StatusCode;
+use warp::hyper;
+use warp::{Filter, Rejection, Reply};
+
+/// How long a client has to complete the TLS handshake
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Where to find the relay's certificate
+#[derive(Debug, Clone)]
+pub struct TlsConfig {
+    /// PEM certificate, followed by any intermediates
+    pub cert_path: PathBuf,
+    /// PEM private key
+    pub key_path: PathBuf,
+    /// Ask clients for a certificate, for `require_client_cert` to check
+    pub request_client_cert: bool,
+    /// How often to check the files for a new certificate
+    pub reload_interval: Duration,
+}
+
+impl TlsConfig {
+    pub fn new(cert_path: PathBuf, key_path: PathBuf) -> Self {
+        Self {
+            cert_path,
+            key_path,
+            request_client_cert: false,
+            reload_interval: Duration::from_secs(60),
+        }
+    }
+}
+
+/// The certificate currently being served
+struct Loaded {
+    cert_pem: Vec<u8>,
+    key_pem: Vec<u8>,
+    acceptor: Arc<SslAcceptor>,
+    fingerprint: String,
+}
+
+/// Accepts TLS connections with the certificate in `TlsConfig`, whichever
+/// version of it is current
+pub struct TlsAcceptor {
+    config: TlsConfig,
+    loaded: RwLock<Loaded>,
+}
+
+impl TlsAcceptor {
+    pub fn new(config: TlsConfig) -> Result<Arc<Self>> {
+        let (cert_pem, key_pem) = Self::read(&config)?;
+        let loaded = Self::build(&config, cert_pem, key_pem)?;
+        Ok(Arc::new(Self {
+            config,
+            loaded: RwLock::new(loaded),
+        }))
+    }
+
+    fn read(config: &TlsConfig) -> Result<(Vec<u8>, Vec<u8>)> {
+        let cert_pem = std::fs::read(&config.cert_path)
+            .with_context(|| format!("reading {}", config.cert_path.display()))?;
+        let key_pem = std::fs::read(&config.key_path)
+            .with_context(|| format!("reading {}", config.key_path.display()))?;
+        Ok((cert_pem, key_pem))
+    }
+
+    fn build(config: &TlsConfig, cert_pem: Vec<u8>, key_pem: Vec<u8>) -> Result<Loaded> {
+        let mut chain = X509::stack_from_pem(&cert_pem)
+            .with_context(|| format!("parsing {}", config.cert_path.display()))?
+            .into_iter();
+        let cert = chain
+            .next()
+            .with_context(|| format!("no certificate in {}", config.cert_path.display()))?;
+        let key = PKey::private_key_from_pem(&key_pem)
+            .with_context(|| format!("parsing {}", config.key_path.display()))?;
+
+        let mut acceptor = SslAcceptor::mozilla_intermediate_v5(SslMethod::tls())?;
+        acceptor.set_certificate(&cert)?;
+        for intermediate in chain {
+            acceptor.add_extra_chain_cert(intermediate)?;
+        }
+        acceptor.set_private_key(&key)?;
+        acceptor
+            .check_private_key()
+            .context("the key does not match the certificate")?;
+        if config.request_client_cert {
+            // Client certificates are self-signed and pinned, so take any
+            acceptor.set_verify_callback(SslVerifyMode::PEER, |_, _| true);
+            acceptor.set_session_id_context(b"lucidity-relay")?;
+        }
+
+        Ok(Loaded {
+            fingerprint: cert_fingerprint(&cert.to_der()?),
+            cert_pem,
+            key_pem,
+            acceptor: Arc::new(acceptor.build()),
+        })
+    }
+
+    /// The fingerprint of the certificate being served, for clients to pin
+    pub fn fingerprint(&self) -> String {
+        self.loaded.read().unwrap().fingerprint.clone()
+    }
+
+    fn acceptor(&self) -> Arc<SslAcceptor> {
+        self.loaded.read().unwrap().acceptor.clone()
+    }
+
+    /// Start serving the certificate on disk if it has changed, returning
+    /// whether it had. A certificate that fails to load is not served.
+    pub fn reload_if_changed(&self) -> Result<bool> {
+        let (cert_pem, key_pem) = Self::read(&self.config)?;
+        {
+            let loaded = self.loaded.read().unwrap();
+            if loaded.cert_pem == cert_pem && loaded.key_pem == key_pem {
+                return Ok(false);
+            }
+        }
+        let reloaded = Self::build(&self.config, cert_pem, key_pem)?;
+        *self.loaded.write().unwrap() = reloaded;
+        Ok(true)
+    }
+
+    /// Check for a new certificate every `reload_interval`, forever
+    pub async fn watch(self: Arc<Self>) {
+        let mut interval = tokio::time::interval(self.config.reload_interval);
+        loop {
+            interval.tick().await;
+            match self.reload_if_changed() {
+                Ok(true) => info!(
+                    "Reloaded TLS certificate {}; SHA-256 {}",
+                    self.config.cert_path.display(),
+                    self.fingerprint()
+                ),
+                Ok(false) => {}
+                Err(e) => error!("Keeping the current TLS certificate: {:#}", e),
+            }
+        }
+    }
+}
+
+/// A TLS connection a request came in on, found in the request's
+/// extensions when served by `serve`
+#[derive(Debug, Clone)]
+pub struct Connection {
+    pub remote: SocketAddr,
+    /// Fingerprint of the certificate the client presented, if any
+    pub client_cert_sha256: Option<String>,
+}
+
+async fn handshake(acceptor: &SslAcceptor, tcp: TcpStream) -> Result<SslStream<TcpStream>> {
+    let ssl = Ssl::new(acceptor.context())?;
+    let mut stream = SslStream::new(ssl, tcp)?;
+    tokio::time::timeout(HANDSHAKE_TIMEOUT, Pin::new(&mut stream).accept())
+        .await
+        .context("timed out")??;
+    Ok(stream)
+}
+
+/// Serve `routes` over TLS on `addr`. Use `remote` rather than
+/// `warp::addr::remote` in the routes to find where a request came from.
+pub async fn serve<R: Reply + 'static>(
+    routes: BoxedFilter<(R,)>,
+    addr: SocketAddr,
+    tls: Arc<TlsAcceptor>,
+) -> Result<()> {
+    let listener = TcpListener::bind(addr)
+        .await
+        .with_context(|| format!("binding {}", addr))?;
+    let service = warp::service(routes);
+    loop {
+        let (tcp, remote) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                error!("accept failed: {}", e);
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                continue;
+            }
+        };
+        tcp.set_nodelay(true).ok();
+        let acceptor = tls.acceptor();
+        let service = service.clone();
+        tokio::spawn(async move {
+            let stream = match handshake(&acceptor, tcp).await {
+                Ok(stream) => stream,
+                Err(e) => {
+                    debug!("TLS handshake with {} failed: {:#}", remote, e);
+                    return;
+                }
+            };
+            let connection = Connection {
+                remote,
+                client_cert_sha256: stream
+                    .ssl()
+                    .peer_certificate()
+                    .and_then(|cert| cert.to_der().ok())
+                    .map(|der| cert_fingerprint(&der)),
+            };
+            let service = hyper::service::service_fn(move |mut req| {
+                req.extensions_mut().insert(connection.clone());
+                let mut service = service.clone();
+                hyper::service::Service::call(&mut service, req)
+            });
+            if let Err(e) = hyper::server::conn::Http::new()
+                .serve_connection(stream, service)
+                .with_upgrades()
+                .await
+            {
+                debug!("Connection from {} ended: {}", remote, e);
+            }
+        });
+    }
+}
+
+/// The address a request came from, whether served by `serve` or by warp
+pub fn remote() -> impl Filter<Extract = (Option<IpAddr>,), Error = Infallible> + Clone {
+    warp::addr::remote()
+        .and(warp::ext::optional::<Connection>())
+        .map(|addr: Option<SocketAddr>, connection: Option<Connection>| {
+            addr.or(connection.map(|c| c.remote)).map(|a| a.ip())
+        })
+}
+
+#[derive(Debug)]
+struct UntrustedClientCert;
+
+impl warp::reject::Reject for UntrustedClientCert {}
+
+/// Requests on connections whose client certificate is one of `pinned`.
+/// With nothing pinned, every request passes.
+pub fn require_client_cert(
+    pinned: Vec<String>,
+    metrics: Arc<RelayMetrics>,
+) -> impl Filter<Extract = (), Error = Rejection> + Clone {
+    let pinned: Arc<Vec<String>> =
+        Arc::new(pinned.iter().map(|p| normalize_fingerprint(p)).collect());
+    warp::ext::optional::<Connection>()
+        .and_then(move |connection: Option<Connection>| {
+            let pinned = pinned.clone();
+            let metrics = metrics.clone();
+            async move {
+                if pinned.is_empty() {
+                    return Ok(());
+                }
+                match connection.and_then(|c| c.client_cert_sha256) {
+                    Some(fingerprint) if pinned.contains(&fingerprint) => Ok(()),
+                    fingerprint => {
+                        warn!(
+                            "Connection REJECTED: client certificate {} is not pinned",
+                            fingerprint.as_deref().unwrap_or("(none)")
+                        );
+                        metrics.auth_rejected();
+                        Err(warp::reject::custom(UntrustedClientCert))
+                    }
+                }
+            }
+        })
+        .untuple_one()
+}
+
+/// Turn a rejection from `require_client_cert` into a 403
+pub async fn recover(rejection: Rejection) -> Result<impl Reply, Rejection> {
+    if rejection.find::<UntrustedClientCert>().is_some() {
+        Ok(warp::reply::with_status(
+            "client certificate not trusted",
+            StatusCode::FORBIDDEN,
+        ))
+    } else {
+        Err(rejection)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write_cert(dir: &std::path::Path) -> String {
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        let cert_pem = cert.serialize_pem().unwrap();
+        std::fs::write(dir.join("cert.pem"), &cert_pem).unwrap();
+        std::fs::write(dir.join("key.pem"), cert.serialize_private_key_pem()).unwrap();
+        let der = X509::from_pem(cert_pem.as_bytes())
+            .unwrap()
+            .to_der()
+            .unwrap();
+        cert_fingerprint(&der)
+    }
+
+    #[test]
+    fn certificates_are_reloaded_when_they_change() {
+        let dir = tempfile::tempdir().unwrap();
+        let first = write_cert(dir.path());
+        let tls = TlsAcceptor::new(TlsConfig::new(
+            dir.path().join("cert.pem"),
+            dir.path().join("key.pem"),
+        ))
+        .unwrap();
+        assert_eq!(tls.fingerprint(), first);
+        assert!(!tls.reload_if_changed().unwrap());
+
+        let second = write_cert(dir.path());
+        assert!(tls.reload_if_changed().unwrap());
+        assert_eq!(tls.fingerprint(), second);
+
+        // A broken certificate leaves the last good one in place
+        std::fs::write(dir.path().join("cert.pem"), "garbage").unwrap();
+        assert!(tls.reload_if_changed().is_err());
+        assert_eq!(tls.fingerprint(), second);
+    }
+}
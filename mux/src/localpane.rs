@@ -517,6 +517,14 @@ impl Pane for LocalPane {
         }
     }
 
+    fn is_bracketed_paste_enabled(&self) -> bool {
+        if self.tmux_domain.lock().is_some() {
+            false
+        } else {
+            self.terminal.lock().bracketed_paste_enabled()
+        }
+    }
+
     fn get_current_working_dir(&self, policy: CachePolicy) -> Option<Url> {
         self.terminal
             .lock()
@@ -310,6 +310,10 @@ pub trait Pane: Downcast + Send + Sync {
     /// handling of clicks.
     fn is_mouse_grabbed(&self) -> bool;
     fn is_alt_screen_active(&self) -> bool;
+    /// Returns true if the application has enabled bracketed paste mode
+    fn is_bracketed_paste_enabled(&self) -> bool {
+        false
+    }
 
     fn set_clipboard(&self, _clipboard: &Arc<dyn Clipboard>) {}
     fn set_download_handler(&self, _handler: &Arc<dyn DownloadHandler>) {}
@@ -1,53 +1,87 @@
+#[cfg(feature = "config")]
 use config::{configuration, ConfigHandle};
 use governor::clock::{Clock, DefaultClock};
 use governor::{NegativeMultiDecision, Quota, RateLimiter as Limiter};
 use std::num::NonZeroU32;
 use std::time::Duration;
 
+type DirectLimiter =
+    Limiter<governor::state::direct::NotKeyed, governor::state::InMemoryState, DefaultClock>;
+
+#[cfg(feature = "config")]
+type GetLimitValue = Box<dyn Fn(&ConfigHandle) -> u32 + 'static + Send>;
+
 pub struct RateLimiter {
-    lim: Limiter<governor::state::direct::NotKeyed, governor::state::InMemoryState, DefaultClock>,
-    get_limit_value: Box<dyn Fn(&ConfigHandle) -> u32 + 'static + Send>,
+    lim: DirectLimiter,
+    #[cfg(feature = "config")]
+    get_limit_value: Option<GetLimitValue>,
+    #[cfg(feature = "config")]
     generation: usize,
+    #[cfg(feature = "config")]
     capacity_per_second: u32,
 }
 
+fn limiter(capacity_per_second: u32) -> DirectLimiter {
+    Limiter::direct(Quota::per_second(
+        NonZeroU32::new(capacity_per_second).expect("RateLimiter capacity to be non-zero"),
+    ))
+}
+
 impl RateLimiter {
+    /// Construct a rate limiter with a fixed limit, for use
+    /// outside of the wezterm configuration.
+    pub fn per_second(capacity_per_second: u32) -> Self {
+        Self {
+            lim: limiter(capacity_per_second),
+            #[cfg(feature = "config")]
+            get_limit_value: None,
+            #[cfg(feature = "config")]
+            generation: 0,
+            #[cfg(feature = "config")]
+            capacity_per_second,
+        }
+    }
+
     /// Construct a new rate limiter.
     /// `get_limit_value` is a function that will extract a limit
     /// from a config handle; the limit will be automatically adjusted
     /// as the config changes.
     /// This will effectively reset the counter if the limit value in
     /// the new generation of config is different to the prior value.
+    #[cfg(feature = "config")]
     pub fn new<F: Fn(&ConfigHandle) -> u32 + 'static + Send>(get_limit_value: F) -> Self {
         let config = configuration();
         let generation = config.generation();
         let get_limit_value = Box::new(get_limit_value);
         let capacity_per_second = get_limit_value(&config);
         Self {
-            lim: Limiter::direct(Quota::per_second(
-                NonZeroU32::new(capacity_per_second).expect("RateLimiter capacity to be non-zero"),
-            )),
-            get_limit_value,
+            lim: limiter(capacity_per_second),
+            get_limit_value: Some(get_limit_value),
             generation,
             capacity_per_second,
         }
     }
 
+    #[cfg(feature = "config")]
     fn check_config_reload(&mut self) {
+        let Some(get_limit_value) = &self.get_limit_value else {
+            return;
+        };
         let config = configuration();
         let generation = config.generation();
         if generation != self.generation {
-            let value = (self.get_limit_value)(&config);
+            let value = get_limit_value(&config);
             if value != self.capacity_per_second {
-                self.lim = Limiter::direct(Quota::per_second(
-                    NonZeroU32::new(value).expect("RateLimiter capacity to be non-zero"),
-                ));
+                self.lim = limiter(value);
                 self.capacity_per_second = value;
             }
             self.generation = generation;
         }
     }
 
+    #[cfg(not(feature = "config"))]
+    fn check_config_reload(&mut self) {}
+
     #[allow(dead_code)]
     pub fn non_blocking_admittance_check(&mut self, amount: u32) -> bool {
         self.check_config_reload();
@@ -71,6 +71,33 @@ pub use termwindow::{set_window_class, set_window_position, TermWindow, ICON_DAT
 
 pub static RELAY_ENABLED: std::sync::atomic::AtomicBool = std::sync::atomic::AtomicBool::new(true);
 
+fn relay_enabled_path() -> PathBuf {
+    config::DATA_DIR.join("lucidity").join("relay_enabled")
+}
+
+/// Connect lucidity-host to its relay, or disconnect it, and remember the
+/// choice for next time
+pub fn set_relay_enabled(enabled: bool) {
+    RELAY_ENABLED.store(enabled, std::sync::atomic::Ordering::Relaxed);
+    lucidity_host::set_relay_enabled(enabled);
+    let path = relay_enabled_path();
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir).ok();
+    }
+    if let Err(e) = std::fs::write(&path, if enabled { "true" } else { "false" }) {
+        log::error!("Failed to save relay state: {}", e);
+    }
+}
+
+/// Restore the relay toggle saved by `set_relay_enabled`
+fn load_relay_enabled() {
+    if let Ok(state) = std::fs::read_to_string(relay_enabled_path()) {
+        let enabled = state.trim() != "false";
+        RELAY_ENABLED.store(enabled, std::sync::atomic::Ordering::Relaxed);
+        lucidity_host::set_relay_enabled(enabled);
+    }
+}
+
 #[derive(Debug, Parser)]
 #[command(
     about = "Wez's Terminal Emulator\nhttp://github.com/wezterm/wezterm",
@@ -432,6 +459,7 @@ async fn async_run_terminal_gui(
     // Phase 1 Lucidity proof: local host bridge for mirroring panes.
     // Defaults to localhost-only; set `LUCIDITY_LISTEN=0.0.0.0:9797` to enable LAN access.
     // Set `LUCIDITY_DISABLE_HOST=1` to disable.
+    load_relay_enabled();
     lucidity_host::autostart_in_process();
 
     if !opts.no_auto_connect {
@@ -852,6 +880,7 @@ fn main() {
     }
     Mux::shutdown();
     frontend::shutdown();
+    lucidity_host::shutdown();
 }
 
 fn maybe_show_configuration_error_window() {
@@ -1,37 +1,138 @@
+use lucidity_host::ShortCodeState;
+use lucidity_pairing::ShortCode;
 use mux::termwiztermtab::TermWizTerminal;
+use std::time::Duration;
 use termwiz::cell::AttributeChange;
 use termwiz::color::ColorAttribute;
 use termwiz::input::{InputEvent, KeyCode, KeyEvent, Modifiers};
 use termwiz::surface::{Change, CursorVisibility, Position};
 use termwiz::terminal::Terminal;
 
-fn build_pairing_screen() -> anyhow::Result<String> {
-    let store_path = config::DATA_DIR.join("lucidity").join("host_keypair.json");
-    let store = lucidity_pairing::KeypairStore::open(&store_path);
-    let keypair = store.load_or_generate()?;
+/// Codes that hellos which didn't pair a phone may use up before the
+/// screen stops putting up new ones, so that someone guessing gets only a
+/// few codes' worth of tries without the user asking for more
+const MAX_USED_UP_CODES: u32 = 3;
 
-    let payload = lucidity_pairing::PairingPayload::new(keypair.public_key());
-    let qr = lucidity_pairing::generate_pairing_qr_ascii(&payload)?;
+/// How often to check on the code shown
+const CODE_CHECK_INTERVAL: Duration = Duration::from_secs(1);
 
-    let relay_url = std::env::var("LUCIDITY_RELAY_URL")
-        .unwrap_or_else(|_| "ws://localhost:9090".to_string());
+struct PairingScreen {
+    /// The relay details and QR code, which stay put when the code changes
+    header: String,
+    /// The code shown, until too many have been used up
+    code: Option<ShortCode>,
+    /// Where the code stood when the screen was last drawn
+    state: ShortCodeState,
+    /// Codes used up by hellos that didn't pair a phone
+    used_up: u32,
+}
 
-    let enabled = crate::RELAY_ENABLED.load(std::sync::atomic::Ordering::Relaxed);
-    let status_str = if enabled { "Active" } else { "Disabled" };
+impl PairingScreen {
+    fn build() -> anyhow::Result<Self> {
+        // Each QR code carries its own one-time pairing token
+        let payload = lucidity_host::current_pairing_payload()?;
+        let qr = lucidity_pairing::generate_pairing_qr_ascii(&payload)?;
+        // A short code as well, for phones that can't scan it
+        let code = lucidity_host::start_short_code()?;
 
-    let mut s = String::new();
-    s.push_str("Lucidity Connectivity\r\n\r\n");
-    s.push_str(&format!("Relay URL: {}\r\n", relay_url));
-    s.push_str(&format!("Relay ID:  {}\r\n", payload.relay_id));
-    s.push_str("\r\n");
-    s.push_str("Scan this QR code in the Lucidity Mobile app:\r\n\r\n");
-    s.push_str(&qr);
-    s.push_str("\r\n");
-    s.push_str(&format!("Status: Relay Agent is {} (managed by background supervisor)\r\n", status_str));
-    s.push_str("\r\n");
-    s.push_str("Press Enter or Escape to exit. (R = refresh QR, T = toggle relay)\r\n");
-    s.push_str("\r\n");
-    Ok(s)
+        let relay_url = std::env::var("LUCIDITY_RELAY_URL")
+            .unwrap_or_else(|_| "ws://localhost:9090".to_string());
+
+        let mut header = String::new();
+        header.push_str("Lucidity Connectivity\r\n\r\n");
+        header.push_str(&format!("Relay URL: {}\r\n", relay_url));
+        header.push_str(&format!("Relay ID:  {}\r\n", payload.relay_id));
+        header.push_str("\r\n");
+        header.push_str("Scan this QR code in the Lucidity Mobile app:\r\n\r\n");
+        header.push_str(&qr);
+        header.push_str("\r\n");
+        Ok(Self {
+            header,
+            code: Some(code),
+            state: lucidity_host::short_code_state(),
+            used_up: 0,
+        })
+    }
+
+    /// Catch up with the code shown: replace it if it has expired or been
+    /// used up, up to `MAX_USED_UP_CODES`. Returns whether the screen
+    /// changed.
+    fn refresh_code(&mut self) -> anyhow::Result<bool> {
+        if self.code.is_none() {
+            return Ok(false);
+        }
+        let state = lucidity_host::short_code_state();
+        match state {
+            ShortCodeState::UsedUp => {
+                self.used_up += 1;
+                if self.used_up >= MAX_USED_UP_CODES {
+                    self.code = None;
+                } else {
+                    self.code = Some(lucidity_host::start_short_code()?);
+                }
+            }
+            ShortCodeState::Expired => {
+                self.code = Some(lucidity_host::start_short_code()?);
+            }
+            _ if state == self.state => return Ok(false),
+            _ => {}
+        }
+        self.state = lucidity_host::short_code_state();
+        Ok(true)
+    }
+
+    fn content(&self) -> String {
+        let enabled = crate::RELAY_ENABLED.load(std::sync::atomic::Ordering::Relaxed);
+        let status_str = if enabled { "Active" } else { "Disabled" };
+
+        let mut s = self.header.clone();
+        match (&self.code, self.state) {
+            (Some(code), ShortCodeState::Live { attempts_left }) => s.push_str(&format!(
+                "Or type this code in the app: {}  ({} minutes, {} of {} tries left)\r\n",
+                code,
+                lucidity_pairing::CODE_TTL_SECS / 60,
+                attempts_left,
+                lucidity_pairing::MAX_ATTEMPTS
+            )),
+            (Some(code), _) => s.push_str(&format!(
+                "Code {} can no longer be used. Press R for a new one.\r\n",
+                code
+            )),
+            (None, _) => s.push_str(&format!(
+                "No code is shown: {} were used up by tries that didn't pair a phone. \
+                 Press R for a new one.\r\n",
+                self.used_up
+            )),
+        }
+        if self.used_up > 0 && self.code.is_some() {
+            s.push_str(&format!(
+                "Warning: {} code(s) used up by failed tries. \
+                 If that wasn't you, someone else may be guessing.\r\n",
+                self.used_up
+            ));
+        }
+        s.push_str("\r\n");
+        s.push_str(&format!(
+            "Status: Relay Agent is {} (managed by background supervisor)\r\n",
+            status_str
+        ));
+        s.push_str("\r\n");
+        s.push_str("Press Enter or Escape to exit. (R = new QR and code, T = toggle relay)\r\n");
+        s.push_str("\r\n");
+        s
+    }
+}
+
+/// The pairing screen, or what to show in its place when it can't be built
+fn build_pairing_screen() -> Result<PairingScreen, String> {
+    PairingScreen::build().map_err(build_pairing_screen_fallback)
+}
+
+fn screen_content(screen: &Result<PairingScreen, String>) -> String {
+    match screen {
+        Ok(screen) => screen.content(),
+        Err(fallback) => fallback.clone(),
+    }
 }
 
 fn build_pairing_screen_fallback(err: anyhow::Error) -> String {
@@ -77,11 +178,28 @@ pub fn lucidity_pair_overlay(mut term: TermWizTerminal) -> anyhow::Result<()> {
     term.set_raw_mode()?;
     term.no_grab_mouse_in_raw_mode();
 
-    let mut content =
-        build_pairing_screen().unwrap_or_else(|err| build_pairing_screen_fallback(err));
-    render(&mut term, &content)?;
+    let mut screen = build_pairing_screen();
+    render(&mut term, &screen_content(&screen))?;
 
-    while let Ok(Some(event)) = term.poll_input(None) {
+    loop {
+        let event = match term.poll_input(Some(CODE_CHECK_INTERVAL)) {
+            Ok(Some(event)) => event,
+            Ok(None) => {
+                // Hellos from anyone can use up the code shown
+                if let Ok(shown) = &mut screen {
+                    match shown.refresh_code() {
+                        Ok(true) => render(&mut term, &shown.content())?,
+                        Ok(false) => {}
+                        Err(err) => {
+                            screen = Err(build_pairing_screen_fallback(err));
+                            render(&mut term, &screen_content(&screen))?;
+                        }
+                    }
+                }
+                continue;
+            }
+            Err(_) => break,
+        };
         match event {
             InputEvent::Key(KeyEvent {
                 key: KeyCode::Enter,
@@ -101,31 +219,26 @@ pub fn lucidity_pair_overlay(mut term: TermWizTerminal) -> anyhow::Result<()> {
                 key: KeyCode::Char('r' | 'R'),
                 ..
             }) => {
-                content =
-                    build_pairing_screen().unwrap_or_else(|err| build_pairing_screen_fallback(err));
-                render(&mut term, &content)?;
+                lucidity_host::invalidate_pairing_tokens();
+                screen = build_pairing_screen();
+                render(&mut term, &screen_content(&screen))?;
             }
             InputEvent::Key(KeyEvent {
                 key: KeyCode::Char('t' | 'T'),
                 ..
             }) => {
                 let current = crate::RELAY_ENABLED.load(std::sync::atomic::Ordering::Relaxed);
-                let new_state = !current;
-                crate::RELAY_ENABLED.store(new_state, std::sync::atomic::Ordering::Relaxed);
-                
-                // Persist state
-                let state_path = config::DATA_DIR.join("lucidity").join("relay_enabled");
-                if let Err(e) = std::fs::write(&state_path, if new_state { "true" } else { "false" }) {
-                    log::error!("Failed to save relay state: {}", e);
-                }
+                crate::set_relay_enabled(!current);
 
-                content =
-                    build_pairing_screen().unwrap_or_else(|err| build_pairing_screen_fallback(err));
-                render(&mut term, &content)?;
+                lucidity_host::invalidate_pairing_tokens();
+                screen = build_pairing_screen();
+                render(&mut term, &screen_content(&screen))?;
             }
             _ => {}
         }
     }
 
+    // The QR codes and code shown can't be used once the screen is gone
+    lucidity_host::invalidate_pairing_tokens();
     Ok(())
 }
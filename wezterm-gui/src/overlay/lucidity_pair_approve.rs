@@ -1,3 +1,4 @@
+use lucidity_pairing::DeviceScopes;
 use mux::termwiztermtab::TermWizTerminal;
 use termwiz::cell::AttributeChange;
 use termwiz::color::ColorAttribute;
@@ -5,6 +6,72 @@ use termwiz::input::{InputEvent, KeyCode, KeyEvent, MouseButtons, MouseEvent};
 use termwiz::surface::{Change, CursorVisibility, Position};
 use termwiz::terminal::Terminal;
 
+/// The pane that was active when the request came in, which the device can
+/// be limited to
+#[derive(Clone, Debug)]
+pub struct ActivePane {
+    pub pane_id: usize,
+    pub domain: Option<String>,
+    pub workspace: Option<String>,
+}
+
+/// Which panes the device may see
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Limit {
+    Everything,
+    Workspace(String),
+    Domain(String),
+    Pane(usize),
+}
+
+impl Limit {
+    /// Every limit on offer, widest first
+    fn choices(active: Option<&ActivePane>) -> Vec<Self> {
+        let mut choices = vec![Limit::Everything];
+        if let Some(active) = active {
+            choices.extend(active.workspace.clone().map(Limit::Workspace));
+            choices.extend(active.domain.clone().map(Limit::Domain));
+            choices.push(Limit::Pane(active.pane_id));
+        }
+        choices
+    }
+
+    fn apply(&self, scopes: &mut DeviceScopes) {
+        scopes.panes.clear();
+        scopes.domains.clear();
+        scopes.workspaces.clear();
+        match self {
+            Limit::Everything => {}
+            Limit::Workspace(workspace) => scopes.workspaces.push(workspace.clone()),
+            Limit::Domain(domain) => scopes.domains.push(domain.clone()),
+            Limit::Pane(pane_id) => scopes.panes.push(*pane_id),
+        }
+    }
+
+    fn describe(&self) -> String {
+        match self {
+            Limit::Everything => "every pane".to_string(),
+            Limit::Workspace(workspace) => format!("panes in workspace {workspace}"),
+            Limit::Domain(domain) => format!("panes in domain {domain}"),
+            Limit::Pane(pane_id) => format!("only pane {pane_id}"),
+        }
+    }
+}
+
+fn scope_lines(scopes: &DeviceScopes, limit: &Limit) -> Vec<String> {
+    let check = |granted: bool| if granted { "x" } else { " " };
+    vec![
+        format!("Sees:   {}  [L]imit", limit.describe()),
+        format!("[{}] [I]nput: type, paste and click", check(scopes.input)),
+        format!("[{}] [C]lipboard", check(scopes.clipboard)),
+        format!(
+            "[{}] [M]anage: panes, pairing and other devices",
+            check(scopes.admin)
+        ),
+        "[V]iew only".to_string(),
+    ]
+}
+
 #[derive(Copy, Clone, Debug, PartialEq, Eq)]
 enum ActiveButton {
     None,
@@ -81,16 +148,19 @@ fn render(
     Ok(())
 }
 
+/// Ask whether to trust the device making `request`, returning what it may
+/// do if approved
 pub fn lucidity_pair_approve_overlay(
     mut term: TermWizTerminal,
     request: lucidity_pairing::PairingRequest,
-) -> anyhow::Result<bool> {
+    active_pane: Option<ActivePane>,
+) -> anyhow::Result<Option<DeviceScopes>> {
     term.set_raw_mode()?;
     term.no_grab_mouse_in_raw_mode();
 
     let fingerprint = request.mobile_public_key.fingerprint_short();
 
-    let lines = vec![
+    let header = vec![
         "Lucidity pairing request".to_string(),
         "".to_string(),
         format!("Email:  {}", request.user_email),
@@ -98,7 +168,13 @@ pub fn lucidity_pair_approve_overlay(
         format!("Key:    {}", fingerprint),
         "".to_string(),
         "Approve adds this device to your trust list.".to_string(),
+        "".to_string(),
     ];
+    let limits = Limit::choices(active_pane.as_ref());
+    let mut limit = 0;
+    let mut scopes = DeviceScopes::full();
+    let mut lines = header.clone();
+    lines.extend(scope_lines(&scopes, &limits[limit]));
 
     let size = term.get_screen_size()?;
     let x_pos = size.cols * 10 / 100;
@@ -128,7 +204,7 @@ pub fn lucidity_pair_approve_overlay(
                 key: KeyCode::Enter,
                 ..
             }) => {
-                return Ok(true);
+                return Ok(Some(scopes));
             }
             InputEvent::Key(KeyEvent {
                 key: KeyCode::Char('r' | 'R'),
@@ -138,8 +214,25 @@ pub fn lucidity_pair_approve_overlay(
                 key: KeyCode::Escape,
                 ..
             }) => {
-                return Ok(false);
+                return Ok(None);
             }
+            InputEvent::Key(KeyEvent {
+                key: KeyCode::Char(c),
+                ..
+            }) => match c.to_ascii_lowercase() {
+                'i' => scopes.input = !scopes.input,
+                'c' => scopes.clipboard = !scopes.clipboard,
+                'm' => scopes.admin = !scopes.admin,
+                'v' => {
+                    scopes = DeviceScopes::view_only();
+                    limits[limit].apply(&mut scopes);
+                }
+                'l' => {
+                    limit = (limit + 1) % limits.len();
+                    limits[limit].apply(&mut scopes);
+                }
+                _ => {}
+            },
             InputEvent::Mouse(MouseEvent {
                 x,
                 y,
@@ -152,29 +245,31 @@ pub fn lucidity_pair_approve_overlay(
                 if y == button_row && x >= approve_x && x < approve_x + approve_w {
                     active = ActiveButton::Approve;
                     if mouse_buttons == MouseButtons::LEFT {
-                        return Ok(true);
+                        return Ok(Some(scopes));
                     }
                 } else if y == button_row && x >= reject_x && x < reject_x + reject_w {
                     active = ActiveButton::Reject;
                     if mouse_buttons == MouseButtons::LEFT {
-                        return Ok(false);
+                        return Ok(None);
                     }
                 } else {
                     active = ActiveButton::None;
                 }
 
                 if mouse_buttons != MouseButtons::NONE {
-                    return Ok(false);
+                    return Ok(None);
                 }
             }
             _ => {}
         }
 
+        lines.truncate(header.len());
+        lines.extend(scope_lines(&scopes, &limits[limit]));
         render(
             &mut term, &lines, x_pos, top_row, button_row, active, approve_x, approve_w, reject_x,
             reject_w,
         )?;
     }
 
-    Ok(false)
+    Ok(None)
 }
@@ -31,12 +31,11 @@ impl PairingApprover for GuiPairingApprover {
                 term_window.show_lucidity_pairing_approval(request, tx);
             })));
 
-        let approved = rx.recv_timeout(Duration::from_secs(300)).unwrap_or(false);
+        let scopes = rx.recv_timeout(Duration::from_secs(300)).unwrap_or(None);
 
-        Ok(if approved {
-            PairingApproval::approved()
-        } else {
-            PairingApproval::rejected("pairing request rejected")
+        Ok(match scopes {
+            Some(scopes) => PairingApproval::approved_with(scopes),
+            None => PairingApproval::rejected("pairing request rejected"),
         })
     }
 }
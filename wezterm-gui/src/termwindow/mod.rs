@@ -4,6 +4,7 @@ use super::utilsprites::RenderMetrics;
 use crate::colorease::ColorEase;
 use crate::frontend::{front_end, try_front_end};
 use crate::inputmap::InputMap;
+use crate::overlay::lucidity_pair_approve::ActivePane;
 use crate::overlay::{
     confirm_close_pane, confirm_close_tab, confirm_close_window, confirm_quit_program, launcher,
     start_overlay, start_overlay_pane, CopyModeParams, CopyOverlay, LauncherArgs, LauncherFlags,
@@ -39,7 +40,7 @@ use config::{
     GeometryOrigin, GuiPosition, TermConfig, WindowCloseConfirmation,
 };
 use lfucache::*;
-use lucidity_pairing::PairingRequest;
+use lucidity_pairing::{DeviceScopes, PairingRequest};
 use mlua::{FromLua, LuaSerdeExt, UserData, UserDataFields};
 use mux::pane::{
     CachePolicy, CloseReason, Pane, PaneId, Pattern as MuxPattern, PerformAssignmentResult,
@@ -2392,26 +2393,35 @@ impl TermWindow {
     pub(crate) fn show_lucidity_pairing_approval(
         &mut self,
         request: PairingRequest,
-        tx: mpsc::Sender<bool>,
+        tx: mpsc::Sender<Option<DeviceScopes>>,
     ) {
         let mux = Mux::get();
         let tab = match mux.get_active_tab_for_window(self.mux_window_id) {
             Some(tab) => tab,
             None => {
-                tx.send(false).ok();
+                tx.send(None).ok();
                 return;
             }
         };
+        let active_pane = tab.get_active_pane().map(|pane| ActivePane {
+            pane_id: pane.pane_id(),
+            domain: mux
+                .get_domain(pane.domain_id())
+                .map(|domain| domain.domain_name().to_string()),
+            workspace: mux
+                .get_window(self.mux_window_id)
+                .map(|window| window.get_workspace().to_string()),
+        });
 
         let (overlay, future) = start_overlay(self, &tab, move |_tab_id, term| {
-            crate::overlay::lucidity_pair_approve_overlay(term, request)
+            crate::overlay::lucidity_pair_approve_overlay(term, request, active_pane)
         });
 
         self.assign_overlay(tab.tab_id(), overlay);
 
         promise::spawn::spawn(async move {
-            let approved = future.await.unwrap_or(false);
-            tx.send(approved).ok();
+            let scopes = future.await.unwrap_or(None);
+            tx.send(scopes).ok();
         })
         .detach();
     }
@@ -2857,9 +2867,7 @@ impl TermWindow {
             ShowTabNavigator => self.show_tab_navigator(),
             ShowDebugOverlay => self.show_debug_overlay(),
             ShowLucidityConfig => self.show_lucidity_config(),
-            SetRelayEnabled(enabled) => {
-                crate::RELAY_ENABLED.store(*enabled, std::sync::atomic::Ordering::Relaxed);
-            }
+            SetRelayEnabled(enabled) => crate::set_relay_enabled(*enabled),
             ShowLauncher => self.show_launcher(),
             ShowLauncherArgs(args) => {
                 let title = args.title.clone().unwrap_or("Launcher".to_string());